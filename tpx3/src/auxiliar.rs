@@ -1,5 +1,5 @@
 //!`auxiliar` is a collection of tools to set acquisition conditions.
-use crate::errorlib::Tp3ErrorKind;
+use crate::errorlib::{Tp3ErrorKind, PathContext};
 use std::net::{TcpListener, TcpStream, SocketAddr};
 use crate::auxiliar::misc::TimepixRead;
 use crate::clusterlib::cluster::ClusterCorrectionTypes;
@@ -7,16 +7,24 @@ use crate::errorlib;
 use std::io::{Read, Write, BufWriter};
 use std::fs::File;
 use crate::constlib::*;
+use crate::configlib::{config, Config};
+use crate::modelib::{AcquisitionMode, OutputAddress, OutputSink};
+use crate::controllib;
+use crate::metricslib::metrics;
 use crate::auxiliar::value_types::*;
 use std::fs::OpenOptions;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json;
 
-struct DebugIO {}
+const ACCEPT_POLL: std::time::Duration = std::time::Duration::from_millis(100);
+const SOURCE_POLL: std::time::Duration = std::time::Duration::from_millis(100); //Longest wait for TP3 packets before the measurement checks if it must stop.
+
+///Nionswift socket of the debug measurement. Frames are discarded and nothing is read.
+pub struct DebugIO {}
 impl Write for DebugIO {
-    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
-        Ok(0)
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Ok(buf.len())
     }
     fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
@@ -30,12 +38,15 @@ impl Read for DebugIO {
 }
 
 
-pub struct FileManager (Option<BufWriter<File>>);
+///Raw data saved locally, and its JSON sidecar with the settings.
+pub struct FileManager (Option<BufWriter<File>>, Option<File>);
 
 impl Write for FileManager {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
         if let Some(buffer) = &mut self.0 {
-            buffer.write(buf) //Write to buffer.
+            let size = buffer.write(buf)?; //Write to buffer.
+            metrics().add_bytes_to_disk(size);
+            Ok(size)
         } else {
             Ok(buf.len()) //this is the behaviour as if the buffer is completely written, altough no written operation has been performed.
         }
@@ -51,7 +62,19 @@ impl Write for FileManager {
 
 impl FileManager {
     pub fn new_empty() -> Self {
-        FileManager(None)
+        FileManager(None, None)
+    }
+
+    ///Flushes the raw data and syncs both files to disk.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        if let Some(buffer) = &mut self.0 {
+            buffer.flush()?;
+            buffer.get_ref().sync_all()?;
+        }
+        if let Some(sidecar) = &self.1 {
+            sidecar.sync_all()?;
+        }
+        Ok(())
     }
 }
 
@@ -61,7 +84,7 @@ pub struct Settings {
     pub bin: bool,
     pub bytedepth: POSITION,
     pub cumul: bool,
-    pub mode: u8,
+    pub mode: AcquisitionMode,
     pub xspim_size: POSITION, //Size returned (must be smaller than xscan_size).
     pub yspim_size: POSITION,
     pub xscan_size: POSITION, //Size of the scanning.
@@ -86,14 +109,20 @@ impl Settings {
         let now: DateTime<Utc> = Utc::now();
         let mut val = String::new();
         let custom_datetime_format = now.format("%Y_%m_%d_%H_%M_%S").to_string();
-        val.push_str(SAVE_LOCALLY_FILE);
+        val.push_str(&config().save_locally_file);
         val.push_str(&custom_datetime_format);
         val
     }
 
+    ///The energy calibration (dispersion and offset) of the spectrometer.
+    pub fn energy_calibration(&self) -> (f32, f32) {
+        (self.sup0, self.sup1)
+    }
+
     //Used a lot for postprocessing to open the correct Settings file
     pub fn get_settings_from_json(file: &str) -> Result<Self, Tp3ErrorKind> {
-        let mut json_file = File::open(file.to_owned() + ".json")?;
+        let json_path = file.to_owned() + ".json";
+        let mut json_file = File::open(&json_path).with_path(&json_path)?;
         let mut json_buffer: Vec<u8> = Vec::new();
         json_file.read_to_end(&mut json_buffer)?;
         let my_settings: Settings = serde_json::from_slice(&json_buffer)?;
@@ -102,49 +131,53 @@ impl Settings {
 
     pub fn create_file(&self) -> Result<FileManager, errorlib::Tp3ErrorKind> {
         match self.save_locally {
-            false => {Ok(FileManager(None))},
+            false => {Ok(FileManager::new_empty())},
             true => {
+            let header = self.create_savefile_header();
+            let json_path = header.clone() + ".json";
             let mut jsonfile = 
                 OpenOptions::new()
                 .create(true)
                 .append(true)
-                .open(self.create_savefile_header() + ".json")?;
+                .open(&json_path).with_path(&json_path)?;
             let jsondata = serde_json::to_vec(&self).expect("Could not serialize data to JSON.");
-            jsonfile.write(&jsondata).expect("Could not write to JSON data file.");
+            jsonfile.write_all(&jsondata).with_path(&json_path)?;
+            let tpx3_path = header + ".tpx3";
             let file =
                 OpenOptions::new()
                 .create(true)
                 .append(true)
-                .open(self.create_savefile_header() + ".tpx3")?;
-            Ok(FileManager(Some(BufWriter::new(file))))
+                .open(&tpx3_path).with_path(&tpx3_path)?;
+            Ok(FileManager(Some(BufWriter::new(file)), Some(jsonfile)))
             }
         }
     }
     pub fn create_ttx_file(&self, prefix: &str) -> Result<FileManager, errorlib::Tp3ErrorKind> {
         match self.save_locally {
-            false => {Ok(FileManager(None))},
+            false => {Ok(FileManager::new_empty())},
             true => {
+            let ttx_path = self.create_savefile_header() + prefix + ".ttx";
             let file =
                 OpenOptions::new()
                 .create(true)
                 .append(true)
-                .open(self.create_savefile_header() + prefix + ".ttx")?;
-            Ok(FileManager(Some(BufWriter::new(file))))
+                .open(&ttx_path).with_path(&ttx_path)?;
+            Ok(FileManager(Some(BufWriter::new(file)), None))
             }
         }
     }
 
     ///Create Settings structure reading from a TCP.
-    pub fn create_settings(host_computer: [u8; 4], port: u16) -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, TcpStream), Tp3ErrorKind> {
+    pub fn create_settings(config: &Config) -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, TcpStream), Tp3ErrorKind> {
     
         let mut _sock_vec: Vec<TcpStream> = Vec::new();
         
         let addrs = [
-            SocketAddr::from((host_computer, port)),
-            SocketAddr::from(([127, 0, 0, 1], port)),
+            SocketAddr::from((config.nionswift_ip_address, config.nionswift_port)),
+            SocketAddr::from(([127, 0, 0, 1], config.nionswift_port)),
         ];
         
-        let pack_listener = TcpListener::bind("127.0.0.1:8098").expect("Could not bind to TP3.");
+        let pack_listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], config.timepix_port))).expect("Could not bind to TP3.");
         let ns_listener = TcpListener::bind(&addrs[..]).expect("Could not bind to NS.");
         println!("***AUXILIAR***: Packet Tcp socket connected at: {:?}", pack_listener);
         println!("***AUXILIAR***: Nionswift Tcp socket connected at: {:?}", ns_listener);
@@ -154,7 +187,7 @@ impl Settings {
             _ => false,
         };
 
-        let (mut ns_sock, ns_addr) = ns_listener.accept().expect("Could not connect to Nionswift.");
+        let (mut ns_sock, ns_addr) = accept_until_shutdown(&ns_listener)?;
         println!("***AUXILIAR***: Nionswift connected at {:?} and {:?}.", ns_addr, ns_sock);
         
         //Reading from a JSON over TCP
@@ -165,12 +198,13 @@ impl Settings {
 
         match debug {
             false => {
-                let (pack_sock, packet_addr) = pack_listener.accept().expect("Could not connect to TP3.");
+                let (pack_sock, packet_addr) = accept_until_shutdown(&pack_listener)?;
+                pack_sock.set_read_timeout(Some(SOURCE_POLL)).map_err(|source| Tp3ErrorKind::SetNoSocket {address: format!("{:?}", packet_addr), source})?;
                 println!("***AUXILIAR***: Localhost TP3 detected at {:?} and {:?}.", packet_addr, pack_sock);
                 Ok((my_settings, Box::new(pack_sock), ns_sock))
             },
             true => {
-                let file = match File::open(READ_DEBUG_FILE) {
+                let file = match File::open(&config.read_debug_file) {
                     Ok(file) => file,
                     //Err(_) => return Err(Tp3ErrorKind::SetNoReadFile),
                     Err(_) => {
@@ -233,23 +267,62 @@ impl Settings {
         }
     }
     */
-
-    
-    pub fn create_debug_settings() -> Result<(Settings, Box<dyn misc::TimepixRead + Send>, Box<dyn Write + Send>), Tp3ErrorKind> {
     
-        println!("***AUXILIAR***: Debug settings are {:?}", READ_DEBUG_FILE_JSON);
-        let my_settings = Settings::get_settings_from_json(READ_DEBUG_FILE_JSON)?;
-        println!("***AUXILIAR***: Received settings is {:?}. Mode is {}.", my_settings, my_settings.mode);
+}
 
-        let in_file = match File::open(READ_DEBUG_FILE) {
-            Ok(file) => file,
-            Err(_) => return Err(Tp3ErrorKind::SetNoReadFile),
-        };
+///The listeners of the extra outputs. They are bound once, at startup, and each measurement
+///takes the clients already connected, so a missing client never holds the TP3 stream.
+pub struct OutputListeners(Vec<(OutputAddress, TcpListener)>);
+
+impl OutputListeners {
+    pub fn bind(outputs: &[OutputAddress]) -> Result<Self, Tp3ErrorKind> {
+        let mut listeners = Vec::new();
+        for output in outputs {
+            let no_socket = |source| Tp3ErrorKind::SetNoSocket {address: output.address.clone(), source};
+            let listener = TcpListener::bind(&output.address).map_err(no_socket)?;
+            listener.set_nonblocking(true).map_err(no_socket)?;
+            println!("***AUXILIAR***: Output of mode {:?} waiting at {:?}.", output.mode, listener);
+            listeners.push((output.clone(), listener));
+        }
+        Ok(OutputListeners(listeners))
+    }
+
+    ///Takes a client for each output that has one. The others are skipped in this measurement.
+    pub fn accept(&self) -> Result<Vec<OutputSink>, Tp3ErrorKind> {
+        let mut sinks: Vec<OutputSink> = Vec::new();
+        for (output, listener) in &self.0 {
+            let no_socket = |source| Tp3ErrorKind::SetNoSocket {address: output.address.clone(), source};
+            match listener.accept() {
+                Ok((sock, addr)) => {
+                    sock.set_nonblocking(false).map_err(no_socket)?;
+                    println!("***AUXILIAR***: Output of mode {:?} connected at {:?}.", output.mode, addr);
+                    sinks.push((output.clone(), Box::new(sock)));
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    println!("***AUXILIAR***: Output of mode {:?} has no client at {}. It is skipped.", output.mode, output.address);
+                },
+                Err(e) => return Err(no_socket(e)),
+            }
+        }
+        Ok(sinks)
+    }
+}
 
-        println!("Spectra Debug mode. Will one file a single time.");
-        Ok((my_settings, Box::new(in_file), Box::new(DebugIO{})))
+///Waits for a connection, checking for a shutdown request (SIGINT/SIGTERM) while waiting.
+fn accept_until_shutdown(listener: &TcpListener) -> Result<(TcpStream, SocketAddr), Tp3ErrorKind> {
+    let no_socket = |source| Tp3ErrorKind::SetNoSocket {address: format!("{:?}", listener.local_addr()), source};
+    listener.set_nonblocking(true).map_err(no_socket)?;
+    loop {
+        if controllib::shutdown_requested() {return Err(Tp3ErrorKind::MiscShutdown);}
+        match listener.accept() {
+            Ok((sock, addr)) => {
+                sock.set_nonblocking(false).map_err(no_socket)?;
+                return Ok((sock, addr));
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => std::thread::sleep(ACCEPT_POLL),
+            Err(e) => return Err(no_socket(e)),
+        }
     }
-    
 }
 
 ///`ConfigAcquisition` is used for post-processing, where reading external TPX3 files is necessary.
@@ -318,11 +391,12 @@ pub mod simple_log {
         Ok(())
     }
 
+    ///Logs the error with its chain of sources, followed by its debug representation.
     pub fn error(file: &mut File, error: Tp3ErrorKind) -> io::Result<()> {
         let date = Local::now().to_string();
         file.write_all(date.as_bytes())?;
         file.write_all(b" - ERROR ")?;
-        let error = format!("{:?}", error);
+        let error = format!("{} ({:?})", error.chain(), error);
         file.write_all(error.as_bytes())?;
         file.write_all(b"\n")?;
         Ok(())
@@ -349,6 +423,11 @@ pub mod misc {
         }
     }
 
+    ///A read of a socket with a timeout that had nothing to read.
+    pub fn is_timeout(error: &std::io::Error) -> bool {
+        matches!(error.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut)
+    }
+
     pub fn default_read_exact<R: Read + ?Sized>(this: &mut R, mut buf: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
         let mut size = 0;
         while size == 0 || size % 8 != 0 {
@@ -359,13 +438,15 @@ pub mod misc {
                     let tmp = buf;
                     buf = &mut tmp[n..];
                 }
-                Err(_) => return Err(Tp3ErrorKind::TimepixReadLoop),
+                //A packet was started, so the rest of it is on the way.
+                Err(source) if size > 0 && is_timeout(&source) => {},
+                Err(source) => return Err(Tp3ErrorKind::TimepixReadLoop {offset: size, source}),
             };
         };
         if size != 0 && size % 8 == 0 {
             Ok(size)
         } else {
-            Err(Tp3ErrorKind::TimepixReadOver)
+            Err(Tp3ErrorKind::TimepixReadOver {offset: size})
         }
     }
 
@@ -395,7 +476,11 @@ pub mod misc {
         }
     }
 
-    impl<R: Read + ?Sized> TimepixRead for Box<R> {}
+    impl<R: TimepixRead + ?Sized> TimepixRead for Box<R> {
+        fn read_timepix(&mut self, buf: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
+            (**self).read_timepix(buf)
+        }
+    }
     impl TimepixRead for TcpStream {}
     impl TimepixRead for File {}
 
@@ -526,20 +611,31 @@ pub mod value_types {
 
 pub mod raw_into_readable {
     use std::fs;
-    use std::io::prelude::*;
-    use crate::constlib::*;
     use indicatif::{ProgressBar, ProgressStyle};
     use crate::clusterlib::cluster::{SinglePhoton, SingleElectron};
-    use crate::packetlib::Packet;
-    use crate::auxiliar::{value_types::*, misc::{output_data, packet_change}};
+    use crate::auxiliar::value_types::*;
     use crate::tdclib::TdcType;
     use crate::errorlib::*;
+    use crate::exportlib::Exporter;
+    use crate::eventlib::{Column, ColumnData, EventWriter, Provenance, EVENT_FILE};
+    use crate::readerlib::{Event, MappedFile};
+    use crate::constlib::MMAP_CHUNK_SIZE;
+
+    fn event_columns() -> Vec<Column> {
+        vec![
+            Column::new::<POSITION>("xH", "pixel", "Dispersive position. TDC hits are 1025 to 1028 (TDC1 rising, TDC1 falling, TDC2 rising, TDC2 falling)."),
+            Column::new::<POSITION>("yH", "pixel", "Non-dispersive position. 0 for TDC hits."),
+            Column::new::<TIME>("tH", "0.260 ns", "Hit time. Electron times are multiplied by 6, so they are in units of 0.260/6 ns."),
+        ]
+    }
 
     struct ToReadable {
         x: Vec<POSITION>, //If None -> TDC hit
         y: Vec<POSITION>, //If None -> TDC hit
         time: Vec<TIME>, // For both Electron 
         file: String,
+        exporter: Option<Exporter>, //Created with the output folder
+        events: Option<EventWriter>, //Created with the output folder
     }
     impl ToReadable {
         fn add_electron(&mut self, ele: SingleElectron) {
@@ -548,11 +644,17 @@ pub mod raw_into_readable {
             self.time.push(ele.time() * 6);
         }
         
-        fn try_create_folder(&self) -> Result<(), Tp3ErrorKind> {
+        fn try_create_folder(&mut self) -> Result<(), Tp3ErrorKind> {
             let path_length = &self.file.len();
-            match fs::create_dir(&self.file[..path_length - 5]) {
-                Ok(_) => {Ok(())},
-                Err(_) => { Err(Tp3ErrorKind::FolderAlreadyCreated) }
+            let folder = &self.file[..path_length - 5];
+            match fs::create_dir(folder) {
+                Ok(_) => {
+                    self.exporter = Some(Exporter::new(folder, None)?);
+                    let provenance = Provenance::from_source(&self.file, "auxiliar::raw_into_readable");
+                    self.events = Some(EventWriter::create(std::path::Path::new(folder).join(EVENT_FILE), event_columns(), None, provenance)?);
+                    Ok(())
+                },
+                Err(source) => { Err(Tp3ErrorKind::FolderAlreadyCreated {path: folder.to_owned(), source}) }
             }
         }
         fn add_tdc(&mut self, tdc: SinglePhoton) {
@@ -569,68 +671,78 @@ pub mod raw_into_readable {
             self.y.push(0);
             self.time.push(tdc.time());
         }
-        fn early_output_data(&mut self) {
-            output_data(&self.x, self.file.clone(), "xH.txt");
-            output_data(&self.y, self.file.clone(), "yH.txt");
-            output_data(&self.time, self.file.clone(), "tH.txt");
+        fn early_output_data(&mut self) -> Result<(), Tp3ErrorKind> {
+            let exporter = self.exporter.as_mut().expect("The exporter is created with the output folder.");
+            exporter.append("xH", &[], &self.x)?;
+            exporter.append("yH", &[], &self.y)?;
+            exporter.append("tH", &[], &self.time)?;
+            let events = self.events.as_mut().expect("The event file is created with the output folder.");
+            events.write_block(&[ColumnData::new(&self.x), ColumnData::new(&self.y), ColumnData::new(&self.time)])?;
             self.x.clear();
             self.y.clear();
             self.time.clear();
+            Ok(())
+        }
+        fn finish(&mut self) -> Result<(), Tp3ErrorKind> {
+            if let Some(events) = self.events.take() {
+                events.finish()?;
+            }
+            match self.exporter.take() {
+                Some(exporter) => exporter.finish(),
+                None => Ok(()),
+            }
         }
         fn new(file: String) -> Self {
             ToReadable {
                 x: Vec::new(),
                 y: Vec::new(),
                 time: Vec::new(),
-                file
+                file,
+                exporter: None,
+                events: None,
             }
         }
     }
 
     pub fn build_data(path: &str, limit_read_size: u32) -> Result<(), Tp3ErrorKind> {
-        //Opening the raw data file. We have already checked if the file opens so no worries here.
-        let mut file = fs::File::open(path).unwrap();
-
-        let progress_size = file.metadata().unwrap().len();
-        let mut ci = 0;
-
-        let mut buffer: Vec<u8> = vec![0; TP3_BUFFER_SIZE];
-        let mut total_size = 0;
+        //The file is mapped in memory and its chunks are decoded in parallel.
+        let file = MappedFile::open(path)?;
+        let chunks = file.chunks(MMAP_CHUNK_SIZE, limit_read_size as usize);
+        let progress_size = chunks.last().map_or(0, |chunk| chunk.end);
 
         let mut data_handler = ToReadable::new(path.to_string());
         data_handler.try_create_folder()?;
         
-        let bar = ProgressBar::new(progress_size);
+        let bar = ProgressBar::new(progress_size as u64);
         bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.white/black} {percent}% {pos:>7}/{len:7} [ETA: {eta}] Searching electron photon coincidences")
                       .unwrap()
                       .progress_chars("=>-"));
 
-        while let Ok(size) = file.read(&mut buffer) {
-            if size == 0 {println!("Finished Reading."); break;}
-            total_size += size;
-            if limit_read_size != 0 && total_size as u32 >= limit_read_size {break;}
-            bar.inc(TP3_BUFFER_SIZE as u64);
-            buffer[0..size].chunks_exact(8).enumerate().for_each(|(current_raw_index, pack_oct)| {
-                let packet = Packet::new(ci, packet_change(pack_oct)[0]);
-                match *pack_oct {
-                    [84, 80, 88, 51, nci, _, _, _] => { ci=nci; },
-                    _ => {
-                        match packet.id() {
-                            6 => { //TDC hit
-                                let photon = SinglePhoton::new(packet, 0, None, current_raw_index);
-                                data_handler.add_tdc(photon);
-                            },
-                            11 => { //Electron hit
-                                let se = SingleElectron::new(packet, None, current_raw_index, None);
-                                data_handler.add_electron(se);
-                            },
-                            _ => {},
-                        };
+        file.for_each_ordered(&chunks, |_, events| {
+            let mut chunk_handler = ToReadable::new(String::new());
+            events.enumerate().for_each(|(current_raw_index, raw)| {
+                match raw.event {
+                    Event::Tdc(packet) => { //TDC hit
+                        let photon = SinglePhoton::new(packet, 0, None, current_raw_index);
+                        chunk_handler.add_tdc(photon);
+                    },
+                    Event::Pixel(packet) if packet.id() == 11 => { //Electron hit
+                        let se = SingleElectron::new(packet, None, current_raw_index, None);
+                        chunk_handler.add_electron(se);
                     },
+                    _ => {},
                 };
             });
-            data_handler.early_output_data();
-        }
-        Ok(())
+            chunk_handler
+        }, |chunk, chunk_handler| {
+            bar.inc(chunk.len() as u64);
+            data_handler.x = chunk_handler.x;
+            data_handler.y = chunk_handler.y;
+            data_handler.time = chunk_handler.time;
+            data_handler.early_output_data()
+        })?;
+        bar.finish();
+        println!("Finished Reading.");
+        data_handler.finish()
     }
 }
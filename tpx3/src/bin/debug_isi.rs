@@ -4,14 +4,15 @@
 use timepix3::auxiliar::ConfigAcquisition;
 use timepix3::clusterlib::cluster;
 //use timepix3::cluster_correction;
+use timepix3::configlib;
 use std::env;
 //use timepix3::isi_box_new;
 //use std::{thread, time};
 //use std::fs::File;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let cluster_correction_type = cluster::grab_cluster_correction(&args[5]);
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+    let cluster_correction_type = cluster::grab_cluster_correction(&args[5]).expect("Unknown cluster correction.");
     let _config_set = ConfigAcquisition::new(&args, cluster_correction_type);
     /*
     //TODO: Must fix this otherwise no data will be output.
@@ -2,11 +2,14 @@ use timepix3::postlib::ntime_resolved::*;
 use timepix3::auxiliar::{ConfigAcquisition, Settings};
 use timepix3::clusterlib::cluster;
 use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::configlib;
+use timepix3::modelib::AcquisitionMode;
 use std::{fs, env};
 use rayon::prelude::*;
 
 fn main() -> Result<(), Tp3ErrorKind> {
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+    configlib::init_calibrations()?;
     
     println!("
     ***Instructions***:
@@ -21,10 +24,20 @@ fn main() -> Result<(), Tp3ErrorKind> {
         -> (mode == 2) => Hyperspectral image;
         -> (mode != 2) => 4D image;
             o xscan_size & yscan_size = Spatial sampling;
-        -> Cluster correction can be activated by parsing a second argument, varying from 0 to 5. There are three automatic cluster correction settings:
-            o '0' => No correction;
-            o '1' => Average;
-            o '2' => Maximum ToT;
+        -> Cluster correction can be activated by parsing a second argument, by its number or by its name followed by the parameters
+        separated by ':' (for example, 'closest_tot:50:20:100'). The parameters not given take the default values:
+            o '0' or 'no_correction' => No correction;
+            o '1' or 'average' => Average;
+            o '2' or 'largest_tot' => Maximum ToT;
+            o '3' or 'largest_tot_threshold:<min>:<max>' => Maximum ToT, if between min (20) and max (100);
+            o '4' or 'closest_tot:<reference>:<min>:<max>' => ToT closest to the reference (50), if between min (20) and max (100);
+            o '5' or 'fixed_tot:<reference>' => Average, with the time of the electrons with the reference ToT (10);
+            o '6' or 'fixed_tot_calibration:<energy>:<sum>' => Clusters of 3 or 4 electrons with one at the reference energy (30 keV)
+            and adding to the sum (60 keV). It needs a ToT calibration;
+            o '7' or 'no_correction_verbose' => No correction, keeping the cluster size;
+            o '8' or 'single_cluster' => Only the clusters of a single electron;
+            o '9' or 'muon_track' => Every electron of the clusters with more than one;
+            o '10' or 'centroid' => One electron per connected cluster, at its ToT-weighted centroid;
         -> The other fields of the json are currently not considered, but they give you the parameters you have used during data
         acquisition. sup0 & sup1, for example, are the EELS dispersion and offset, respectively;
 
@@ -37,6 +50,7 @@ fn main() -> Result<(), Tp3ErrorKind> {
         &args[2]
     };
 
+    let correction_type = cluster::grab_cluster_correction(cluster_correction).expect("***Time resolved***: Unknown cluster correction.");
     
     let entries = fs::read_dir(&args[1]).unwrap();
     entries.into_iter().par_bridge().for_each(|x| {
@@ -46,7 +60,7 @@ fn main() -> Result<(), Tp3ErrorKind> {
         if &dir[path_length - 4 ..path_length] == "tpx3" {
             match Settings::get_settings_from_json(&dir[0..path_length - 5]) {
                 Ok(settings) => {
-                    let config_set = ConfigAcquisition{file: dir.to_owned(), is_spim: settings.mode != 0, xspim: settings.xscan_size, yspim: settings.yscan_size, correction_type: cluster::grab_cluster_correction(cluster_correction)};
+                    let config_set = ConfigAcquisition{file: dir.to_owned(), is_spim: settings.mode != AcquisitionMode::Live, xspim: settings.xscan_size, yspim: settings.yscan_size, correction_type: correction_type.clone()};
                     println!("***Time resolved***: File {} has the following settings from json: {:?}.", dir, settings);
                     let mut meas = TimeSpectralSpatial::new(config_set, settings).unwrap();
                     if let Err(_) = analyze_data(&mut meas) {
@@ -1,20 +1,17 @@
 use timepix3::postlib::calibration::*;
 use timepix3::auxiliar::ConfigAcquisition;
 use timepix3::clusterlib::cluster;
+use timepix3::configlib;
 use std::env;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
 
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+    configlib::init_calibrations()?;
     
-    //Pick ONE!
-    //let config_set = ConfigAcquisition::new(&args, cluster::ClusterCorrectionTypes::FixedToTCalibration(30, 60));
-    //let config_set = ConfigAcquisition::new(&args, cluster::ClusterCorrectionTypes::MuonTrack);
-    //let config_set = ConfigAcquisition::new(&args, cluster::SingleClusterToTCalibration);
-    let config_set = ConfigAcquisition::new(&args, cluster::ClusterCorrectionTypes::NoCorrection);
-    
-    //let config_set = ConfigAcquisition::new(&args, cluster::NoCorrectionVerbose);
-    //let config_set = ConfigAcquisition::new(&args, cluster::AverageCorrection);
+    //The cluster correction is the fifth argument, by number or by name (for example, 'fixed_tot_calibration:30:60' or 'muon_track').
+    let correction_type = args.get(5).map_or(Ok(cluster::ClusterCorrectionTypes::NoCorrection), |name| cluster::grab_cluster_correction(name))?;
+    let config_set = ConfigAcquisition::new(&args, correction_type);
     calibrate(&config_set.file(), &config_set.correction_type).unwrap();
     Ok(())
 }
@@ -1,12 +1,14 @@
 use timepix3::postlib::coincidence::*;
 use timepix3::clusterlib::cluster;
 use timepix3::auxiliar::Settings;
+use timepix3::configlib;
 use std::{fs, env};
 use rayon::prelude::*;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+    configlib::init_calibrations()?;
 
     println!("
     ***Instructions***:
@@ -22,13 +24,25 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         -> (mode != 0) => No hyperspectral image;
         -> (mode == 2) => Hyperspectral image;
             o xscan_size & yscan_size => Hyperspectral image sampling;
-        -> Cluster correction can be activated by parsing a second argument, varying from 0 to 5. There are three automatic cluster correction settings:
-            o '0' => No correction;
-            o '1' => Average;
-            o '2' => Maximum ToT;
+        -> Cluster correction can be activated by parsing a second argument, by its number or by its name followed by the parameters
+        separated by ':' (for example, 'closest_tot:50:20:100'). The parameters not given take the default values:
+            o '0' or 'no_correction' => No correction;
+            o '1' or 'average' => Average;
+            o '2' or 'largest_tot' => Maximum ToT;
+            o '3' or 'largest_tot_threshold:<min>:<max>' => Maximum ToT, if between min (20) and max (100);
+            o '4' or 'closest_tot:<reference>:<min>:<max>' => ToT closest to the reference (50), if between min (20) and max (100);
+            o '5' or 'fixed_tot:<reference>' => Average, with the time of the electrons with the reference ToT (10);
+            o '6' or 'fixed_tot_calibration:<energy>:<sum>' => Clusters of 3 or 4 electrons with one at the reference energy (30 keV)
+            and adding to the sum (60 keV). It needs a ToT calibration;
+            o '7' or 'no_correction_verbose' => No correction, keeping the cluster size;
+            o '8' or 'single_cluster' => Only the clusters of a single electron;
+            o '9' or 'muon_track' => Every electron of the clusters with more than one;
+            o '10' or 'centroid' => One electron per connected cluster, at its ToT-weighted centroid;
         -> The other fields of the json are currently not considered, but they give you the parameters you have used during data
         acquisition. sup0 & sup1, for example, are the EELS dispersion and offset, respectively;
         -> The time delay and time width are defined at compile-time, so you should change them at constlib.rs insted;
+        -> The instrument configuration (TDCs, detector orientation, cluster window) can be given with '--config <file.json>' and
+        single values overriden with '--set <field>=<value>';
 
     "
     );
@@ -39,6 +53,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         &args[2]
     };
 
+    let correction_type = cluster::grab_cluster_correction(cluster_correction).expect("***Coincidence***: Unknown cluster correction.");
+
     let entries = fs::read_dir(&args[1]).unwrap();
     entries.into_iter().par_bridge().for_each(|x| {
         let path = x.unwrap().path();
@@ -47,12 +63,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         if &dir[path_length - 4 ..path_length] == "tpx3" {
             if let Ok(settings) = Settings::get_settings_from_json(&dir[0..path_length - 5]) {
                 println!("***Coincidence***: File {} has the following settings from json: {:?}.", dir, settings);
-                let mut electron_data = ElectronDataSettings::new(dir.to_owned(), cluster::grab_cluster_correction(cluster_correction), settings, true);
+                let mut electron_data = ElectronDataSettings::new(dir.to_owned(), correction_type.clone(), settings, true);
                 if let Err(error) = electron_data.prepare_to_search() {
                     println!("***Coincidence***: Error during prepare: {:?}.", error);
                     return;
                 }
-                search_coincidence(electron_data, 0);
+                if let Err(error) = search_coincidence(electron_data, 0) {
+                    println!("***Coincidence***: Error during search: {}.", error);
+                }
             } else {
                 println!("***Coincidence***: Skipping file {}. No JSON file has been found.", dir);
          }
@@ -0,0 +1,41 @@
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::eventlib::Provenance;
+use timepix3::pixellib::{FloodThresholds, PixelCalibration};
+use timepix3::configlib;
+use std::env;
+
+fn flat(args: &[String]) -> Result<(), Tp3ErrorKind> {
+    let file = args.get(1).ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(String::from("<flood.tpx3>")))?;
+    let output = args.get(2).ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(String::from("<calibration.tpev>")))?;
+    let mut thresholds = FloodThresholds::default();
+    if let Some(dead) = args.get(3) {
+        thresholds.dead = dead.parse().map_err(|_| Tp3ErrorKind::ConfigBadArgument(dead.clone()))?;
+    }
+    if let Some(hot) = args.get(4) {
+        thresholds.hot = hot.parse().map_err(|_| Tp3ErrorKind::ConfigBadArgument(hot.clone()))?;
+    }
+
+    let calibration = PixelCalibration::from_flood_field(file, thresholds)?;
+    calibration.save(output, Provenance::from_source(file, "tp3_flat"))
+}
+
+fn main() {
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+
+    println!("
+    ***Instructions***:
+
+    The first argument is a recorded flood field (.tpx3), with the detector uniformly illuminated. The second argument is
+    the calibration file to create. The third and fourth optional arguments are the counts, relative to the median, below
+    which a pixel is dead (default 0.2) and above which it is hot (default 5). Set pixel_calibration_file in the
+    configuration to use the calibration.
+
+    Example: tp3_flat flood.tpx3 pixels.tpev 0.2 5
+    "
+    );
+
+    match flat(&args) {
+        Ok(()) => println!("***Flat***: Calibration created."),
+        Err(e) => println!("***Flat***: Error creating the calibration. Message is: {}.", e.chain()),
+    }
+}
@@ -1,10 +1,11 @@
 use timepix3::auxiliar::raw_into_readable;
+use timepix3::configlib;
 use std::{fs, env};
 use rayon::prelude::*;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     
-    let args: Vec<String> = env::args().collect();
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
 
     println!("
     ***Instructions***:
@@ -0,0 +1,51 @@
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::replaylib::{self, ReplaySink, ReplaySource};
+use timepix3::configlib;
+use std::env;
+
+fn replay(args: &[String]) -> Result<u8, Tp3ErrorKind> {
+    let file = args.get(1).ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(String::from("<file.tpx3>")))?;
+    let speed = match args.get(3).map(|speed| speed.parse::<f64>()) {
+        Some(Ok(speed)) if speed > 0.0 => Some(speed),
+        Some(Ok(_)) | None => None,
+        Some(Err(_)) => return Err(Tp3ErrorKind::ConfigBadArgument(args[3].clone())),
+    };
+
+    let my_settings = replaylib::settings_from_sidecar(file)?;
+    println!("***Replay***: Settings are {:?}.", my_settings);
+    let source = ReplaySource::open(file, speed)?;
+    let sink = match args.get(2).map(|output| output.as_str()) {
+        None | Some("-") => ReplaySink::discard(),
+        Some(output) => match output.strip_prefix("tcp://") {
+            Some(address) => ReplaySink::connect(address)?,
+            None => ReplaySink::to_file(output)?,
+        },
+    };
+    let sink = match args.get(4) {
+        Some(scan_list) => sink.with_scan_list(scan_list)?,
+        None => sink,
+    };
+    replaylib::replay(my_settings, source, sink)
+}
+
+fn main() {
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+    configlib::init_calibrations().expect("Could not load the calibrations.");
+
+    println!("
+    ***Instructions***:
+
+    The first argument is the recorded .tpx3 file. Settings are read from the .json file of the same name.
+    The second optional argument is the output: a file, tcp://<host>:<port> to send it to a listening client, or - to
+    discard it (default). The third optional argument is the replay speed relative to the recording (1 is real time, 0 is
+    as fast as possible, default). The fourth optional argument is the scan list file for mode 14.
+
+    Example: tp3_replay 2025_10_06_14_55_40.tpx3 tcp://127.0.0.1:8088 1
+    "
+    );
+
+    match replay(&args) {
+        Ok(val) => println!("***Replay***: Replay over. Mode is {}.", val),
+        Err(e) => println!("***Replay***: Error in the replay. Message is: {}.", e.chain()),
+    }
+}
@@ -0,0 +1,35 @@
+use timepix3::simlib::{SimSettings, Simulator};
+use timepix3::configlib;
+use std::{fs, env};
+use std::io::BufWriter;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+
+    println!("
+    ***Instructions***:
+
+    The first argument is the output .tpx3 file. A second optional argument is a json file with the simulation settings. Missing
+    fields take their default values. All times are in units of 0.260 ns and rates are in events per second.
+
+    Example of the json file:
+
+    {{\"seed\": 1, \"duration\": 384000000, \"electron_rate\": 2000000.0, \"spectrum\": {{\"Peaks\": [{{\"center\": 300.0, \"sigma\": 4.0, \"weight\": 1.0}}]}},
+    \"periodic\": [{{\"tdc_type\": \"TdcOneRisingEdge\", \"period\": 38400, \"high_time\": 7680, \"offset\": 1000}}],
+    \"photons\": {{\"tdc_type\": \"TdcTwoRisingEdge\", \"rate\": 100000.0, \"coincidence_fraction\": 0.05, \"delay\": 400, \"jitter\": 10.0}}}}
+    "
+    );
+
+    let settings = match args.get(2) {
+        Some(file) => serde_json::from_slice(&fs::read(file)?)?,
+        None => SimSettings::default(),
+    };
+    println!("***Sim***: Simulation settings are {:?}.", settings);
+
+    let mut file = BufWriter::new(fs::File::create(&args[1])?);
+    let mut simulator = Simulator::new(settings)?;
+    simulator.write_all(&mut file)?;
+    println!("***Sim***: Stream created. Summary is {:?}.", simulator.summary());
+    Ok(())
+}
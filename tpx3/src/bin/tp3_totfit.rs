@@ -0,0 +1,54 @@
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::eventlib::Provenance;
+use timepix3::energylib::{TotCalibration, TotSamples};
+use timepix3::configlib;
+use std::env;
+
+fn fit(args: &[String]) -> Result<(), Tp3ErrorKind> {
+    let output = args.get(1).ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(String::from("<calibration.tpev>")))?;
+    let inputs = &args[2..];
+
+    if inputs.first().map(String::as_str) == Some("--tables") {
+        let atot = inputs.get(1).ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(String::from("<atot.dat>")))?;
+        let btot = inputs.get(2).ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(String::from("<btot.dat>")))?;
+        let calibration = TotCalibration::from_tables(atot, btot)?;
+        return calibration.save(output, Provenance::from_source(atot, "tp3_totfit"));
+    }
+
+    let surrogate = inputs.iter().any(|arg| arg == "--surrogate");
+    let mut runs = Vec::new();
+    for run in inputs.iter().filter(|arg| *arg != "--surrogate") {
+        let (energy, file) = run.split_once(':').ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(run.clone()))?;
+        let energy: f32 = energy.parse().map_err(|_| Tp3ErrorKind::ConfigBadArgument(run.clone()))?;
+        let samples = TotSamples::from_events(file)?;
+        println!("***TotFit***: {} hits at {} keV.", samples.hits(), energy);
+        runs.push((energy, samples));
+    }
+
+    let calibration = TotCalibration::fit(&runs, surrogate)?;
+    let source = inputs.iter().filter_map(|run| Some(run.split_once(':')?.1)).collect::<Vec<&str>>().join(",");
+    calibration.save(output, Provenance::from_source(&source, "tp3_totfit"))
+}
+
+fn main() {
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+
+    println!("
+    ***Instructions***:
+
+    The first argument is the calibration file to create. The next ones are runs of tp3_calib, as <energy in keV>:<file.tpev>,
+    with the electrons of a known energy. Two energies give linear pixels. With four energies and --surrogate, the curvature
+    near the threshold is also fitted. Set tot_calibration_file in the configuration to use the calibration.
+
+    The former linear tables are converted with --tables <atot.dat> <btot.dat>.
+
+    Example: tp3_totfit tot.tpev 20:run20.tpev 40:run40.tpev 60:run60.tpev 80:run80.tpev --surrogate
+    Example: tp3_totfit tot.tpev --tables atot_v2.dat btot_v2.dat
+    "
+    );
+
+    match fit(&args) {
+        Ok(()) => println!("***TotFit***: Calibration created."),
+        Err(e) => println!("***TotFit***: Error creating the calibration. Message is: {}.", e.chain()),
+    }
+}
@@ -0,0 +1,39 @@
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::walklib::{self, TimeWalk};
+use timepix3::configlib;
+use std::env;
+
+fn fit(args: &[String]) -> Result<(), Tp3ErrorKind> {
+    let output = args.get(1).ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(String::from("<walk.json>")))?;
+    let per_pixel = args[2..].iter().any(|arg| arg == "--per-pixel");
+    let mut samples = Vec::new();
+    for file in args[2..].iter().filter(|arg| *arg != "--per-pixel") {
+        let run = walklib::samples_from_events(file)?;
+        println!("***Walk***: {} coincidences in {}.", run.len(), file);
+        samples.extend(run);
+    }
+
+    let walk = TimeWalk::fit(&samples, per_pixel)?;
+    println!("***Walk***: The delay goes from {:.1} to {:.1} TDC units.", walk.curve()[0], walk.curve()[walklib::TOT_VALUES - 1]);
+    walk.save(output)
+}
+
+fn main() {
+    let args: Vec<String> = configlib::init_from_args(env::args()).expect("Could not load the configuration.");
+
+    println!("
+    ***Instructions***:
+
+    The first argument is the correction file to create. The next ones are coincidence event files of tp3_post (with
+    the columns xH, yH, tot and tH). With --per-pixel, the delay of every pixel is also fitted. Set time_walk_file in the
+    configuration to use the correction.
+
+    Example: tp3_walk walk.json coinc_0.tpev coinc_1.tpev --per-pixel
+    "
+    );
+
+    match fit(&args) {
+        Ok(()) => println!("***Walk***: Correction created."),
+        Err(e) => println!("***Walk***: Error creating the correction. Message is: {}.", e.chain()),
+    }
+}
@@ -0,0 +1,144 @@
+//!`broadcastlib` fans the live output out to other clients. Nionswift drives the measurement, and
+//!any number of subscribers (a logging client, a web viewer) can connect to the address given to
+//!`serve_subscribers` and receive the same bytes: the JSON header and frame of `speclib`, or the
+//!index lists of `spimlib`. Subscribers stay connected between measurements.
+//!
+//!The subscribers have their own port, `config().subscriber_address`, instead of sharing the one
+//!of Nionswift. That port is only listened to between measurements, and the client that connects
+//!to it sends the settings of the next one. A subscriber there would be taken for Nionswift, and
+//!it could not stay connected across measurements.
+//!
+//!Each subscriber has a queue of whole outputs and its own writer thread, so a slow subscriber
+//!never stalls the acquisition unless its policy is `SlowConsumer::Block`. With `Drop`, the
+//!outputs that do not fit in the queue are skipped, and the subscriber only receives whole ones.
+//!The extra outputs of `pipelinelib` are fed by the same `Queue`.
+use crate::pipelinelib::Sink;
+use crate::metricslib::metrics;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+
+static SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(Vec::new());
+
+///What to do with a subscriber whose queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SlowConsumer {
+    ///The output is not sent to this subscriber.
+    #[default]
+    Drop,
+    ///The acquisition waits for the subscriber.
+    Block,
+}
+
+///A bounded queue to another thread. What happens when it is full is set by its `SlowConsumer`.
+pub struct Queue<T> {
+    sender: SyncSender<T>,
+    policy: SlowConsumer,
+}
+
+impl<T> Queue<T> {
+    ///A queue of up to `size` items, and the end the other thread reads them from.
+    pub fn new(size: usize, policy: SlowConsumer) -> (Self, Receiver<T>) {
+        let (sender, receiver) = mpsc::sync_channel(size.max(1));
+        (Queue {sender, policy}, receiver)
+    }
+
+    ///Queues an item. A full queue waits with `Block`, and skips the item with `Drop`. False if
+    ///the other end is gone.
+    pub fn push(&self, item: T) -> bool {
+        match self.policy {
+            SlowConsumer::Block => self.sender.send(item).is_ok(),
+            SlowConsumer::Drop => match self.sender.try_send(item) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    metrics().add_output_dropped();
+                    true
+                },
+                Err(TrySendError::Disconnected(_)) => false,
+            },
+        }
+    }
+}
+
+impl<T> Clone for Queue<T> {
+    fn clone(&self) -> Self {
+        Queue {sender: self.sender.clone(), policy: self.policy}
+    }
+}
+
+struct Subscriber {
+    address: SocketAddr,
+    queue: Queue<Arc<Vec<u8>>>,
+}
+
+///Accepts subscribers at `address` in a background thread and returns the bound address. Each
+///subscriber queues up to `queue` outputs. An empty address disables it.
+pub fn serve_subscribers(address: &str, policy: SlowConsumer, queue: usize) -> std::io::Result<Option<SocketAddr>> {
+    if address.is_empty() {return Ok(None);}
+    let listener = TcpListener::bind(address)?;
+    let local_address = listener.local_addr()?;
+    println!("***Broadcastlib***: Subscribers of the live output at {:?}. Slow subscribers policy is {:?}.", local_address, policy);
+    thread::spawn(move || {
+        for sock in listener.incoming().flatten() {
+            if let Err(e) = subscribe(sock, policy, queue) {
+                println!("***Broadcastlib***: Could not add the subscriber. Error is {:?}.", e);
+            }
+        }
+    });
+    Ok(Some(local_address))
+}
+
+fn subscribe(mut sock: TcpStream, policy: SlowConsumer, queue: usize) -> std::io::Result<()> {
+    let address = sock.peer_addr()?;
+    let (queue, rx) = Queue::<Arc<Vec<u8>>>::new(queue, policy);
+    thread::spawn(move || {
+        //The queue is closed when the subscriber is removed, and then the socket too.
+        for output in rx {
+            if sock.write_all(&output).is_err() {break;}
+        }
+        println!("***Broadcastlib***: Subscriber at {:?} disconnected.", address);
+    });
+    println!("***Broadcastlib***: Subscriber connected at {:?}.", address);
+    SUBSCRIBERS.lock().unwrap().push(Subscriber {address, queue});
+    Ok(())
+}
+
+///Number of connected subscribers.
+pub fn subscribers() -> usize {
+    SUBSCRIBERS.lock().unwrap().len()
+}
+
+///Sends an output to every subscriber. The subscribers whose writer is over are removed. The
+///queues are taken out of the list first, so a blocking subscriber does not keep the others from
+///connecting.
+pub fn publish(parts: &[&[u8]]) {
+    let queues: Vec<(SocketAddr, Queue<Arc<Vec<u8>>>)> = SUBSCRIBERS.lock().unwrap().iter()
+        .map(|subscriber| (subscriber.address, subscriber.queue.clone()))
+        .collect();
+    if queues.is_empty() {return;}
+    let output = Arc::new(parts.concat());
+    let gone: Vec<SocketAddr> = queues.into_iter()
+        .filter(|(_, queue)| !queue.push(output.clone()))
+        .map(|(address, _)| address)
+        .collect();
+    if gone.is_empty() {return;}
+    SUBSCRIBERS.lock().unwrap().retain(|subscriber| {
+        let sent = !gone.contains(&subscriber.address);
+        if !sent {println!("***Broadcastlib***: Removing the subscriber at {:?}.", subscriber.address);}
+        sent
+    });
+}
+
+///The sink of Nionswift, whose outputs are also published to the subscribers.
+pub struct Broadcast<U>(pub U);
+
+impl<U: Sink> Sink for Broadcast<U> {
+    fn send(&mut self, parts: &[&[u8]]) -> std::io::Result<()> {
+        self.0.send(parts)?;
+        publish(parts);
+        Ok(())
+    }
+}
@@ -5,18 +5,14 @@ pub mod cluster {
     use crate::spimlib;
     use crate::tdclib::TdcRef;
     use std::ops::{Deref, DerefMut};
-    use crate::constlib::*;
+    use crate::configlib::config;
     use rayon::prelude::*;
     use std::cmp::Ordering;
     use crate::auxiliar::{value_types::*, misc};
-    
-    fn transform_energy_calibration(v: &[u8]) -> &[f32] {
-        unsafe {
-            std::slice::from_raw_parts(
-                v.as_ptr() as *const f32,
-                v.len() * std::mem::size_of::<u8>() / std::mem::size_of::<f32>() )
-        }
-    }
+    use crate::energylib::tot_calibration;
+    use crate::walklib::time_walk;
+    use crate::errorlib::Tp3ErrorKind;
+    use super::engine::{self, ClusterEngine, Hit};
 
     pub struct CollectionElectron {
         data: Vec<SingleElectron>,
@@ -79,19 +75,29 @@ pub mod cluster {
                     //    new_elist.add_electron(*x);
                     //}
             }
+            //The last cluster has no electron after it.
+            if let Some(new_from_cluster) = correction_type.new_from_cluster(&cluster_vec) {
+                new_elist.data.extend(new_from_cluster);
+            }
             self.data = new_elist.data;
         }
 
         pub fn sort(&mut self) {
             self.data.par_sort_unstable();
         }
-        fn clean(&mut self, correction_type: &ClusterCorrectionTypes) {
-            self.remove_clusters(correction_type);
+        fn clean(&mut self, correction_type: &ClusterCorrectionTypes, clusters: &mut CentroidEngine) {
+            match correction_type {
+                //The engine finds the clusters by itself, instead of the time sweep.
+                ClusterCorrectionTypes::Centroid => self.data = clusters.centroids(std::mem::take(&mut self.data)).data,
+                _ => self.remove_clusters(correction_type),
+            }
         }
-        pub fn try_clean(&mut self, min_size: usize, correction_type: &ClusterCorrectionTypes) -> bool {
+        //`clusters` is the engine of the `Centroid` correction. It is kept by the caller for the
+        //whole file, and its last clusters are taken with `CentroidEngine::flush`.
+        pub fn try_clean(&mut self, min_size: usize, correction_type: &ClusterCorrectionTypes, clusters: &mut CentroidEngine) -> bool {
             if self.data.len() > min_size && correction_type.must_correct() {
                 let nelectrons = self.data.len();
-                self.clean(correction_type);
+                self.clean(correction_type, clusters);
                 let new_nelectrons = self.data.len();
                 println!("Number of electrons: {}. Number of clusters: {}. Electrons per cluster: {}", nelectrons, new_nelectrons, nelectrons as f32/new_nelectrons as f32); 
                 return true
@@ -101,6 +107,11 @@ pub mod cluster {
         pub fn clear(&mut self) {
             self.data.clear();
         }
+        //Keeps the electrons that deposited between `min` and `max` keV. Without a ToT calibration,
+        //all of them are kept.
+        pub fn retain_energy(&mut self, min: f32, max: f32) {
+            self.data.retain(|electron| electron.energy().is_none_or(|energy| (min..=max).contains(&energy)));
+        }
         //This should return an Iterator so there is no need of allocating two vectors.
         pub fn search_coincidence(&self, photon_list: &CollectionPhoton, raw_packet_index: &mut Vec<usize>, time_delay: TIME, time_width: TIME) -> Self {
             let mut corr_array = Self::new();
@@ -126,10 +137,6 @@ pub mod cluster {
             }
             corr_array
         }
-        pub fn reorder_by_packet_index(&mut self) {
-            self.data.par_sort_unstable_by_key(|i| i.raw_packet_index());
-        }
-
         //This prints the maximum value of dt between consecutive electrons.
         pub fn maximum_dt(&self) {
             let mut last = None;
@@ -148,10 +155,21 @@ pub mod cluster {
         }
     }
 
-    ///Spim dT, Spim Slice, raw packet, packet index, CoincidencePhoton, Electron Time substitute
+    ///Size of the cluster an electron stands for and, if they are not the ones of the packet, its
+    ///time, x, y and ToT. The centroid is the sub-pixel position, if it was found.
+    #[derive(Clone, Copy, PartialEq)]
+    struct Cluster {
+        size: u16,
+        values: Option<(TIME, POSITION, POSITION, u16)>,
+        centroid: Option<(f32, f32)>,
+    }
+    impl Eq for Cluster {}
+
+    ///Spim dT, Spim Slice, raw packet, packet index, CoincidencePhoton, Electron Time substitute,
+    ///Time overflow, Cluster
     #[derive(Clone, Eq)]
     pub struct SingleElectron {
-        data: (TIME, COUNTER, Packet, usize, Option<SinglePhoton>, Option<TIME>),
+        data: (TIME, COUNTER, Packet, usize, Option<SinglePhoton>, Option<TIME>, TIME, Cluster),
     }
     
     ///Important for sorting
@@ -173,36 +191,76 @@ pub mod cluster {
 
     impl SingleElectron {
         pub fn new(pack: Packet, begin_frame: Option<&TdcRef>, raw_index: usize, subs_etime: Option<TIME>) -> Self {
+            //The corrected time is also corrected by the time walk, if there is a correction.
+            let subs_etime = match time_walk() {
+                Some(walk) => Some(walk.correct(&pack, subs_etime.unwrap_or_else(|| pack.electron_time_in_tdc_units()))),
+                None => subs_etime,
+            };
             match begin_frame {
                 Some(spim_tdc) => {
                     let ele_time = spim_tdc.sync_electron_frame_time(&pack).unwrap();
                     let frame = spim_tdc.frame().unwrap_or(0);
                     SingleElectron {
-                        data: (ele_time, frame, pack, raw_index, None, subs_etime)
+                        data: (ele_time, frame, pack, raw_index, None, subs_etime, 0, Cluster {size: 1, values: None, centroid: None})
                     }
                 },
                 None => {
                     SingleElectron {
-                        data: (0, 0, pack, raw_index, None, subs_etime),
+                        data: (0, 0, pack, raw_index, None, subs_etime, 0, Cluster {size: 1, values: None, centroid: None}),
                     }
                 },
             }
         }
 
         pub fn time(&self) -> TIME {
-            self.raw_packet_data().electron_time_in_tdc_units()
+            match self.data.7.values {
+                Some((time, _, _, _)) => time,
+                None => self.raw_packet_data().electron_time_in_tdc_units() + self.data.6,
+            }
         }
         pub fn corrected_time(&self) -> Option<TIME> {
-            self.data.5
+            Some(self.data.5? + self.data.6)
+        }
+        //Uses the time extended by a `ChipTimestamps` instead of the wrapping packet time.
+        pub fn set_extended_time(&mut self, time: TIME) {
+            self.data.6 = time - self.raw_packet_data().electron_time_in_tdc_units();
         }
         pub fn x(&self) -> POSITION {
-            self.raw_packet_data().x()
+            self.data.7.values.map_or_else(|| self.raw_packet_data().x(), |values| values.1)
         }
         pub fn y(&self) -> POSITION {
-            self.raw_packet_data().y()
+            self.data.7.values.map_or_else(|| self.raw_packet_data().y(), |values| values.2)
         }
         pub fn tot(&self) -> u16 {
-            self.raw_packet_data().tot()
+            self.data.7.values.map_or_else(|| self.raw_packet_data().tot(), |values| values.3)
+        }
+        //Number of electrons of the cluster this electron stands for. It is one before the cluster
+        //correction.
+        pub fn cluster_size(&self) -> u16 {
+            self.data.7.size
+        }
+        //A copy standing for a cluster, with the cluster values if they are not the ones of the packet.
+        fn clustered(&self, size: u16, values: Option<(TIME, POSITION, POSITION, u16)>) -> Self {
+            let mut electron = self.clone();
+            electron.data.7 = Cluster {size, values, centroid: None};
+            electron
+        }
+        //A copy standing for a cluster of the engine.
+        fn centroided(&self, cluster: &engine::Cluster) -> Self {
+            let tot = cluster.tot().min(u16::MAX as u32) as u16;
+            let mut electron = self.clustered(cluster.size().min(u16::MAX as u32) as u16, Some((cluster.time(), cluster.x(), cluster.y(), tot)));
+            electron.data.7.centroid = Some(cluster.centroid());
+            electron
+        }
+        //Sub-pixel position of the cluster, found with `ClusterCorrectionTypes::Centroid`. Otherwise
+        //it is the pixel.
+        pub fn centroid(&self) -> (f32, f32) {
+            self.data.7.centroid.unwrap_or((self.x() as f32, self.y() as f32))
+        }
+        //The spim dT holds the time of the reference electron of the cluster instead.
+        fn referenced_to(mut self, time_reference: TIME) -> Self {
+            self.data.0 = time_reference;
+            self
         }
         pub fn frame_dt(&self) -> TIME {
             self.data.0
@@ -223,7 +281,7 @@ pub mod cluster {
             self.data.4 = Some(photon)
         }
         pub fn image_index(&self) -> POSITION {
-            self.x() + PIXELS_X*self.y()
+            self.x() + Packet::chip_array().0 * self.y()
         }
         pub fn relative_time(&self, reference_time: TIME) -> i64 {
             self.time() as i64 - reference_time as i64
@@ -239,17 +297,12 @@ pub mod cluster {
             let reference_time = self.coincident_photon()?.time();
             Some((self.corrected_time()?) as i64 - reference_time as i64)
         }
-        fn tot_to_energy(&self) -> u16 {
-            let index = self.x() as usize + 1024 * self.y() as usize;
-            let temp = (self.tot() as f32 - transform_energy_calibration(BTOT)[index]) / transform_energy_calibration(ATOT)[index];
-            if temp < 0.0 {
-                0_u16
-            } else {
-                temp.round() as u16
-            }
+        //Deposited energy, in keV. None without a ToT calibration (see `energylib`).
+        pub fn energy(&self) -> Option<f32> {
+            Some(tot_calibration()?.energy(self.x(), self.y(), self.tot()))
         }
         fn is_new_cluster(&self, s: &SingleElectron) -> bool {
-            self.time() > s.time() + CLUSTER_DET || (self.x() as isize - s.x() as isize).abs() > CLUSTER_SPATIAL || (self.y() as isize - s.y() as isize).abs() > CLUSTER_SPATIAL
+            self.time() > s.time() + config().cluster_det || (self.x() as isize - s.x() as isize).abs() > config().cluster_spatial || (self.y() as isize - s.y() as isize).abs() > config().cluster_spatial
         }
         pub fn get_or_not_spim_index(&self, spim_tdc: Option<&TdcRef>, xspim: POSITION, yspim: POSITION) -> Option<INDEXHYPERSPEC> {
             spimlib::get_spimindex(self.x(), self.frame_dt(), spim_tdc?, xspim, yspim, None)
@@ -344,7 +397,7 @@ pub mod cluster {
             self.data.1
         }
         pub fn get_or_not_spim_index(&self, spim_tdc: Option<&TdcRef>, xspim: POSITION, yspim: POSITION) -> Option<INDEXHYPERSPEC> {
-            spimlib::get_spimindex(PIXELS_X-1, self.frame_dt(), spim_tdc?, xspim, yspim, None)
+            spimlib::get_spimindex(Packet::chip_array().0 - 1, self.frame_dt(), spim_tdc?, xspim, yspim, None)
         }
         pub fn frame_dt(&self) -> TIME {
             self.data.3
@@ -352,6 +405,10 @@ pub mod cluster {
         pub fn time(&self) -> TIME {
             self.data.0
         }
+        //Uses the time extended by a `ChipTimestamps` instead of the wrapping packet time.
+        pub fn set_extended_time(&mut self, time: TIME) {
+            self.data.0 = time;
+        }
         pub fn g2_time(&self) -> Option<i16> {
             self.data.2
         }
@@ -360,266 +417,155 @@ pub mod cluster {
         }
     }
 
-    ///This function is used to select a given ClusterCorrectionType with a string
-    pub fn grab_cluster_correction(val: &str) -> ClusterCorrectionTypes {
-        match val {
-            "0" => ClusterCorrectionTypes::NoCorrection,
-            //"1" => ClusterCorrectionTypes::AverageCorrection,
-            //"2" => ClusterCorrectionTypes::LargestToT,
-            //"3" => ClusterCorrectionTypes::LargestToTWithThreshold(20, 100),
-            //"4" => ClusterCorrectionTypes::ClosestToTWithThreshold(50, 20, 100),
-            //"5" => ClusterCorrectionTypes::FixedToT(10),
-            //"6" => ClusterCorrectionTypes::FixedToTCalibration(30, 60),
-            //"7" => ClusterCorrectionTypes::NoCorrectionVerbose,
-            //"8" => ClusterCorrectionTypes::SingleClusterToTCalibration,
-            _ => ClusterCorrectionTypes::NoCorrection,
-        }
+    ///This function is used to select a given ClusterCorrectionType with a string. It is either
+    ///the former number, with its default parameters, or the name followed by the parameters
+    ///separated by ':', as in `closest_tot:50:20:100`.
+    pub fn grab_cluster_correction(val: &str) -> Result<ClusterCorrectionTypes, Tp3ErrorKind> {
+        let bad_argument = || Tp3ErrorKind::ConfigBadArgument(val.to_owned());
+        let mut fields = val.split(':');
+        let name = fields.next().unwrap_or("");
+        let parameters = fields.map(|field| field.parse::<u16>().map_err(|_| bad_argument())).collect::<Result<Vec<u16>, Tp3ErrorKind>>()?;
+        //The parameters not given take the default values.
+        let parameter = |index: usize, default: u16| *parameters.get(index).unwrap_or(&default);
+        let correction = match name {
+            "0" | "no_correction" => ClusterCorrectionTypes::NoCorrection,
+            "1" | "average" => ClusterCorrectionTypes::AverageCorrection,
+            "2" | "largest_tot" => ClusterCorrectionTypes::LargestToT,
+            "3" | "largest_tot_threshold" => ClusterCorrectionTypes::LargestToTWithThreshold(parameter(0, 20), parameter(1, 100)),
+            "4" | "closest_tot" => ClusterCorrectionTypes::ClosestToTWithThreshold(parameter(0, 50), parameter(1, 20), parameter(2, 100)),
+            "5" | "fixed_tot" => ClusterCorrectionTypes::FixedToT(parameter(0, 10)),
+            "6" | "fixed_tot_calibration" => ClusterCorrectionTypes::FixedToTCalibration(parameter(0, 30), parameter(1, 60)),
+            "7" | "no_correction_verbose" => ClusterCorrectionTypes::NoCorrectionVerbose,
+            "8" | "single_cluster" => ClusterCorrectionTypes::SingleClusterToTCalibration,
+            "9" | "muon_track" => ClusterCorrectionTypes::MuonTrack,
+            "10" | "centroid" => ClusterCorrectionTypes::Centroid,
+            _ => return Err(bad_argument()),
+        };
+        if parameters.len() > correction.parameters() {
+            return Err(bad_argument());
+        }
+        Ok(correction)
     }
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, PartialEq)]
     pub enum ClusterCorrectionTypes {
         NoCorrection,
-        //AverageCorrection,
-        //LargestToT,
-        //LargestToTWithThreshold(u16, u16), //Threshold min and max
-        //ClosestToTWithThreshold(u16, u16, u16), //Reference, Threshold min and max
-        //FixedToT(u16), //Reference
-        //FixedToTCalibration(u16, u16), //Reference
-        //MuonTrack,
-        //NoCorrectionVerbose,
-        //SingleClusterToTCalibration,
+        AverageCorrection,
+        LargestToT,
+        LargestToTWithThreshold(u16, u16), //Threshold min and max
+        ClosestToTWithThreshold(u16, u16, u16), //Reference, Threshold min and max
+        FixedToT(u16), //Reference
+        FixedToTCalibration(u16, u16), //Reference energy and cluster energy, in keV. The ToT of the electrons is their energy.
+        MuonTrack,
+        NoCorrectionVerbose,
+        SingleClusterToTCalibration,
+        Centroid, //Clusters of the `engine`, at their ToT-weighted centroid.
     }
 
     impl ClusterCorrectionTypes {
         fn new_from_cluster(&self, cluster: &[SingleElectron]) -> Option<CollectionElectron> {
+            let cluster_size = cluster.len() as u16;
+            let first = cluster.first()?;
+            let mut val = CollectionElectron::new();
             match &self {
                 ClusterCorrectionTypes::NoCorrection => {
-                    let mut val = CollectionElectron::new();
                     for electron in cluster {
                         val.add_electron(electron.clone());
-                        //val.add_electron(SingleElectron{
-                        //    data: (electron.frame_dt(), electron.spim_slice(), 1, electron.raw_packet_data(), electron.raw_packet_index(), 0, electron.coincident_photon()),
-                        //});
                     }
-                    Some(val)
                 },
-                /*
                 ClusterCorrectionTypes::AverageCorrection => {
-                    let cluster_size = cluster.len() as u16;
-                    let t_mean:TIME = cluster.iter().
-                        map(|se| se.time()).
-                        sum::<TIME>() / cluster_size as TIME;
-                    
-                    let x_mean:POSITION = cluster.iter().
-                        map(|se| se.x()).
-                        sum::<POSITION>() / cluster_size as POSITION;
-                    
-                    let y_mean:POSITION = cluster.iter().
-                        map(|se| se.y()).
-                        sum::<POSITION>() / cluster_size as POSITION;
-                    
-                    let time_dif: TIME = cluster.iter().
-                        map(|se| se.frame_dt()).
-                        next().
-                        unwrap();
-                    
-                    let slice: COUNTER = cluster.iter().
-                        map(|se| se.spim_slice()).
-                        next().
-                        unwrap();
-                    
-                    let tot_sum: u16 = cluster.iter().
-                        map(|se| se.tot() as usize).
-                        sum::<usize>() as u16;
-                    
-                    let raw_data = cluster.iter().
-                        map(|se| se.raw_packet_data()).
-                        next().
-                        unwrap();
-                    
-                    let raw_index = cluster.iter().
-                        map(|se| se.raw_packet_index()).
-                        next().
-                        unwrap();
-                    
-                    let mut val = CollectionElectron::new();
-                    val.add_electron(SingleElectron {
-                        data: (Some(t_mean), Some(x_mean), Some(y_mean), Some(tot_sum), time_dif, slice, cluster_size, raw_data, raw_index, 0),
-                    });
-                    Some(val)
+                    let t_mean = cluster.iter().map(|se| se.time()).sum::<TIME>() / cluster_size as TIME;
+                    let x_mean = cluster.iter().map(|se| se.x()).sum::<POSITION>() / cluster_size as POSITION;
+                    let y_mean = cluster.iter().map(|se| se.y()).sum::<POSITION>() / cluster_size as POSITION;
+                    val.add_electron(first.clustered(cluster_size, Some((t_mean, x_mean, y_mean, tot_sum(cluster.iter())))));
                 },
                 ClusterCorrectionTypes::LargestToT => {
-                    let cluster_size = cluster.len() as u16;
-
                     let electron = cluster.iter().
-                        reduce(|accum, item| if accum.tot() > item.tot() {accum} else {item}).
-                        unwrap();
-
-                    let mut val = CollectionElectron::new();
-                    val.add_electron(SingleElectron {
-                        data: (None, None, None, None, electron.frame_dt(), electron.spim_slice(), cluster_size, electron.raw_packet_data(), electron.raw_packet_index(), 0),
-                    });
-                    Some(val)
-                }
+                        reduce(|accum, item| if accum.tot() > item.tot() {accum} else {item})?;
+                    val.add_electron(electron.clustered(cluster_size, None));
+                },
                 ClusterCorrectionTypes::LargestToTWithThreshold(min, max) => {
-                    let cluster_size = cluster.len() as u16;
-
                     let electron = cluster.iter().
-                        reduce(|accum, item| if accum.tot() > item.tot() {accum} else {item}).
-                        unwrap();
+                        reduce(|accum, item| if accum.tot() > item.tot() {accum} else {item})?;
 
                     if electron.tot() < *min {return None;}
                     if electron.tot() > *max {return None;}
-
-                    let mut val = CollectionElectron::new();
-                    val.add_electron(SingleElectron {
-                        data: (None, None, None, None, electron.frame_dt(), electron.spim_slice(), cluster_size, electron.raw_packet_data(), electron.raw_packet_index(), 0),
-                    });
-                    Some(val)
+                    val.add_electron(electron.clustered(cluster_size, None));
                 },
                 ClusterCorrectionTypes::ClosestToTWithThreshold(reference, min_th, max_th) => {
-                    let cluster_size = cluster.len() as u16;
-
                     let electron = cluster.iter().
-                        reduce(|accum, item| if (accum.tot() as i16 - *reference as i16).abs() < (item.tot() as i16 - *reference as i16).abs() {accum} else {item}).
-                        unwrap();
+                        reduce(|accum, item| if (accum.tot() as i32 - *reference as i32).abs() <= (item.tot() as i32 - *reference as i32).abs() {accum} else {item})?;
 
                     if electron.tot() < *min_th {return None;}
                     if electron.tot() > *max_th {return None;}
-
-                    let mut val = CollectionElectron::new();
-                    val.add_electron(SingleElectron {
-                        data: (None, None, None, None, electron.frame_dt(), electron.spim_slice(), cluster_size, electron.raw_packet_data(), electron.raw_packet_index(), 0),
-                    });
-                    Some(val)
+                    val.add_electron(electron.clustered(cluster_size, None));
                 },
                 ClusterCorrectionTypes::FixedToT(reference) => {
-                    let cluster_size = cluster.len() as u16;
-                    
                     let cluster_filter_size = cluster.iter().
                         filter(|se| se.tot() == *reference).
                         count();
 
                     if cluster_filter_size == 0 {return None};
 
-                    let t_mean:TIME = cluster.iter().
+                    //The time is the one of the reference ToT, so it has always the same time walk.
+                    let t_mean = cluster.iter().
                         filter(|se| se.tot() == *reference).
                         map(|se| se.time()).sum::<TIME>() / cluster_filter_size as TIME;
-                    
-                    let x_mean:POSITION = cluster.iter().
-                        map(|se| se.x()).
-                        sum::<POSITION>() / cluster_size as POSITION;
-                    
-                    let y_mean:POSITION = cluster.iter().
-                        map(|se| se.y()).
-                        sum::<POSITION>() / cluster_size as POSITION;
-                    
-                    let time_dif: TIME = cluster.iter().
-                        map(|se| se.frame_dt()).
-                        next().
-                        unwrap();
-                    
-                    let slice: COUNTER = cluster.iter().
-                        map(|se| se.spim_slice()).
-                        next().
-                        unwrap();
-                    
-                    let tot_sum: u16 = cluster.iter().
-                        map(|se| se.tot() as usize).
-                        sum::<usize>() as u16;
-                    
-                    let raw_packet_data: Packet = cluster.iter().
-                        map(|se| se.raw_packet_data()).
-                        next().
-                        unwrap();
-                    
-                    let raw_packet_index: usize = cluster.iter().
-                        map(|se| se.raw_packet_index()).
-                        next().
-                        unwrap();
-
-                    let mut val = CollectionElectron::new();
-                    val.add_electron(SingleElectron{
-                        data: (Some(t_mean), Some(x_mean), Some(y_mean), Some(tot_sum), time_dif, slice, cluster_size, raw_packet_data, raw_packet_index, 0),
-                    });
-                    Some(val)
+                    let x_mean = cluster.iter().map(|se| se.x()).sum::<POSITION>() / cluster_size as POSITION;
+                    let y_mean = cluster.iter().map(|se| se.y()).sum::<POSITION>() / cluster_size as POSITION;
+                    val.add_electron(first.clustered(cluster_size, Some((t_mean, x_mean, y_mean, tot_sum(cluster.iter())))));
                 },
                 ClusterCorrectionTypes::FixedToTCalibration(energy_ref, energy_sum_ref) => {
-                    let cluster_size = cluster.len() as u16;
+                    if !(3..=4).contains(&cluster_size) {return None;} //3 to 4 objects in the cluster
 
-                    if (cluster_size < 3) || (cluster_size > 4) {return None;} //3 to 4 objects in the cluster
-                    
-                    let cluster_filter_size = cluster.iter().
-                        filter(|se| se.tot_to_energy() == *energy_ref).
+                    let energies = cluster.iter().
+                        map(|se| Some(se.energy()?.round() as u16)).
+                        collect::<Option<Vec<u16>>>()?; //There is no energy without a ToT calibration
+
+                    let cluster_filter_size = energies.iter().
+                        filter(|energy| **energy == *energy_ref).
                         count(); //Number of elements in the reference energy value
-                    
+
                     if cluster_filter_size != 1 {return None}; //It must be one for complete control
-                    
-                    let energy_sum: u16 = cluster.iter().
-                        map(|se| se.tot_to_energy() as usize).
-                        sum::<usize>() as u16;
 
-                    if energy_sum < energy_sum_ref - 15 {return None;} //The energy sum must be close to the electron energy value
-                    if energy_sum > energy_sum_ref + 15 {return None;}
+                    let energy_sum = energies.iter().map(|energy| *energy as u32).sum::<u32>();
 
-                    let time_reference = cluster.iter().
-                        filter(|se| se.tot_to_energy() == *energy_ref).
-                        map(|se| se.time()).
-                        next().
-                        unwrap();
+                    //The energy sum must be close to the electron energy value
+                    if energy_sum.abs_diff(*energy_sum_ref as u32) > 15 {return None;}
 
-                    let mut val = CollectionElectron::new();
-                    for electron in cluster {
-                        //if electron.tot_to_energy() == self.0 {continue;} //ToT reference not need to be output
+                    let time_reference = cluster.iter().zip(energies.iter()).
+                        find(|(_, energy)| **energy == *energy_ref).
+                        map(|(se, _)| se.time())?;
+
+                    for (electron, energy) in cluster.iter().zip(energies.iter()) {
                         let time_diference = electron.time() as i64 - time_reference as i64;
                         if time_diference.abs() > 100 {continue;} //must not output far-away data from tot==reference value
-                        val.add_electron(SingleElectron{
-                            data: (None, None, None, Some(electron.tot_to_energy()), time_reference, electron.spim_slice(), cluster_size, electron.raw_packet_data(), electron.raw_packet_index(), 0),
-                        });
+                        val.add_electron(electron.clustered(cluster_size, Some((electron.time(), electron.x(), electron.y(), *energy))).referenced_to(time_reference));
                     }
-                    Some(val)
                 },
                 ClusterCorrectionTypes::MuonTrack => {
-                    let cluster_size = cluster.len() as u16;
                     if cluster_size == 1 {return None;}
-                    let time_reference = cluster.iter().
-                        map(|se| se.time()).
-                        next().
-                        unwrap();
-
-                    let mut val = CollectionElectron::new();
+                    let time_reference = first.time();
                     for electron in cluster {
-                        //let time_diference = electron.time() as i64 - time_reference as i64;
-                        val.add_electron(SingleElectron{
-                            data: (None, None, None, None, time_reference, electron.spim_slice(), cluster_size, electron.raw_packet_data(), electron.raw_packet_index(), 0),
-                        });
+                        val.add_electron(electron.clustered(cluster_size, None).referenced_to(time_reference));
                     }
-                    Some(val)
                 },
                 ClusterCorrectionTypes::NoCorrectionVerbose => {
-                    let cluster_size = cluster.len() as u16;
-                    let mut val = CollectionElectron::new();
                     for electron in cluster {
-                        val.add_electron(SingleElectron{
-                            data: (None, None, None, None, electron.frame_dt(), electron.spim_slice(), cluster_size, electron.raw_packet_data(), electron.raw_packet_index(), 0),
-                    });
+                        val.add_electron(electron.clustered(cluster_size, None));
                     }
-                    Some(val)
                 },
                 ClusterCorrectionTypes::SingleClusterToTCalibration => {
-                    let cluster_size = cluster.len() as u16;
                     if cluster_size != 1 {return None}; //It must be single cluster
-                    let mut val = CollectionElectron::new();
-                    for electron in cluster {
-                        val.add_electron(SingleElectron {
-                            data: (None, None, None, None, electron.frame_dt(), electron.spim_slice(), cluster_size, electron.raw_packet_data(), electron.raw_packet_index(), 0),
-                        });
-                    }
-                    Some(val)
+                    val.add_electron(first.clustered(cluster_size, None));
+                },
+                ClusterCorrectionTypes::Centroid => {
+                    let mut clusters = CentroidEngine::default();
+                    val = clusters.centroids(cluster.to_vec());
+                    val.data.extend(clusters.flush().data);
                 },
-                */
             }
-
+            Some(val)
         }
         fn must_correct(&self) -> bool {
             match &self {
@@ -627,20 +573,314 @@ pub mod cluster {
                 _ => true,
             }
         }
-        pub fn set_thresholds(&mut self, _min_value: u16, _max_value: u16) {
+        //Number of parameters that can be given by name.
+        fn parameters(&self) -> usize {
+            match &self {
+                ClusterCorrectionTypes::LargestToTWithThreshold(..) | ClusterCorrectionTypes::FixedToTCalibration(..) => 2,
+                ClusterCorrectionTypes::ClosestToTWithThreshold(..) => 3,
+                ClusterCorrectionTypes::FixedToT(..) => 1,
+                _ => 0,
+            }
+        }
+        pub fn set_thresholds(&mut self, min_value: u16, max_value: u16) {
             match self {
-                //ClusterCorrectionTypes::LargestToTWithThreshold(min, max) => {*min = min_value; *max=max_value;},
-                //ClusterCorrectionTypes::ClosestToTWithThreshold(_, min, max) => {*min = min_value; *max = max_value},
+                ClusterCorrectionTypes::LargestToTWithThreshold(min, max) => {*min = min_value; *max=max_value;},
+                ClusterCorrectionTypes::ClosestToTWithThreshold(_, min, max) => {*min = min_value; *max = max_value},
                 _ => {},
             }
         }
-        pub fn set_reference(&mut self, _reference: u16) {
+        pub fn set_reference(&mut self, reference: u16) {
             match self {
-                //ClusterCorrectionTypes::FixedToT(ref_value) => {*ref_value = reference}
-                //ClusterCorrectionTypes::ClosestToTWithThreshold(ref_value, _, _) => {*ref_value = reference}
-                //ClusterCorrectionTypes::FixedToTCalibration(ref_value, _) => {*ref_value = reference}
+                ClusterCorrectionTypes::FixedToT(ref_value) => {*ref_value = reference}
+                ClusterCorrectionTypes::ClosestToTWithThreshold(ref_value, _, _) => {*ref_value = reference}
+                ClusterCorrectionTypes::FixedToTCalibration(ref_value, _) => {*ref_value = reference}
                 _ => {},
             }
         }
     }
+
+    ///The engine of the `Centroid` correction. The clusters span the buffers, so the electrons of
+    ///the clusters still open are kept for the next one.
+    #[derive(Default)]
+    pub struct CentroidEngine {
+        clusters: ClusterEngine,
+        electrons: Vec<SingleElectron>, //Added to the engine from the index `first` on.
+        first: usize,
+    }
+
+    impl CentroidEngine {
+        //One electron per closed cluster, sorted by time. It stands for the first electron of the
+        //cluster, at the centroid of its hits.
+        fn centroids(&mut self, electrons: Vec<SingleElectron>) -> CollectionElectron {
+            let CentroidEngine {clusters, electrons: kept, first} = self;
+            let start = kept.len();
+            kept.extend(electrons);
+            let mut val = CollectionElectron::new();
+            for index in start..kept.len() {
+                let electron = &kept[index];
+                clusters.add(Hit {x: electron.x(), y: electron.y(), time: electron.time(), tot: electron.tot(), index: *first + index});
+                val.data.extend(clusters.finished().map(|cluster| kept[cluster.index() - *first].centroided(&cluster)));
+            }
+            let keep_from = clusters.first_open_index().unwrap_or(*first + kept.len());
+            kept.drain(..keep_from - *first);
+            *first = keep_from;
+            val.sort();
+            val
+        }
+
+        ///Closes the clusters still open, at the end of the data, and gives their electrons.
+        pub fn flush(&mut self) -> CollectionElectron {
+            let CentroidEngine {clusters, electrons, first} = self;
+            clusters.flush();
+            let mut val = CollectionElectron::new();
+            val.data.extend(clusters.finished().map(|cluster| electrons[cluster.index() - *first].centroided(&cluster)));
+            *first += electrons.len();
+            electrons.clear();
+            val.sort();
+            val
+        }
+
+        ///Number of clusters still open.
+        pub fn open_clusters(&self) -> usize {
+            self.clusters.open_clusters()
+        }
+    }
+
+    fn tot_sum<'a>(cluster: impl Iterator<Item = &'a SingleElectron>) -> u16 {
+        cluster.map(|se| se.tot() as usize).sum::<usize>().min(u16::MAX as usize) as u16
+    }
+}
+
+///Groups the hits of the electrons into clusters while they stream in. Two hits are in the same
+///cluster if they are within `cluster_spatial` pixels and `cluster_det` of each other, directly or
+///through other hits of the cluster. A cluster is closed once the hits have gone past its last
+///time by more than the window, so it can span several buffers.
+pub mod engine {
+    use crate::packetlib::Packet;
+    use crate::configlib::config;
+    use crate::energylib::tot_calibration;
+    use crate::auxiliar::value_types::*;
+
+    ///Open clusters kept at most. Above it, the oldest ones are closed.
+    const MAX_OPEN_CLUSTERS: usize = 4096;
+    ///Most bins per pixel of the super-resolution spectra.
+    pub const MAX_SUPER_RESOLUTION: POSITION = 8;
+
+    ///Bins per pixel along x of the super-resolution spectra, from `config().super_resolution`.
+    pub fn super_resolution() -> POSITION {
+        config().super_resolution.clamp(1, MAX_SUPER_RESOLUTION)
+    }
+
+    ///Bin of a position along x among the `factor` bins of its pixel. A pixel goes from half a
+    ///pixel before its center to half a pixel after it, so a hit alone is in the middle bin.
+    pub fn subpixel(position: f32, factor: POSITION) -> POSITION {
+        let offset = position - position.round() + 0.5;
+        ((offset * factor as f32).floor() as POSITION).min(factor - 1)
+    }
+
+    ///A hit of an electron. Its time is in TDC units (0.260 ns) and must not wrap around, as the
+    ///times of a `ChipTimestamps`. `index` is kept for the caller, such as the position in a list
+    ///or the time in the scan.
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    pub struct Hit {
+        pub x: POSITION,
+        pub y: POSITION,
+        pub time: TIME,
+        pub tot: u16,
+        pub index: usize,
+    }
+
+    impl Hit {
+        pub fn from_packet(packet: &Packet, time: TIME, index: usize) -> Self {
+            Hit {x: packet.x(), y: packet.y(), time, tot: packet.tot(), index}
+        }
+    }
+
+    ///A closed cluster.
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Cluster {
+        size: u32,
+        time: TIME,
+        index: usize,
+        tot: u32,
+        energy: Option<f32>,
+        centroid: (f32, f32),
+    }
+
+    impl Cluster {
+        ///Number of hits.
+        pub fn size(&self) -> u32 {
+            self.size
+        }
+        ///Time of the first hit.
+        pub fn time(&self) -> TIME {
+            self.time
+        }
+        ///Index of the first hit.
+        pub fn index(&self) -> usize {
+            self.index
+        }
+        pub fn tot(&self) -> u32 {
+            self.tot
+        }
+        ///Summed energy of the hits, in keV. None without a ToT calibration (see `energylib`).
+        pub fn energy(&self) -> Option<f32> {
+            self.energy
+        }
+        ///Position weighted by the ToT of the hits.
+        pub fn centroid(&self) -> (f32, f32) {
+            self.centroid
+        }
+        ///Pixel of the centroid.
+        pub fn x(&self) -> POSITION {
+            self.centroid.0.round() as POSITION
+        }
+        pub fn y(&self) -> POSITION {
+            self.centroid.1.round() as POSITION
+        }
+    }
+
+    struct OpenCluster {
+        pixels: Vec<(POSITION, POSITION)>,
+        first: Hit,
+        last_time: TIME,
+        tot: u32,
+        energy: Option<f32>,
+        weighted: (f64, f64, f64), //x and y times the ToT, and the ToT
+    }
+
+    impl OpenCluster {
+        fn new(hit: &Hit) -> Self {
+            let mut cluster = OpenCluster {pixels: Vec::new(), first: *hit, last_time: hit.time, tot: 0, energy: Some(0.0), weighted: (0.0, 0.0, 0.0)};
+            cluster.add(hit);
+            cluster
+        }
+        fn add(&mut self, hit: &Hit) {
+            if hit.time < self.first.time {
+                self.first = *hit;
+            }
+            self.last_time = self.last_time.max(hit.time);
+            self.pixels.push((hit.x, hit.y));
+            self.tot += hit.tot as u32;
+            self.energy = self.energy.and_then(|energy| Some(energy + tot_calibration()?.energy(hit.x, hit.y, hit.tot)));
+            //A hit without ToT still counts for the position.
+            let weight = hit.tot.max(1) as f64;
+            self.weighted.0 += hit.x as f64 * weight;
+            self.weighted.1 += hit.y as f64 * weight;
+            self.weighted.2 += weight;
+        }
+        fn merge(&mut self, other: OpenCluster) {
+            if other.first.time < self.first.time {
+                self.first = other.first;
+            }
+            self.last_time = self.last_time.max(other.last_time);
+            self.pixels.extend(other.pixels);
+            self.tot += other.tot;
+            self.energy = self.energy.zip(other.energy).map(|(first, second)| first + second);
+            self.weighted = (self.weighted.0 + other.weighted.0, self.weighted.1 + other.weighted.1, self.weighted.2 + other.weighted.2);
+        }
+        fn touches(&self, hit: &Hit, window: TIME, spatial: POSITION) -> bool {
+            hit.time + window >= self.first.time && hit.time <= self.last_time + window &&
+                self.pixels.iter().any(|(x, y)| x.abs_diff(hit.x) <= spatial && y.abs_diff(hit.y) <= spatial)
+        }
+        fn close(self) -> Cluster {
+            Cluster {
+                size: self.pixels.len() as u32,
+                time: self.first.time,
+                index: self.first.index,
+                tot: self.tot,
+                energy: self.energy,
+                centroid: ((self.weighted.0 / self.weighted.2) as f32, (self.weighted.1 / self.weighted.2) as f32),
+            }
+        }
+    }
+
+    pub struct ClusterEngine {
+        window: TIME,
+        spatial: POSITION,
+        open: Vec<OpenCluster>,
+        closed: Vec<Cluster>,
+        latest: TIME,
+    }
+
+    ///An engine with the cluster window of the configuration.
+    impl Default for ClusterEngine {
+        fn default() -> Self {
+            Self::new(config().cluster_det, config().cluster_spatial.max(0) as POSITION)
+        }
+    }
+
+    impl ClusterEngine {
+        pub fn new(window: TIME, spatial: POSITION) -> Self {
+            ClusterEngine {window, spatial, open: Vec::new(), closed: Vec::new(), latest: 0}
+        }
+
+        ///Adds a hit. The hits are expected in about time order, as they come in a buffer.
+        pub fn add(&mut self, hit: Hit) {
+            if hit.time > self.latest {
+                self.latest = hit.time;
+                self.close_older_than(self.latest.saturating_sub(self.window));
+            }
+
+            //Every open cluster the hit touches becomes one.
+            let mut joined: Option<usize> = None;
+            let mut index = 0;
+            while index < self.open.len() {
+                if !self.open[index].touches(&hit, self.window, self.spatial) {
+                    index += 1;
+                    continue;
+                }
+                match joined {
+                    None => {
+                        self.open[index].add(&hit);
+                        joined = Some(index);
+                        index += 1;
+                    },
+                    Some(first) => {
+                        let other = self.open.swap_remove(index);
+                        self.open[first].merge(other);
+                    },
+                }
+            }
+            if joined.is_none() {
+                self.open.push(OpenCluster::new(&hit));
+            }
+
+            if self.open.len() > MAX_OPEN_CLUSTERS {
+                let oldest = (0..self.open.len()).min_by_key(|index| self.open[*index].last_time).unwrap_or(0);
+                self.closed.push(self.open.swap_remove(oldest).close());
+            }
+        }
+
+        fn close_older_than(&mut self, time: TIME) {
+            let mut index = 0;
+            while index < self.open.len() {
+                if self.open[index].last_time < time {
+                    self.closed.push(self.open.swap_remove(index).close());
+                } else {
+                    index += 1;
+                }
+            }
+        }
+
+        ///Closes every open cluster, as at the end of the data.
+        pub fn flush(&mut self) {
+            self.closed.extend(self.open.drain(..).map(OpenCluster::close));
+        }
+
+        ///Takes the closed clusters. They are in the order they were closed.
+        pub fn finished(&mut self) -> std::vec::Drain<'_, Cluster> {
+            self.closed.drain(..)
+        }
+
+        ///Number of clusters still open.
+        pub fn open_clusters(&self) -> usize {
+            self.open.len()
+        }
+
+        ///Index of the oldest hit that an open cluster can still give as its first.
+        pub fn first_open_index(&self) -> Option<usize> {
+            self.open.iter().map(|cluster| cluster.first.index).min()
+        }
+    }
 }
@@ -0,0 +1,265 @@
+//!`configlib` is a collection of tools to set the per-instrument values at runtime.
+//!
+//!Values are resolved once, in this order of precedence (the last one wins):
+//!1. The defaults of `Config`;
+//!2. A JSON or TOML file, given by `--config <path>`, by the environment variable `TP3_CONFIG` or
+//!   found as `tp3_config.json` or `tp3_config.toml` in the working directory. Files ending in
+//!   `.toml` are read as TOML, any other as JSON;
+//!3. Environment variables `TP3_<FIELD>` (e.g. `TP3_NIONSWIFT_PORT=8089`);
+//!4. Command line overrides `--set <field>=<value>` (e.g. `--set inverse_detector=false`).
+//!
+//!Values in the environment and in the command line are parsed as JSON. If they are not valid
+//!JSON they are taken as plain strings, so paths do not need to be quoted.
+//!
+//!The configuration is a process global, read with `config()`, instead of a value passed to
+//!`Settings`, `TdcRef` and `Packet`. These types are created per measurement from what Nionswift
+//!sends, and `Packet` is a copy of a raw word decoded in the hottest loops, so a reference in
+//!each of them would cost memory and a parameter in every decoding function for a value that
+//!never changes while the server runs. It is set once, at startup, and only read afterwards.
+use crate::auxiliar::value_types::*;
+use crate::errorlib::{Tp3ErrorKind, PathContext};
+use crate::tdclib::TdcType;
+use crate::exportlib::ExportFormat;
+use crate::geometrylib::{self, Geometry, Layout};
+use crate::pixellib;
+use crate::energylib;
+use crate::walklib;
+use crate::modelib::OutputAddress;
+use crate::broadcastlib::SlowConsumer;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::OnceLock;
+
+pub const CONFIG_ENV_FILE: &str = "TP3_CONFIG";
+pub const CONFIG_ENV_PREFIX: &str = "TP3_";
+pub const CONFIG_DEFAULT_FILE: &str = "tp3_config.json";
+pub const CONFIG_DEFAULT_TOML_FILE: &str = "tp3_config.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+///Per-instrument values. Every field can be omitted in the configuration file.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    //***Connection, TCP, and transfer values***//
+    pub nionswift_ip_address: [u8; 4],
+    pub nionswift_port: u16,
+    pub timepix_port: u16,
+    pub save_locally_file: String,
+    pub read_debug_file: String,
+    pub read_debug_file_json: String,
+    pub status_address: String, //Metrics endpoint. An empty address disables it.
+    pub subscriber_address: String, //Other clients of the live output, besides Nionswift, on their own port. An empty address disables it.
+    pub slow_subscriber: SlowConsumer, //Drop the outputs a subscriber cannot keep up with, or block the acquisition.
+    pub subscriber_queue: usize, //Outputs waiting to be written to each subscriber.
+    pub extra_outputs: Vec<OutputAddress>, //Other modes sent along each measurement, to the clients connected when it starts.
+
+    //***General Values***//
+    pub main_tdc: TdcType, //The main TDC, used for external sync
+    pub secondary_tdc: TdcType, //Secondary TDC
+    pub period_divider: TIME, //This divides the period detected by the Timepix3. DEFAULT to 1 but 65536 for the oscillator;
+    pub blanking_period: TIME, //This is what is received by the TDC after division.
+    pub tdc_period_tolerance: f64, //Largest deviation of a period from the median, as a fraction of it. Whole multiples are missed edges and pass.
+    pub activate_ttx: bool,
+    pub time_interval_frames: u64, //in milliseconds
+    pub time_interval_coincidence_histogram: u64, //in milliseconds
+    pub export_format: ExportFormat, //Format of the post-processed data.
+
+    //***Packet-related values***//
+    pub inverse_detector: bool, //This mirror the detector in the dispersive direction (EELS);
+    pub layout: Layout, //Placement of the chips. See `geometrylib`.
+    pub edge_pixel_width: POSITION, //Width of the pixels at the chip edges, in pixels. Above 1, they are spread over the gaps.
+    pub correct_electron_time_coarse: bool,
+
+    //***Cluster settings***//
+    pub cluster_det: TIME, //Cluster time window (in TDC units, 0.260 ns).
+    pub cluster_spatial: isize, // If electron hit position in both X or Y > cluster_spatial, then we have a new cluster.
+    pub live_clustering: bool, //The live spectra (1D, 2D, chrono and hyperspectral) count the clusters at their centroid instead of the hits.
+    pub super_resolution: POSITION, //Bins per pixel of the 1D, chrono and hyperspectral spectra (1, 2, 4 or 8). Above 1, the clusters are always used.
+
+    //***TDCLIB***//
+    pub tdc_timeout: u64, //in seconds
+    pub isi_ip_port: String,
+
+    //***4D STEM***//
+    pub mask_file: String,
+    pub detector_limits: ((POSITION, POSITION), (POSITION, POSITION)),
+    pub time_interval_4dframes: u64, //In milliseconds
+
+    //***Pixel calibration***//
+    pub pixel_calibration_file: String, //Mask and gain of the pixels. An empty path disables it.
+    pub tot_calibration_file: String, //ToT to energy coefficients of the pixels. An empty path disables it.
+    pub time_walk_file: String, //Time walk correction of the electrons. An empty path disables it.
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            nionswift_ip_address: [192, 168, 0, 11],
+            nionswift_port: 8088,
+            timepix_port: 8098,
+            save_locally_file: String::from("/media/asi/Data21/TP3_Data/"),
+            read_debug_file: String::from("C:\\Users\\AUAD\\Downloads\\2025_10_06_14_55_40.tpx3"),
+            read_debug_file_json: String::from("C:\\Users\\AUAD\\Documents\\Tp3_tools\\tpx3\\src\\bin\\Data\\reduced_raw_alissa"),
+            status_address: String::from("127.0.0.1:8099"),
+            subscriber_address: String::new(),
+            slow_subscriber: SlowConsumer::Drop,
+            subscriber_queue: 16,
+            extra_outputs: Vec::new(),
+            main_tdc: TdcType::TdcOneRisingEdge,
+            secondary_tdc: TdcType::TdcTwoRisingEdge,
+            period_divider: 1,
+            blanking_period: 6446292,
+            tdc_period_tolerance: 0.01,
+            activate_ttx: false,
+            time_interval_frames: 200,
+            time_interval_coincidence_histogram: 2000,
+            export_format: ExportFormat::Raw,
+            inverse_detector: true,
+            layout: Layout::default(),
+            edge_pixel_width: 1,
+            correct_electron_time_coarse: true,
+            cluster_det: 32,
+            cluster_spatial: 4,
+            live_clustering: false,
+            super_resolution: 1,
+            tdc_timeout: 10,
+            isi_ip_port: String::from("192.168.199.10:9592"),
+            mask_file: String::from("C:\\ProgramData\\Microscope\\masks.dat"),
+            detector_limits: ((512, 768), (0, 256)),
+            time_interval_4dframes: 100,
+            pixel_calibration_file: String::new(),
+            tot_calibration_file: String::new(),
+            time_walk_file: String::new(),
+        }
+    }
+}
+
+impl Config {
+    ///Reads a JSON or TOML configuration file. Missing fields take their default values.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Tp3ErrorKind> {
+        let path = path.as_ref();
+        let mut file = File::open(path).with_path(path)?;
+        let mut buffer: Vec<u8> = Vec::new();
+        file.read_to_end(&mut buffer).with_path(path)?;
+        if path.extension().is_some_and(|extension| extension == "toml") {
+            let text = std::str::from_utf8(&buffer)?;
+            let value = toml::from_str::<serde_json::Value>(text).map_err(|error| {
+                //The line of the error, counted from the byte where it starts.
+                let line = error.span().map_or(0, |span| text[..span.start].matches('\n').count() + 1);
+                Tp3ErrorKind::ConfigBadToml {line, message: error.message().to_string()}
+            })?;
+            return Ok(serde_json::from_value(value)?);
+        }
+        let config: Config = serde_json::from_slice(&buffer)?;
+        Ok(config)
+    }
+
+    ///Checks the values that would otherwise only fail in a measurement: the layout, whose
+    ///geometry is returned, and the modes of the extra outputs.
+    fn check(&self) -> Result<Geometry, Tp3ErrorKind> {
+        for output in &self.extra_outputs {
+            output.check()?;
+        }
+        Geometry::from_layout(&self.layout, self.inverse_detector)
+    }
+
+    ///Builds the configuration from the file, environment and command line arguments. The
+    ///configuration arguments are consumed and the remaining ones are returned, in order.
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<(Self, Vec<String>), Tp3ErrorKind> {
+        let mut config_file: Option<String> = None;
+        let mut overrides: Vec<(String, String)> = Vec::new();
+        let mut remaining: Vec<String> = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => {
+                    config_file = Some(args.next().ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(arg.clone()))?);
+                },
+                "--set" => {
+                    let key_value = args.next().ok_or_else(|| Tp3ErrorKind::ConfigBadArgument(arg.clone()))?;
+                    overrides.push(split_key_value(&key_value)?);
+                },
+                _ => remaining.push(arg),
+            }
+        }
+
+        let config_file = config_file
+            .or_else(|| std::env::var(CONFIG_ENV_FILE).ok())
+            .or_else(|| [CONFIG_DEFAULT_FILE, CONFIG_DEFAULT_TOML_FILE].iter().find(|file| Path::new(file).exists()).map(|file| String::from(*file)));
+        let base = match config_file {
+            Some(file) => {
+                println!("***Configlib***: Reading configuration from {}.", file);
+                Config::from_file(file)?
+            },
+            None => Config::default(),
+        };
+
+        let mut value = serde_json::to_value(&base)?;
+        let fields = value.as_object_mut().expect("Config is always a JSON object.");
+        let keys: Vec<String> = fields.keys().cloned().collect();
+        for key in keys {
+            if let Ok(raw) = std::env::var(CONFIG_ENV_PREFIX.to_owned() + &key.to_uppercase()) {
+                set_field(fields, &key, &raw)?;
+            }
+        }
+        for (key, raw) in overrides {
+            set_field(fields, &key, &raw)?;
+        }
+        let config: Config = serde_json::from_value(value)?;
+        config.check()?;
+        Ok((config, remaining))
+    }
+}
+
+fn split_key_value(key_value: &str) -> Result<(String, String), Tp3ErrorKind> {
+    match key_value.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.trim().to_owned(), value.to_owned())),
+        _ => Err(Tp3ErrorKind::ConfigBadArgument(key_value.to_owned())),
+    }
+}
+
+fn set_field(fields: &mut Map<String, Value>, key: &str, raw: &str) -> Result<(), Tp3ErrorKind> {
+    if !fields.contains_key(key) {
+        return Err(Tp3ErrorKind::ConfigUnknownField(key.to_owned()));
+    }
+    let value = serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_owned()));
+    fields.insert(key.to_owned(), value);
+    Ok(())
+}
+
+///Sets the global configuration, and the global geometry from its layout. It can only be set
+///once, and before the first call to `config`. A bad layout or extra output is refused before
+///anything is set.
+pub fn init(config: Config) -> Result<(), Tp3ErrorKind> {
+    let geometry = config.check()?;
+    CONFIG.set(config).map_err(|_| Tp3ErrorKind::ConfigAlreadyLoaded)?;
+    geometrylib::init(geometry)
+}
+
+///Sets the global configuration from the command line arguments and returns the arguments that
+///were not used by the configuration.
+pub fn init_from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Vec<String>, Tp3ErrorKind> {
+    let (config, remaining) = Config::from_args(args)?;
+    println!("***Configlib***: Configuration is {:?}.", config);
+    init(config)?;
+    Ok(remaining)
+}
+
+///Loads the calibrations named in the global configuration, so that a missing or bad file stops
+///the program at startup instead of in the middle of a measurement.
+pub fn init_calibrations() -> Result<(), Tp3ErrorKind> {
+    pixellib::init_from_config()?;
+    energylib::init_from_config()?;
+    walklib::init_from_config()
+}
+
+///The global configuration. Every program sets it once at startup with `init` or
+///`init_from_args`, so that a bad file or variable is reported there.
+pub fn config() -> &'static Config {
+    CONFIG.get().expect("***Configlib***: The configuration was read before `init`.")
+}
@@ -0,0 +1,167 @@
+//!`controllib` is a collection of tools to stop a running measurement.
+//!
+//!A measurement stops when the packet source is over, when Nionswift sends `stop` or `abort` on the
+//!settings socket, when Nionswift disconnects, or when the process receives SIGINT/SIGTERM. In every
+//!case the reader thread stops, files are flushed and a summary is reported. A stop sends the data
+//!already built, while an abort discards it.
+use crate::auxiliar::DebugIO;
+use crate::metricslib::metrics;
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const STOP_MESSAGE: &[u8] = b"stop";
+pub const ABORT_MESSAGE: &[u8] = b"abort";
+const CONTROL_POLL: Duration = Duration::from_millis(100);
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+
+///Why the measurement was stopped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum StopReason {
+    Running = 0,
+    SourceOver = 1,
+    ClientStop = 2,
+    ClientAbort = 3,
+    ClientDisconnected = 4,
+    Signal = 5,
+}
+
+impl StopReason {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => StopReason::SourceOver,
+            2 => StopReason::ClientStop,
+            3 => StopReason::ClientAbort,
+            4 => StopReason::ClientDisconnected,
+            5 => StopReason::Signal,
+            _ => StopReason::Running,
+        }
+    }
+}
+
+///Installs the SIGINT/SIGTERM handler. After a signal, the running measurement stops and
+///`shutdown_requested` is true. A second signal exits at once.
+pub fn install_signal_handler() -> Result<(), ctrlc::Error> {
+    ctrlc::set_handler(|| {
+        if SHUTDOWN.swap(true, Ordering::SeqCst) {
+            println!("***Controllib***: Second shutdown request. Exiting now.");
+            std::process::exit(130);
+        }
+        println!("***Controllib***: Shutdown requested. Send it again to exit now.");
+    })
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+///Shared state of a running measurement. It is cheap to clone, and clones refer to the same
+///measurement.
+#[derive(Clone)]
+pub struct Control(Arc<ControlState>);
+
+struct ControlState {
+    reason: AtomicU8,
+    finished: AtomicBool,
+    start: Instant,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        Control::new()
+    }
+}
+
+impl Control {
+    pub fn new() -> Self {
+        Control(Arc::new(ControlState {
+            reason: AtomicU8::new(StopReason::Running as u8),
+            finished: AtomicBool::new(false),
+            start: Instant::now(),
+        }))
+    }
+
+    ///Requests the measurement to stop. Only the first reason is kept.
+    pub fn stop(&self, reason: StopReason) {
+        let _ = self.0.reason.compare_exchange(StopReason::Running as u8, reason as u8, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    pub fn should_stop(&self) -> bool {
+        if shutdown_requested() {self.stop(StopReason::Signal);}
+        self.reason() != StopReason::Running
+    }
+
+    pub fn is_aborted(&self) -> bool {
+        self.reason() == StopReason::ClientAbort
+    }
+
+    pub fn reason(&self) -> StopReason {
+        StopReason::from_u8(self.0.reason.load(Ordering::SeqCst))
+    }
+
+
+    ///Watches the control socket for stop and abort messages until the measurement finishes.
+    pub fn watch<R: 'static + Read + Send>(&self, mut control_sock: R) {
+        let control = self.clone();
+        thread::spawn(move || {
+            let mut buffer = [0_u8; 64];
+            while !control.0.finished.load(Ordering::SeqCst) {
+                match control_sock.read(&mut buffer) {
+                    //Nionswift can close its side after sending the settings.
+                    Ok(0) => break,
+                    Ok(size) => {
+                        let message = &buffer[..size];
+                        if contains(message, ABORT_MESSAGE) {
+                            control.stop(StopReason::ClientAbort);
+                        } else if contains(message, STOP_MESSAGE) {
+                            control.stop(StopReason::ClientStop);
+                        }
+                    },
+                    Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {},
+                    Err(_) => break,
+                }
+            }
+        });
+    }
+
+    ///Marks the measurement as over and reports its summary.
+    pub fn finish(&self) -> StopReason {
+        self.stop(StopReason::SourceOver);
+        self.0.finished.store(true, Ordering::SeqCst);
+        metrics().finish();
+        let summary = metrics().snapshot();
+        println!("***Controllib***: Measurement is over ({:?}). Elapsed time is {:?}. Read {} bytes and sent {} outputs. Dropped {} indexes.",
+            self.reason(), self.0.start.elapsed(), summary.bytes_read, summary.outputs_sent, summary.dropped_indexes);
+        self.reason()
+    }
+}
+
+fn contains(message: &[u8], pattern: &[u8]) -> bool {
+    message.windows(pattern.len()).any(|window| window.eq_ignore_ascii_case(pattern))
+}
+
+///A Nionswift socket that can also receive control messages.
+pub trait ControlSocket: Read + Write {
+    ///An independent handle to read control messages from. Reads must time out so the watcher
+    ///can finish.
+    fn control_reader(&self) -> Option<Box<dyn Read + Send>>;
+}
+
+impl ControlSocket for TcpStream {
+    fn control_reader(&self) -> Option<Box<dyn Read + Send>> {
+        let sock = self.try_clone().ok()?;
+        sock.set_read_timeout(Some(CONTROL_POLL)).ok()?;
+        Some(Box::new(sock))
+    }
+}
+
+impl ControlSocket for DebugIO {
+    fn control_reader(&self) -> Option<Box<dyn Read + Send>> {
+        None
+    }
+}
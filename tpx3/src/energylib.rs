@@ -0,0 +1,325 @@
+//!`energylib` is a collection of tools to convert the time over threshold (ToT) of the pixels to
+//!the energy they received. Every pixel follows the surrogate function
+//!`tot = a * energy + b - c / (energy - t)`, with the energy in keV and the ToT in units of 25 ns.
+//!With `c` at zero it is the linear calibration of the former `atot`/`btot` tables, which can still
+//!be read with `TotCalibration::from_tables`.
+//!
+//!New coefficients are fitted from `tp3_calib` runs at known energies (see `TotSamples`). The mean
+//!ToT of every pixel at every energy gives `a` and `b` from two energies, and also `c` and `t` from
+//!four. Pixels without enough hits take the coefficients fitted over the whole detector. The
+//!calibration is saved as an event file (see `eventlib`) with one row per pixel and the columns
+//!`a`, `b`, `c` and `t`. Set `config().tot_calibration_file` to use it.
+use crate::auxiliar::value_types::*;
+use crate::configlib::config;
+use crate::errorlib::{Tp3ErrorKind, PathContext};
+use crate::eventlib::{Column, ColumnData, EventReader, EventWriter, Provenance};
+use crate::packetlib::Packet;
+use rayon::prelude::*;
+use std::path::Path;
+use std::sync::OnceLock;
+
+static TOT_CALIBRATION: OnceLock<Option<TotCalibration>> = OnceLock::new();
+
+///Size of the former `atot`/`btot` tables, indexed by `x + 1024 * y`.
+const TABLE_SIZE: (POSITION, POSITION) = (1024, 256);
+///Hits a pixel needs at an energy for its mean ToT to be used in the fit.
+const MIN_HITS: u32 = 10;
+///Values of `t` tried by the surrogate fit, between zero and the lowest energy.
+const SURROGATE_STEPS: usize = 64;
+
+///Coefficients of the surrogate function of one pixel.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TotCoefficients {
+    pub a: f32, //ToT per keV
+    pub b: f32, //ToT offset
+    pub c: f32, //Curvature near the threshold. Zero for a linear pixel
+    pub t: f32, //Energy of the asymptote, in keV
+}
+
+impl Default for TotCoefficients {
+    fn default() -> Self {
+        TotCoefficients::linear(1.0, 0.0)
+    }
+}
+
+impl TotCoefficients {
+    pub fn linear(a: f32, b: f32) -> Self {
+        TotCoefficients {a, b, c: 0.0, t: 0.0}
+    }
+
+    ///ToT given by an energy.
+    pub fn tot(&self, energy: f32) -> f32 {
+        if self.c == 0.0 {
+            self.a * energy + self.b
+        } else {
+            self.a * energy + self.b - self.c / (energy - self.t)
+        }
+    }
+
+    ///Energy given by a ToT. Zero if the ToT is below the calibration.
+    pub fn energy(&self, tot: f32) -> f32 {
+        let energy = if self.c == 0.0 {
+            (tot - self.b) / self.a
+        } else {
+            //Largest root of a * e^2 + (b - a * t - tot) * e + (tot - b) * t - c = 0.
+            let p = self.b - self.a * self.t - tot;
+            let q = (tot - self.b) * self.t - self.c;
+            (-p + (p * p - 4.0 * self.a * q).sqrt()) / (2.0 * self.a)
+        };
+        if energy.is_finite() {energy.max(0.0)} else {0.0}
+    }
+}
+
+///Mean ToT of every pixel at a known energy, from the electrons of a `tp3_calib` run.
+pub struct TotSamples {
+    sum: Vec<f64>,
+    count: Vec<u32>,
+}
+
+impl Default for TotSamples {
+    fn default() -> Self {
+        TotSamples::new()
+    }
+}
+
+impl TotSamples {
+    pub fn new() -> Self {
+        let (width, height) = Packet::chip_array();
+        let pixels = (width * height) as usize;
+        TotSamples {sum: vec![0.0; pixels], count: vec![0; pixels]}
+    }
+
+    #[inline]
+    pub fn add(&mut self, x: POSITION, y: POSITION, tot: u16) {
+        let index = (x + Packet::chip_array().0 * y) as usize;
+        self.sum[index] += tot as f64;
+        self.count[index] += 1;
+    }
+
+    ///Reads the columns `x`, `y` and `tot` of the event file created by `tp3_calib`.
+    pub fn from_events<P: AsRef<Path>>(path: P) -> Result<Self, Tp3ErrorKind> {
+        let mut reader = EventReader::open(path)?;
+        let x = reader.read_column::<u16>("x")?;
+        let y = reader.read_column::<u8>("y")?;
+        let tot = reader.read_column::<u16>("tot")?;
+        let mut samples = TotSamples::new();
+        for ((x, y), tot) in x.iter().zip(y.iter()).zip(tot.iter()) {
+            samples.add(*x as POSITION, *y as POSITION, *tot);
+        }
+        Ok(samples)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.count.iter().map(|count| *count as u64).sum()
+    }
+
+    fn mean(&self, index: usize) -> Option<f32> {
+        if self.count[index] < MIN_HITS {return None;}
+        Some((self.sum[index] / self.count[index] as f64) as f32)
+    }
+
+    fn total_mean(&self) -> Option<f32> {
+        let hits = self.hits();
+        if hits == 0 {return None;}
+        Some((self.sum.iter().sum::<f64>() / hits as f64) as f32)
+    }
+}
+
+///Least squares fit of `tot = a * energy + b`.
+fn fit_linear(points: &[(f32, f32)]) -> Option<TotCoefficients> {
+    let n = points.len() as f64;
+    let mean_e = points.iter().map(|(e, _)| *e as f64).sum::<f64>() / n;
+    let mean_tot = points.iter().map(|(_, tot)| *tot as f64).sum::<f64>() / n;
+    let var = points.iter().map(|(e, _)| (*e as f64 - mean_e).powi(2)).sum::<f64>();
+    let cov = points.iter().map(|(e, tot)| (*e as f64 - mean_e) * (*tot as f64 - mean_tot)).sum::<f64>();
+    if var == 0.0 || cov <= 0.0 {return None;}
+    let a = cov / var;
+    Some(TotCoefficients::linear(a as f32, (mean_tot - a * mean_e) as f32))
+}
+
+///Solves a 3×3 linear system by Cramer's rule.
+fn solve3(m: [[f64; 3]; 3], v: [f64; 3]) -> Option<[f64; 3]> {
+    let det = |m: [[f64; 3]; 3]| {
+        m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+            - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+            + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0])
+    };
+    let d = det(m);
+    if d.abs() < 1e-12 {return None;}
+    let mut solution = [0.0; 3];
+    for (column, value) in solution.iter_mut().enumerate() {
+        let mut replaced = m;
+        replaced.iter_mut().zip(v.iter()).for_each(|(row, v)| row[column] = *v);
+        *value = det(replaced) / d;
+    }
+    Some(solution)
+}
+
+///Least squares fit of the surrogate function. For every `t`, the function is linear in `a`, `b`
+///and `c`; the `t` with the smallest residual is kept.
+fn fit_surrogate(points: &[(f32, f32)]) -> Option<TotCoefficients> {
+    let lowest = points.iter().map(|(e, _)| *e).fold(f32::INFINITY, f32::min) as f64;
+    (0..SURROGATE_STEPS).filter_map(|step| {
+        let t = lowest * step as f64 / SURROGATE_STEPS as f64;
+        let regressors = |e: f64| [e, 1.0, -1.0 / (e - t)];
+        let mut m = [[0.0; 3]; 3];
+        let mut v = [0.0; 3];
+        for (e, tot) in points {
+            let r = regressors(*e as f64);
+            for i in 0..3 {
+                for j in 0..3 {
+                    m[i][j] += r[i] * r[j];
+                }
+                v[i] += r[i] * *tot as f64;
+            }
+        }
+        let [a, b, c] = solve3(m, v)?;
+        if a <= 0.0 || c < 0.0 {return None;}
+        let residual: f64 = points.iter().map(|(e, tot)| {
+            let r = regressors(*e as f64);
+            (a * r[0] + b * r[1] + c * r[2] - *tot as f64).powi(2)
+        }).sum();
+        Some((residual, TotCoefficients {a: a as f32, b: b as f32, c: c as f32, t: t as f32}))
+    })
+    .min_by(|first, second| first.0.total_cmp(&second.0))
+    .map(|(_, coefficients)| coefficients)
+}
+
+fn fit_points(points: &[(f32, f32)], surrogate: bool) -> Option<TotCoefficients> {
+    if surrogate && points.len() >= 4 {
+        if let Some(coefficients) = fit_surrogate(points) {
+            return Some(coefficients);
+        }
+    }
+    if points.len() >= 2 {fit_linear(points)} else {None}
+}
+
+///Coefficients of every pixel, indexed by `x + width * y`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TotCalibration {
+    coefficients: Vec<TotCoefficients>,
+}
+
+impl TotCalibration {
+    ///The same coefficients for every pixel.
+    pub fn uniform(coefficients: TotCoefficients) -> Self {
+        let (width, height) = Packet::chip_array();
+        TotCalibration {coefficients: vec![coefficients; (width * height) as usize]}
+    }
+
+    #[inline]
+    fn index(x: POSITION, y: POSITION) -> usize {
+        (x + Packet::chip_array().0 * y) as usize
+    }
+
+    pub fn coefficients(&self, x: POSITION, y: POSITION) -> &TotCoefficients {
+        &self.coefficients[TotCalibration::index(x, y)]
+    }
+
+    pub fn set_coefficients(&mut self, x: POSITION, y: POSITION, coefficients: TotCoefficients) {
+        self.coefficients[TotCalibration::index(x, y)] = coefficients;
+    }
+
+    ///Energy, in keV, given by a ToT at (`x`, `y`).
+    #[inline]
+    pub fn energy(&self, x: POSITION, y: POSITION, tot: u16) -> f32 {
+        self.coefficients[TotCalibration::index(x, y)].energy(tot as f32)
+    }
+
+    ///Reads the former linear tables of `a` and `b`: 1024×256 little-endian `f32`, indexed by
+    ///`x + 1024 * y`. Positions out of the tables keep `tot = energy`.
+    pub fn from_tables<P: AsRef<Path>>(atot: P, btot: P) -> Result<Self, Tp3ErrorKind> {
+        let read = |path: &Path| -> Result<Vec<f32>, Tp3ErrorKind> {
+            let bytes = std::fs::read(path).with_path(path)?;
+            if bytes.len() != (TABLE_SIZE.0 * TABLE_SIZE.1) as usize * 4 {
+                return Err(Tp3ErrorKind::EnergyBadCalibration {reason: "the tables must have 1024×256 values"});
+            }
+            Ok(bytes.chunks_exact(4).map(|value| f32::from_le_bytes([value[0], value[1], value[2], value[3]])).collect())
+        };
+        let (a, b) = (read(atot.as_ref())?, read(btot.as_ref())?);
+        let (width, height) = Packet::chip_array();
+        let mut calibration = TotCalibration::uniform(TotCoefficients::default());
+        for y in 0..TABLE_SIZE.1.min(height) {
+            for x in 0..TABLE_SIZE.0.min(width) {
+                let index = (x + TABLE_SIZE.0 * y) as usize;
+                calibration.set_coefficients(x, y, TotCoefficients::linear(a[index], b[index]));
+            }
+        }
+        Ok(calibration)
+    }
+
+    ///Fits the coefficients from the samples of several known energies, in keV. The surrogate
+    ///function needs four energies; otherwise, or if `surrogate` is false, the pixels are linear.
+    pub fn fit(runs: &[(f32, TotSamples)], surrogate: bool) -> Result<Self, Tp3ErrorKind> {
+        let points: Vec<(f32, f32)> = runs.iter()
+            .filter_map(|(energy, samples)| Some((*energy, samples.total_mean()?)))
+            .collect();
+        let detector = fit_points(&points, surrogate)
+            .ok_or(Tp3ErrorKind::EnergyBadCalibration {reason: "the runs need two energies with hits and a ToT growing with the energy"})?;
+
+        let pixels = runs.first().map_or(0, |(_, samples)| samples.count.len());
+        let coefficients: Vec<TotCoefficients> = (0..pixels).into_par_iter().map(|index| {
+            let points: Vec<(f32, f32)> = runs.iter()
+                .filter_map(|(energy, samples)| Some((*energy, samples.mean(index)?)))
+                .collect();
+            fit_points(&points, surrogate).unwrap_or(detector)
+        }).collect();
+        let fitted = coefficients.iter().filter(|pixel| **pixel != detector).count();
+        println!("***Energylib***: {} pixels were fitted. The others follow {:?}.", fitted, detector);
+        Ok(TotCalibration {coefficients})
+    }
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new::<f32>("a", "25 ns/keV", "ToT per energy."),
+            Column::new::<f32>("b", "25 ns", "ToT offset."),
+            Column::new::<f32>("c", "25 ns keV", "Curvature near the threshold."),
+            Column::new::<f32>("t", "keV", "Energy of the asymptote."),
+        ]
+    }
+
+    ///Saves the calibration. `provenance` gives the runs it was fitted from.
+    pub fn save<P: AsRef<Path>>(&self, path: P, provenance: Provenance) -> Result<(), Tp3ErrorKind> {
+        let column = |value: fn(&TotCoefficients) -> f32| self.coefficients.iter().map(value).collect::<Vec<f32>>();
+        let (a, b, c, t) = (column(|pixel| pixel.a), column(|pixel| pixel.b), column(|pixel| pixel.c), column(|pixel| pixel.t));
+        let mut writer = EventWriter::create(path, TotCalibration::columns(), None, provenance)?;
+        writer.write_block(&[ColumnData::new(&a), ColumnData::new(&b), ColumnData::new(&c), ColumnData::new(&t)])?;
+        writer.finish()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Tp3ErrorKind> {
+        let mut reader = EventReader::open(path)?;
+        let a = reader.read_column::<f32>("a")?;
+        let b = reader.read_column::<f32>("b")?;
+        let c = reader.read_column::<f32>("c")?;
+        let t = reader.read_column::<f32>("t")?;
+        let (width, height) = Packet::chip_array();
+        if a.len() != (width * height) as usize {
+            return Err(Tp3ErrorKind::EnergyBadCalibration {reason: "wrong number of pixels"});
+        }
+        let coefficients = (0..a.len()).map(|index| TotCoefficients {a: a[index], b: b[index], c: c[index], t: t[index]}).collect();
+        Ok(TotCalibration {coefficients})
+    }
+}
+
+///Sets the global calibration. It can only be set once, and before the first call to
+///`tot_calibration`.
+pub fn init(calibration: TotCalibration) -> Result<(), Tp3ErrorKind> {
+    TOT_CALIBRATION.set(Some(calibration)).map_err(|_| Tp3ErrorKind::EnergyCalibrationAlreadyLoaded)
+}
+
+///Sets the global calibration if `config().tot_calibration_file` is given. A file that cannot be
+///read is an error, rather than running without the calibration.
+pub fn init_from_config() -> Result<(), Tp3ErrorKind> {
+    let path = &config().tot_calibration_file;
+    if path.is_empty() {
+        return Ok(());
+    }
+    println!("***Energylib***: Reading the ToT calibration from {}.", path);
+    init(TotCalibration::load(path)?)
+}
+
+///The global calibration, if one was initialized.
+pub fn tot_calibration() -> Option<&'static TotCalibration> {
+    TOT_CALIBRATION.get_or_init(|| None).as_ref()
+}
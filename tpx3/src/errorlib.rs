@@ -1,4 +1,12 @@
-//!`errorlib` is a simply enumeration to control error handling and logging.
+//!`errorlib` is a simply enumeration to control error handling and logging. Variants carry the
+//!context in which they happened (paths, TDC types, socket addresses, etc.) and the underlying
+//!error, if any, is available as `source`.
+use crate::tdclib::TdcType;
+use crate::exportlib::ExportFormat;
+use crate::auxiliar::value_types::*;
+use std::fmt;
+use std::path::Path;
+
 #[derive(Debug)]
 pub enum Tp3ErrorKind {
     //From settings
@@ -8,39 +16,43 @@ pub enum Tp3ErrorKind {
     SetMode,
     SetXSize,
     SetYSize,
-    SetNoReadFile,
+    SetNoReadFile {path: String, source: std::io::Error},
     SetNoWriteFile,
+    SetNoSocket {address: String, source: std::io::Error},
 
     //From TDC
-    TdcNoReceived,
-    TdcBadPeriod,
-    TdcBadHighTime,
-    TdcNotAscendingOrder,
+    TdcNoReceived {tdc_type: TdcType, timeout: u64},
+    TdcBadPeriod {tdc_type: TdcType, expected: TIME, observed: TIME},
+    TdcBadHighTime {tdc_type: TdcType},
+    TdcNotAscendingOrder {tdc_type: TdcType, index: usize, previous: TIME, next: TIME},
     TdcZeroBytes,
 
     //Mode implementation
     MiscModeNotImplemented(u8),
+    MiscModeNotAnOutput(u8),
+    MiscShutdown,
 
     //From IO-based, such as external libraries (like json parser)
-    IOGeneralError,
+    IOGeneralError {path: Option<String>, source: std::io::Error},
     IOCouldNotCreateFile,
-    IOFileNotFound,
-    SerdeGeneralError,
-    Utf8GeneralError,
-    FolderAlreadyCreated,
+    IOFileNotFound {path: Option<String>, source: std::io::Error},
+    SerdeGeneralError(serde_json::Error),
+    Utf8GeneralError(std::str::Utf8Error),
+    FolderAlreadyCreated {path: String, source: std::io::Error},
 
     //Coincidence-related
-    CoincidenceCantReadFile,
+    CoincidenceCantReadFile {path: String, source: std::io::Error},
+    CoincidenceConsumerStopped,
 
     //Read-packet related
-    TimepixReadLoop,
-    TimepixReadOver,
+    TimepixReadLoop {offset: usize, source: std::io::Error},
+    TimepixReadOver {offset: usize},
 
     //IsiBox
     IsiBoxAttempt(u8),
-    IsiBoxCouldNotConnect,
-    IsiBoxCouldNotSetParameters,
-    IsiBoxCouldNotConfigure,
+    IsiBoxCouldNotConnect {address: String, source: std::io::Error},
+    IsiBoxCouldNotSetParameters(std::io::Error),
+    IsiBoxCouldNotConfigure(std::io::Error),
     IsiBoxCouldNotSync,
 
     //4D from mask
@@ -55,27 +67,154 @@ pub enum Tp3ErrorKind {
     TRFolderNotCreated,
     TRScanOutofBounds,
     TRMinGreaterThanMax,
+
+    //Runtime configuration
+    ConfigBadArgument(String),
+    ConfigUnknownField(String),
+    ConfigAlreadyLoaded,
+    ConfigBadToml {line: usize, message: String},
+
+    //Export of post-processed data
+    ExportFormatNotCompiled(ExportFormat),
+    ExportBadShape {name: String, dtype: &'static str, row_shape: Vec<usize>},
+    ExportHdf5 {path: String, operation: &'static str},
+
+    //Event files
+    EventBadFile {path: String, reason: &'static str},
+    EventUnsupportedVersion {path: String, version: u32},
+    EventBadColumn {name: String, reason: &'static str},
+
+    //Packet reader
+    ReaderTruncatedPacket {offset: u64, size: usize},
+
+    //Pixel calibration
+    PixelBadCalibration {reason: &'static str},
+    PixelCalibrationAlreadyLoaded,
+
+    //Detector geometry
+    GeometryBadLayout {ci: u8, reason: &'static str},
+    GeometryAlreadyLoaded,
+
+    //ToT calibration
+    EnergyBadCalibration {reason: &'static str},
+    EnergyCalibrationAlreadyLoaded,
+
+    //Time walk correction
+    WalkBadCorrection {reason: &'static str},
+    WalkAlreadyLoaded,
+
+    //Simulation
+    SimNoTdcLine,
+}
+
+impl fmt::Display for Tp3ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Tp3ErrorKind::*;
+        match self {
+            SetNoReadFile {path, ..} => write!(f, "could not open the file {} to read", path),
+            SetNoSocket {address, ..} => write!(f, "could not connect the socket at {}", address),
+            TdcNoReceived {tdc_type, timeout} => write!(f, "{} was not received after {} s", tdc_type.associate_str(), timeout),
+            TdcBadPeriod {tdc_type, expected, observed} => write!(f, "{} has period {} but {} was expected", tdc_type.associate_str(), observed, expected),
+            TdcBadHighTime {tdc_type} => write!(f, "{} has no valid high time", tdc_type.associate_str()),
+            TdcNotAscendingOrder {tdc_type, index, previous, next} => write!(f, "{} is not in ascending order at index {} ({} is followed by {})", tdc_type.associate_str(), index, previous, next),
+            MiscModeNotImplemented(mode) => write!(f, "mode {} is not implemented", mode),
+            MiscModeNotAnOutput(mode) => write!(f, "mode {} cannot be an extra output", mode),
+            MiscShutdown => write!(f, "shutdown was requested"),
+            IOGeneralError {path: Some(path), ..} => write!(f, "IO error on {}", path),
+            IOGeneralError {path: None, ..} => write!(f, "IO error"),
+            IOFileNotFound {path: Some(path), ..} => write!(f, "{} was not found", path),
+            IOFileNotFound {path: None, ..} => write!(f, "file was not found"),
+            SerdeGeneralError(_) => write!(f, "could not (de)serialize the JSON"),
+            Utf8GeneralError(_) => write!(f, "data is not valid UTF-8"),
+            FolderAlreadyCreated {path, ..} => write!(f, "could not create the folder {}", path),
+            CoincidenceCantReadFile {path, ..} => write!(f, "could not read the coincidence file {}", path),
+            TimepixReadLoop {offset, ..} => write!(f, "packet source failed after {} bytes", offset),
+            TimepixReadOver {offset} => write!(f, "packet source is over after {} bytes", offset),
+            IsiBoxCouldNotConnect {address, ..} => write!(f, "could not connect to the IsiBox at {}", address),
+            IsiBoxCouldNotSetParameters(_) => write!(f, "could not set the IsiBox scan parameters"),
+            IsiBoxCouldNotConfigure(_) => write!(f, "could not configure the IsiBox measurement"),
+            ConfigBadArgument(arg) => write!(f, "bad configuration argument {}", arg),
+            ConfigUnknownField(field) => write!(f, "unknown configuration field {}", field),
+            ConfigBadToml {line, message} => write!(f, "bad TOML configuration at line {}: {}", line, message),
+            ExportFormatNotCompiled(format) => write!(f, "export format {:?} is not compiled in", format),
+            ExportBadShape {name, dtype, row_shape} => write!(f, "dataset {} does not accept {} rows of shape {:?}", name, dtype, row_shape),
+            ExportHdf5 {path, operation} => write!(f, "could not {} in {}", operation, path),
+            EventBadFile {path, reason} => write!(f, "bad event file {}: {}", path, reason),
+            EventUnsupportedVersion {path, version} => write!(f, "event file {} has the unsupported version {}", path, version),
+            EventBadColumn {name, reason} => write!(f, "event column {}: {}", name, reason),
+            ReaderTruncatedPacket {offset, size} => write!(f, "stream ends with a truncated packet of {} bytes at {}", size, offset),
+            PixelBadCalibration {reason} => write!(f, "bad pixel calibration: {}", reason),
+            GeometryBadLayout {ci, reason} => write!(f, "bad detector layout for the chip {}: {}", ci, reason),
+            EnergyBadCalibration {reason} => write!(f, "bad ToT calibration: {}", reason),
+            WalkBadCorrection {reason} => write!(f, "bad time walk correction: {}", reason),
+            SimNoTdcLine => write!(f, "a simulated TDC signal must have an input line"),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+impl std::error::Error for Tp3ErrorKind {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use Tp3ErrorKind::*;
+        match self {
+            SetNoReadFile {source, ..} | SetNoSocket {source, ..} |
+            IOGeneralError {source, ..} | IOFileNotFound {source, ..} |
+            FolderAlreadyCreated {source, ..} | CoincidenceCantReadFile {source, ..} |
+            TimepixReadLoop {source, ..} | IsiBoxCouldNotConnect {source, ..} |
+            IsiBoxCouldNotSetParameters(source) | IsiBoxCouldNotConfigure(source) => Some(source),
+            SerdeGeneralError(source) => Some(source),
+            Utf8GeneralError(source) => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl Tp3ErrorKind {
+    ///The error followed by its chain of sources, separated by `: `.
+    pub fn chain(&self) -> String {
+        let mut message = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            message.push_str(": ");
+            message.push_str(&error.to_string());
+            source = error.source();
+        }
+        message
+    }
+
+    fn from_io(source: std::io::Error, path: Option<String>) -> Tp3ErrorKind {
+        match source.kind() {
+            std::io::ErrorKind::NotFound => Tp3ErrorKind::IOFileNotFound {path, source},
+            _ => Tp3ErrorKind::IOGeneralError {path, source},
+        }
+    }
+}
+
+///Adds the path to IO errors.
+pub trait PathContext<T> {
+    fn with_path<P: AsRef<Path>>(self, path: P) -> Result<T, Tp3ErrorKind>;
+}
+
+impl<T> PathContext<T> for std::io::Result<T> {
+    fn with_path<P: AsRef<Path>>(self, path: P) -> Result<T, Tp3ErrorKind> {
+        self.map_err(|error| Tp3ErrorKind::from_io(error, Some(path.as_ref().display().to_string())))
+    }
 }
 
 impl From<std::io::Error> for Tp3ErrorKind {
     fn from(e: std::io::Error) -> Tp3ErrorKind {
-        match e.kind() {
-            std::io::ErrorKind::NotFound => Tp3ErrorKind::IOFileNotFound,
-            _ => Tp3ErrorKind::IOGeneralError,
-        }
+        Tp3ErrorKind::from_io(e, None)
     }
 }
 
 impl From<std::str::Utf8Error> for Tp3ErrorKind {
-    fn from(_: std::str::Utf8Error) -> Tp3ErrorKind {
-        Tp3ErrorKind::Utf8GeneralError
+    fn from(e: std::str::Utf8Error) -> Tp3ErrorKind {
+        Tp3ErrorKind::Utf8GeneralError(e)
     }
 }
 
 impl From<serde_json::Error> for Tp3ErrorKind {
     fn from(error: serde_json::Error) -> Tp3ErrorKind {
-        println!("***Errorlib***: Serde general error is {:?}", error); 
-        Tp3ErrorKind::SerdeGeneralError
+        Tp3ErrorKind::SerdeGeneralError(error)
     }
 }
-
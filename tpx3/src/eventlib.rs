@@ -0,0 +1,239 @@
+//!`eventlib` is a collection of tools to write and read event lists in a self-describing format.
+//!
+//!An event file (`.tpev`) starts with `MAGIC`, followed by the length of the header (u32) and the
+//!header itself, in JSON. The header gives the format version, the name, dtype, unit and
+//!description of every column, the acquisition settings and the provenance of the file. The events
+//!follow in blocks, one per buffer processed: the number of rows (u64) and then every column, in
+//!the order of the header. Numbers are little-endian and dtypes follow the numpy convention, as in
+//!`exportlib`.
+use crate::auxiliar::{Settings, misc::{as_bytes, as_bytes_mut}};
+use crate::errorlib::{Tp3ErrorKind, PathContext};
+use crate::exportlib::ExportType;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+pub const MAGIC: &[u8; 8] = b"TP3EVENT";
+pub const VERSION: u32 = 1;
+pub const EVENT_FILE: &str = "events.tpev";
+
+///A column of the event list.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Column {
+    pub name: String,
+    pub dtype: String,
+    pub unit: String,
+    pub description: String,
+}
+
+impl Column {
+    pub fn new<T: ExportType>(name: &str, unit: &str, description: &str) -> Self {
+        Column {name: name.to_owned(), dtype: T::DTYPE.to_owned(), unit: unit.to_owned(), description: description.to_owned()}
+    }
+
+    ///Size in bytes of a value of this column.
+    pub fn size(&self) -> Option<usize> {
+        self.dtype.get(2..)?.parse().ok()
+    }
+}
+
+///Where the events come from.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Provenance {
+    pub source: String,
+    pub source_size: Option<u64>,
+    pub tool: String,
+    pub version: String,
+    pub created: String,
+}
+
+impl Provenance {
+    ///Provenance of events produced by `tool` from the file `source`.
+    pub fn from_source<P: AsRef<Path>>(source: P, tool: &str) -> Self {
+        let source = source.as_ref();
+        Provenance {
+            source: source.display().to_string(),
+            source_size: std::fs::metadata(source).ok().map(|metadata| metadata.len()),
+            tool: tool.to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            created: Local::now().to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EventHeader {
+    pub version: u32,
+    pub columns: Vec<Column>,
+    pub settings: Option<Settings>,
+    pub provenance: Provenance,
+}
+
+impl EventHeader {
+    pub fn column(&self, name: &str) -> Option<(usize, &Column)> {
+        self.columns.iter().enumerate().find(|(_, column)| column.name == name)
+    }
+}
+
+///The values of one column in a block.
+pub struct ColumnData<'a> {
+    dtype: &'static str,
+    rows: usize,
+    bytes: &'a [u8],
+}
+
+impl<'a> ColumnData<'a> {
+    pub fn new<T: ExportType>(data: &'a [T]) -> Self {
+        ColumnData {dtype: T::DTYPE, rows: data.len(), bytes: as_bytes(data)}
+    }
+}
+
+///Writes an event file block by block.
+pub struct EventWriter {
+    path: PathBuf,
+    file: BufWriter<File>,
+    header: EventHeader,
+    rows: u64,
+}
+
+impl EventWriter {
+    pub fn create<P: AsRef<Path>>(path: P, columns: Vec<Column>, settings: Option<&Settings>, provenance: Provenance) -> Result<Self, Tp3ErrorKind> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(column) = columns.iter().find(|column| column.size().is_none()) {
+            return Err(Tp3ErrorKind::EventBadColumn {name: column.name.clone(), reason: "unknown dtype"});
+        }
+        let header = EventHeader {version: VERSION, columns, settings: settings.copied(), provenance};
+        let json = serde_json::to_vec(&header)?;
+        let mut file = BufWriter::new(File::create(&path).with_path(&path)?);
+        file.write_all(MAGIC).with_path(&path)?;
+        file.write_all(&(json.len() as u32).to_le_bytes()).with_path(&path)?;
+        file.write_all(&json).with_path(&path)?;
+        Ok(EventWriter {path, file, header, rows: 0})
+    }
+
+    ///Appends a block. There must be one `ColumnData` per column, in the order of the header, all
+    ///with the same number of rows.
+    pub fn write_block(&mut self, columns: &[ColumnData]) -> Result<(), Tp3ErrorKind> {
+        if columns.len() != self.header.columns.len() {
+            return Err(Tp3ErrorKind::EventBadColumn {name: String::new(), reason: "wrong number of columns"});
+        }
+        let rows = columns.first().map(|column| column.rows).unwrap_or(0);
+        for (data, column) in columns.iter().zip(self.header.columns.iter()) {
+            if data.dtype != column.dtype {
+                return Err(Tp3ErrorKind::EventBadColumn {name: column.name.clone(), reason: "wrong dtype"});
+            }
+            if data.rows != rows {
+                return Err(Tp3ErrorKind::EventBadColumn {name: column.name.clone(), reason: "wrong number of rows"});
+            }
+        }
+        if rows == 0 {return Ok(());}
+        self.file.write_all(&(rows as u64).to_le_bytes()).with_path(&self.path)?;
+        for data in columns {
+            self.file.write_all(data.bytes).with_path(&self.path)?;
+        }
+        self.rows += rows as u64;
+        Ok(())
+    }
+
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    pub fn finish(mut self) -> Result<(), Tp3ErrorKind> {
+        self.file.flush().with_path(&self.path)
+    }
+}
+
+///Reads an event file. Columns are read one at a time, so only the ones needed are loaded.
+pub struct EventReader {
+    path: PathBuf,
+    file: BufReader<File>,
+    header: EventHeader,
+    data_start: u64,
+}
+
+impl EventReader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Tp3ErrorKind> {
+        let path = path.as_ref().to_path_buf();
+        let bad_file = |reason| Tp3ErrorKind::EventBadFile {path: path.display().to_string(), reason};
+        let mut file = BufReader::new(File::open(&path).with_path(&path)?);
+
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic).map_err(|_| bad_file("no header"))?;
+        if &magic != MAGIC {return Err(bad_file("not an event file"));}
+        let mut length = [0; 4];
+        file.read_exact(&mut length).map_err(|_| bad_file("no header"))?;
+        let mut json = vec![0; u32::from_le_bytes(length) as usize];
+        file.read_exact(&mut json).map_err(|_| bad_file("truncated header"))?;
+        let header: EventHeader = serde_json::from_slice(&json)?;
+        if header.version > VERSION {
+            return Err(Tp3ErrorKind::EventUnsupportedVersion {path: path.display().to_string(), version: header.version});
+        }
+        if let Some(column) = header.columns.iter().find(|column| column.size().is_none()) {
+            return Err(Tp3ErrorKind::EventBadColumn {name: column.name.clone(), reason: "unknown dtype"});
+        }
+        let data_start = (MAGIC.len() + length.len() + json.len()) as u64;
+        Ok(EventReader {path, file, header, data_start})
+    }
+
+    pub fn header(&self) -> &EventHeader {
+        &self.header
+    }
+
+    fn row_size(&self) -> u64 {
+        self.header.columns.iter().map(|column| column.size().unwrap() as u64).sum()
+    }
+
+    ///Visits every block. `f` receives the number of rows and the position of the block data.
+    fn for_each_block<F: FnMut(&mut BufReader<File>, u64, u64) -> Result<(), Tp3ErrorKind>>(&mut self, mut f: F) -> Result<(), Tp3ErrorKind> {
+        let row_size = self.row_size();
+        let end = self.file.get_ref().metadata().with_path(&self.path)?.len();
+        let mut position = self.data_start;
+        while position < end {
+            self.file.seek(SeekFrom::Start(position)).with_path(&self.path)?;
+            let mut rows = [0; 8];
+            self.file.read_exact(&mut rows).map_err(|_| Tp3ErrorKind::EventBadFile {path: self.path.display().to_string(), reason: "truncated block"})?;
+            position += rows.len() as u64;
+            let rows = u64::from_le_bytes(rows);
+            //A corrupted count must not wrap around and pass as a small block.
+            let block_end = rows.checked_mul(row_size).and_then(|size| size.checked_add(position));
+            let block_end = match block_end {
+                Some(block_end) if block_end <= end => block_end,
+                _ => return Err(Tp3ErrorKind::EventBadFile {path: self.path.display().to_string(), reason: "truncated block"}),
+            };
+            f(&mut self.file, rows, position)?;
+            position = block_end;
+        }
+        Ok(())
+    }
+
+    ///Total number of events.
+    pub fn rows(&mut self) -> Result<u64, Tp3ErrorKind> {
+        let mut total = 0;
+        self.for_each_block(|_, rows, _| {total += rows; Ok(())})?;
+        Ok(total)
+    }
+
+    ///Reads a whole column. `T` must match the dtype of the column.
+    pub fn read_column<T: ExportType + Default>(&mut self, name: &str) -> Result<Vec<T>, Tp3ErrorKind> {
+        let (index, column) = self.header.column(name).ok_or_else(|| Tp3ErrorKind::EventBadColumn {name: name.to_owned(), reason: "no such column"})?;
+        if column.dtype != T::DTYPE {
+            return Err(Tp3ErrorKind::EventBadColumn {name: name.to_owned(), reason: "wrong dtype"});
+        }
+        let sizes: Vec<u64> = self.header.columns.iter().map(|column| column.size().unwrap() as u64).collect();
+        let offset_per_row: u64 = sizes[..index].iter().sum();
+        let path = self.path.clone();
+
+        //The blocks are checked against the size of the file, so these products cannot overflow.
+        let mut values = Vec::new();
+        self.for_each_block(|file, rows, position| {
+            file.seek(SeekFrom::Start(position + rows * offset_per_row)).with_path(&path)?;
+            let start = values.len();
+            values.resize(start + rows as usize, T::default());
+            file.read_exact(as_bytes_mut(&mut values[start..])).with_path(&path)
+        })?;
+        Ok(values)
+    }
+}
@@ -0,0 +1,337 @@
+//!`exportlib` is a collection of tools to save post-processed data with its type, shape and
+//!acquisition metadata.
+//!
+//!Datasets are appended buffer by buffer, each append adding rows of a fixed `row_shape`. The
+//!format is chosen by `Config.export_format`:
+//!- `Raw`: each dataset is a flat binary file in the output folder (`xH.txt`, `si.txt`, ...), as
+//!  written before, and `metadata.json` gives the dtype and shape of every file together with the
+//!  acquisition settings;
+//!- `Hdf5` (needs the `hdf5` feature): datasets are chunked and compressed in `data.h5`, following
+//!  the NeXus layout (`/entry/data/<name>`). The settings and the energy calibration (`sup0`,
+//!  `sup1`) are attributes of `/entry`. The feature links the system HDF5 library (found with
+//!  `HDF5_DIR`) and is tested with `cargo test --features hdf5`.
+use crate::auxiliar::{Settings, misc::as_bytes};
+use crate::configlib::config;
+use crate::errorlib::{Tp3ErrorKind, PathContext};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+pub const METADATA_FILE: &str = "metadata.json";
+pub const HDF5_FILE: &str = "data.h5";
+
+///The format of the post-processed data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExportFormat {
+    Raw,
+    Hdf5,
+}
+
+///Types that can be exported. `DTYPE` follows the numpy convention.
+pub trait ExportType: Copy {
+    const DTYPE: &'static str;
+    #[cfg(feature = "hdf5")]
+    fn h5_type() -> hdf5_sys::h5i::hid_t;
+}
+
+macro_rules! export_type {
+    ($t:ty, $dtype:expr, $h5:ident) => {
+        impl ExportType for $t {
+            const DTYPE: &'static str = $dtype;
+            #[cfg(feature = "hdf5")]
+            fn h5_type() -> hdf5_sys::h5i::hid_t {
+                *hdf5_sys::h5t::$h5
+            }
+        }
+    };
+}
+
+export_type!(u8, "|u1", H5T_NATIVE_UINT8);
+export_type!(i8, "|i1", H5T_NATIVE_INT8);
+export_type!(u16, "<u2", H5T_NATIVE_UINT16);
+export_type!(i16, "<i2", H5T_NATIVE_INT16);
+export_type!(u32, "<u4", H5T_NATIVE_UINT32);
+export_type!(i32, "<i4", H5T_NATIVE_INT32);
+export_type!(u64, "<u8", H5T_NATIVE_UINT64);
+export_type!(i64, "<i8", H5T_NATIVE_INT64);
+export_type!(f32, "<f4", H5T_NATIVE_FLOAT);
+export_type!(f64, "<f8", H5T_NATIVE_DOUBLE);
+
+///Type and shape of an exported dataset. The first dimension is the number of rows appended.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DatasetInfo {
+    pub dtype: String,
+    pub shape: Vec<usize>,
+    pub file: Option<String>,
+}
+
+///The content of `metadata.json` in the `Raw` format.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Metadata {
+    pub settings: Option<Settings>,
+    pub datasets: BTreeMap<String, DatasetInfo>,
+}
+
+enum Backend {
+    Raw,
+    #[cfg(feature = "hdf5")]
+    Hdf5(hdf5_writer::H5Writer),
+}
+
+///Writes the datasets of a post-processing run in `folder`.
+pub struct Exporter {
+    folder: PathBuf,
+    settings: Option<Settings>,
+    datasets: BTreeMap<String, DatasetInfo>,
+    backend: Backend,
+}
+
+impl Exporter {
+    ///Creates an exporter in the format given by the configuration. `folder` must exist.
+    pub fn new<P: AsRef<Path>>(folder: P, settings: Option<&Settings>) -> Result<Self, Tp3ErrorKind> {
+        Exporter::with_format(folder, settings, config().export_format)
+    }
+
+    pub fn with_format<P: AsRef<Path>>(folder: P, settings: Option<&Settings>, format: ExportFormat) -> Result<Self, Tp3ErrorKind> {
+        let folder = folder.as_ref().to_path_buf();
+        let backend = match format {
+            ExportFormat::Raw => Backend::Raw,
+            #[cfg(feature = "hdf5")]
+            ExportFormat::Hdf5 => Backend::Hdf5(hdf5_writer::H5Writer::create(&folder.join(HDF5_FILE), settings)?),
+            #[cfg(not(feature = "hdf5"))]
+            ExportFormat::Hdf5 => return Err(Tp3ErrorKind::ExportFormatNotCompiled(format)),
+        };
+        Ok(Exporter {folder, settings: settings.copied(), datasets: BTreeMap::new(), backend})
+    }
+
+    ///Appends rows to the dataset `name`. Every row has `row_shape` (empty for scalars), so
+    ///`data.len()` must be a multiple of its product. The dataset is created by the first append.
+    pub fn append<T: ExportType>(&mut self, name: &str, row_shape: &[usize], data: &[T]) -> Result<(), Tp3ErrorKind> {
+        let row_size: usize = row_shape.iter().product();
+        let bad_shape = || Tp3ErrorKind::ExportBadShape {name: name.to_owned(), dtype: T::DTYPE, row_shape: row_shape.to_vec()};
+        if row_size == 0 || !data.len().is_multiple_of(row_size) {return Err(bad_shape());}
+        let rows = data.len() / row_size;
+
+        let file = match self.backend {
+            Backend::Raw => Some(name.to_owned() + ".txt"),
+            #[cfg(feature = "hdf5")]
+            Backend::Hdf5(_) => None,
+        };
+        let info = self.datasets.entry(name.to_owned()).or_insert_with(|| {
+            let mut shape = vec![0];
+            shape.extend_from_slice(row_shape);
+            DatasetInfo {dtype: T::DTYPE.to_owned(), shape, file}
+        });
+        if info.dtype != T::DTYPE || info.shape[1..] != *row_shape {return Err(bad_shape());}
+        info.shape[0] += rows;
+
+        match &mut self.backend {
+            Backend::Raw => {
+                let path = self.folder.join(info.file.as_ref().expect("Raw datasets have a file."));
+                let mut file = OpenOptions::new().append(true).create(true).open(&path).with_path(&path)?;
+                file.write_all(as_bytes(data)).with_path(&path)?;
+            },
+            #[cfg(feature = "hdf5")]
+            Backend::Hdf5(writer) => writer.append(name, row_shape, data)?,
+        }
+        Ok(())
+    }
+
+    pub fn datasets(&self) -> &BTreeMap<String, DatasetInfo> {
+        &self.datasets
+    }
+
+    ///Writes the metadata (`Raw`) or closes the file (`Hdf5`).
+    pub fn finish(self) -> Result<(), Tp3ErrorKind> {
+        match self.backend {
+            Backend::Raw => {
+                let path = self.folder.join(METADATA_FILE);
+                let metadata = Metadata {settings: self.settings, datasets: self.datasets};
+                let data = serde_json::to_vec_pretty(&metadata)?;
+                std::fs::write(&path, data).with_path(&path)?;
+            },
+            #[cfg(feature = "hdf5")]
+            Backend::Hdf5(writer) => writer.close()?,
+        }
+        Ok(())
+    }
+}
+
+///Minimal HDF5 writer over the C library. Only what `Exporter` needs is wrapped.
+#[cfg(feature = "hdf5")]
+mod hdf5_writer {
+    use super::ExportType;
+    use crate::auxiliar::Settings;
+    use crate::errorlib::Tp3ErrorKind;
+    use hdf5_sys::{h5, h5a, h5d, h5f, h5g, h5p, h5s, h5t};
+    use hdf5_sys::h5i::hid_t;
+    use hdf5_sys::h5::{herr_t, hsize_t};
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::os::raw::c_void;
+    use std::path::Path;
+
+    const DEFLATE_LEVEL: u32 = 4;
+    const CHUNK_ELEMENTS: usize = 1 << 16;
+
+    pub struct H5Writer {
+        path: String,
+        file: hid_t,
+        entry: hid_t,
+        data: hid_t,
+        datasets: HashMap<String, (hid_t, hsize_t)>,
+    }
+
+    ///An identifier closed when it goes out of scope, so the early returns do not leak it.
+    struct Handle(hid_t, unsafe extern "C" fn(hid_t) -> herr_t);
+
+    impl Drop for Handle {
+        fn drop(&mut self) {
+            if self.0 >= 0 {
+                unsafe {(self.1)(self.0);}
+            }
+        }
+    }
+
+    fn c_string(value: &str) -> CString {
+        CString::new(value).expect("HDF5 names have no null characters.")
+    }
+
+    impl H5Writer {
+        fn check<I: Into<i64>>(&self, id: I, operation: &'static str) -> Result<(), Tp3ErrorKind> {
+            if id.into() < 0 {
+                Err(Tp3ErrorKind::ExportHdf5 {path: self.path.clone(), operation})
+            } else {
+                Ok(())
+            }
+        }
+
+        pub fn create(path: &Path, settings: Option<&Settings>) -> Result<Self, Tp3ErrorKind> {
+            let mut writer = H5Writer {path: path.display().to_string(), file: -1, entry: -1, data: -1, datasets: HashMap::new()};
+            unsafe {
+                writer.check(h5::H5open(), "open the library")?;
+                let name = c_string(&writer.path);
+                writer.file = h5f::H5Fcreate(name.as_ptr(), h5f::H5F_ACC_TRUNC, h5p::H5P_DEFAULT, h5p::H5P_DEFAULT);
+                writer.check(writer.file, "create the file")?;
+                writer.entry = h5g::H5Gcreate2(writer.file, c_string("entry").as_ptr(), h5p::H5P_DEFAULT, h5p::H5P_DEFAULT, h5p::H5P_DEFAULT);
+                writer.check(writer.entry, "create /entry")?;
+                writer.data = h5g::H5Gcreate2(writer.entry, c_string("data").as_ptr(), h5p::H5P_DEFAULT, h5p::H5P_DEFAULT, h5p::H5P_DEFAULT);
+                writer.check(writer.data, "create /entry/data")?;
+            }
+            writer.string_attribute(writer.entry, "NX_class", "NXentry")?;
+            writer.string_attribute(writer.data, "NX_class", "NXdata")?;
+            if let Some(settings) = settings {
+                let json = serde_json::to_string(settings)?;
+                writer.string_attribute(writer.entry, "settings", &json)?;
+                let (sup0, sup1) = settings.energy_calibration();
+                writer.scalar_attribute(writer.entry, "sup0", sup0)?;
+                writer.scalar_attribute(writer.entry, "sup1", sup1)?;
+            }
+            Ok(writer)
+        }
+
+        fn string_attribute(&self, location: hid_t, name: &str, value: &str) -> Result<(), Tp3ErrorKind> {
+            unsafe {
+                let dtype = Handle(h5t::H5Tcopy(*h5t::H5T_C_S1), h5t::H5Tclose);
+                self.check(dtype.0, "create a string type")?;
+                self.check(h5t::H5Tset_size(dtype.0, value.len().max(1)), "set the string size")?;
+                let space = Handle(h5s::H5Screate(h5s::H5S_SCALAR), h5s::H5Sclose);
+                self.check(space.0, "create a dataspace")?;
+                let attribute = Handle(h5a::H5Acreate2(location, c_string(name).as_ptr(), dtype.0, space.0, h5p::H5P_DEFAULT, h5p::H5P_DEFAULT), h5a::H5Aclose);
+                self.check(attribute.0, "create an attribute")?;
+                self.check(h5a::H5Awrite(attribute.0, dtype.0, value.as_ptr() as *const c_void), "write an attribute")
+            }
+        }
+
+        fn scalar_attribute<T: ExportType>(&self, location: hid_t, name: &str, value: T) -> Result<(), Tp3ErrorKind> {
+            unsafe {
+                let space = Handle(h5s::H5Screate(h5s::H5S_SCALAR), h5s::H5Sclose);
+                self.check(space.0, "create a dataspace")?;
+                let attribute = Handle(h5a::H5Acreate2(location, c_string(name).as_ptr(), T::h5_type(), space.0, h5p::H5P_DEFAULT, h5p::H5P_DEFAULT), h5a::H5Aclose);
+                self.check(attribute.0, "create an attribute")?;
+                self.check(h5a::H5Awrite(attribute.0, T::h5_type(), &value as *const T as *const c_void), "write an attribute")
+            }
+        }
+
+        fn create_dataset<T: ExportType>(&self, name: &str, row_shape: &[usize]) -> Result<hid_t, Tp3ErrorKind> {
+            let row_size: usize = row_shape.iter().product();
+            let mut dims: Vec<hsize_t> = vec![0];
+            let mut max_dims: Vec<hsize_t> = vec![h5s::H5S_UNLIMITED];
+            let mut chunk: Vec<hsize_t> = vec![(CHUNK_ELEMENTS / row_size).max(1) as hsize_t];
+            for size in row_shape {
+                dims.push(*size as hsize_t);
+                max_dims.push(*size as hsize_t);
+                chunk.push(*size as hsize_t);
+            }
+            unsafe {
+                let space = Handle(h5s::H5Screate_simple(dims.len() as i32, dims.as_ptr(), max_dims.as_ptr()), h5s::H5Sclose);
+                self.check(space.0, "create a dataspace")?;
+                let plist = Handle(h5p::H5Pcreate(*h5p::H5P_CLS_DATASET_CREATE), h5p::H5Pclose);
+                self.check(plist.0, "create a property list")?;
+                self.check(h5p::H5Pset_chunk(plist.0, chunk.len() as i32, chunk.as_ptr()), "set the chunk size")?;
+                self.check(h5p::H5Pset_deflate(plist.0, DEFLATE_LEVEL), "set the compression")?;
+                let dataset = h5d::H5Dcreate2(self.data, c_string(name).as_ptr(), T::h5_type(), space.0, h5p::H5P_DEFAULT, plist.0, h5p::H5P_DEFAULT);
+                self.check(dataset, "create a dataset")?;
+                Ok(dataset)
+            }
+        }
+
+        pub fn append<T: ExportType>(&mut self, name: &str, row_shape: &[usize], data: &[T]) -> Result<(), Tp3ErrorKind> {
+            if !self.datasets.contains_key(name) {
+                let dataset = self.create_dataset::<T>(name, row_shape)?;
+                self.datasets.insert(name.to_owned(), (dataset, 0));
+            }
+            let (dataset, rows) = self.datasets[name];
+            let new_rows = (data.len() / row_shape.iter().product::<usize>()) as hsize_t;
+            let mut start: Vec<hsize_t> = vec![rows];
+            let mut count: Vec<hsize_t> = vec![new_rows];
+            let mut extent: Vec<hsize_t> = vec![rows + new_rows];
+            for size in row_shape {
+                start.push(0);
+                count.push(*size as hsize_t);
+                extent.push(*size as hsize_t);
+            }
+            unsafe {
+                self.check(h5d::H5Dset_extent(dataset, extent.as_ptr()), "extend a dataset")?;
+                let file_space = Handle(h5d::H5Dget_space(dataset), h5s::H5Sclose);
+                self.check(file_space.0, "get a dataspace")?;
+                let status = h5s::H5Sselect_hyperslab(file_space.0, h5s::H5S_SELECT_SET, start.as_ptr(), std::ptr::null(), count.as_ptr(), std::ptr::null());
+                self.check(status, "select the new rows")?;
+                let memory_space = Handle(h5s::H5Screate_simple(count.len() as i32, count.as_ptr(), std::ptr::null()), h5s::H5Sclose);
+                self.check(memory_space.0, "create a dataspace")?;
+                let status = h5d::H5Dwrite(dataset, T::h5_type(), memory_space.0, file_space.0, h5p::H5P_DEFAULT, data.as_ptr() as *const c_void);
+                self.check(status, "write a dataset")?;
+            }
+            self.datasets.insert(name.to_owned(), (dataset, rows + new_rows));
+            Ok(())
+        }
+
+        pub fn close(mut self) -> Result<(), Tp3ErrorKind> {
+            self.close_all()
+        }
+
+        fn close_all(&mut self) -> Result<(), Tp3ErrorKind> {
+            unsafe {
+                for (dataset, _) in self.datasets.values() {
+                    h5d::H5Dclose(*dataset);
+                }
+                self.datasets.clear();
+                if self.data >= 0 {h5g::H5Gclose(self.data);}
+                if self.entry >= 0 {h5g::H5Gclose(self.entry);}
+                let status = if self.file >= 0 {h5f::H5Fclose(self.file)} else {0};
+                self.data = -1;
+                self.entry = -1;
+                self.file = -1;
+                self.check(status, "close the file")
+            }
+        }
+    }
+
+    impl Drop for H5Writer {
+        fn drop(&mut self) {
+            let _ = self.close_all();
+        }
+    }
+}
@@ -20,7 +20,7 @@ pub mod external_library_coincidence {
         let settings = Settings::get_settings_from_json(&str_slice[0..bytes.len()-5]).expect("JSON not properly open.");
 
         //Creating the electron data structure
-        let coinc_data = coincidence::ElectronData::new(str_slice.to_owned(), cluster::grab_cluster_correction("0"), settings, save_locally);
+        let coinc_data = coincidence::ElectronData::new(str_slice.to_owned(), cluster::ClusterCorrectionTypes::NoCorrection, settings, save_locally);
 
         //Returning the RAW pointer
         Box::into_raw(Box::new(coinc_data))
@@ -0,0 +1,305 @@
+//!`geometrylib` is a collection of tools to place the chips of the detector in its frames. Every
+//!chip has 256×256 pixels, addressed by their column and row. A `ChipPlacement` flips, rotates
+//!and offsets them to the position of the pixel in the frame (`x` is the dispersive direction).
+//!
+//!The layout is set at runtime by `config().layout`: the 4×1 linear detector (the default, in
+//!which the frames have an extra column for the TDCs), the 2×2 quad, a single chip or a custom
+//!placement of every chip. Gap pixels between the chips of the presets are positions of the frame
+//!that no chip covers.
+//!
+//!The pixels at the edges between two chips are wider than the others (three pixels on
+//!Timepix3), so they count more electrons and give spikes in the spectra. With
+//!`config().edge_pixel_width` above one, `EdgePixels` spreads their electrons over the gap next to
+//!them, one position after the other, so the energy axis is linear. The gap between the chips must
+//!then be of `2 * (edge_pixel_width - 1)` pixels, such as `{"linear": {"gap": 4}}`.
+use crate::auxiliar::value_types::*;
+use crate::configlib::config;
+use crate::errorlib::Tp3ErrorKind;
+use serde::{Deserialize, Serialize};
+use std::sync::OnceLock;
+
+pub const CHIP_SIZE: POSITION = 256;
+
+static GEOMETRY: OnceLock<Geometry> = OnceLock::new();
+
+///Counter-clockwise rotation of a chip, applied after the flip.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Rotation {
+    #[default]
+    Deg0,
+    Deg90,
+    Deg180,
+    Deg270,
+}
+
+///Where a chip is in the frame.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChipPlacement {
+    pub ci: u8,
+    pub offset: (POSITION, POSITION), //Frame position of the chip corner with the lowest x and y
+    #[serde(default)]
+    pub rotation: Rotation,
+    #[serde(default)]
+    pub flip: bool, //Mirrors the columns
+}
+
+impl ChipPlacement {
+    pub fn new(ci: u8, offset: (POSITION, POSITION), rotation: Rotation, flip: bool) -> Self {
+        ChipPlacement {ci, offset, rotation, flip}
+    }
+
+    ///Position in the frame of a pixel of this chip.
+    #[inline]
+    pub fn position(&self, column: POSITION, row: POSITION) -> (POSITION, POSITION) {
+        let last = CHIP_SIZE - 1;
+        let column = if self.flip {last - column} else {column};
+        let (x, y) = match self.rotation {
+            Rotation::Deg0 => (column, row),
+            Rotation::Deg90 => (last - row, column),
+            Rotation::Deg180 => (last - column, last - row),
+            Rotation::Deg270 => (row, last - column),
+        };
+        (x + self.offset.0, y + self.offset.1)
+    }
+
+    ///Column and row of the pixel at a position of the frame, if this chip covers it.
+    pub fn pixel(&self, x: POSITION, y: POSITION) -> Option<(POSITION, POSITION)> {
+        let last = CHIP_SIZE - 1;
+        let x = x.checked_sub(self.offset.0).filter(|x| *x <= last)?;
+        let y = y.checked_sub(self.offset.1).filter(|y| *y <= last)?;
+        let (column, row) = match self.rotation {
+            Rotation::Deg0 => (x, y),
+            Rotation::Deg90 => (y, last - x),
+            Rotation::Deg180 => (last - x, last - y),
+            Rotation::Deg270 => (last - y, x),
+        };
+        Some((if self.flip {last - column} else {column}, row))
+    }
+}
+
+///The detector layouts. See the module documentation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    Linear {gap: POSITION}, //4×1. The chip order follows `config().inverse_detector`.
+    Quad {gap: POSITION}, //2×2
+    Single,
+    Custom {size: (POSITION, POSITION), chips: Vec<ChipPlacement>},
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Layout::Linear {gap: 0}
+    }
+}
+
+///An edge pixel and the positions its electrons are spread over, along x.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EdgeSpan {
+    pub x: POSITION,
+    pub rows: (POSITION, POSITION), //First and last y of the edge
+    pub slots: (POSITION, POSITION), //First and last x, including the edge pixel
+}
+
+impl EdgeSpan {
+    ///Number of positions the electrons are spread over.
+    pub fn width(&self) -> POSITION {
+        self.slots.1 - self.slots.0 + 1
+    }
+
+    fn contains(&self, x: POSITION, y: POSITION) -> bool {
+        x == self.x && (self.rows.0..=self.rows.1).contains(&y)
+    }
+}
+
+///Size of the frames and placement of every chip.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Geometry {
+    size: (POSITION, POSITION),
+    chips: Vec<Option<ChipPlacement>>, //Indexed by the chip index
+}
+
+impl Geometry {
+    ///A geometry with frames of `size`. Chips must be inside the frames and must not overlap.
+    pub fn new(size: (POSITION, POSITION), placements: &[ChipPlacement]) -> Result<Self, Tp3ErrorKind> {
+        let mut chips: Vec<Option<ChipPlacement>> = Vec::new();
+        for placement in placements {
+            let (x, y) = placement.offset;
+            if x + CHIP_SIZE > size.0 || y + CHIP_SIZE > size.1 {
+                return Err(Tp3ErrorKind::GeometryBadLayout {ci: placement.ci, reason: "the chip is out of the frame"});
+            }
+            let overlaps = chips.iter().flatten().any(|other| {
+                x < other.offset.0 + CHIP_SIZE && other.offset.0 < x + CHIP_SIZE &&
+                y < other.offset.1 + CHIP_SIZE && other.offset.1 < y + CHIP_SIZE
+            });
+            let ci = placement.ci as usize;
+            if overlaps || chips.get(ci).is_some_and(Option::is_some) {
+                return Err(Tp3ErrorKind::GeometryBadLayout {ci: placement.ci, reason: "the chip overlaps another"});
+            }
+            if chips.len() <= ci {
+                chips.resize(ci + 1, None);
+            }
+            chips[ci] = Some(*placement);
+        }
+        Ok(Geometry {size, chips})
+    }
+
+    ///Four chips side by side, with `gap` pixels between them and an extra column for the TDCs.
+    ///`inverse` mirrors the detector in the dispersive direction.
+    pub fn linear(gap: POSITION, inverse: bool) -> Self {
+        let step = CHIP_SIZE + gap;
+        //Chip indexes from the lowest x.
+        let (order, flip) = if inverse {([1, 2, 3, 0], false)} else {([0, 3, 2, 1], true)};
+        let chips: Vec<ChipPlacement> = order.iter().enumerate()
+            .map(|(slot, ci)| ChipPlacement::new(*ci, (slot as POSITION * step, 0), Rotation::Deg0, flip))
+            .collect();
+        Geometry::new((3 * step + CHIP_SIZE + 1, CHIP_SIZE), &chips).expect("The linear layout is valid.")
+    }
+
+    ///Four chips in a square, with `gap` pixels between them.
+    pub fn quad(gap: POSITION) -> Self {
+        let step = CHIP_SIZE + gap;
+        let chips = [
+            ChipPlacement::new(0, (0, 0), Rotation::Deg0, true),
+            ChipPlacement::new(1, (0, step), Rotation::Deg180, true),
+            ChipPlacement::new(2, (step, step), Rotation::Deg180, true),
+            ChipPlacement::new(3, (step, 0), Rotation::Deg0, true),
+        ];
+        Geometry::new((step + CHIP_SIZE, step + CHIP_SIZE), &chips).expect("The quad layout is valid.")
+    }
+
+    pub fn single() -> Self {
+        Geometry::new((CHIP_SIZE, CHIP_SIZE), &[ChipPlacement::new(0, (0, 0), Rotation::Deg0, false)]).expect("The single layout is valid.")
+    }
+
+    ///The geometry of `layout`. `inverse` only applies to the linear layout.
+    pub fn from_layout(layout: &Layout, inverse: bool) -> Result<Self, Tp3ErrorKind> {
+        match layout {
+            Layout::Linear {gap} => Ok(Geometry::linear(*gap, inverse)),
+            Layout::Quad {gap} => Ok(Geometry::quad(*gap)),
+            Layout::Single => Ok(Geometry::single()),
+            Layout::Custom {size, chips} => Geometry::new(*size, chips),
+        }
+    }
+
+    ///Width and height of the frames.
+    pub fn size(&self) -> (POSITION, POSITION) {
+        self.size
+    }
+
+    #[inline]
+    pub fn chip(&self, ci: u8) -> Option<&ChipPlacement> {
+        self.chips.get(ci as usize)?.as_ref()
+    }
+
+    #[inline]
+    pub fn has_chip(&self, ci: u8) -> bool {
+        self.chip(ci).is_some()
+    }
+
+    ///Position in the frame of a pixel. Pixels of unknown chips keep their column and row.
+    #[inline]
+    pub fn position(&self, ci: u8, column: POSITION, row: POSITION) -> (POSITION, POSITION) {
+        match self.chip(ci) {
+            Some(chip) => chip.position(column, row),
+            None => (column, row),
+        }
+    }
+
+    ///Chip index, column and row of the pixel at a position of the frame. `None` for the gaps and
+    ///the positions out of the chips.
+    pub fn pixel(&self, x: POSITION, y: POSITION) -> Option<(u8, POSITION, POSITION)> {
+        self.chips.iter().flatten().find_map(|chip| {
+            chip.pixel(x, y).map(|(column, row)| (chip.ci, column, row))
+        })
+    }
+
+    ///Whether a chip covers the position.
+    pub fn is_pixel(&self, x: POSITION, y: POSITION) -> bool {
+        self.pixel(x, y).is_some()
+    }
+
+    ///The edge pixels facing another chip across a gap, for edge pixels of `edge_width` pixels.
+    ///Every edge pixel takes half the gap on its side. Chips further apart than
+    ///`2 * (edge_width - 1)` pixels are not neighbours.
+    pub fn edge_spans(&self, edge_width: POSITION) -> Vec<EdgeSpan> {
+        let last = CHIP_SIZE - 1;
+        let mut spans = Vec::new();
+        for chip in self.chips.iter().flatten() {
+            let (x, y) = chip.offset;
+            for other in self.chips.iter().flatten() {
+                let rows = (y.max(other.offset.1), (y + last).min(other.offset.1 + last));
+                //Only the chips on the left, on the same rows.
+                if rows.0 > rows.1 || other.offset.0 + last >= x {continue;}
+                let gap = x - (other.offset.0 + last) - 1;
+                if gap > 2 * edge_width.saturating_sub(1) {continue;}
+                let spread = gap / 2;
+                if spread == 0 {continue;}
+                spans.push(EdgeSpan {x, rows, slots: (x - spread, x)});
+                let other_x = other.offset.0 + last;
+                spans.push(EdgeSpan {x: other_x, rows, slots: (other_x, other_x + spread)});
+            }
+        }
+        spans
+    }
+}
+
+///Spreads the electrons of the edge pixels over the gaps next to them. The counts stay integers:
+///every electron of an edge pixel goes to the next position of its span.
+pub struct EdgePixels {
+    spans: Vec<EdgeSpan>,
+    is_edge: Vec<bool>, //Indexed by x
+    next: Vec<POSITION>, //Next position of every span
+}
+
+impl Default for EdgePixels {
+    fn default() -> Self {
+        EdgePixels::new(geometry(), config().edge_pixel_width)
+    }
+}
+
+impl EdgePixels {
+    ///Spans for edge pixels of `edge_width` pixels. One keeps every electron in its pixel.
+    pub fn new(geometry: &Geometry, edge_width: POSITION) -> Self {
+        let spans = if edge_width > 1 {geometry.edge_spans(edge_width)} else {Vec::new()};
+        let mut is_edge = vec![false; geometry.size().0 as usize];
+        spans.iter().for_each(|span| is_edge[span.x as usize] = true);
+        EdgePixels {next: vec![0; spans.len()], spans, is_edge}
+    }
+
+    ///The span of the edge pixel at (`x`, `y`), if it is spread.
+    #[inline]
+    pub fn span(&self, x: POSITION, y: POSITION) -> Option<&EdgeSpan> {
+        if !self.is_edge[x as usize] {return None;}
+        self.spans.iter().find(|span| span.contains(x, y))
+    }
+
+    ///Position along x of the next electron at (`x`, `y`).
+    #[inline]
+    pub fn x(&mut self, x: POSITION, y: POSITION) -> POSITION {
+        if !self.is_edge[x as usize] {return x;}
+        match self.spans.iter().position(|span| span.contains(x, y)) {
+            Some(index) => {
+                let span = &self.spans[index];
+                let slot = span.slots.0 + self.next[index];
+                self.next[index] = (self.next[index] + 1) % span.width();
+                slot
+            },
+            None => x,
+        }
+    }
+}
+
+///Sets the global geometry. It can only be set once, and before the first call to `geometry`.
+pub fn init(geometry: Geometry) -> Result<(), Tp3ErrorKind> {
+    GEOMETRY.set(geometry).map_err(|_| Tp3ErrorKind::GeometryAlreadyLoaded)
+}
+
+///The global geometry. `configlib::init` sets it, and otherwise it is built from `config().layout`,
+///which was checked when the configuration was read.
+pub fn geometry() -> &'static Geometry {
+    GEOMETRY.get_or_init(|| {
+        let config = config();
+        Geometry::from_layout(&config.layout, config.inverse_detector).unwrap_or_else(|_| Geometry::linear(0, config.inverse_detector))
+    })
+}
@@ -7,6 +7,7 @@
 
 pub mod auxiliar;
 pub mod constlib;
+pub mod configlib;
 pub mod tdclib;
 pub mod packetlib;
 pub mod postlib;
@@ -14,5 +15,20 @@ pub mod speclib;
 pub mod spimlib;
 pub mod errorlib;
 pub mod clusterlib;
+pub mod simlib;
+pub mod modelib;
+pub mod controllib;
+pub mod metricslib;
+pub mod replaylib;
+pub mod exportlib;
+pub mod eventlib;
+pub mod readerlib;
+pub mod timelib;
+pub mod pixellib;
+pub mod geometrylib;
+pub mod energylib;
+pub mod walklib;
+pub mod pipelinelib;
+pub mod broadcastlib;
 pub mod ttx;
 //pub mod external;
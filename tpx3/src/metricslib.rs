@@ -0,0 +1,291 @@
+//!`metricslib` is a collection of tools to follow a live acquisition. Counters are global and reset
+//!at the beginning of each measurement, and a snapshot is served as JSON by `serve_status`.
+//!
+//!The endpoint answers any request (a plain TCP connection or an HTTP `GET`) with an HTTP response
+//!containing the snapshot, so it can be polled with `curl` or from the Nionswift plugin.
+use crate::packetlib::Packet;
+use crate::timelib::SyncMonitor;
+use crate::auxiliar::value_types::*;
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, AtomicU8, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CHIPS: usize = 4;
+const TDC_TYPES: usize = 16;
+
+static METRICS: Metrics = Metrics::new();
+
+///Counters of the current (or last) acquisition.
+pub struct Metrics {
+    running: AtomicBool,
+    mode: AtomicU8,
+    start: Mutex<Option<Instant>>,
+    chip_packets: [AtomicU64; CHIPS],
+    electron_packets: AtomicU64,
+    tdc_packets: AtomicU64,
+    shutter_packets: AtomicU64,
+    other_packets: AtomicU64,
+    dropped_indexes: AtomicU64,
+    global_times: AtomicU64,
+    timestamp_corrections: AtomicU64,
+    chip_desyncs: AtomicU64,
+    dropped_packets: AtomicU64,
+    max_chip_spread: AtomicU64,
+    tdc_expected_period: [AtomicU64; TDC_TYPES],
+    tdc_observed_period: [AtomicU64; TDC_TYPES],
+    tdc_max_drift: [AtomicI64; TDC_TYPES],
+    bytes_read: AtomicU64,
+    bytes_to_disk: AtomicU64,
+    bytes_to_client: AtomicU64,
+    outputs_sent: AtomicU64,
+    client_blocked_ns: AtomicU64,
+    outputs_dropped: AtomicU64,
+}
+
+///The global metrics.
+pub fn metrics() -> &'static Metrics {
+    &METRICS
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            running: AtomicBool::new(false),
+            mode: AtomicU8::new(0),
+            start: Mutex::new(None),
+            chip_packets: [const {AtomicU64::new(0)}; CHIPS],
+            electron_packets: AtomicU64::new(0),
+            tdc_packets: AtomicU64::new(0),
+            shutter_packets: AtomicU64::new(0),
+            other_packets: AtomicU64::new(0),
+            dropped_indexes: AtomicU64::new(0),
+            global_times: AtomicU64::new(0),
+            timestamp_corrections: AtomicU64::new(0),
+            chip_desyncs: AtomicU64::new(0),
+            dropped_packets: AtomicU64::new(0),
+            max_chip_spread: AtomicU64::new(0),
+            tdc_expected_period: [const {AtomicU64::new(0)}; TDC_TYPES],
+            tdc_observed_period: [const {AtomicU64::new(0)}; TDC_TYPES],
+            tdc_max_drift: [const {AtomicI64::new(0)}; TDC_TYPES],
+            bytes_read: AtomicU64::new(0),
+            bytes_to_disk: AtomicU64::new(0),
+            bytes_to_client: AtomicU64::new(0),
+            outputs_sent: AtomicU64::new(0),
+            client_blocked_ns: AtomicU64::new(0),
+            outputs_dropped: AtomicU64::new(0),
+        }
+    }
+
+    ///Resets every counter for a new acquisition.
+    pub fn start(&self, mode: u8) {
+        let counters = self.chip_packets.iter()
+            .chain(self.tdc_expected_period.iter())
+            .chain(self.tdc_observed_period.iter())
+            .chain([&self.electron_packets, &self.tdc_packets, &self.shutter_packets, &self.other_packets,
+                &self.dropped_indexes, &self.global_times, &self.timestamp_corrections, &self.chip_desyncs,
+                &self.dropped_packets, &self.max_chip_spread, &self.bytes_read, &self.bytes_to_disk, &self.bytes_to_client,
+                &self.outputs_sent, &self.client_blocked_ns, &self.outputs_dropped]);
+        for counter in counters {
+            counter.store(0, Ordering::Relaxed);
+        }
+        for drift in &self.tdc_max_drift {
+            drift.store(0, Ordering::Relaxed);
+        }
+        self.mode.store(mode, Ordering::Relaxed);
+        *self.start.lock().unwrap() = Some(Instant::now());
+        self.running.store(true, Ordering::SeqCst);
+    }
+
+    pub fn finish(&self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+
+    pub fn add_dropped_index(&self) {
+        self.dropped_indexes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    ///A new period of a periodic TDC. The drift is the observed minus the expected period.
+    pub fn add_tdc_period(&self, tdc_type: u8, expected: TIME, observed: TIME) {
+        let index = tdc_type as usize % TDC_TYPES;
+        self.tdc_expected_period[index].store(expected, Ordering::Relaxed);
+        self.tdc_observed_period[index].store(observed, Ordering::Relaxed);
+        let drift = observed as i64 - expected as i64;
+        let max_drift = &self.tdc_max_drift[index];
+        if drift.abs() > max_drift.load(Ordering::Relaxed).abs() {
+            max_drift.store(drift, Ordering::Relaxed);
+        }
+    }
+
+    ///The state of the chip synchronization. The monitor lives as long as the acquisition, so its
+    ///values replace the previous ones.
+    pub fn set_sync(&self, sync: &SyncMonitor) {
+        self.global_times.store(sync.global_times(), Ordering::Relaxed);
+        self.timestamp_corrections.store(sync.corrections(), Ordering::Relaxed);
+        self.chip_desyncs.store(sync.desyncs(), Ordering::Relaxed);
+        self.dropped_packets.store(sync.dropped_packets(), Ordering::Relaxed);
+        self.max_chip_spread.store(sync.max_spread(), Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_read(&self, size: usize) {
+        self.bytes_read.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_bytes_to_disk(&self, size: usize) {
+        self.bytes_to_disk.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    ///An output sent to the client of the main measurement. The extra outputs of `pipelinelib` are
+    ///not counted. `blocked` is the time spent writing it, which grows when the client does not
+    ///keep up.
+    pub fn add_output_sent(&self, size: usize, blocked: Duration) {
+        self.outputs_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_to_client.fetch_add(size as u64, Ordering::Relaxed);
+        self.client_blocked_ns.fetch_add(blocked.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    ///An output not sent to a slow subscriber (see `broadcastlib`).
+    pub fn add_output_dropped(&self) {
+        self.outputs_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let elapsed = self.start.lock().unwrap().map_or(0.0, |start| start.elapsed().as_secs_f64());
+        let rate = |count: u64| if elapsed > 0.0 {count as f64 / elapsed} else {0.0};
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        let chip_packets: Vec<u64> = self.chip_packets.iter().map(load).collect();
+        let tdc = (0..TDC_TYPES)
+            .filter(|index| load(&self.tdc_expected_period[*index]) != 0)
+            .map(|index| TdcPeriodSnapshot {
+                tdc_type: index as u8,
+                expected_period: load(&self.tdc_expected_period[index]),
+                observed_period: load(&self.tdc_observed_period[index]),
+                max_drift: self.tdc_max_drift[index].load(Ordering::Relaxed),
+            })
+            .collect();
+        MetricsSnapshot {
+            running: self.running.load(Ordering::SeqCst),
+            mode: self.mode.load(Ordering::Relaxed),
+            elapsed_s: elapsed,
+            chip_packets_per_s: chip_packets.iter().map(|count| rate(*count)).collect(),
+            chip_packets,
+            electron_packets: load(&self.electron_packets),
+            tdc_packets: load(&self.tdc_packets),
+            shutter_packets: load(&self.shutter_packets),
+            other_packets: load(&self.other_packets),
+            dropped_indexes: load(&self.dropped_indexes),
+            global_times: load(&self.global_times),
+            timestamp_corrections: load(&self.timestamp_corrections),
+            chip_desyncs: load(&self.chip_desyncs),
+            dropped_packets: load(&self.dropped_packets),
+            max_chip_spread: load(&self.max_chip_spread),
+            tdc,
+            bytes_read: load(&self.bytes_read),
+            bytes_to_disk: load(&self.bytes_to_disk),
+            bytes_to_client: load(&self.bytes_to_client),
+            outputs_sent: load(&self.outputs_sent),
+            client_blocked_s: load(&self.client_blocked_ns) as f64 * 1e-9,
+            outputs_dropped: load(&self.outputs_dropped),
+        }
+    }
+}
+
+///Period of a periodic TDC, in units of 0.260 ns. `tdc_type` is the value in the packet.
+#[derive(Debug, Serialize)]
+pub struct TdcPeriodSnapshot {
+    pub tdc_type: u8,
+    pub expected_period: TIME,
+    pub observed_period: TIME,
+    pub max_drift: i64,
+}
+
+///A copy of the metrics, as served by the status endpoint.
+#[derive(Debug, Serialize)]
+pub struct MetricsSnapshot {
+    pub running: bool,
+    pub mode: u8,
+    pub elapsed_s: f64,
+    pub chip_packets: Vec<u64>,
+    pub chip_packets_per_s: Vec<f64>,
+    pub electron_packets: u64,
+    pub tdc_packets: u64,
+    pub shutter_packets: u64,
+    pub other_packets: u64,
+    pub dropped_indexes: u64,
+    pub global_times: u64,
+    pub timestamp_corrections: u64,
+    pub chip_desyncs: u64,
+    pub dropped_packets: u64, //Missing between the SPIDR packet ids
+    pub max_chip_spread: TIME, //In units of 0.260 ns
+    pub tdc: Vec<TdcPeriodSnapshot>,
+    pub bytes_read: u64,
+    pub bytes_to_disk: u64,
+    pub bytes_to_client: u64,
+    pub outputs_sent: u64,
+    pub client_blocked_s: f64,
+    pub outputs_dropped: u64, //Not sent to slow subscribers
+}
+
+///Counts packets of a buffer locally, so the global counters are updated once per buffer.
+#[derive(Default)]
+pub struct PacketCounter {
+    chip: [u64; CHIPS],
+    electron: u64,
+    tdc: u64,
+    shutter: u64,
+    other: u64,
+}
+
+impl PacketCounter {
+    #[inline]
+    pub fn count(&mut self, packet: &Packet) {
+        self.chip[packet.ci() as usize % CHIPS] += 1;
+        match packet.id() {
+            11 | 10 => self.electron += 1,
+            6 => self.tdc += 1,
+            5 if packet.tdc_type() == 10 || packet.tdc_type() == 15 => self.shutter += 1,
+            _ => self.other += 1,
+        }
+    }
+
+    pub fn publish(&self) {
+        let metrics = metrics();
+        for (counter, value) in metrics.chip_packets.iter().zip(self.chip.iter()) {
+            counter.fetch_add(*value, Ordering::Relaxed);
+        }
+        metrics.electron_packets.fetch_add(self.electron, Ordering::Relaxed);
+        metrics.tdc_packets.fetch_add(self.tdc, Ordering::Relaxed);
+        metrics.shutter_packets.fetch_add(self.shutter, Ordering::Relaxed);
+        metrics.other_packets.fetch_add(self.other, Ordering::Relaxed);
+    }
+}
+
+///Serves the metrics snapshot at `address` in a background thread and returns the bound address.
+///An empty address disables it.
+pub fn serve_status(address: &str) -> std::io::Result<Option<SocketAddr>> {
+    if address.is_empty() {return Ok(None);}
+    let listener = TcpListener::bind(address)?;
+    let local_address = listener.local_addr()?;
+    println!("***Metricslib***: Status endpoint at {:?}.", local_address);
+    thread::spawn(move || {
+        for sock in listener.incoming().flatten() {
+            if let Err(e) = answer(sock) {
+                println!("***Metricslib***: Could not answer the status request. Error is {:?}.", e);
+            }
+        }
+    });
+    Ok(Some(local_address))
+}
+
+fn answer(mut sock: TcpStream) -> std::io::Result<()> {
+    //The request is not parsed, every request receives the snapshot.
+    sock.set_read_timeout(Some(Duration::from_millis(100)))?;
+    let mut request = [0_u8; 1024];
+    let _ = sock.read(&mut request);
+    let body = serde_json::to_string(&metrics().snapshot())?;
+    let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body);
+    sock.write_all(response.as_bytes())
+}
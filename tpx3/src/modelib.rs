@@ -0,0 +1,300 @@
+//!`modelib` dispatches a measurement to the acquisition mode requested by Nionswift. It is used by
+//!the main binary, the debug binary and by the integration tests, which drive it over loopback sockets.
+//!
+//!Nionswift sends the mode as a number in `Settings.mode`. `AcquisitionMode` is (de)serialized from
+//!and to this number, and `MODES` is the registry that associates each mode to its measurement
+//!type and to its TDC requirements. A new mode is a variant and a line in the registry.
+//!
+//!A measurement can also have extra outputs, each one with its own mode and client. They run off
+//!the same packets, TDCs and filters as the main measurement (see `pipelinelib::Output`).
+use crate::errorlib::Tp3ErrorKind;
+use crate::auxiliar::{Settings, FileManager, misc::{self, TimepixRead}};
+use crate::tdclib::{TdcType, TdcRef};
+use crate::ttx;
+use crate::pipelinelib::{Pipeline, Source, Tdcs, TimeGate, Output};
+use crate::controllib::{Control, ControlSocket};
+use crate::metricslib::metrics;
+use crate::broadcastlib::Broadcast;
+use crate::constlib::MaskValues;
+use crate::{speclib, speclib::SpecKind, spimlib, spimlib::SpimKind};
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+
+///The acquisition modes. Modes `Live` and `LiveFrame` are also chosen by `Settings.bin`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "u8", into = "u8")]
+pub enum AcquisitionMode {
+    Live,
+    LiveSpim,
+    LiveFrame4D,
+    Chrono,
+    Coincidence2D,
+    ChronoFrame,
+    LiveFrame,
+    Live1DFrameHyperspec,
+    LiveCoincidence,
+    Live4D,
+    LiveSpimScanList,
+    Live2DFrameHyperspec,
+    NotImplemented(u8),
+}
+
+///How the measurement output is built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModeOutput {
+    ///Frames are built by `speclib::build_spectrum`.
+    Spectrum,
+    ///Lists of indexes are built by `spimlib::build_spim`.
+    Spim,
+}
+
+///Runs the measurement of a mode.
+type RunFn = fn(Run) -> Result<(), Tp3ErrorKind>;
+///Builds a mode as an extra output of another measurement.
+type OutputFn = fn(Settings, Tdcs, Box<dyn Write + Send>) -> Box<dyn Output>;
+
+///The constructors of the measurement type of a mode.
+#[derive(Clone, Copy, Debug)]
+pub struct Build {
+    output: ModeOutput,
+    run: RunFn,
+    extra: OutputFn,
+}
+
+///The mode runs the `SpecKind` measurement `W`.
+const fn spectrum<W: 'static + Send + SpecKind>() -> Build {
+    Build {output: ModeOutput::Spectrum, run: run_spectrum::<W>, extra: spectrum_output::<W>}
+}
+
+///The mode runs the `SpimKind` measurement `W`.
+const fn spim<W: 'static + Send + SpimKind>() -> Build {
+    Build {output: ModeOutput::Spim, run: run_spim::<W>, extra: spim_output::<W>}
+}
+
+fn spectrum_output<W: 'static + Send + SpecKind>(set: Settings, tdcs: Tdcs, sink: Box<dyn Write + Send>) -> Box<dyn Output> {
+    Box::new(speclib::SpecOutput::<W, _>::new(set, tdcs, sink))
+}
+
+fn spim_output<W: 'static + Send + SpimKind>(set: Settings, tdcs: Tdcs, sink: Box<dyn Write + Send>) -> Box<dyn Output> {
+    Box::new(spimlib::SpimOutput::<W, _>::new(set, tdcs, sink))
+}
+
+///Registry entry of an acquisition mode.
+#[derive(Debug)]
+pub struct ModeInfo {
+    pub number: u8,
+    pub mode: AcquisitionMode,
+    pub name: &'static str,
+    pub output: ModeOutput,
+    ///The main TDC is the scan line reference. Electrons are indexed by their scan position.
+    pub spatial: bool,
+    ///A scan list is read from Nionswift before the measurement.
+    pub scan_list: bool,
+    ///Main and auxiliary TDCs that replace the ones given by the measurement type. The main TDC is
+    ///searched as a periodic TDC, while the auxiliary one is not read.
+    pub main_tdc: Option<TdcType>,
+    pub aux_tdc: Option<TdcType>,
+    ///In time-resolved measurements, the pipeline drops the electrons out of the window of the
+    ///auxiliary TDC (see `pipelinelib::TimeGate`).
+    pub time_gate: bool,
+    build: Build,
+    ///The measurement when `Settings.bin` is set, if it is another one.
+    binned: Option<Build>,
+}
+
+const fn entry(number: u8, mode: AcquisitionMode, name: &'static str, build: Build, spatial: bool) -> ModeInfo {
+    ModeInfo {number, mode, name, output: build.output, spatial, scan_list: false, main_tdc: None, aux_tdc: None, time_gate: false, build, binned: None}
+}
+
+pub const MODES: [ModeInfo; 12] = [
+    ModeInfo {
+        binned: Some(spectrum::<speclib::Live1D>()),
+        ..entry(0, AcquisitionMode::Live, "Live", spectrum::<speclib::Live2D>(), false)
+    },
+    entry(2, AcquisitionMode::LiveSpim, "LiveSpim", spim::<spimlib::Live>(), true),
+    entry(3, AcquisitionMode::LiveFrame4D, "LiveFrame4D", spim::<spimlib::LiveFrame4D<MaskValues>>(), true),
+    entry(6, AcquisitionMode::Chrono, "Chrono", spectrum::<speclib::Chrono>(), false),
+    entry(7, AcquisitionMode::Coincidence2D, "Coincidence2D", spectrum::<speclib::Coincidence2D>(), false),
+    entry(8, AcquisitionMode::ChronoFrame, "ChronoFrame", spectrum::<speclib::ChronoFrame>(), false),
+    ModeInfo {
+        binned: Some(spectrum::<speclib::Live1DFrame>()),
+        ..entry(10, AcquisitionMode::LiveFrame, "LiveFrame", spectrum::<speclib::Live2DFrame>(), false)
+    },
+    entry(11, AcquisitionMode::Live1DFrameHyperspec, "Live1DFrameHyperspec", spectrum::<speclib::Live1DFrameHyperspec>(), false),
+    entry(12, AcquisitionMode::LiveCoincidence, "LiveCoincidence", spim::<spimlib::LiveCoincidence>(), true),
+    ModeInfo {
+        time_gate: true,
+        ..entry(13, AcquisitionMode::Live4D, "Live4D", spim::<spimlib::Live4D>(), true)
+    },
+    ModeInfo {
+        scan_list: true,
+        main_tdc: Some(TdcType::TdcOneFallingEdge),
+        aux_tdc: Some(TdcType::TdcTwoRisingEdge),
+        ..entry(14, AcquisitionMode::LiveSpimScanList, "LiveSpimScanList", spim::<spimlib::Live>(), true)
+    },
+    entry(15, AcquisitionMode::Live2DFrameHyperspec, "Live2DFrameHyperspec", spectrum::<speclib::Live2DFrameHyperspec>(), false),
+];
+
+impl AcquisitionMode {
+    ///The registry entry of the mode. `None` if the mode is not implemented.
+    pub fn info(self) -> Option<&'static ModeInfo> {
+        MODES.iter().find(|info| info.mode == self)
+    }
+
+    pub fn number(self) -> u8 {
+        self.into()
+    }
+
+    ///Electrons are indexed by their scan position.
+    pub fn is_spatial(self) -> bool {
+        self.info().is_some_and(|info| info.spatial)
+    }
+}
+
+impl ModeInfo {
+    fn build(&self, bin: bool) -> Build {
+        match self.binned {
+            Some(binned) if bin => binned,
+            _ => self.build,
+        }
+    }
+
+    ///The stages between the decoder and the measurement, and the extra outputs.
+    fn pipeline(&self, settings: Settings, tdcs: Tdcs, ttx: Option<ttx::TTXRef>, outputs: Vec<OutputSink>) -> Result<Pipeline, Tp3ErrorKind> {
+        let mut pipeline = Pipeline::new(settings, tdcs, ttx);
+        if self.time_gate {pipeline = pipeline.with_filter(TimeGate);}
+        for (address, sink) in outputs {
+            let output = extra_output(&address, settings, pipeline.tdcs().clone(), sink)?;
+            pipeline = pipeline.with_output(output);
+        }
+        Ok(pipeline)
+    }
+}
+
+///An extra output of a measurement. It takes the settings of the main measurement, with its own
+///mode and binning, and its frames are sent to the client connected at `address`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputAddress {
+    pub mode: AcquisitionMode,
+    #[serde(default)]
+    pub bin: bool,
+    pub address: String,
+}
+
+impl OutputAddress {
+    ///The mode of the output. It must be implemented and read no scan list.
+    pub fn check(&self) -> Result<&'static ModeInfo, Tp3ErrorKind> {
+        let info = self.mode.info().ok_or(Tp3ErrorKind::MiscModeNotImplemented(self.mode.number()))?;
+        if info.scan_list {return Err(Tp3ErrorKind::MiscModeNotAnOutput(info.number));}
+        Ok(info)
+    }
+}
+
+///An extra output and its connected client.
+pub type OutputSink = (OutputAddress, Box<dyn Write + Send>);
+
+///Builds the measurement of an extra output. The modes that read a scan list cannot be extra
+///outputs.
+fn extra_output(address: &OutputAddress, mut set: Settings, tdcs: Tdcs, sink: Box<dyn Write + Send>) -> Result<Box<dyn Output>, Tp3ErrorKind> {
+    let info = address.check()?;
+    set.mode = address.mode;
+    set.bin = address.bin;
+    println!("***Modelib***: Extra output {} ({}) at {}.", info.name, info.number, address.address);
+    Ok((info.build(set.bin).extra)(set, tdcs, sink))
+}
+
+impl From<u8> for AcquisitionMode {
+    fn from(number: u8) -> Self {
+        MODES.iter()
+            .find(|info| info.number == number)
+            .map_or(AcquisitionMode::NotImplemented(number), |info| info.mode)
+    }
+}
+
+impl From<AcquisitionMode> for u8 {
+    fn from(mode: AcquisitionMode) -> u8 {
+        match mode {
+            AcquisitionMode::NotImplemented(number) => number,
+            _ => mode.info().expect("Every implemented mode is in the registry.").number,
+        }
+    }
+}
+
+///Everything a measurement needs besides its type.
+struct Run {
+    info: &'static ModeInfo,
+    my_settings: Settings,
+    pack: Box<dyn TimepixRead + Send>,
+    ns: Box<dyn ControlSocket + Send>,
+    file_to_write: FileManager,
+    ttx: Option<ttx::TTXRef>,
+    outputs: Vec<OutputSink>,
+    control: Control,
+}
+
+///Searches the TDCs and runs a `SpecKind` measurement.
+fn run_spectrum<W: SpecKind>(run: Run) -> Result<(), Tp3ErrorKind> {
+    let Run {info, my_settings, mut pack, ns, mut file_to_write, ttx, outputs, control} = run;
+    let measurement = W::new(&my_settings);
+    if let Some(control_sock) = ns.control_reader() {control.watch(control_sock);}
+    let frame_tdc = match info.main_tdc {
+        Some(tdc_type) => TdcRef::new_periodic(tdc_type, &mut pack, &my_settings, &mut file_to_write)?,
+        None => measurement.build_main_tdc(&mut pack, &my_settings, &mut file_to_write)?,
+    };
+    let aux_tdc = match info.aux_tdc {
+        Some(tdc_type) => TdcRef::new_no_read(tdc_type)?,
+        None => measurement.build_aux_tdc(&mut pack, &my_settings, &mut file_to_write)?,
+    };
+    let pipeline = info.pipeline(my_settings, Tdcs {main: frame_tdc, aux: aux_tdc}, ttx, outputs)?;
+    speclib::build_spectrum(Source::new(pack, file_to_write), Broadcast(ns), pipeline, measurement, control)
+}
+
+///Reads the scan list, if needed, searches the TDCs and runs a `SpimKind` measurement.
+fn run_spim<W: 'static + Send + SpimKind>(run: Run) -> Result<(), Tp3ErrorKind> {
+    let Run {info, my_settings, mut pack, mut ns, mut file_to_write, ttx, outputs, control} = run;
+    let mut measurement = W::new(&my_settings);
+    let vec_list = if info.scan_list {
+        let number_of_points = my_settings.xscan_size * my_settings.yscan_size;
+        Some(misc::create_list(&mut ns, number_of_points)?)
+    } else {
+        None
+    };
+    if let Some(control_sock) = ns.control_reader() {control.watch(control_sock);}
+    let spim_tdc = match info.main_tdc {
+        Some(tdc_type) => TdcRef::new_periodic(tdc_type, &mut pack, &my_settings, &mut file_to_write)?,
+        None => measurement.build_main_tdc(&mut pack, &my_settings, &mut file_to_write)?,
+    };
+    let np_tdc = match info.aux_tdc {
+        Some(tdc_type) => TdcRef::new_no_read(tdc_type)?,
+        None => measurement.build_aux_tdc(&mut pack, &my_settings, &mut file_to_write)?,
+    };
+    let pipeline = info.pipeline(my_settings, Tdcs {main: spim_tdc, aux: np_tdc}, ttx, outputs)?;
+    spimlib::build_spim(Source::new(pack, file_to_write), Broadcast(ns), pipeline, measurement, vec_list.as_deref(), control)
+}
+
+///Runs a single measurement. `pack` is the TP3 packet source and `ns` is the Nionswift socket, from
+///which the scan list (mode 14) and the control messages are read, and to which the frames are
+///written. The frames are also published to the subscribers of `broadcastlib`. Returns the mode
+///number when the measurement is over.
+pub fn run_measurement<U>(my_settings: Settings, pack: Box<dyn TimepixRead + Send>, ns: U, file_to_write: FileManager, ttx: Option<ttx::TTXRef>) -> Result<u8, Tp3ErrorKind>
+    where U: 'static + Send + ControlSocket
+{
+    run_measurement_with_outputs(my_settings, pack, ns, file_to_write, ttx, Vec::new())
+}
+
+///Runs a measurement and its extra outputs. An extra output whose client disconnects is dropped,
+///and the measurement goes on.
+pub fn run_measurement_with_outputs<U>(my_settings: Settings, pack: Box<dyn TimepixRead + Send>, ns: U, file_to_write: FileManager, ttx: Option<ttx::TTXRef>, outputs: Vec<OutputSink>) -> Result<u8, Tp3ErrorKind>
+    where U: 'static + Send + ControlSocket
+{
+    let mode = my_settings.mode;
+    let info = mode.info().ok_or(Tp3ErrorKind::MiscModeNotImplemented(mode.number()))?;
+    println!("***Modelib***: Starting mode {} ({}).", info.name, info.number);
+    let control = Control::new();
+    metrics().start(info.number);
+    let build = info.build(my_settings.bin);
+    let run = Run {info, my_settings, pack, ns: Box::new(ns), file_to_write, ttx, outputs, control: control.clone()};
+    let result = (build.run)(run);
+    control.finish();
+    result.map(|_| mode.number())
+}
@@ -3,6 +3,19 @@
 
 use crate::auxiliar::value_types::*;
 use crate::constlib::*;
+use crate::configlib::config;
+use crate::tdclib::TdcType;
+use crate::geometrylib::{geometry, Layout};
+
+///Global time and SPIDR control packets (ids 4, 5 and 7), identified by their header byte.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ControlPacket {
+    GlobalTimeLow(TIME), //0x44. Bits 31 to 0 of the global time, in units of 25 ns.
+    GlobalTimeHigh(TIME), //0x45. Bits 47 to 32 of the global time.
+    PacketId(u64), //0x50. SPIDR packet counter, sent periodically by every chip.
+    Command(u16), //0x71. SPIDR command, such as 0x71A0 or 0x71B0.
+    Other(u8), //Any other header of these ids.
+}
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub struct Packet {
@@ -29,63 +42,31 @@ impl Packet {
         self.chip_index
     }
 
+    #[inline]
+    fn column(&self) -> POSITION {
+        (((self.data() & 0x0F_E0_00_00_00_00_00_00) >> 52) | ((self.data() & 0x00_00_40_00_00_00_00_00) >> 46)) as POSITION
+    }
+
+    #[inline]
+    fn row(&self) -> POSITION {
+        (((self.data() & 0x00_1F_80_00_00_00_00_00) >> 45) | ((self.data() & 0x00_00_30_00_00_00_00_00) >> 44)) as POSITION
+        //(((self.data() >> 45) & 0xFC) | ((self.data() >> 44) & 0x03)) as POSITION
+    }
+
+    ///Position of the pixel in the frame, given by the detector geometry.
+    #[inline]
+    pub fn position(&self) -> (POSITION, POSITION) {
+        geometry().position(self.ci(), self.column(), self.row())
+    }
+
     #[inline]
     pub fn x(&self) -> POSITION {
-        let temp2 = (((self.data() & 0x0F_E0_00_00_00_00_00_00) >> 52) | ((self.data() & 0x00_00_40_00_00_00_00_00) >> 46)) as POSITION;
-        match PIXELS_X {
-            1024 | 1025 => {
-                if !INVERSE_DETECTOR {
-                    match self.ci() {
-                        0 => 255 - temp2,
-                        1 => 256 * 4 - 1 - temp2,
-                        2 => 256 * 3 - 1 - temp2,
-                        3 => 256 * 2 - 1 - temp2,
-                        _ => panic!("More than four CIs."),
-                    }
-                } else {
-                    match self.ci() {
-                        0 => temp2 + 256 * 3,
-                        1 => temp2,
-                        2 => temp2 + 256,
-                        3 => temp2 + 256 * 2,
-                        _ => panic!("More than four CIs."),
-                    }
-                }
-            },
-            512 => {
-                match self.chip_index {
-                    0 => 255 - temp2,
-                    1 => temp2,
-                    2 => temp2 + 256,
-                    3 => 256 * 2 - 1 - temp2,
-                    _ => panic!("More than four CI."),
-                }
-            },
-            _ => {
-                temp2
-            },
-        }
+        self.position().0
     }
     
     #[inline]
     pub fn y(&self) -> POSITION {
-        let temp = (((self.data() & 0x00_1F_80_00_00_00_00_00) >> 45) | ((self.data() & 0x00_00_30_00_00_00_00_00) >> 44)) as POSITION;
-        match PIXELS_Y {
-            1024 | 1025 => {
-                temp
-            },
-            512 => {
-                match self.chip_index {
-                    0 => temp,
-                    1 => 256 * 2 - 1 - temp,
-                    2 => 256 * 2 - 1 - temp,
-                    3 => temp,
-                    _ => panic!("More than four CI."),
-                }
-            },
-            _ => temp,
-        }
-                //(((self.data() >> 45) & 0xFC) | ((self.data() >> 44) & 0x03)) as POSITION
+        self.position().1
     }
 
     /*
@@ -113,6 +94,23 @@ impl Packet {
         //((self.data() >> 60)) as u8
     }
 
+    #[inline]
+    pub fn header(&self) -> u8 {
+        (self.data() >> 56) as u8
+    }
+
+    ///Decodes global time and SPIDR control packets. Shutter packets are `Other`.
+    pub fn control(&self) -> Option<ControlPacket> {
+        match self.header() {
+            0x44 => Some(ControlPacket::GlobalTimeLow((self.data() >> 16) & 0xFF_FF_FF_FF)),
+            0x45 => Some(ControlPacket::GlobalTimeHigh((self.data() >> 16) & 0xFF_FF)),
+            0x50 => Some(ControlPacket::PacketId(self.data() & 0xFF_FF_FF_FF_FF_FF)),
+            0x71 => Some(ControlPacket::Command((self.data() >> 48) as u16)),
+            header @ (0x40..=0x5F | 0x70..=0x7F) => Some(ControlPacket::Other(header)),
+            _ => None,
+        }
+    }
+
     #[inline]
     fn spidr(&self) -> TIME {
         (self.data() & 0x00_00_00_00_00_00_FF_FF) as TIME
@@ -155,27 +153,20 @@ impl Packet {
     fn electron_time(&self) -> TIME {
         let spidr = self.spidr();
         let ctoa = self.ctoa();
-        match CORRECT_ELECTRON_TIME_COARSE {
-            false => spidr * 262_144 + ctoa,
-            true => {
-                match PIXELS_X {
-                    1024 | 1025 => {
-                        let mut x = self.x();
-                        if INVERSE_DETECTOR {
-                            x = (4 * 256 - 1) - x;
-                        }
-                        let t = spidr * 262_144 + ctoa;
-                        match x {
-                            52..=61 | 306..=317 | 324..=325 | 564..=573 | 820..=829 => t-16,
-                            _ => t,
-                        }
-                    },
-                    _ => spidr * 262_144 + ctoa
-                }
-            }
+        let time = spidr * 262_144 + ctoa;
+        if config().correct_electron_time_coarse && Packet::is_coarse_time_late(self.ci(), self.column()) {
+            time - 16
+        } else {
+            time
         }
     }
 
+    //Pixels of the linear detector whose coarse time is late by 16 units of 1.5625 ns.
+    fn is_coarse_time_late(ci: u8, column: POSITION) -> bool {
+        matches!(config().layout, Layout::Linear {..}) &&
+            matches!((ci, column), (0..=2, 194..=203) | (3, 186..=187 | 194..=205))
+    }
+
     #[inline]
     ///In units of 0.260 ps.
     pub fn electron_time_in_tdc_units(&self) -> TIME {
@@ -260,9 +251,74 @@ impl Packet {
         }
     }
 
+    ///Width and height of the frames, given by the detector geometry.
     #[inline]
-    pub const fn chip_array() -> (POSITION, POSITION) {
-        (PIXELS_X, PIXELS_Y)
+    pub fn chip_array() -> (POSITION, POSITION) {
+        geometry().size()
+    }
+
+    ///Creates an electron packet (id 11) that decodes back to the given position and time. Time
+    ///is in units of 1.5625 ns and wraps at `ELECTRON_OVERFLOW`.
+    pub fn new_inverse_electron(x: POSITION, y: POSITION, time: TIME, tot: u16) -> Self {
+        let (ci, column, row) = geometry().pixel(x, y).expect("No chip at this position.");
+
+        let mut time = time % ELECTRON_OVERFLOW;
+        if config().correct_electron_time_coarse && Packet::is_coarse_time_late(ci, column) {
+            time = (time + 16) % ELECTRON_OVERFLOW;
+        }
+        let (column, row) = (column as u64, row as u64);
+        let spidr = time / 262_144;
+        let ctoa = time % 262_144;
+        let toa = ctoa >> 4;
+        let ftoa = !ctoa & 15;
+
+        let data = (11 << 60) |
+            ((column & 0xFE) << 52) |
+            ((row & 0xFC) << 45) |
+            ((column & 0x01) << 46) |
+            ((row & 0x03) << 44) |
+            ((toa & 0x3F_FF) << 30) |
+            (((tot as u64) & 0x3_FF) << 20) |
+            ((ftoa & 0xF) << 16) |
+            (spidr & 0xFF_FF);
+        Packet::new(ci, data)
+    }
+
+    ///Creates a TDC packet (id 6) that decodes back to the given counter and time. Time is in
+    ///units of 0.260 ps and wraps at the coarse counter overflow.
+    pub fn new_inverse_tdc(tdc_type: TdcType, counter: u16, time: TIME) -> Self {
+        let coarse = (time / 12) & 0x07_FF_FF_FF_FF;
+        let fine = time % 12;
+        let data = (6 << 60) |
+            (((tdc_type.associate_value() as u64) & 0xF) << 56) |
+            (((counter as u64) & 0xF_FF) << 44) |
+            (coarse << 9) |
+            (fine << 5);
+        Packet::new(0, data)
+    }
+
+    ///Creates a shutter packet (id 5). A closed shutter has the type 10 and an open one has the
+    ///type 15.
+    pub fn new_inverse_shutter(ci: u8, shutter_closed: bool, frame_time: u64) -> Self {
+        let shutter_type: u64 = if shutter_closed {10} else {15};
+        let data = (5 << 60) |
+            (shutter_type << 56) |
+            ((frame_time & 0x03_FF_FF_FF_FF) << 12);
+        Packet::new(ci, data)
+    }
+
+    ///Creates the two global time packets (0x44 and 0x45) of a chip. Time is in units of 25 ns.
+    pub fn new_inverse_global_time(ci: u8, time: TIME) -> [Self; 2] {
+        let spidr = time & 0xFF_FF;
+        [
+            Packet::new(ci, (0x44 << 56) | ((time & 0xFF_FF_FF_FF) << 16) | spidr),
+            Packet::new(ci, (0x45 << 56) | (((time >> 32) & 0xFF_FF) << 16) | spidr),
+        ]
+    }
+
+    ///Creates a SPIDR packet id (0x50).
+    pub fn new_inverse_packet_id(ci: u8, id: u64) -> Self {
+        Packet::new(ci, (0x50 << 56) | (id & 0xFF_FF_FF_FF_FF_FF))
     }
 
 }
@@ -0,0 +1,395 @@
+//!`pipelinelib` assembles the live measurements from stages. A `Source` reads the packets and saves
+//!them, a `Pipeline` decodes them and passes the electrons through its `Filter`s, and an
+//!`Accumulator` builds the output, which is written to a `Sink`.
+//!
+//!The measurements of `speclib` and `spimlib` are accumulators through `SpecAccumulator` and
+//!`SpimAccumulator`, so a new mode is a new accumulator, or an existing one with other filters.
+//!`Clustering` is the stage of the accumulators that count the clusters instead of the hits.
+//!
+//!Other measurements can run off the same events as extra `Output`s of the pipeline. Each one has
+//!its own copy of the TDCs and its own sink, and the filters of the pipeline apply to all of them.
+//!Every output runs in its own thread, and the events of each buffer are queued to it, so building
+//!and sending its frames does not hold up the acquisition.
+use crate::packetlib::Packet;
+use crate::auxiliar::{Settings, FileManager, misc::{self, TimepixRead}};
+use crate::auxiliar::value_types::*;
+use crate::errorlib::Tp3ErrorKind;
+use crate::tdclib::TdcRef;
+use crate::constlib::{BUFFER_SIZE, OUTPUT_QUEUE_SIZE};
+use crate::configlib::config;
+use crate::metricslib::{metrics, PacketCounter};
+use crate::readerlib::{Event, RawEvent, StreamDecoder};
+use crate::pixellib::{calibration, PixelCalibration, FlatField};
+use crate::clusterlib::engine::{self, ClusterEngine, Hit};
+use crate::speclib::SpecKind;
+use crate::spimlib::SpimKind;
+use crate::broadcastlib::{Queue, SlowConsumer};
+use crate::ttx;
+use std::io::Write;
+use std::sync::Arc;
+use std::thread;
+
+///Reads the packets and saves them in the file of the measurement.
+pub struct Source<V> {
+    reader: V,
+    file: FileManager,
+    buffer: Vec<u8>,
+}
+
+impl<V: TimepixRead> Source<V> {
+    pub fn new(reader: V, file: FileManager) -> Self {
+        Source {reader, file, buffer: vec![0; BUFFER_SIZE]}
+    }
+
+    ///Reads and saves the next buffer. None when the source is over, and empty when a socket
+    ///had nothing to read before its timeout, so the caller can check if it must stop.
+    pub fn next_buffer(&mut self) -> Result<Option<&[u8]>, Tp3ErrorKind> {
+        let size = match self.reader.read_timepix(&mut self.buffer) {
+            Ok(size) => size,
+            Err(Tp3ErrorKind::TimepixReadOver {offset: 0}) => return Ok(None),
+            Err(Tp3ErrorKind::TimepixReadLoop {offset: 0, source}) if misc::is_timeout(&source) => return Ok(Some(&[])),
+            Err(error) => return Err(error),
+        };
+        metrics().add_bytes_read(size);
+        self.file.write_all(&self.buffer[0..size])?;
+        Ok(Some(&self.buffer[0..size]))
+    }
+
+    ///Flushes the file.
+    pub fn finish(&mut self) -> Result<(), Tp3ErrorKind> {
+        self.file.finish()?;
+        Ok(())
+    }
+}
+
+///The TDCs of a measurement. `main` marks the frames or the scan lines, and `aux` is the
+///reference of the time-resolved measurements or the photons.
+#[derive(Clone)]
+pub struct Tdcs {
+    pub main: TdcRef,
+    pub aux: TdcRef,
+}
+
+///Decides which electrons go on to the accumulator.
+pub trait Filter: Send {
+    fn keep(&mut self, packet: &Packet, tdcs: &Tdcs, settings: &Settings) -> bool;
+}
+
+///Drops the masked pixels of `pixellib::calibration`.
+pub struct BadPixels(&'static PixelCalibration);
+
+impl Default for BadPixels {
+    fn default() -> Self {
+        BadPixels(calibration())
+    }
+}
+
+impl Filter for BadPixels {
+    #[inline]
+    fn keep(&mut self, packet: &Packet, _tdcs: &Tdcs, _settings: &Settings) -> bool {
+        !self.0.is_packet_masked(packet)
+    }
+}
+
+///In time-resolved measurements, keeps the electrons within `time_delay` and `time_width` of the
+///auxiliary TDC.
+pub struct TimeGate;
+
+impl Filter for TimeGate {
+    #[inline]
+    fn keep(&mut self, packet: &Packet, tdcs: &Tdcs, settings: &Settings) -> bool {
+        !settings.time_resolved || tdcs.aux.tr_electron_check_if_in(packet, settings).is_some()
+    }
+}
+
+///Builds the output of a measurement from the decoded events.
+pub trait Accumulator {
+    ///`time` is the time of the electron extended by the decoder, in TDC units.
+    fn add_electron(&mut self, packet: Packet, time: TIME, tdcs: &mut Tdcs, settings: &Settings);
+    ///Frame-based hits. They are dropped unless the measurement takes them.
+    fn add_frame_electron(&mut self, _packet: Packet, _time: TIME, _tdcs: &mut Tdcs, _settings: &Settings) {}
+    fn add_main_tdc(&mut self, packet: Packet, tdcs: &mut Tdcs, settings: &Settings);
+    fn add_aux_tdc(&mut self, packet: Packet, tdcs: &mut Tdcs, settings: &Settings);
+    fn add_shutter(&mut self, _packet: Packet, _tdcs: &mut Tdcs, _settings: &Settings) {}
+    ///Takes the events of the TimeTagger, after every buffer.
+    fn add_ttx(&mut self, _ttx: &mut ttx::TTXRef) {}
+}
+
+///A `SpecKind` measurement as an accumulator.
+pub struct SpecAccumulator<W>(pub W);
+
+impl<W: SpecKind> Accumulator for SpecAccumulator<W> {
+    #[inline]
+    fn add_electron(&mut self, packet: Packet, time: TIME, tdcs: &mut Tdcs, settings: &Settings) {
+        self.0.add_electron_hit(packet, time, settings, &tdcs.main, &tdcs.aux);
+    }
+    #[inline]
+    fn add_frame_electron(&mut self, packet: Packet, time: TIME, tdcs: &mut Tdcs, settings: &Settings) {
+        self.0.add_electron_hit(packet, time, settings, &tdcs.main, &tdcs.aux);
+    }
+    fn add_main_tdc(&mut self, packet: Packet, tdcs: &mut Tdcs, settings: &Settings) {
+        self.0.add_tdc_hit1(packet, &mut tdcs.main, settings);
+    }
+    fn add_aux_tdc(&mut self, packet: Packet, tdcs: &mut Tdcs, settings: &Settings) {
+        self.0.add_tdc_hit2(packet, settings, &mut tdcs.aux);
+    }
+    fn add_shutter(&mut self, packet: Packet, tdcs: &mut Tdcs, settings: &Settings) {
+        self.0.add_shutter_hit(packet, &mut tdcs.main, settings);
+    }
+    fn add_ttx(&mut self, ttx: &mut ttx::TTXRef) {
+        ttx.build_spec_data(&mut self.0);
+    }
+}
+
+///A `SpimKind` measurement as an accumulator.
+pub struct SpimAccumulator<W>(pub W);
+
+impl<W: SpimKind> Accumulator for SpimAccumulator<W> {
+    #[inline]
+    fn add_electron(&mut self, packet: Packet, time: TIME, tdcs: &mut Tdcs, settings: &Settings) {
+        self.0.add_electron_hit(&packet, time, &tdcs.main, &tdcs.aux, settings);
+    }
+    fn add_main_tdc(&mut self, packet: Packet, tdcs: &mut Tdcs, settings: &Settings) {
+        self.0.upt_line(&packet, settings, &mut tdcs.main);
+    }
+    fn add_aux_tdc(&mut self, packet: Packet, tdcs: &mut Tdcs, _settings: &Settings) {
+        self.0.add_tdc_hit(&packet, &tdcs.main, &mut tdcs.aux);
+    }
+    fn add_ttx(&mut self, ttx: &mut ttx::TTXRef) {
+        ttx.build_spim_data(&mut self.0);
+    }
+}
+
+///Gives an event to an accumulator. The electrons must have gone through the filters already.
+///Without an extended time, the electrons take the time of their packet.
+#[inline]
+pub fn dispatch<A: Accumulator + ?Sized>(raw: RawEvent, accumulator: &mut A, tdcs: &mut Tdcs, settings: &Settings) {
+    let time = |packet: &Packet| raw.time.unwrap_or_else(|| packet.electron_time_in_tdc_units());
+    match raw.event {
+        Event::Pixel(packet) => accumulator.add_electron(packet, time(&packet), tdcs, settings),
+        Event::FramePixel(packet) => accumulator.add_frame_electron(packet, time(&packet), tdcs, settings),
+        Event::Tdc(packet) if packet.tdc_type() == tdcs.main.id() => accumulator.add_main_tdc(packet, tdcs, settings),
+        Event::Tdc(packet) if packet.tdc_type() == tdcs.aux.id() => accumulator.add_aux_tdc(packet, tdcs, settings),
+        Event::Shutter(packet) => accumulator.add_shutter(packet, tdcs, settings),
+        _ => {},
+    }
+}
+
+///A measurement that runs along the main one, off the same events, with its own TDCs, settings
+///and sink. The TimeTagger events only go to the main measurement.
+pub trait Output: Send {
+    fn add_event(&mut self, raw: RawEvent);
+    ///Sends the output if it is ready. An error means the client is gone.
+    fn send_if_ready(&mut self) -> std::io::Result<()>;
+}
+
+///The thread of an extra output, and the queue of the events it has not seen yet. The queue
+///blocks when it is full: the TDCs of the output follow the events, so none can be skipped.
+struct OutputThread {
+    events: Queue<Arc<Vec<RawEvent>>>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl OutputThread {
+    fn spawn(mut output: Box<dyn Output>) -> Self {
+        let (events, buffers) = Queue::<Arc<Vec<RawEvent>>>::new(OUTPUT_QUEUE_SIZE, SlowConsumer::Block);
+        let thread = thread::spawn(move || {
+            for buffer in buffers {
+                buffer.iter().for_each(|raw| output.add_event(*raw));
+                if output.send_if_ready().is_err() {
+                    println!("***Pipeline***: Client of an extra output disconnected.");
+                    break;
+                }
+            }
+        });
+        OutputThread {events, thread}
+    }
+}
+
+///Decodes the buffers of a measurement and dispatches the events to an accumulator and to the
+///extra outputs. The masked pixels are always dropped; the other filters are added with
+///`with_filter`.
+pub struct Pipeline {
+    decoder: StreamDecoder,
+    filters: Vec<Box<dyn Filter>>,
+    outputs: Vec<OutputThread>,
+    tdcs: Tdcs,
+    settings: Settings,
+    ttx: Option<ttx::TTXRef>,
+}
+
+impl Pipeline {
+    pub fn new(settings: Settings, tdcs: Tdcs, ttx: Option<ttx::TTXRef>) -> Self {
+        Pipeline {decoder: StreamDecoder::new(), filters: vec![Box::new(BadPixels::default())], outputs: Vec::new(), tdcs, settings, ttx}
+    }
+
+    pub fn with_filter<F: Filter + 'static>(mut self, filter: F) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    ///Adds an extra output, which starts its thread.
+    pub fn with_output(mut self, output: Box<dyn Output>) -> Self {
+        self.outputs.push(OutputThread::spawn(output));
+        self
+    }
+
+    ///Extra outputs whose client is still connected.
+    pub fn outputs(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn tdcs(&self) -> &Tdcs {
+        &self.tdcs
+    }
+
+    pub fn ttx_mut(&mut self) -> Option<&mut ttx::TTXRef> {
+        self.ttx.as_mut()
+    }
+
+    ///Informs the TimeTagger of the main TDC, so its times follow the ones of the TP3.
+    pub fn inform_ttx(&mut self) {
+        if let Some(in_ttx) = &mut self.ttx {
+            in_ttx.inform_scan_tdc(&mut self.tdcs.main);
+        }
+    }
+
+    ///Dispatches the events of a buffer of whole packets, and then the ones of the TimeTagger.
+    ///The events are then queued to the extra outputs, and the ones whose client is gone are
+    ///dropped.
+    pub fn process<A: Accumulator>(&mut self, data: &[u8], accumulator: &mut A) {
+        let Pipeline {decoder, filters, outputs, tdcs, settings, ttx} = self;
+        let mut counter = PacketCounter::default();
+        let mut events: Vec<RawEvent> = Vec::with_capacity(if outputs.is_empty() {0} else {data.len() / 8});
+        for raw in decoder.decode(data) {
+            if let Some(packet) = raw.event.packet() {
+                counter.count(packet);
+            }
+            if let Event::Pixel(packet) | Event::FramePixel(packet) = raw.event {
+                if !filters.iter_mut().all(|filter| filter.keep(&packet, tdcs, settings)) {continue;}
+            }
+            dispatch(raw, accumulator, tdcs, settings);
+            if !outputs.is_empty() {events.push(raw);}
+        }
+        counter.publish();
+        metrics().set_sync(decoder.timestamps().sync());
+
+        if let Some(in_ttx) = ttx {
+            in_ttx.inform_scan_tdc(&mut tdcs.main);
+            accumulator.add_ttx(in_ttx);
+        }
+        if !outputs.is_empty() {
+            let events = Arc::new(events);
+            outputs.retain(|output| output.events.push(events.clone()));
+        }
+    }
+
+    ///Flushes the file of the TimeTagger, and waits for the extra outputs to send what they were
+    ///given.
+    pub fn finish(&mut self) -> Result<(), Tp3ErrorKind> {
+        for OutputThread {events, thread} in self.outputs.drain(..) {
+            drop(events);
+            thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        }
+        if let Some(in_ttx) = &mut self.ttx {
+            in_ttx.finish()?;
+        }
+        Ok(())
+    }
+}
+
+///Where the output of an accumulator goes. An error means the client is gone.
+pub trait Sink {
+    ///Sends a frame made of `parts`, such as a header and its data.
+    fn send(&mut self, parts: &[&[u8]]) -> std::io::Result<()>;
+}
+
+impl<U: Write> Sink for U {
+    fn send(&mut self, parts: &[&[u8]]) -> std::io::Result<()> {
+        parts.iter().try_for_each(|part| self.write_all(part))
+    }
+}
+
+///An electron as counted by the accumulators: a hit, or a cluster at its centroid.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Electron {
+    pub x: POSITION,
+    pub y: POSITION,
+    pub subpixel: POSITION, //Bin along x inside the pixel (see `engine::subpixel`).
+    pub index: usize, //As given with the hit. For a cluster, the one of its first hit.
+    pub counts: u32, //Gain of the pixel (see `pixellib::FlatField`).
+}
+
+///Counts the hits or, with `live_clustering` or a super-resolution above 1, the clusters at their
+///centroid. The clusters span the buffers, so an electron is given once the hits have gone past it.
+pub struct Clustering {
+    engine: Option<ClusterEngine>,
+    super_resolution: POSITION,
+    flat: FlatField,
+    electrons: Vec<Electron>,
+}
+
+impl Default for Clustering {
+    fn default() -> Self {
+        Clustering::new(1)
+    }
+}
+
+impl Clustering {
+    ///`super_resolution` bins per pixel along x, up to `engine::MAX_SUPER_RESOLUTION`.
+    pub fn new(super_resolution: POSITION) -> Self {
+        let super_resolution = super_resolution.clamp(1, engine::MAX_SUPER_RESOLUTION);
+        let engine = (config().live_clustering || super_resolution > 1).then(ClusterEngine::default);
+        Clustering {engine, super_resolution, flat: FlatField::default(), electrons: Vec::new()}
+    }
+
+    pub fn is_clustering(&self) -> bool {
+        self.engine.is_some()
+    }
+
+    pub fn super_resolution(&self) -> POSITION {
+        self.super_resolution
+    }
+
+    ///Adds a hit at `time`, in TDC units, and gives the electrons that are complete. A hit that is
+    ///not `kept` is left out, and does not close any cluster.
+    #[inline]
+    pub fn add(&mut self, packet: &Packet, time: TIME, index: usize, kept: bool) -> std::vec::Drain<'_, Electron> {
+        match self.engine.as_mut() {
+            Some(clusters) => {
+                if kept {
+                    clusters.add(Hit::from_packet(packet, time, index));
+                }
+                self.close_finished();
+            },
+            None => {
+                if kept {
+                    self.electrons.push(Electron {x: packet.x(), y: packet.y(), subpixel: 0, index, counts: self.flat.packet_counts(packet)});
+                }
+            },
+        }
+        self.electrons.drain(..)
+    }
+
+    ///Closes the clusters that are still open and gives their electrons.
+    pub fn flush(&mut self) -> std::vec::Drain<'_, Electron> {
+        if let Some(clusters) = self.engine.as_mut() {
+            clusters.flush();
+            self.close_finished();
+        }
+        self.electrons.drain(..)
+    }
+
+    fn close_finished(&mut self) {
+        let Clustering {engine: clusters, super_resolution, flat, electrons} = self;
+        for cluster in clusters.iter_mut().flat_map(ClusterEngine::finished) {
+            let (x, y) = (cluster.x(), cluster.y());
+            let subpixel = engine::subpixel(cluster.centroid().0, *super_resolution);
+            electrons.push(Electron {x, y, subpixel, index: cluster.index(), counts: flat.counts(x, y)});
+        }
+    }
+}
@@ -0,0 +1,267 @@
+//!`pixellib` is a collection of tools to calibrate the detector pixel by pixel. A `PixelCalibration`
+//!holds a mask of the bad (hot or dead) pixels and a gain (flat-field) map over the frames of the
+//!detector geometry (see `geometrylib`).
+//!Masked pixels are dropped by the live modes and by the post-processing, and the others are
+//!counted by their gain.
+//!
+//!The calibration is computed from a flood-field acquisition, in which the detector is uniformly
+//!illuminated. Pixels far from the median counts are masked, and the gain of the others is the
+//!median over their counts, so the larger pixels at the chip edges are also corrected. If the edge
+//!pixels are spread over the gaps (see `geometrylib`), their counts are shared by the positions of
+//!their span first. It is saved as an event file (see `eventlib`) with one row per pixel and the
+//!columns `mask` and `gain`.
+//!
+//!Positions of the frame that no chip covers, such as the gaps and the column that counts the TDCs
+//!in the live modes, are never masked and their gain is one.
+use crate::auxiliar::value_types::*;
+use crate::configlib::config;
+use crate::constlib::MMAP_CHUNK_SIZE;
+use crate::errorlib::Tp3ErrorKind;
+use crate::eventlib::{Column, ColumnData, EventReader, EventWriter, Provenance};
+use crate::geometrylib::{geometry, EdgePixels};
+use crate::packetlib::Packet;
+use crate::readerlib::{Event, MappedFile};
+use std::path::Path;
+use std::sync::OnceLock;
+
+static CALIBRATION: OnceLock<PixelCalibration> = OnceLock::new();
+
+///Limits of the good pixels in a flood field, relative to the median counts.
+#[derive(Copy, Clone, Debug)]
+pub struct FloodThresholds {
+    pub dead: f32, //Pixels counting less are masked
+    pub hot: f32, //Pixels counting more are masked
+}
+
+impl Default for FloodThresholds {
+    fn default() -> Self {
+        FloodThresholds {dead: 0.2, hot: 5.0}
+    }
+}
+
+///Mask and gain of every pixel, indexed by `x + width * y`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PixelCalibration {
+    mask: Vec<bool>,
+    gain: Vec<f32>,
+    uniform: bool, //No pixel is masked and all gains are one
+}
+
+impl Default for PixelCalibration {
+    fn default() -> Self {
+        PixelCalibration::uniform()
+    }
+}
+
+impl PixelCalibration {
+    ///A calibration that keeps every pixel as it is.
+    pub fn uniform() -> Self {
+        let pixels = PixelCalibration::pixels();
+        PixelCalibration {mask: vec![false; pixels], gain: vec![1.0; pixels], uniform: true}
+    }
+
+    fn pixels() -> usize {
+        let (width, height) = Packet::chip_array();
+        (width * height) as usize
+    }
+
+    #[inline]
+    fn index(x: POSITION, y: POSITION) -> usize {
+        (x + Packet::chip_array().0 * y) as usize
+    }
+
+    fn is_detector_pixel(index: usize) -> bool {
+        let width = Packet::chip_array().0 as usize;
+        geometry().is_pixel((index % width) as POSITION, (index / width) as POSITION)
+    }
+
+    fn update_uniform(&mut self) {
+        self.uniform = !self.mask.iter().any(|masked| *masked) && self.gain.iter().all(|gain| *gain == 1.0);
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        self.uniform
+    }
+
+    pub fn is_masked(&self, x: POSITION, y: POSITION) -> bool {
+        !self.uniform && self.mask[PixelCalibration::index(x, y)]
+    }
+
+    ///Whether the pixel of an electron packet is masked.
+    #[inline]
+    pub fn is_packet_masked(&self, packet: &Packet) -> bool {
+        !self.uniform && self.mask[PixelCalibration::index(packet.x(), packet.y())]
+    }
+
+    pub fn gain(&self, x: POSITION, y: POSITION) -> f32 {
+        self.gain[PixelCalibration::index(x, y)]
+    }
+
+    pub fn mask_pixel(&mut self, x: POSITION, y: POSITION) {
+        let index = PixelCalibration::index(x, y);
+        if PixelCalibration::is_detector_pixel(index) {
+            self.mask[index] = true;
+            self.uniform = false;
+        }
+    }
+
+    pub fn set_gain(&mut self, x: POSITION, y: POSITION, gain: f32) {
+        let index = PixelCalibration::index(x, y);
+        if PixelCalibration::is_detector_pixel(index) {
+            self.gain[index] = gain;
+            self.update_uniform();
+        }
+    }
+
+    pub fn masked_pixels(&self) -> usize {
+        self.mask.iter().filter(|masked| **masked).count()
+    }
+
+    ///Computes the calibration from the counts of every pixel of a flood field.
+    pub fn from_counts(counts: &[u64], thresholds: FloodThresholds) -> Result<Self, Tp3ErrorKind> {
+        if counts.len() != PixelCalibration::pixels() {
+            return Err(Tp3ErrorKind::PixelBadCalibration {reason: "wrong number of pixels"});
+        }
+        let mut sorted: Vec<u64> = counts.iter().enumerate()
+            .filter(|(index, _)| PixelCalibration::is_detector_pixel(*index))
+            .map(|(_, count)| *count)
+            .collect();
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2] as f32;
+        if median == 0.0 {
+            return Err(Tp3ErrorKind::PixelBadCalibration {reason: "the flood field is empty"});
+        }
+
+        let width = Packet::chip_array().0 as usize;
+        let edges = EdgePixels::default();
+        let mut calibration = PixelCalibration::uniform();
+        for (index, count) in counts.iter().enumerate().filter(|(index, _)| PixelCalibration::is_detector_pixel(*index)) {
+            let (x, y) = ((index % width) as POSITION, (index / width) as POSITION);
+            let shared = edges.span(x, y).map_or(1, |span| span.width());
+            let relative = *count as f32 / shared as f32 / median;
+            if relative < thresholds.dead || relative > thresholds.hot {
+                calibration.mask[index] = true;
+            } else {
+                calibration.gain[index] = 1.0 / relative;
+            }
+        }
+        calibration.update_uniform();
+        Ok(calibration)
+    }
+
+    ///Computes the calibration from a recorded flood field.
+    pub fn from_flood_field<P: AsRef<Path>>(path: P, thresholds: FloodThresholds) -> Result<Self, Tp3ErrorKind> {
+        let calibration = PixelCalibration::from_counts(&count_pixels(path)?, thresholds)?;
+        println!("***Pixellib***: {} pixels were masked.", calibration.masked_pixels());
+        Ok(calibration)
+    }
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new::<u8>("mask", "", "1 if the pixel is masked."),
+            Column::new::<f32>("gain", "", "Counts per electron of the pixel."),
+        ]
+    }
+
+    ///Saves the calibration. `provenance` gives the flood field it was computed from.
+    pub fn save<P: AsRef<Path>>(&self, path: P, provenance: Provenance) -> Result<(), Tp3ErrorKind> {
+        let mask: Vec<u8> = self.mask.iter().map(|masked| *masked as u8).collect();
+        let mut writer = EventWriter::create(path, PixelCalibration::columns(), None, provenance)?;
+        writer.write_block(&[ColumnData::new(&mask), ColumnData::new(&self.gain)])?;
+        writer.finish()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Tp3ErrorKind> {
+        let mut reader = EventReader::open(path)?;
+        let mask = reader.read_column::<u8>("mask")?;
+        let gain = reader.read_column::<f32>("gain")?;
+        if mask.len() != PixelCalibration::pixels() || gain.len() != PixelCalibration::pixels() {
+            return Err(Tp3ErrorKind::PixelBadCalibration {reason: "wrong number of pixels"});
+        }
+        let mut calibration = PixelCalibration {mask: mask.iter().map(|masked| *masked != 0).collect(), gain, uniform: false};
+        calibration.update_uniform();
+        Ok(calibration)
+    }
+}
+
+///Counts the electrons of every pixel in a recorded file.
+pub fn count_pixels<P: AsRef<Path>>(path: P) -> Result<Vec<u64>, Tp3ErrorKind> {
+    let file = MappedFile::open(path)?;
+    let chunks = file.chunks(MMAP_CHUNK_SIZE, 0);
+    let mut counts = vec![0; PixelCalibration::pixels()];
+    file.for_each_ordered(&chunks, |_, events| {
+        let mut local = vec![0; PixelCalibration::pixels()];
+        for raw in events {
+            if let Event::Pixel(packet) = raw.event {
+                local[PixelCalibration::index(packet.x(), packet.y())] += 1;
+            }
+        }
+        local
+    }, |_, local| {
+        counts.iter_mut().zip(local).for_each(|(count, local)| *count += local);
+        Ok(())
+    })?;
+    Ok(counts)
+}
+
+///Sets the global calibration. It can only be set once, and before the first call to
+///`calibration`.
+pub fn init(calibration: PixelCalibration) -> Result<(), Tp3ErrorKind> {
+    CALIBRATION.set(calibration).map_err(|_| Tp3ErrorKind::PixelCalibrationAlreadyLoaded)
+}
+
+///Sets the global calibration from `config().pixel_calibration_file`. Nothing is set if the file
+///is not given.
+pub fn init_from_config() -> Result<(), Tp3ErrorKind> {
+    let path = &config().pixel_calibration_file;
+    if path.is_empty() {
+        return Ok(());
+    }
+    println!("***Pixellib***: Reading the pixel calibration from {}.", path);
+    init(PixelCalibration::load(path)?)
+}
+
+///The global calibration. It is uniform if it was not initialized.
+pub fn calibration() -> &'static PixelCalibration {
+    CALIBRATION.get_or_init(PixelCalibration::uniform)
+}
+
+///Applies the gains to integer counts. Every pixel keeps the fraction of a count it did not give
+///yet, so that summed over many electrons a pixel is counted its gain times.
+pub struct FlatField {
+    calibration: &'static PixelCalibration,
+    remainder: Vec<f32>, //Only allocated if the calibration is not uniform
+}
+
+impl Default for FlatField {
+    fn default() -> Self {
+        FlatField::new(calibration())
+    }
+}
+
+impl FlatField {
+    pub fn new(calibration: &'static PixelCalibration) -> Self {
+        FlatField {calibration, remainder: Vec::new()}
+    }
+
+    ///Counts given by an electron at (`x`, `y`). Zero for masked pixels.
+    #[inline]
+    pub fn counts(&mut self, x: POSITION, y: POSITION) -> u32 {
+        if self.calibration.uniform {return 1;}
+        let index = PixelCalibration::index(x, y);
+        if self.calibration.mask[index] {return 0;}
+        if self.remainder.is_empty() {
+            self.remainder = vec![0.0; self.calibration.gain.len()];
+        }
+        let total = self.remainder[index] + self.calibration.gain[index];
+        let counts = total.floor();
+        self.remainder[index] = total - counts;
+        counts as u32
+    }
+
+    ///Counts given by an electron packet.
+    #[inline]
+    pub fn packet_counts(&mut self, packet: &Packet) -> u32 {
+        self.counts(packet.x(), packet.y())
+    }
+}
@@ -2,17 +2,25 @@
 pub mod coincidence {
     //!Used for temporally correlation between electrons and external events, by
     //!means of the TDCs in the SPIDR readout or not.
-    use crate::packetlib::Packet;
     use crate::tdclib::TdcRef;
     use crate::errorlib::Tp3ErrorKind;
     use crate::clusterlib::cluster::ClusterCorrectionTypes;
-    use std::io::prelude::*;
     use std::fs;
     use crate::clusterlib::cluster::{SingleElectron, CollectionElectron, SinglePhoton, CollectionPhoton};
+    use crate::clusterlib::cluster::CentroidEngine;
+    use crate::clusterlib::engine;
     use crate::auxiliar::{Settings, value_types::*, misc::{output_data, packet_change}, FileManager};
     use crate::constlib::*;
+    use crate::configlib::config;
+    use crate::modelib::AcquisitionMode;
+    use crate::exportlib::Exporter;
+    use crate::eventlib::{Column, ColumnData, EventWriter, Provenance, EVENT_FILE};
+    use crate::readerlib::{Decoder, Event, MappedFile};
+    use crate::timelib::SyncMonitor;
+    use crate::pixellib::{calibration, FlatField};
+    use crate::packetlib::Packet;
     use indicatif::{ProgressBar, ProgressStyle};
-    use std::sync::{mpsc, Arc, Mutex, Condvar};
+    use std::sync::mpsc;
     use std::thread;
 
     //When we would like to have large E-PH timeoffsets, such as skipping entire line periods, the
@@ -28,6 +36,21 @@ pub mod coincidence {
         }
     }
 
+    //Columns of the coincidence event file, in the order they are written.
+    fn event_columns() -> Vec<Column> {
+        vec![
+            Column::new::<u8>("channel", "", "Channel of the coincident photon."),
+            Column::new::<i16>("tH", "0.260 ns", "Electron time minus photon time, folded into i16."),
+            Column::new::<i16>("tcorH", "0.260 ns", "As tH, using the corrected electron time. i16::MIN if the time is not corrected."),
+            Column::new::<u16>("xH", "pixel", "Dispersive position."),
+            Column::new::<u16>("yH", "pixel", "Non-dispersive position."),
+            Column::new::<u16>("tot", "25 ns", "Time over threshold."),
+            Column::new::<u64>("tabsH", "0.260 ns", "Electron time. It wraps around at the electron time overflow."),
+            Column::new::<u64>("condensed_packet", "", "Raw packet with the chip index in the header."),
+            Column::new::<u32>("si", "", "Spectral image index. u32::MAX if out of the scan."),
+        ]
+    }
+
     //This is the struct that is sent over the channels for parallelization. Basically we send data
     //here and process in another thread.
     struct ChannelSender {
@@ -87,12 +110,12 @@ pub mod coincidence {
 
         //Condition to be a SpectralImage
         fn is_spim(&self) -> bool {
-            self.my_settings.mode == 2
+            self.my_settings.mode == AcquisitionMode::LiveSpim
         }
 
         //Condition of the FastOscillator
         fn is_fast_oscillator(&self) -> bool {
-            self.my_settings.mode == 99 //TODO: this is incorrect and for debug purposes only
+            self.my_settings.mode == AcquisitionMode::NotImplemented(99) //TODO: this is incorrect and for debug purposes only
         }
 
         //Get spectralImage TDC
@@ -140,30 +163,34 @@ pub mod coincidence {
                     panic!("***Coincidence***: Spim mode is on. X and Y pixels must be greater than 0.");
                 }
                 let mut empty_filemanager = FileManager::new_empty();
-                let temp = TdcRef::new_periodic(MAIN_TDC, &mut file0, &self.my_settings, &mut empty_filemanager).expect("Could not create period TDC reference.");
+                let temp = TdcRef::new_periodic(config().main_tdc, &mut file0, &self.my_settings, &mut empty_filemanager).expect("Could not create period TDC reference.");
                 self.tdc1 = temp;
             };
             
 
             if self.is_fast_oscillator() {
                 let mut empty_filemanager = FileManager::new_empty();
-                let temp = TdcRef::new_periodic(SECONDARY_TDC, &mut file0, &self.my_settings, &mut empty_filemanager).expect("Could not create period TDC reference.");
+                let temp = TdcRef::new_periodic(config().secondary_tdc, &mut file0, &self.my_settings, &mut empty_filemanager).expect("Could not create period TDC reference.");
                 self.tdc2 = temp;
             };
         }
 
+        fn folder(&self) -> &str {
+            &self.file[..self.file.len() - 5]
+        }
+
         fn try_create_folder(&self) -> Result<(), Tp3ErrorKind> {
-            let path_length = &self.file.len();
-            match fs::create_dir(&self.file[..path_length - 5]) {
+            let folder = self.folder();
+            match fs::create_dir(folder) {
                 Ok(_) => {Ok(())},
-                Err(_) => { Err(Tp3ErrorKind::FolderAlreadyCreated) }
+                Err(source) => { Err(Tp3ErrorKind::FolderAlreadyCreated {path: folder.to_owned(), source}) }
             }
         }
         
         fn is_file_readable(&self) -> Result<(), Tp3ErrorKind> {
             match fs::File::open(&self.file) {
                 Ok(_) => {Ok(())},
-                Err(_) => { Err(Tp3ErrorKind::CoincidenceCantReadFile) }
+                Err(source) => { Err(Tp3ErrorKind::CoincidenceCantReadFile {path: self.file.clone(), source}) }
             }
         }
 		
@@ -197,8 +224,8 @@ pub mod coincidence {
                 file: file_path,
                 my_settings,
                 save_locally,
-                tdc1: TdcRef::new_no_read(MAIN_TDC).expect("Could not create non-periodic TDC reference."),
-                tdc2: TdcRef::new_no_read(SECONDARY_TDC).expect("Could not create non-periodic TDC reference.")
+                tdc1: TdcRef::new_no_read(config().main_tdc).expect("Could not create non-periodic TDC reference."),
+                tdc2: TdcRef::new_no_read(config().secondary_tdc).expect("Could not create non-periodic TDC reference.")
             }
         } 
     }
@@ -214,21 +241,37 @@ pub mod coincidence {
         spim_frame: Vec<u32>,
         spim_size: (POSITION, POSITION),
         edata_settings: ElectronDataSettings,
+        flat: FlatField,
+        corr_flat: FlatField, //Coincident electrons are also in `spectrum`, so they keep their own remainders
+        super_resolution: POSITION, //Bins per pixel of the spectra. The spectral image keeps one.
+        clusters: CentroidEngine, //Of the `Centroid` correction, for the whole file
+        last_photons: CollectionPhoton, //Of the last buffer, for the clusters still open at the end
+        exporter: Option<Exporter>, //Only if the data is saved locally
+        events: Option<EventWriter>, //Only if the data is saved locally
     }
 
     impl ElectronData {
 
         //Called for all the electrons (not only coincident).
+        //Bin of an electron in the spectra. Its centroid is only sub-pixel with the `Centroid`
+        //cluster correction.
+        fn spectrum_bin(&self, val: &SingleElectron) -> usize {
+            (val.x() * self.super_resolution + engine::subpixel(val.centroid().0, self.super_resolution)) as usize
+        }
+
         fn add_electron(&mut self, val: &SingleElectron) {
-            self.spectrum[val.x() as usize] += 1;
+            let counts = self.flat.counts(val.x(), val.y());
+            let bin = self.spectrum_bin(val);
+            self.spectrum[bin] += counts;
             if let Some(index) = val.get_or_not_spim_index(self.edata_settings.try_get_spim_tdc(), self.spim_size.0, self.spim_size.1) {
-                self.spim_frame[index as usize] += 1;
+                self.spim_frame[index as usize] += counts;
             }
         }
         
         //Called for all the photons (not only coincident).
         fn add_photon(&mut self, val: &SinglePhoton) {
-            self.spectrum[PIXELS_X as usize - 1] += 1;
+            let last = self.spectrum.len() - 1;
+            self.spectrum[last] += 1;
             if let Some(index) = val.get_or_not_spim_index(self.edata_settings.try_get_spim_tdc(), self.spim_size.0, self.spim_size.1) {
                 self.spim_frame[index as usize] += 1;
             }
@@ -240,29 +283,36 @@ pub mod coincidence {
             self.index_to_add_in_raw.append(&mut channel_sender.raw_index);
         }
         
-        //This adds the packet to the reduced raw value and clear the index list afterwards
-        fn add_packets_to_reduced_data(&mut self, buffer: &[u8]) {
+        //This adds the packet to the reduced raw value and clear the index list afterwards. The
+        //indexes count the packets from the start of the file, and `first_index` is the one of the
+        //buffer.
+        fn add_packets_to_reduced_data(&mut self, buffer: &[u8], first_index: usize) {
             //Now we must add the concerned data to the reduced raw. We should first sort the indexes
             //that we have saved, ensuring that the data is saved in the same order as the raw
             //data.
             self.index_to_add_in_raw.sort();
             //Then we should iterate and see matching indexes to add.
+            //The electrons of a cluster open across buffers can come from the buffer before, which
+            //is already written, so they are left out.
             for index in self.index_to_add_in_raw.iter() {
-                let value = packet_change(&buffer[index * 8..(index + 1) * 8])[0];
-                self.reduced_raw_data.push(value);
+                if let Some(packet) = index.checked_sub(first_index).and_then(|index| buffer.get(index * 8..(index + 1) * 8)) {
+                    self.reduced_raw_data.push(packet_change(packet)[0]);
+                }
             }
             self.index_to_add_in_raw.clear();
         }
 
         fn add_coincident_electron(&mut self, val: SingleElectron) {
-            self.corr_spectrum[val.x() as usize] += 1; //Adding the electron
-            self.corr_spectrum[PIXELS_X as usize-1] += 1; //Adding the photon
+            let bin = self.spectrum_bin(&val);
+            self.corr_spectrum[bin] += self.corr_flat.counts(val.x(), val.y()); //Adding the electron
+            let last = self.corr_spectrum.len() - 1;
+            self.corr_spectrum[last] += 1; //Adding the photon
             self.coinc_electrons.add_electron(val);
         }
         
         fn add_events(&mut self, channel_sender: &mut ChannelSender, time_delay: TIME, time_width: TIME, _line_offset: i64) {
             //Removing clusters (if need) for electrons.
-            channel_sender.temp_electron.try_clean(0, &self.edata_settings.remove_clusters);
+            channel_sender.temp_electron.try_clean(0, &self.edata_settings.remove_clusters, &mut self.clusters);
 
             //Adding photons to the last pixel. We also add the photons in the spectra image.
             channel_sender.temp_photon.iter().for_each(|photon| self.add_photon(photon));
@@ -275,24 +325,61 @@ pub mod coincidence {
 
             //Adding electron in the coincidence action
             coinc_electron.into_iter().for_each(|electron| self.add_coincident_electron(electron));
+            self.last_photons = std::mem::replace(&mut channel_sender.temp_photon, CollectionPhoton::new());
         }
 
-        fn new_from_settings(eds: &ElectronDataSettings) -> Self {
-            Self {
+        //Adds the electrons of the clusters still open at the end of the file, with the photons of
+        //the last buffer. True if there were any.
+        fn add_last_clusters(&mut self, time_delay: TIME, time_width: TIME) -> bool {
+            let electrons = self.clusters.flush();
+            electrons.iter().for_each(|electron| self.add_electron(electron));
+            let coinc_electron = electrons.search_coincidence(&self.last_photons, &mut self.index_to_add_in_raw, time_delay, time_width);
+            coinc_electron.into_iter().for_each(|electron| self.add_coincident_electron(electron));
+            //Their packets are in the buffers already written.
+            self.index_to_add_in_raw.clear();
+            !electrons.is_empty()
+        }
+
+        fn new_from_settings(eds: &ElectronDataSettings) -> Result<Self, Tp3ErrorKind> {
+            let (exporter, events) = if eds.save_locally {
+                let exporter = Exporter::new(eds.folder(), Some(&eds.my_settings))?;
+                let provenance = Provenance::from_source(&eds.file, "postlib::coincidence");
+                let events = EventWriter::create(std::path::Path::new(eds.folder()).join(EVENT_FILE), event_columns(), Some(&eds.my_settings), provenance)?;
+                (Some(exporter), Some(events))
+            } else {
+                (None, None)
+            };
+            let width = Packet::chip_array().0;
+            let super_resolution = engine::super_resolution();
+            Ok(Self {
                 reduced_raw_data: Vec::new(),
                 index_to_add_in_raw: Vec::new(),
                 coinc_electrons: CollectionElectron::new(),
-                spim_frame: vec![0; (PIXELS_X * eds.my_settings.xspim_size * eds.my_settings.yspim_size) as usize],
-                spectrum: vec![0; PIXELS_X as usize],
-                corr_spectrum: vec![0; PIXELS_X as usize],
+                spim_frame: vec![0; (width * eds.my_settings.xspim_size * eds.my_settings.yspim_size) as usize],
+                spectrum: vec![0; (width * super_resolution) as usize],
+                corr_spectrum: vec![0; (width * super_resolution) as usize],
                 spim_size: (eds.my_settings.xspim_size, eds.my_settings.yspim_size),
                 edata_settings: eds.clone(),
-            }
+                flat: FlatField::default(),
+                corr_flat: FlatField::default(),
+                super_resolution,
+                clusters: CentroidEngine::default(),
+                last_photons: CollectionPhoton::new(),
+                exporter,
+                events,
+            })
         }
               
-        fn output_hyperspec(&self) {
-            if !self.edata_settings.save_locally { return; };
-            output_data(&self.spim_frame, self.edata_settings.file.clone(), "spim_frame.txt");
+        fn output_hyperspec(&mut self) -> Result<(), Tp3ErrorKind> {
+            if let Some(mut exporter) = self.exporter.take() {
+                let row_shape = [self.spim_size.1 as usize, self.spim_size.0 as usize, Packet::chip_array().0 as usize];
+                exporter.append("spim_frame", &row_shape, &self.spim_frame)?;
+                exporter.finish()?;
+            }
+            if let Some(events) = self.events.take() {
+                events.finish()?;
+            }
+            Ok(())
         }
 
         fn create_x(&self) -> Vec<u16> {
@@ -320,18 +407,20 @@ pub mod coincidence {
                 .filter_map(|se| se.relative_corrected_time_from_coincident_photon()
                             .map(|value| value.fold())).collect()
         }
+        //Aligned with the other columns, for the event file.
+        fn create_rel_corrected_time_or_min(&self) -> Vec<i16> {
+            self.coinc_electrons.iter()
+                .map(|se| se.relative_corrected_time_from_coincident_photon()
+                            .map_or(i16::MIN, |value| value.fold())).collect()
+        }
         fn create_condensed_packet(&self) -> Vec<u64> {
             self.coinc_electrons.iter().map(|se| se.raw_packet_data().modified_packet_data()).collect()
         }
         fn create_spim_index(&self) -> Vec<INDEXHYPERSPEC> {
             self.coinc_electrons.iter().map(|se| se.get_or_not_spim_index(self.edata_settings.try_get_spim_tdc(), self.spim_size.0, self.spim_size.1).unwrap_or(POSITION::MAX)).collect()
         }
-        fn early_output_data(&mut self) {
-            //This reorders the packet based on how they have arrived. If you are in the middle of
-            //a time overflow and sort the data, output data would be strange without this.
-            self.coinc_electrons.reorder_by_packet_index();
-
-            if !self.edata_settings.save_locally { return; };
+        fn early_output_data(&mut self) -> Result<(), Tp3ErrorKind> {
+            if !self.edata_settings.save_locally { return Ok(()); };
             
             let relative_corrected_time: Vec<i16> = self.create_rel_corrected_time();
             let channel: Vec<u8> = self.create_channel();
@@ -342,164 +431,150 @@ pub mod coincidence {
             let time: Vec<TIME> = self.create_abs_time();
             let condensed_packet: Vec<u64> = self.create_condensed_packet();
             let spim_index: Vec<INDEXHYPERSPEC> = self.create_spim_index();
-
-            output_data(&channel, self.edata_settings.file.clone(), "channel.txt");
-            output_data(&relative_time, self.edata_settings.file.clone(), "tH.txt");
-            output_data(&relative_corrected_time, self.edata_settings.file.clone(), "tcorH.txt");
-            output_data(&x, self.edata_settings.file.clone(), "xH.txt");
-            output_data(&y, self.edata_settings.file.clone(), "yH.txt");
-            output_data(&tot, self.edata_settings.file.clone(), "tot.txt");
-            output_data(&time, self.edata_settings.file.clone(), "tabsH.txt");
-            output_data(&condensed_packet, self.edata_settings.file.clone(), "condensed_packet.txt");
-            output_data(&spim_index, self.edata_settings.file.clone(), "si.txt");
+            let aligned_corrected_time: Vec<i16> = self.create_rel_corrected_time_or_min();
+
+            let exporter = self.exporter.as_mut().expect("The exporter is created when saving locally.");
+            exporter.append("channel", &[], &channel)?;
+            exporter.append("tH", &[], &relative_time)?;
+            exporter.append("tcorH", &[], &relative_corrected_time)?;
+            exporter.append("xH", &[], &x)?;
+            exporter.append("yH", &[], &y)?;
+            exporter.append("tot", &[], &tot)?;
+            exporter.append("tabsH", &[], &time)?;
+            exporter.append("condensed_packet", &[], &condensed_packet)?;
+            exporter.append("si", &[], &spim_index)?;
+
+            let events = self.events.as_mut().expect("The event file is created when saving locally.");
+            events.write_block(&[
+                ColumnData::new(&channel), ColumnData::new(&relative_time), ColumnData::new(&aligned_corrected_time),
+                ColumnData::new(&x), ColumnData::new(&y), ColumnData::new(&tot), ColumnData::new(&time),
+                ColumnData::new(&condensed_packet), ColumnData::new(&spim_index),
+            ])?;
             self.coinc_electrons.clear();
 
-            //Output corr EELS spectrum
-            output_data(&self.corr_spectrum, self.edata_settings.file.clone(), "cspec.txt");
+            //Output corr EELS spectrum. Each buffer adds a row.
+            exporter.append("cspec", &[self.corr_spectrum.len()], &self.corr_spectrum)?;
             self.corr_spectrum.iter_mut().for_each(|x| *x = 0);
             
             //Output total EELS spectrum
-            output_data(&self.spectrum, self.edata_settings.file.clone(), "spec.txt");
+            exporter.append("spec", &[self.spectrum.len()], &self.spectrum)?;
             self.spectrum.iter_mut().for_each(|x| *x = 0);
                 
-            //Output reduced raw
+            //Output reduced raw. It is a .tpx3 file, so it is kept outside of the exporter.
             output_data(&self.reduced_raw_data, self.edata_settings.file.clone(), "reduced_raw.tpx3");
             self.reduced_raw_data.clear();
-            
+            Ok(())
         }
             
     }
     
-    pub fn search_coincidence(mut coinc_data_set: ElectronDataSettings, limit_read_size: u32){
+    pub fn search_coincidence(mut coinc_data_set: ElectronDataSettings, limit_read_size: u32) -> Result<(), Tp3ErrorKind> {
 
-        //Consumer & Producer 
-        let (tx, rx) = mpsc::channel();
+        //Consumer & Producer. This memory-bounds the problem: the producer waits while the consumer
+        //has MEMORY_BOUND_QUEUE_SIZE buffers, counting the one it is working on.
+        let (tx, rx) = mpsc::sync_channel(MEMORY_BOUND_QUEUE_SIZE - 1);
 
         //Creating the appropriate TDCs
         coinc_data_set.create_tdcs();
-        let mut coinc_data = ElectronData::new_from_settings(&coinc_data_set);
+        let mut coinc_data = ElectronData::new_from_settings(&coinc_data_set)?;
 
-        //Opening the raw data file. We have already checked if the file opens so no worries here.
-        let mut ci = 0;
-        let mut file = fs::File::open(&coinc_data_set.file).unwrap();
-        let mut total_size = 0;
+        //Mapping the raw data file. The chunks are decoded in parallel, each one starting from the
+        //clocks of the file before it.
+        let file = MappedFile::open(&coinc_data_set.file)?;
+        let mut chunks = file.chunks(MMAP_CHUNK_SIZE, limit_read_size as usize);
+        file.track_overflows(&mut chunks);
         
         //Setting the progress bar
-        let progress_size = file.metadata().unwrap().len();
-        let bar = ProgressBar::new(progress_size);
+        let progress_size = chunks.last().map_or(0, |chunk| chunk.end);
+        let bar = ProgressBar::new(progress_size as u64);
         bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.white/black} {percent}% {pos:>7}/{len:7} [ETA: {eta}] Searching electron photon coincidences")
                       .unwrap()
                       .progress_chars("=>-"));
 
-        //This memory-bounds the problem. It means we cannot have the producer TOO fast.
-        let counter = Arc::new((Mutex::new(0), Condvar::new()));
-
-        //Producer. It is a single thread but in principle if the consumer is too slow this could
-        //explose in memory. The MEMORY_BOUND_QUEUE_SIZE limits the value this thread can be ahead
-        //of the consumer.
-        let counter_tx = Arc::clone(&counter);
-        thread::spawn( move || {
-            let mut buffer: Vec<u8> = vec![0; TP3_BUFFER_SIZE];
-            while let Ok(size) = file.read(&mut buffer) {
-                
-                //Memory-bound the thread using Condvar.
-                let (lock, cvar) = &*counter_tx;
-                let mut count = lock.lock().unwrap();
-                while *count >= MEMORY_BOUND_QUEUE_SIZE {
-                    count = cvar.wait(count).unwrap();
-                }
-                *count += 1;
-
-                if size == 0 {println!("Finished Reading."); break;}
-                total_size += size;
-                if limit_read_size != 0 && total_size as u32 >= limit_read_size {break;}
-                bar.inc(TP3_BUFFER_SIZE as u64);
+        //Producer. The TDCs are updated in the order of the file, so only the decoding is
+        //parallel.
+        let producer = thread::spawn(move || -> Result<(), Tp3ErrorKind> {
+            let mut sync = SyncMonitor::default();
+            let result = file.for_each_ordered(&chunks, |chunk, events| {
+                //Times are extended, so sorting them does not mix the ones around an overflow.
+                let mut timestamps = chunk.timestamps().unwrap_or_default();
+                let times: Vec<TIME> = events.map(|raw| timestamps.event(&raw.event).unwrap_or(0)).collect();
+                (times, timestamps.sync().clone())
+            }, |chunk, (times, chunk_sync)| {
+                sync.merge(&chunk_sync);
+                bar.inc(chunk.len() as u64);
+                let buffer = file.data()[chunk.start..chunk.end].to_vec();
                 let mut channel_sender = ChannelSender::new();
-                buffer[0..size].chunks_exact(8).enumerate().for_each(|(current_raw_index, pack_oct)| {
-                    let packet = Packet::new(ci, packet_change(pack_oct)[0]);
-                    match *pack_oct {
-                        [84, 80, 88, 51, nci, _, _, _] => {
-                            ci=nci;
+                let mut decoder = Decoder::with_offset(chunk.start as u64);
+                //The indexes count the packets from the start of the file, as the clusters span the chunks.
+                decoder.decode(&buffer).zip(times).enumerate().for_each(|(current_raw_index, (raw, time))| {
+                    let current_raw_index = chunk.start / 8 + current_raw_index;
+                    match raw.event {
+                        Event::Tdc(packet) if packet.tdc_type() == config().secondary_tdc.associate_value() => { //Oscillator or Normal Event
+                            if let Some(fast_oscillator_tdc) = coinc_data_set.try_get_oscillator_tdc_mut() {
+                                fast_oscillator_tdc.upt(&packet);
+                            } else { //if its not synchronized measurement, this tdc is used as a event-channel.
+                                let mut photon = SinglePhoton::new(packet, 0, coinc_data_set.try_get_spim_tdc(), current_raw_index);
+                                photon.set_extended_time(time);
+                                channel_sender.add_photon(photon);
+                            }
                             channel_sender.add_packet_index(current_raw_index);
                         },
-                        _ => {
-                            match packet.id() {
-                                6 if packet.tdc_type() == SECONDARY_TDC.associate_value() => { //Oscillator or Normal Event
-                                    if let Some(fast_oscillator_tdc) = coinc_data_set.try_get_oscillator_tdc_mut() {
-                                        fast_oscillator_tdc.upt(&packet);
-                                    } else { //if its not synchronized measurement, this tdc is used as a event-channel.
-                                        let photon = SinglePhoton::new(packet, 0, coinc_data_set.try_get_spim_tdc(), current_raw_index);
-                                        channel_sender.add_photon(photon);
-                                    }
-                                    channel_sender.add_packet_index(current_raw_index);
-                                },
-                                6 if packet.tdc_type() == MAIN_TDC.associate_value() => { //Hyperspec or Normal Event
-                                    if let Some(spim_tdc) = coinc_data_set.try_get_spim_tdc_mut() {
-                                        spim_tdc.upt(&packet);
-                                    } else { //if its not synchronized measurement, this tdc is used as a event-channel.
-                                        let photon = SinglePhoton::new(packet, 1, coinc_data_set.try_get_spim_tdc(), current_raw_index);
-                                        channel_sender.add_photon(photon);
-                                    }
-                                    channel_sender.add_packet_index(current_raw_index);
-                                },
-                                11 => {
-                                    if let Some(oscillator_tdc) = coinc_data_set.try_get_oscillator_tdc() { //Oscillator is present
-                                        if let Some(electron_time) = oscillator_tdc.tr_electron_correct_by_blanking(&packet) { //The electron time can be corrected
-                                            let se = SingleElectron::new(packet, coinc_data_set.try_get_spim_tdc(), current_raw_index, Some(electron_time));
-                                            channel_sender.add_electron(se);
-                                        }
-                                    } else {
-                                        let se = SingleElectron::new(packet, coinc_data_set.try_get_spim_tdc(), current_raw_index, None);
-                                        channel_sender.add_electron(se);
-                                    }
-                                },
-                                12 => { //In some versions, the id can be a modified one, based on the CI.
-                                    let packet = Packet::new(0, packet_change(pack_oct)[0]);
-                                    let se = SingleElectron::new(packet, coinc_data_set.try_get_spim_tdc(), current_raw_index, None);
-                                    channel_sender.add_electron(se);
-                                },
-                                13 => { //In some versions, the id can be a modified one, based on the CI.
-                                    let packet = Packet::new(1, packet_change(pack_oct)[0]);
-                                    let se = SingleElectron::new(packet, coinc_data_set.try_get_spim_tdc(), current_raw_index, None);
-                                    channel_sender.add_electron(se);
-                                },
-                                14 => { //In some versions, the id can be a modified one, based on the CI.
-                                    let packet = Packet::new(2, packet_change(pack_oct)[0]);
-                                    let se = SingleElectron::new(packet, coinc_data_set.try_get_spim_tdc(), current_raw_index, None);
-                                    channel_sender.add_electron(se);
-                                },
-                                15 => { //In some versions, the id can be a modified one, based on the CI.
-                                    let packet = Packet::new(3, packet_change(pack_oct)[0]);
-                                    let se = SingleElectron::new(packet, coinc_data_set.try_get_spim_tdc(), current_raw_index, None);
+                        Event::Tdc(packet) if packet.tdc_type() == config().main_tdc.associate_value() => { //Hyperspec or Normal Event
+                            if let Some(spim_tdc) = coinc_data_set.try_get_spim_tdc_mut() {
+                                spim_tdc.upt(&packet);
+                            } else { //if its not synchronized measurement, this tdc is used as a event-channel.
+                                let mut photon = SinglePhoton::new(packet, 1, coinc_data_set.try_get_spim_tdc(), current_raw_index);
+                                photon.set_extended_time(time);
+                                channel_sender.add_photon(photon);
+                            }
+                            channel_sender.add_packet_index(current_raw_index);
+                        },
+                        Event::Pixel(packet) if calibration().is_packet_masked(&packet) => {}, //Bad pixel
+                        Event::Pixel(packet) => { //Modified packets from reduced files are also here.
+                            if let Some(oscillator_tdc) = coinc_data_set.try_get_oscillator_tdc() { //Oscillator is present
+                                if let Some(electron_time) = oscillator_tdc.tr_electron_correct_by_blanking(&packet) { //The electron time can be corrected
+                                    let mut se = SingleElectron::new(packet, coinc_data_set.try_get_spim_tdc(), current_raw_index, Some(electron_time));
+                                    se.set_extended_time(time);
                                     channel_sender.add_electron(se);
-                                },
-                                _ => {
-                                    channel_sender.add_packet_index(current_raw_index);
-                                },
-                            };
+                                }
+                            } else {
+                                let mut se = SingleElectron::new(packet, coinc_data_set.try_get_spim_tdc(), current_raw_index, None);
+                                se.set_extended_time(time);
+                                channel_sender.add_electron(se);
+                            }
+                        },
+                        _ => { //Chip headers and any other packet are kept in the reduced raw data.
+                            channel_sender.add_packet_index(current_raw_index);
                         },
                     };
                 });
-                tx.send((channel_sender, buffer.clone())).unwrap();
+                //The consumer only hangs up after an error, which it returns itself.
+                tx.send((channel_sender, buffer, chunk.start / 8)).map_err(|_| Tp3ErrorKind::CoincidenceConsumerStopped)
+            });
+            if sync.desyncs() > 0 || sync.dropped_packets() > 0 {
+                println!("***Coincidence***: The chips were desynchronized {} times (up to {} units of 0.260 ns) and {} packets were dropped.", sync.desyncs(), sync.max_spread(), sync.dropped_packets());
             }
+            result?;
+            println!("Finished Reading.");
+            Ok(())
         });
 
         //Consumer
-        let counter_rx = Arc::clone(&counter);
         for received in rx {
-            let (mut channel_sender, buffer): (ChannelSender, Vec<u8>) = received;
+            let (mut channel_sender, buffer, first_index): (ChannelSender, Vec<u8>, usize) = received;
             channel_sender.sort_all();
             coinc_data.add_packet_to_raw_index_from_channel_sender(&mut channel_sender); //Add standard packets
             coinc_data.add_events(&mut channel_sender, coinc_data.edata_settings.my_settings.time_delay, coinc_data.edata_settings.my_settings.time_width, 0); //Ad coincidence packets
-            coinc_data.add_packets_to_reduced_data(&buffer); //Sort and exports the packets to raw_reduced_data
-            coinc_data.early_output_data();
-            
-            let (lock, cvar) = &*counter_rx;
-            let mut count = lock.lock().unwrap();
-            *count -= 1;
-            cvar.notify_one();
+            coinc_data.add_packets_to_reduced_data(&buffer, first_index); //Sort and exports the packets to raw_reduced_data
+            coinc_data.early_output_data()?;
+        }
+        //The producer drops the sender when it is over, so its result is ready.
+        producer.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
+        if coinc_data.add_last_clusters(coinc_data.edata_settings.my_settings.time_delay, coinc_data.edata_settings.my_settings.time_width) {
+            coinc_data.early_output_data()?;
         }
-        coinc_data.output_hyperspec();
+        coinc_data.output_hyperspec()
 
     }
 }
@@ -509,9 +584,13 @@ pub mod ntime_resolved {
     use crate::tdclib::{TdcType, TdcRef};
     use crate::errorlib::Tp3ErrorKind;
     use crate::clusterlib::cluster::{SingleElectron, CollectionElectron, ClusterCorrectionTypes};
-    use crate::auxiliar::{misc::{packet_change, output_data}, value_types::*, ConfigAcquisition, Settings, FileManager};
-    use crate::constlib::*;
-    use std::io::prelude::*;
+    use crate::clusterlib::cluster::CentroidEngine;
+    use crate::auxiliar::{value_types::*, ConfigAcquisition, Settings, FileManager};
+    use crate::configlib::config;
+    use crate::modelib::AcquisitionMode;
+    use crate::exportlib::Exporter;
+    use crate::readerlib::{Decoder, Event, MappedFile};
+    use crate::constlib::MMAP_CHUNK_SIZE;
     use std::convert::TryInto;
     use std::fs;
     use indicatif::{ProgressBar, ProgressStyle};
@@ -531,9 +610,11 @@ pub mod ntime_resolved {
         spim_tdc_type: TdcType, //The tdc type for the spim,
         extra_tdc_type: TdcType, //The tdc type for the external,
         remove_clusters: ClusterCorrectionTypes,
+        clusters: CentroidEngine, //Of the `Centroid` correction, for the whole file
         file: String,
         fourd_data: bool,
         my_settings: Settings,
+        exporter: Option<Exporter>, //Created with the output folder
     }
 
     impl TimeSpectralSpatial {
@@ -541,21 +622,26 @@ pub mod ntime_resolved {
             let mut empty_filemanager = FileManager::new_empty();
             
             if self.tdc_periodic.is_none() && self.spimx>1 && self.spimy>1 {
-                self.tdc_periodic = Some(TdcRef::new_periodic(self.spim_tdc_type.clone(), file, &self.my_settings, &mut empty_filemanager).expect("Problem in creating periodic tdc ref."))
+                self.tdc_periodic = Some(TdcRef::new_periodic(self.spim_tdc_type, file, &self.my_settings, &mut empty_filemanager).expect("Problem in creating periodic tdc ref."))
             }
             Ok(())
         }
     
-        fn try_create_folder(&self) -> Result<(), Tp3ErrorKind> {
+        fn try_create_folder(&mut self) -> Result<(), Tp3ErrorKind> {
             let path_length = &self.file.len();
-            match fs::create_dir(&self.file[..path_length - 5]) {
-                Ok(_) => {Ok(())},
-                Err(_) => { Err(Tp3ErrorKind::FolderAlreadyCreated) }
+            let folder = &self.file[..path_length - 5];
+            match fs::create_dir(folder) {
+                Ok(_) => {
+                    self.exporter = Some(Exporter::new(folder, Some(&self.my_settings))?);
+                    Ok(())
+                },
+                Err(source) => { Err(Tp3ErrorKind::FolderAlreadyCreated {path: folder.to_owned(), source}) }
             }
         }
 
-        fn add_electron(&mut self, packet: Packet, packet_index: usize) {
-            let se = SingleElectron::new(packet, self.tdc_periodic.as_ref(), packet_index, None);
+        fn add_electron(&mut self, packet: Packet, packet_index: usize, time: TIME) {
+            let mut se = SingleElectron::new(packet, self.tdc_periodic.as_ref(), packet_index, None);
+            se.set_extended_time(time);
             self.ensemble.add_electron(se);
         }
 
@@ -572,66 +658,80 @@ pub mod ntime_resolved {
         }
 
         fn process(&mut self) -> Result<(), Tp3ErrorKind> {
+            if !self.ensemble.try_clean(0, &self.remove_clusters, &mut self.clusters) {
+                return Ok(());
+            }
+            if self.fourd_data {
+                self.process_fourd()
+            } else {
+                self.process_hyperspec()
+            }
+        }
+
+        //The electrons of the clusters still open at the end of the file.
+        fn flush_clusters(&mut self) -> Result<(), Tp3ErrorKind> {
+            self.ensemble = self.clusters.flush();
+            if self.ensemble.is_empty() {
+                return Ok(());
+            }
             if self.fourd_data {
-                Ok(self.process_fourd()?)
+                self.process_fourd()
             } else {
-                Ok(self.process_hyperspec()?)
+                self.process_hyperspec()
             }
         }
         
         fn process_hyperspec(&mut self) -> Result<(), Tp3ErrorKind> {
-            if self.ensemble.try_clean(0, &self.remove_clusters) {
-                for val in self.ensemble.iter() {
-                    if let Some(index) = val.get_or_not_spim_index(self.tdc_periodic.as_ref(), self.spimx, self.spimy) {
-                        self.hyperspec_index.push(index);
-                        self.frame_indices.push((val.spim_slice()).try_into().expect("Exceeded the maximum number of indices"));
-                    }
-                    
-                    if let Some(index) = val.get_or_not_return_spim_index(self.tdc_periodic.as_ref(), self.spimx, self.spimy) {
-                        self.hyperspec_return_index.push(index);
-                        self.frame_return_indices.push((val.spim_slice()).try_into().expect("Exceeded the maximum number of indices"));
-                    }
+            for val in self.ensemble.iter() {
+                if let Some(index) = val.get_or_not_spim_index(self.tdc_periodic.as_ref(), self.spimx, self.spimy) {
+                    self.hyperspec_index.push(index);
+                    self.frame_indices.push((val.spim_slice()).try_into().expect("Exceeded the maximum number of indices"));
+                }
+                
+                if let Some(index) = val.get_or_not_return_spim_index(self.tdc_periodic.as_ref(), self.spimx, self.spimy) {
+                    self.hyperspec_return_index.push(index);
+                    self.frame_return_indices.push((val.spim_slice()).try_into().expect("Exceeded the maximum number of indices"));
+                }
             }
             self.ensemble.clear();
 
-            output_data(&self.hyperspec_index, self.file.clone(), "si_complete.txt");
-            output_data(&self.hyperspec_return_index, self.file.clone(), "si_return_complete.txt");
-            output_data(&self.frame_indices, self.file.clone(), "si_complete_indices.txt");
-            output_data(&self.frame_return_indices, self.file.clone(), "si_complete_return_indices.txt");
+            let exporter = self.exporter.as_mut().expect("The exporter is created with the output folder.");
+            exporter.append("si_complete", &[], &self.hyperspec_index)?;
+            exporter.append("si_return_complete", &[], &self.hyperspec_return_index)?;
+            exporter.append("si_complete_indices", &[], &self.frame_indices)?;
+            exporter.append("si_complete_return_indices", &[], &self.frame_return_indices)?;
 
             self.hyperspec_index.clear();
             self.hyperspec_return_index.clear();
             self.frame_indices.clear();
             self.frame_return_indices.clear();
-            }
             Ok(())
         }
         
         fn process_fourd(&mut self) -> Result<(), Tp3ErrorKind> {
-            if self.ensemble.try_clean(0, &self.remove_clusters) {
-                for val in self.ensemble.iter() {
-                    if let Some(index) = val.get_or_not_4d_index(self.tdc_periodic.as_ref(), self.spimx, self.spimy) {
-                        self.fourd_index.push(index);
-                        self.frame_indices.push((val.spim_slice()).try_into().expect("Exceeded the maximum number of indices"));
-                    }
-                    
-                    if let Some(index) = val.get_or_not_return_4d_index(self.tdc_periodic.as_ref(), self.spimx, self.spimy) {
-                        self.fourd_return_index.push(index);
-                        self.frame_return_indices.push((val.spim_slice()).try_into().expect("Exceeded the maximum number of indices"));
-                    }
+            for val in self.ensemble.iter() {
+                if let Some(index) = val.get_or_not_4d_index(self.tdc_periodic.as_ref(), self.spimx, self.spimy) {
+                    self.fourd_index.push(index);
+                    self.frame_indices.push((val.spim_slice()).try_into().expect("Exceeded the maximum number of indices"));
+                }
+                
+                if let Some(index) = val.get_or_not_return_4d_index(self.tdc_periodic.as_ref(), self.spimx, self.spimy) {
+                    self.fourd_return_index.push(index);
+                    self.frame_return_indices.push((val.spim_slice()).try_into().expect("Exceeded the maximum number of indices"));
+                }
             }
             self.ensemble.clear();
 
-            output_data(&self.fourd_index, self.file.clone(), "fourd_complete.txt");
-            output_data(&self.fourd_return_index, self.file.clone(), "fourd_return_complete.txt");
-            output_data(&self.frame_indices, self.file.clone(), "fourd_complete_indices.txt");
-            output_data(&self.frame_return_indices, self.file.clone(), "fourd_complete_return_indices.txt");
+            let exporter = self.exporter.as_mut().expect("The exporter is created with the output folder.");
+            exporter.append("fourd_complete", &[], &self.fourd_index)?;
+            exporter.append("fourd_return_complete", &[], &self.fourd_return_index)?;
+            exporter.append("fourd_complete_indices", &[], &self.frame_indices)?;
+            exporter.append("fourd_complete_return_indices", &[], &self.frame_return_indices)?;
 
             self.fourd_index.clear();
             self.fourd_return_index.clear();
             self.frame_indices.clear();
             self.frame_return_indices.clear();
-            }
             Ok(())
         }
         
@@ -648,13 +748,14 @@ pub mod ntime_resolved {
                 spimx: my_config.xspim,
                 spimy: my_config.yspim,
                 tdc_periodic: None,
-                spim_tdc_type: MAIN_TDC,
-                extra_tdc_type: SECONDARY_TDC,
+                spim_tdc_type: config().main_tdc,
+                extra_tdc_type: config().secondary_tdc,
                 remove_clusters: my_config.correction_type,
+                clusters: CentroidEngine::default(),
                 file: my_config.file,
-                fourd_data: my_settings.mode != 2,
+                fourd_data: my_settings.mode != AcquisitionMode::LiveSpim,
                 my_settings,
-                
+                exporter: None,
             })
         }
     }
@@ -664,44 +765,45 @@ pub mod ntime_resolved {
         data.try_create_folder()?;
         
         let mut prepare_file = fs::File::open(&data.file).expect("Could not open desired file.");
-        let progress_size = prepare_file.metadata().unwrap().len();
         data.prepare(&mut prepare_file)?;
         
-        let mut my_file = fs::File::open(&data.file).expect("Could not open desired file.");
-        let mut buffer: Vec<u8> = vec![0; 512_000_000];
-        
-        let mut ci = 0;
+        //The chunks are decoded in parallel. The periodic TDC is followed in the order of the file.
+        let file = MappedFile::open(&data.file)?;
+        let mut chunks = file.chunks(MMAP_CHUNK_SIZE, 0);
+        file.track_overflows(&mut chunks);
+        let progress_size = chunks.last().map_or(0, |chunk| chunk.end);
             
-        let bar = ProgressBar::new(progress_size);
+        let bar = ProgressBar::new(progress_size as u64);
         bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.white/black} {percent}% {pos:>7}/{len:7} [ETA: {eta}] Reconstructing hyperspectral image.")
                       .unwrap()
                       .progress_chars("=>-"));
 
-        while let Ok(size) = my_file.read(&mut buffer) {
-            if size==0 {break;}
-            bar.inc(512_000_000_u64);
-            buffer[0..size].chunks_exact(8).enumerate().for_each(|(current_raw_index, pack_oct)| {
-                match pack_oct {
-                    &[84, 80, 88, 51, nci, _, _, _] => {ci = nci},
-                    _ => {
-                        let packet = Packet::new(ci, packet_change(pack_oct)[0]);
-                        match packet.id() {
-                            6 if packet.tdc_type() == data.spim_tdc_type.associate_value() => {
-                                data.add_spim_tdc(packet);
-                            },
-                            6 if packet.tdc_type() == data.extra_tdc_type.associate_value() => {
-                                data.add_extra_tdc(packet);
-                            },
-                            11 => {
-                                data.add_electron(packet, current_raw_index);
-                            },
-                            _ => {},
-                        };
+        file.for_each_ordered(&chunks, |chunk, events| {
+            let mut timestamps = chunk.timestamps().unwrap_or_default();
+            events.map(|raw| timestamps.event(&raw.event).unwrap_or(0)).collect::<Vec<TIME>>()
+        }, |chunk, times| {
+            bar.inc(chunk.len() as u64);
+            let mut decoder = Decoder::with_offset(chunk.start as u64);
+            decoder.decode(&file.data()[chunk.start..chunk.end]).zip(times).enumerate().for_each(|(current_raw_index, (raw, time))| {
+                match raw.event {
+                    Event::Tdc(packet) if packet.tdc_type() == data.spim_tdc_type.associate_value() => {
+                        data.add_spim_tdc(packet);
                     },
-                }
+                    Event::Tdc(packet) if packet.tdc_type() == data.extra_tdc_type.associate_value() => {
+                        data.add_extra_tdc(packet);
+                    },
+                    Event::Pixel(packet) if packet.id() == 11 => {
+                        data.add_electron(packet, current_raw_index, time);
+                    },
+                    _ => {},
+                };
             });
-            data.process()?
-        };
+            data.process()
+        })?;
+        data.flush_clusters()?;
+        if let Some(exporter) = data.exporter.take() {
+            exporter.finish()?;
+        }
         println!("File has been succesfully read.");
         Ok(())
     }
@@ -710,13 +812,15 @@ pub mod ntime_resolved {
 pub mod calibration {
 
     use std::fs::OpenOptions;
-    use crate::packetlib::Packet;
-    use crate::auxiliar::misc::{as_bytes, packet_change};
-    use std::io;
+    use crate::auxiliar::misc::as_bytes;
     use std::io::prelude::*;
-    use std::fs;
     use std::convert::TryInto;
     use crate::clusterlib::cluster::{SingleElectron, CollectionElectron, ClusterCorrectionTypes};
+    use crate::clusterlib::cluster::CentroidEngine;
+    use crate::eventlib::{Column, ColumnData, EventWriter, Provenance};
+    use crate::errorlib::Tp3ErrorKind;
+    use crate::readerlib::{Event, MappedFile};
+    use crate::constlib::MMAP_CHUNK_SIZE;
     use indicatif::{ProgressBar, ProgressStyle};
     
     fn output_data<T>(data: &[T], name: &str) {
@@ -750,7 +854,7 @@ pub mod calibration {
                 let electron_tot_reference = electron.frame_dt() as i64;
                 let time_diference = (electron_time - electron_tot_reference) as i8;
                 self.rel_time.push(time_diference);
-                //self.cluster_size.push(electron.cluster_size());
+                self.cluster_size.push(electron.cluster_size());
             }
         }
         pub fn output_relative_calibration_time(&self) {
@@ -768,50 +872,64 @@ pub mod calibration {
         pub fn output_cluster_size(&self) {
             output_data(&self.cluster_size, "relative_calibration_cluster_size.txt");
         }
+        //The cluster size is not filled, so it is not part of the event file.
+        pub fn output_events(&self, source: &str) -> Result<(), Tp3ErrorKind> {
+            let name = "relative_calibration.tpev";
+            let columns = vec![
+                Column::new::<i8>("rel_time", "0.260 ns", "Electron time minus the time of the cluster reference, wrapped into i8."),
+                Column::new::<u16>("x", "pixel", "Dispersive position."),
+                Column::new::<u8>("y", "pixel", "Non-dispersive position."),
+                Column::new::<u16>("tot", "25 ns", "Time over threshold."),
+            ];
+            let mut events = EventWriter::create(name, columns, None, Provenance::from_source(source, "postlib::calibration"))?;
+            events.write_block(&[ColumnData::new(&self.rel_time), ColumnData::new(&self.x), ColumnData::new(&self.y), ColumnData::new(&self.tot)])?;
+            println!("Outputting events under {:?} name. Number of events is {}", name, events.rows());
+            events.finish()
+        }
     }
 
-    pub fn calibrate(file: &str, correction_type: &ClusterCorrectionTypes) -> io::Result<()> {
+    pub fn calibrate(path: &str, correction_type: &ClusterCorrectionTypes) -> Result<(), Tp3ErrorKind> {
 
-        let mut ci = 0;
-        let mut file = fs::File::open(file)?;
-        let progress_size = file.metadata().unwrap().len();
-        let mut buffer: Vec<u8> = vec![0; 512_000_000];
-        let mut total_size = 0;
+        //The file is mapped in memory and every chunk is decoded in parallel. The clusters are searched
+        //in the order of the file, with a single engine.
+        let file = MappedFile::open(path)?;
+        let chunks = file.chunks(MMAP_CHUNK_SIZE, 0);
+        let total_size = chunks.last().map_or(0, |chunk| chunk.end);
         
-        let bar = ProgressBar::new(progress_size);
+        let bar = ProgressBar::new(total_size as u64);
         bar.set_style(ProgressStyle::with_template("[{elapsed_precise}] {bar:40.white/black} {percent}% {pos:>7}/{len:7} [ETA: {eta}] Searching for clusters and calibrating data")
                       .unwrap()
                       .progress_chars("=>-"));
         
         let mut calibration_data = CalibrationData::new();
-        while let Ok(size) = file.read(&mut buffer) {
+        let mut clusters = CentroidEngine::default();
+        file.for_each_ordered(&chunks, |_, events| {
             let mut temp_electrons = CollectionElectron::new();
-            if size == 0 {println!("Finished Reading."); break;}
-            total_size += size;
-            //if total_size / 1_000_000_000 > 2 {break;}
-            bar.inc(512_000_000_u64);
-            buffer[0..size].chunks_exact(8).enumerate().for_each(|(current_raw_index, pack_oct)| {
-                match *pack_oct {
-                    [84, 80, 88, 51, nci, _, _, _] => {ci=nci;},
-                    _ => {
-                        let packet = Packet::new(ci, packet_change(pack_oct)[0]);
-                        if packet.id() == 11 {
-                            let se = SingleElectron::new(packet, None, current_raw_index, None);
-                            temp_electrons.add_electron(se);
-                            //temp_edata.electron.add_electron(se);
-                        }
-                    },
-                };
+            events.enumerate().for_each(|(current_raw_index, raw)| {
+                if let Event::Pixel(packet) = raw.event {
+                    if packet.id() == 11 {
+                        let se = SingleElectron::new(packet, None, current_raw_index, None);
+                        temp_electrons.add_electron(se);
+                        //temp_edata.electron.add_electron(se);
+                    }
+                }
             });
             temp_electrons.sort();
-            temp_electrons.try_clean(0, correction_type);
+            temp_electrons
+        }, |chunk, mut temp_electrons| {
+            bar.inc(chunk.len() as u64);
+            temp_electrons.try_clean(0, correction_type, &mut clusters);
             calibration_data.append_from_collection(temp_electrons);
-        }
+            Ok(())
+        })?;
+        calibration_data.append_from_collection(clusters.flush());
         calibration_data.output_relative_calibration_time();
         calibration_data.output_x();
         calibration_data.output_y();
         calibration_data.output_tot();
         calibration_data.output_cluster_size();
+        println!("Finished Reading.");
+        calibration_data.output_events(path)?;
         println!("Total number of bytes read {}", total_size);
         Ok(())
     }
@@ -0,0 +1,356 @@
+//!`readerlib` is a collection of tools to decode a stream of TP3 packets.
+//!
+//!`Decoder` turns 8-byte words into typed `Event`s, keeping track of the chip index given by the
+//!chip headers. It keeps its state between buffers, so it can be used on the buffers of a live
+//!acquisition. `PacketReader` reads the packets from any `Read` source (files or sockets), keeping
+//!the packets split between two reads, and gives the events one by one or buffer by buffer.
+//!
+//!`MappedFile` maps a recorded file in memory and splits it in chunks starting at chip headers,
+//!so the chunks can be decoded in parallel. The results are given back in the order of the file.
+use crate::auxiliar::{misc::packet_change, value_types::*};
+use crate::constlib::TP3_BUFFER_SIZE;
+use crate::errorlib::{Tp3ErrorKind, PathContext};
+use crate::packetlib::Packet;
+use crate::geometrylib::geometry;
+use crate::timelib::ChipTimestamps;
+use memmap2::Mmap;
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::Path;
+use std::slice::ChunksExact;
+
+///A decoded packet.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Event {
+    ChipHeader(u8), //The chip index of the packets that follow.
+    Pixel(Packet), //Data-driven pixel hit. Modified packets (ids 12 to 15) carry their chip index.
+    FramePixel(Packet), //Frame-based pixel hit.
+    Tdc(Packet),
+    Shutter(Packet),
+    GlobalTime(Packet),
+    Control(Packet),
+    Unknown(Packet),
+}
+
+impl Event {
+    fn decode(chip_index: &mut u8, word: &[u8]) -> Self {
+        match *word {
+            [84, 80, 88, 51, nci, _, _, _] => {
+                *chip_index = nci;
+                Event::ChipHeader(nci)
+            },
+            _ => {
+                let packet = Packet::new(*chip_index, packet_change(word)[0]);
+                match packet.id() {
+                    10 | 11 if !geometry().has_chip(packet.ci()) => Event::Unknown(packet), //Not in the layout
+                    11 => Event::Pixel(packet),
+                    10 => Event::FramePixel(packet),
+                    id @ 12..=15 if !geometry().has_chip(id - 12) => Event::Unknown(packet),
+                    id @ 12..=15 => Event::Pixel(Packet::new(id - 12, packet.data())),
+                    6 => Event::Tdc(packet),
+                    5 if packet.tdc_type() == 10 || packet.tdc_type() == 15 => Event::Shutter(packet),
+                    4 => Event::GlobalTime(packet),
+                    5 | 7 => Event::Control(packet),
+                    _ => Event::Unknown(packet),
+                }
+            },
+        }
+    }
+
+    ///The packet, unless it is a chip header.
+    pub fn packet(&self) -> Option<&Packet> {
+        match self {
+            Event::ChipHeader(_) => None,
+            Event::Pixel(packet) | Event::FramePixel(packet) | Event::Tdc(packet) | Event::Shutter(packet) |
+            Event::GlobalTime(packet) | Event::Control(packet) | Event::Unknown(packet) => Some(packet),
+        }
+    }
+}
+
+///An event and the position of its packet in the stream, in bytes.
+#[derive(Copy, Clone)]
+pub struct RawEvent {
+    pub offset: u64,
+    pub event: Event,
+    pub time: Option<TIME>, //Extended time in TDC units, given by `StreamDecoder`.
+}
+
+///Decodes packets. The chip index and the offset are kept between buffers.
+#[derive(Default)]
+pub struct Decoder {
+    chip_index: u8,
+    offset: u64,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    ///A decoder for packets starting at `offset` bytes in the stream.
+    pub fn with_offset(offset: u64) -> Self {
+        Decoder {chip_index: 0, offset}
+    }
+
+    pub fn chip_index(&self) -> u8 {
+        self.chip_index
+    }
+
+    ///Bytes decoded so far.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    ///Decodes a buffer of whole packets. There is one event per packet, so the position of an
+    ///event in the buffer is given by `enumerate`.
+    pub fn decode<'a>(&'a mut self, data: &'a [u8]) -> Decode<'a> {
+        Decode {data, chunks: data.chunks_exact(8), decoder: self}
+    }
+}
+
+///Iterator over the events of a buffer.
+pub struct Decode<'a> {
+    data: &'a [u8],
+    chunks: ChunksExact<'a, u8>,
+    decoder: &'a mut Decoder,
+}
+
+impl<'a> Decode<'a> {
+    ///The buffer being decoded.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+}
+
+impl Iterator for Decode<'_> {
+    type Item = RawEvent;
+
+    #[inline]
+    fn next(&mut self) -> Option<RawEvent> {
+        let word = self.chunks.next()?;
+        let offset = self.decoder.offset;
+        self.decoder.offset += 8;
+        Some(RawEvent {offset, event: Event::decode(&mut self.decoder.chip_index, word), time: None})
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+}
+
+///Decodes a live stream and follows its timestamps, so the global time and the SPIDR packet ids
+///are monitored while the data is processed.
+#[derive(Default)]
+pub struct StreamDecoder {
+    decoder: Decoder,
+    timestamps: ChipTimestamps,
+}
+
+impl StreamDecoder {
+    pub fn new() -> Self {
+        StreamDecoder::default()
+    }
+
+    pub fn timestamps(&self) -> &ChipTimestamps {
+        &self.timestamps
+    }
+
+    ///Decodes a buffer of whole packets, following the timestamps of every event. The events
+    ///carry their extended time.
+    pub fn decode<'a>(&'a mut self, data: &'a [u8]) -> impl Iterator<Item = RawEvent> + 'a {
+        let timestamps = &mut self.timestamps;
+        self.decoder.decode(data).map(move |mut raw| {
+            raw.time = timestamps.event(&raw.event);
+            raw
+        })
+    }
+}
+
+///Reads and decodes packets from a file or a socket.
+pub struct PacketReader<R> {
+    source: R,
+    decoder: Decoder,
+    buffer: Vec<u8>,
+    start: usize, //First byte not given yet.
+    end: usize, //End of the bytes read.
+    finished: bool,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(source: R) -> Self {
+        PacketReader::with_capacity(source, TP3_BUFFER_SIZE)
+    }
+
+    ///`capacity` is the maximum size of a buffer. It is rounded up to whole packets.
+    pub fn with_capacity(source: R, capacity: usize) -> Self {
+        let capacity = capacity.max(8).div_ceil(8) * 8;
+        PacketReader {source, decoder: Decoder::new(), buffer: vec![0; capacity], start: 0, end: 0, finished: false}
+    }
+
+    pub fn decoder(&self) -> &Decoder {
+        &self.decoder
+    }
+
+    pub fn into_inner(self) -> R {
+        self.source
+    }
+
+    //Reads until there is at least one whole packet, or the source is over.
+    fn fill(&mut self) -> Result<(), Tp3ErrorKind> {
+        self.buffer.copy_within(self.start..self.end, 0);
+        self.end -= self.start;
+        self.start = 0;
+        while self.end < 8 {
+            match self.source.read(&mut self.buffer[self.end..]) {
+                Ok(0) => {
+                    self.finished = true;
+                    if self.end > 0 {
+                        return Err(Tp3ErrorKind::ReaderTruncatedPacket {offset: self.decoder.offset, size: self.end});
+                    }
+                    return Ok(());
+                },
+                Ok(size) => self.end += size,
+                Err(error) if error.kind() == ErrorKind::Interrupted => {},
+                Err(error) => return Err(error.into()),
+            }
+        }
+        Ok(())
+    }
+
+    ///Reads the next buffer of whole packets, which can be decoded with the returned iterator.
+    ///Returns `None` when the source is over. Partial packets are kept for the next read.
+    pub fn next_buffer(&mut self) -> Result<Option<Decode<'_>>, Tp3ErrorKind> {
+        if self.end - self.start < 8 {
+            if self.finished {return Ok(None);}
+            self.fill()?;
+            if self.end == 0 {return Ok(None);}
+        }
+        let whole = (self.end - self.start) / 8 * 8;
+        let data = &self.buffer[self.start..self.start + whole];
+        self.start += whole;
+        Ok(Some(self.decoder.decode(data)))
+    }
+}
+
+impl<R: Read> Iterator for PacketReader<R> {
+    type Item = Result<RawEvent, Tp3ErrorKind>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.end - self.start < 8 {
+            if self.finished {return None;}
+            if let Err(error) = self.fill() {return Some(Err(error));}
+            if self.end < 8 {return None;}
+        }
+        let word = &self.buffer[self.start..self.start + 8];
+        self.start += 8;
+        let offset = self.decoder.offset;
+        self.decoder.offset += 8;
+        Some(Ok(RawEvent {offset, event: Event::decode(&mut self.decoder.chip_index, word), time: None}))
+    }
+}
+
+///A part of a mapped file. Every chunk but the first starts at a chip header.
+#[derive(Clone, Debug)]
+pub struct Chunk {
+    pub index: usize,
+    pub start: usize,
+    pub end: usize,
+    timestamps: Option<ChipTimestamps>,
+}
+
+impl Chunk {
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+
+    ///The clocks at the start of the chunk, if the overflows were tracked with
+    ///`MappedFile::track_overflows`. They give the same times as decoding the file from the start.
+    pub fn timestamps(&self) -> Option<ChipTimestamps> {
+        self.timestamps.clone()
+    }
+}
+
+///A recorded file mapped in memory.
+pub struct MappedFile {
+    map: Mmap,
+}
+
+impl MappedFile {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Tp3ErrorKind> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|source| Tp3ErrorKind::SetNoReadFile {path: path.display().to_string(), source})?;
+        //The file must not be truncated while mapped. Recorded files are only appended to.
+        let map = unsafe { Mmap::map(&file) }.with_path(path)?;
+        Ok(MappedFile {map})
+    }
+
+    ///The whole packets of the file.
+    pub fn data(&self) -> &[u8] {
+        &self.map[..self.map.len() / 8 * 8]
+    }
+
+    ///Splits the first `limit` bytes (all if 0) in chunks of about `chunk_size` bytes. A chunk
+    ///ends at the first chip header after its nominal size, so the chip index of every chunk is
+    ///known. If there is no chip header, the chunk goes on.
+    pub fn chunks(&self, chunk_size: usize, limit: usize) -> Vec<Chunk> {
+        let data = self.data();
+        let end = if limit == 0 {data.len()} else {limit.min(data.len()) / 8 * 8};
+        let chunk_size = chunk_size.max(8) / 8 * 8;
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        while start < end {
+            let nominal = (start + chunk_size).min(end);
+            let next = data[nominal..end].chunks_exact(8)
+                .position(|word| word[0..4] == [84, 80, 88, 51])
+                .map_or(end, |position| nominal + position * 8);
+            chunks.push(Chunk {index: chunks.len(), start, end: next, timestamps: None});
+            start = next;
+        }
+        chunks
+    }
+
+    ///Decodes the times of every chunk in parallel to find where the timestamps wrap around, and
+    ///sets the clocks at the start of each chunk.
+    pub fn track_overflows(&self, chunks: &mut [Chunk]) {
+        let data = self.data();
+        let local: Vec<ChipTimestamps> = chunks.par_iter().map(|chunk| {
+            let mut timestamps = ChipTimestamps::new();
+            for raw in Decoder::new().decode(&data[chunk.start..chunk.end]) {
+                timestamps.event(&raw.event);
+            }
+            timestamps
+        }).collect();
+
+        let mut global = ChipTimestamps::new();
+        for (chunk, local) in chunks.iter_mut().zip(local) {
+            let next = global.chain(&local);
+            chunk.timestamps = Some(global);
+            global = next;
+        }
+    }
+
+    ///Decodes the chunks in parallel with `f` and gives the results to `sink` in the order of the
+    ///file. Chunks are decoded in groups, so only a few results are in memory at a time.
+    pub fn for_each_ordered<T, F, S>(&self, chunks: &[Chunk], f: F, mut sink: S) -> Result<(), Tp3ErrorKind>
+        where T: Send,
+              F: Fn(&Chunk, Decode) -> T + Sync,
+              S: FnMut(&Chunk, T) -> Result<(), Tp3ErrorKind> {
+        let data = self.data();
+        let group = rayon::current_num_threads() * 2;
+        for group in chunks.chunks(group) {
+            let results: Vec<T> = group.par_iter().map(|chunk| {
+                let mut decoder = Decoder::with_offset(chunk.start as u64);
+                f(chunk, decoder.decode(&data[chunk.start..chunk.end]))
+            }).collect();
+            for (chunk, result) in group.iter().zip(results) {
+                sink(chunk, result)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+//!`replaylib` is a collection of tools to replay a recorded `.tpx3` file through the live modes.
+//!The file is read as if it was the detector socket and the measurement runs in
+//!`modelib::run_measurement`, so the same `SpecKind`/`SpimKind` pipeline used live is exercised.
+//!
+//!The settings are read from the JSON sidecar saved next to the raw data. The replay can be paced
+//!by the packet timestamps to reproduce the live timing, and the output is written to a file, to a
+//!socket (such as a Nionswift listening for it) or discarded.
+use crate::auxiliar::{DebugIO, FileManager, Settings, misc::{TimepixRead, default_read_exact}};
+use crate::auxiliar::value_types::*;
+use crate::controllib::ControlSocket;
+use crate::errorlib::{Tp3ErrorKind, PathContext};
+use crate::modelib;
+use crate::readerlib::Decoder;
+use crate::timelib::ChipTimestamps;
+use crate::simlib::TDC_UNITS_PER_SECOND;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+///A recorded `.tpx3` file used as the packet source.
+pub struct ReplaySource {
+    file: File,
+    pace: Option<Pace>,
+}
+
+///Paces the replay by the timestamps of the packets. Timestamps wrap around, so they are extended
+///before being compared to the clock.
+struct Pace {
+    decoder: Decoder,
+    speed: f64,
+    start: Option<(Instant, TIME)>,
+    timestamps: ChipTimestamps,
+}
+
+impl Pace {
+    fn wait(&mut self, data: &[u8]) {
+        for raw in self.decoder.decode(data) {
+            if let Some(time) = self.timestamps.event(&raw.event) {
+                if self.start.is_none() {
+                    self.start = Some((Instant::now(), time));
+                }
+            }
+        }
+        if let Some((start, first_time)) = self.start {
+            let stream_time = (self.timestamps.latest() - first_time) as f64 / TDC_UNITS_PER_SECOND / self.speed;
+            let target = start + Duration::from_secs_f64(stream_time);
+            let now = Instant::now();
+            if target > now {
+                std::thread::sleep(target - now);
+            }
+        }
+    }
+}
+
+impl ReplaySource {
+    ///Opens a recorded file. `speed` paces the replay relative to the recording (1.0 is real time).
+    ///Without `speed`, the file is read as fast as possible.
+    pub fn open<P: AsRef<Path>>(path: P, speed: Option<f64>) -> Result<Self, Tp3ErrorKind> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(|source| Tp3ErrorKind::SetNoReadFile {path: path.display().to_string(), source})?;
+        let pace = speed.map(|speed| Pace {decoder: Decoder::new(), speed, start: None, timestamps: ChipTimestamps::new()});
+        Ok(ReplaySource {file, pace})
+    }
+}
+
+impl Read for ReplaySource {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl TimepixRead for ReplaySource {
+    fn read_timepix(&mut self, buf: &mut [u8]) -> Result<usize, Tp3ErrorKind> {
+        let size = default_read_exact(&mut self.file, buf)?;
+        if let Some(pace) = &mut self.pace {
+            pace.wait(&buf[..size]);
+        }
+        Ok(size)
+    }
+}
+
+///Where the replay output goes. It takes the place of the Nionswift socket.
+pub struct ReplaySink {
+    output: Box<dyn Write + Send>,
+    socket: Option<TcpStream>,
+    scan_list: Option<File>,
+}
+
+impl ReplaySink {
+    ///The output is discarded.
+    pub fn discard() -> Self {
+        ReplaySink {output: Box::new(DebugIO {}), socket: None, scan_list: None}
+    }
+
+    ///The output is written to a file, exactly as it would be sent to Nionswift.
+    pub fn to_file<P: AsRef<Path>>(path: P) -> Result<Self, Tp3ErrorKind> {
+        let file = File::create(path.as_ref()).with_path(path)?;
+        Ok(ReplaySink {output: Box::new(file), socket: None, scan_list: None})
+    }
+
+    ///The output is sent to a listening client. Control messages (`stop`, `abort`) are read from
+    ///the same socket.
+    pub fn connect(address: &str) -> Result<Self, Tp3ErrorKind> {
+        let sock = TcpStream::connect(address).map_err(|source| Tp3ErrorKind::SetNoSocket {address: address.to_owned(), source})?;
+        let output = sock.try_clone().map_err(|source| Tp3ErrorKind::SetNoSocket {address: address.to_owned(), source})?;
+        Ok(ReplaySink {output: Box::new(output), socket: Some(sock), scan_list: None})
+    }
+
+    ///The scan list (mode 14) is read from a file instead of the client.
+    pub fn with_scan_list<P: AsRef<Path>>(mut self, path: P) -> Result<Self, Tp3ErrorKind> {
+        self.scan_list = Some(File::open(path.as_ref()).with_path(path)?);
+        Ok(self)
+    }
+}
+
+impl Write for ReplaySink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl Read for ReplaySink {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match (&mut self.scan_list, &mut self.socket) {
+            (Some(file), _) => file.read(buf),
+            (None, Some(sock)) => sock.read(buf),
+            (None, None) => Ok(0),
+        }
+    }
+}
+
+impl ControlSocket for ReplaySink {
+    fn control_reader(&self) -> Option<Box<dyn Read + Send>> {
+        self.socket.as_ref()?.control_reader()
+    }
+}
+
+///Reads the settings from the JSON sidecar of a recorded `.tpx3` file.
+pub fn settings_from_sidecar<P: AsRef<Path>>(path: P) -> Result<Settings, Tp3ErrorKind> {
+    let prefix = path.as_ref().with_extension("");
+    Settings::get_settings_from_json(&prefix.to_string_lossy())
+}
+
+///Replays a recorded file through the live mode in `my_settings.mode`. Raw data is never saved
+///again, regardless of the settings. Returns the mode number when the replay is over.
+pub fn replay(my_settings: Settings, source: ReplaySource, sink: ReplaySink) -> Result<u8, Tp3ErrorKind> {
+    println!("***Replaylib***: Replaying mode {:?}.", my_settings.mode);
+    modelib::run_measurement(my_settings, Box::new(source), sink, FileManager::new_empty(), None)
+}
@@ -0,0 +1,383 @@
+//!`simlib` is a collection of tools to generate synthetic TP3 raw streams. Streams are framed in
+//!chip chunks exactly like the detector output, so they can be fed to `speclib`, `spimlib` and
+//!`postlib` without the detector. All times are in units of 0.260 ns (TDC units), and rates are
+//!in events per second.
+use crate::auxiliar::{misc::{TimepixRead, as_bytes}, value_types::*};
+use crate::constlib::*;
+use crate::errorlib::Tp3ErrorKind;
+use crate::packetlib::Packet;
+use crate::geometrylib::geometry;
+use crate::tdclib::TdcType;
+use rand::{rngs::StdRng, Rng, SeedableRng, seq::SliceRandom};
+use rand_distr::{Distribution, Normal, Poisson};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+///Number of TDC units (0.260 ns) in a second.
+pub const TDC_UNITS_PER_SECOND: f64 = 6.0 / 1.5625e-9;
+///Maximum number of packets in a single chip chunk.
+const MAX_PACKETS_PER_CHUNK: usize = 8191;
+
+///A gaussian peak in the dispersive direction.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Peak {
+    pub center: f64,
+    pub sigma: f64,
+    pub weight: f64,
+}
+
+///The distribution of the electrons in the dispersive (x) direction.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum SpectrumShape {
+    Uniform,
+    Peaks(Vec<Peak>),
+}
+
+///A periodic signal in a TDC input line, such as the line or the frame signal of a scan. Both
+///rising and falling edges are emitted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PeriodicSignal {
+    pub tdc_type: TdcType, //The TDC line is taken from the type. Rising or falling is not important.
+    pub period: TIME,
+    pub high_time: TIME,
+    pub offset: TIME, //Time of the first rising edge after the start of the stream.
+}
+
+///Random photons in a TDC input line. A fraction of the electrons has a correlated photon
+///arriving `delay` after them, with a gaussian `jitter`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PhotonSignal {
+    pub tdc_type: TdcType,
+    pub rate: f64,
+    pub coincidence_fraction: f64,
+    pub delay: TIME,
+    pub jitter: f64,
+}
+
+///Shutter used in the frame-based modes. Every chip sends the open and closed shutter packets.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ShutterSignal {
+    pub period: TIME,
+    pub open_time: TIME,
+}
+
+///Parameters of the synthetic stream.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimSettings {
+    pub seed: u64,
+    pub start_time: TIME, //Absolute time of the first event. Use it to cross the timestamp overflows.
+    pub duration: TIME,
+    pub slice_time: TIME, //Events are generated and framed in chunks of this time interval.
+    pub electron_rate: f64,
+    pub spectrum: SpectrumShape,
+    pub y_range: (POSITION, POSITION),
+    pub tot_range: (u16, u16),
+    pub satellites: POSITION, //Extra hits of every electron, in the next pixels along x, with less ToT and a bit later.
+    pub periodic: Vec<PeriodicSignal>,
+    pub photons: Option<PhotonSignal>,
+    pub shutter: Option<ShutterSignal>,
+    pub tdc_chip: u8, //Chip in which the TDC packets are sent.
+    pub interleave_chips: bool, //Randomize the chip order inside each slice.
+    pub global_time_period: Option<TIME>, //Every chip sends its global time and a SPIDR packet id.
+}
+
+impl Default for SimSettings {
+    fn default() -> Self {
+        SimSettings {
+            seed: 0,
+            start_time: 0,
+            duration: TDC_UNITS_PER_SECOND as TIME,
+            slice_time: 384_000, //100 us
+            electron_rate: 1_000_000.0,
+            spectrum: SpectrumShape::Peaks(vec![
+                Peak {center: 200.0, sigma: 3.0, weight: 1.0},
+                Peak {center: 450.0, sigma: 40.0, weight: 0.2},
+            ]),
+            y_range: (0, 256),
+            tot_range: (20, 100),
+            satellites: 0,
+            periodic: vec![PeriodicSignal {
+                tdc_type: TdcType::TdcOneRisingEdge,
+                period: 384_000,
+                high_time: 76_800,
+                offset: 1_000,
+            }],
+            photons: None,
+            shutter: None,
+            tdc_chip: 0,
+            interleave_chips: true,
+            global_time_period: None,
+        }
+    }
+}
+
+///Ground truth of the generated stream.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SimSummary {
+    pub electrons: u64,
+    pub periodic_edges: Vec<u64>,
+    pub photons: u64,
+    pub coincidences: u64,
+    pub shutters: u64,
+    pub global_times: u64, //Per chip
+    pub bytes: u64,
+}
+
+//Both edges of the same TDC input line.
+fn tdc_edges(tdc_type: TdcType) -> (TdcType, TdcType) {
+    match tdc_type {
+        TdcType::TdcOneRisingEdge | TdcType::TdcOneFallingEdge => (TdcType::TdcOneRisingEdge, TdcType::TdcOneFallingEdge),
+        TdcType::TdcTwoRisingEdge | TdcType::TdcTwoFallingEdge => (TdcType::TdcTwoRisingEdge, TdcType::TdcTwoFallingEdge),
+        TdcType::NoTdc => unreachable!("***Sim Lib***: The input lines are checked in Simulator::new."),
+    }
+}
+
+//Counters are shared between the edges of the same input line.
+fn tdc_line(tdc_type: TdcType) -> usize {
+    match tdc_edges(tdc_type).0 {
+        TdcType::TdcOneRisingEdge => 0,
+        _ => 1,
+    }
+}
+
+///Generates a synthetic stream slice by slice. It can be read as a `TimepixRead` socket.
+pub struct Simulator {
+    settings: SimSettings,
+    rng: StdRng,
+    time: TIME,
+    next_edge: Vec<(TIME, bool)>,
+    next_shutter: Option<(TIME, bool)>,
+    next_global_time: TIME,
+    tdc_counter: [u16; 2],
+    buffer: Vec<u8>,
+    position: usize,
+    summary: SimSummary,
+}
+
+impl Simulator {
+    ///Creates the simulator. Every periodic and photon signal must have a TDC input line.
+    pub fn new(settings: SimSettings) -> Result<Self, Tp3ErrorKind> {
+        let mut tdc_types = settings.periodic.iter().map(|signal| signal.tdc_type)
+            .chain(settings.photons.iter().map(|photons| photons.tdc_type));
+        if tdc_types.any(|tdc_type| tdc_type == TdcType::NoTdc) {
+            return Err(Tp3ErrorKind::SimNoTdcLine);
+        }
+        let rng = StdRng::seed_from_u64(settings.seed);
+        let next_edge = settings.periodic.iter().map(|signal| (signal.offset, true)).collect();
+        let next_shutter = settings.shutter.as_ref().map(|_| (0, false));
+        let summary = SimSummary {
+            periodic_edges: vec![0; settings.periodic.len()],
+            ..Default::default()
+        };
+        Ok(Simulator {
+            settings,
+            rng,
+            time: 0,
+            next_edge,
+            next_shutter,
+            next_global_time: 0,
+            tdc_counter: [0; 2],
+            buffer: Vec::new(),
+            position: 0,
+            summary,
+        })
+    }
+
+    pub fn settings(&self) -> &SimSettings {
+        &self.settings
+    }
+
+    pub fn summary(&self) -> &SimSummary {
+        &self.summary
+    }
+
+    fn electron_x(&mut self) -> POSITION {
+        let max_x = (Packet::chip_array().0 - 1) as f64;
+        let x = match &self.settings.spectrum {
+            SpectrumShape::Uniform => self.rng.gen_range(0.0..=max_x),
+            SpectrumShape::Peaks(peaks) => {
+                let total: f64 = peaks.iter().map(|peak| peak.weight).sum();
+                let mut choice = self.rng.gen_range(0.0..total);
+                let peak = peaks.iter().find(|peak| {
+                    choice -= peak.weight;
+                    choice < 0.0
+                }).unwrap_or(&peaks[peaks.len() - 1]);
+                let normal = Normal::new(peak.center, peak.sigma).expect("***Sim Lib***: Invalid peak width.");
+                normal.sample(&mut self.rng)
+            },
+        };
+        x.round().clamp(0.0, max_x) as POSITION
+    }
+
+    fn poisson(&mut self, rate: f64, interval: TIME) -> u64 {
+        let mean = rate * interval as f64 / TDC_UNITS_PER_SECOND;
+        if mean <= 0.0 {
+            return 0;
+        }
+        Poisson::new(mean).expect("***Sim Lib***: Invalid rate.").sample(&mut self.rng) as u64
+    }
+
+    fn tdc_packet(&mut self, tdc_type: TdcType, time: TIME) -> Packet {
+        let line = tdc_line(tdc_type);
+        self.tdc_counter[line] = (self.tdc_counter[line] + 1) & 0xF_FF;
+        let packet = Packet::new_inverse_tdc(tdc_type, self.tdc_counter[line], self.settings.start_time + time);
+        Packet::new(self.settings.tdc_chip, packet.data())
+    }
+
+    ///Creates the events of the next slice, already framed in chip chunks. Returns `None` when
+    ///the duration is reached.
+    pub fn next_slice(&mut self) -> Option<Vec<u8>> {
+        if self.time >= self.settings.duration {
+            return None;
+        }
+        let begin = self.time;
+        let end = (begin + self.settings.slice_time.max(1)).min(self.settings.duration);
+        //The photons are `None` until their counter is known.
+        let mut events: Vec<(TIME, Option<Packet>)> = Vec::new();
+
+        //Electrons and their correlated photons
+        let number_of_electrons = self.poisson(self.settings.electron_rate, end - begin);
+        for _ in 0..number_of_electrons {
+            let time = self.rng.gen_range(begin..end);
+            let x = self.electron_x();
+            let y = self.rng.gen_range(self.settings.y_range.0..self.settings.y_range.1);
+            let tot = self.rng.gen_range(self.settings.tot_range.0..=self.settings.tot_range.1);
+            if !geometry().is_pixel(x, y) {continue;} //Gaps and the TDC column
+            events.push((time, Some(Packet::new_inverse_electron(x, y, (self.settings.start_time + time) / 6, tot))));
+            self.summary.electrons += 1;
+            for satellite in 1..=self.settings.satellites {
+                if !geometry().is_pixel(x + satellite, y) {break;}
+                let satellite_time = time + 6 * satellite as TIME;
+                events.push((satellite_time, Some(Packet::new_inverse_electron(x + satellite, y, (self.settings.start_time + satellite_time) / 6, tot / (satellite as u16 + 1)))));
+            }
+
+            if let Some(photons) = &self.settings.photons {
+                if self.rng.gen_bool(photons.coincidence_fraction.clamp(0.0, 1.0)) {
+                    let jitter = Normal::new(0.0, photons.jitter.max(f64::MIN_POSITIVE)).expect("***Sim Lib***: Invalid jitter.").sample(&mut self.rng);
+                    let photon_time = (time as f64 + photons.delay as f64 + jitter).max(0.0) as TIME;
+                    events.push((photon_time, None));
+                    self.summary.coincidences += 1;
+                }
+            }
+        }
+
+        //Uncorrelated photons
+        if let Some(photons) = self.settings.photons.clone() {
+            let number_of_photons = self.poisson(photons.rate, end - begin);
+            for _ in 0..number_of_photons {
+                let time = self.rng.gen_range(begin..end);
+                events.push((time, None));
+            }
+            //Photons are only created once their times are known, so the counter is ascending.
+            events.sort_by_key(|(time, _)| *time);
+            for (time, packet) in events.iter_mut().filter(|(_, packet)| packet.is_none()) {
+                *packet = Some(self.tdc_packet(photons.tdc_type, *time));
+                self.summary.photons += 1;
+            }
+        }
+
+        //Periodic signals
+        for index in 0..self.settings.periodic.len() {
+            let signal = self.settings.periodic[index].clone();
+            let (rising, falling) = tdc_edges(signal.tdc_type);
+            while self.next_edge[index].0 < end {
+                let (time, is_rising) = self.next_edge[index];
+                let packet = self.tdc_packet(if is_rising {rising} else {falling}, time);
+                events.push((time, Some(packet)));
+                self.summary.periodic_edges[index] += 1;
+                self.next_edge[index] = if is_rising {
+                    (time + signal.high_time, false)
+                } else {
+                    (time + signal.period - signal.high_time, true)
+                };
+            }
+        }
+
+        //Shutter
+        if let Some(shutter) = self.settings.shutter.clone() {
+            while let Some((time, closed)) = self.next_shutter.filter(|(time, _)| *time < end) {
+                let frame_time = (self.settings.start_time + time) / 96; //In units of 25 ns
+                for ci in 0..4 {
+                    events.push((time, Some(Packet::new_inverse_shutter(ci, closed, frame_time))));
+                }
+                self.summary.shutters += 1;
+                self.next_shutter = Some(if closed {
+                    (time + shutter.period - shutter.open_time, false)
+                } else {
+                    (time + shutter.open_time, true)
+                });
+            }
+        }
+
+        //Global time and packet ids
+        if let Some(period) = self.settings.global_time_period {
+            while self.next_global_time < end {
+                let time = self.next_global_time;
+                let global_time = (self.settings.start_time + time) / GLOBAL_TIME_IN_TDC_UNITS;
+                for ci in 0..4 {
+                    let [low, high] = Packet::new_inverse_global_time(ci, global_time);
+                    events.push((time, Some(low)));
+                    events.push((time, Some(high)));
+                    events.push((time, Some(Packet::new_inverse_packet_id(ci, self.summary.global_times))));
+                }
+                self.summary.global_times += 1;
+                self.next_global_time += period.max(1);
+            }
+        }
+
+        events.sort_by_key(|(time, _)| *time);
+        let mut chips: Vec<u8> = (0..4).collect();
+        if self.settings.interleave_chips {
+            chips.shuffle(&mut self.rng);
+        }
+
+        let mut output: Vec<u8> = Vec::new();
+        for ci in chips {
+            let data: Vec<u64> = events.iter()
+                .filter_map(|(_, packet)| packet.filter(|packet| packet.ci() == ci))
+                .map(|packet| packet.data())
+                .collect();
+            for chunk in data.chunks(MAX_PACKETS_PER_CHUNK) {
+                let size = (chunk.len() * 8) as u16;
+                output.extend_from_slice(&[84, 80, 88, 51, ci, 0, (size & 255) as u8, (size >> 8) as u8]);
+                output.extend_from_slice(as_bytes(chunk));
+            }
+        }
+
+        self.time = end;
+        self.summary.bytes += output.len() as u64;
+        Some(output)
+    }
+
+    ///Writes the full stream and returns the number of bytes written.
+    pub fn write_all<W: Write>(&mut self, writer: &mut W) -> std::io::Result<u64> {
+        let mut size = 0;
+        while let Some(slice) = self.next_slice() {
+            writer.write_all(&slice)?;
+            size += slice.len() as u64;
+        }
+        Ok(size)
+    }
+}
+
+impl Read for Simulator {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.next_slice() {
+                Some(slice) => {
+                    self.buffer = slice;
+                    self.position = 0;
+                },
+                None => return Ok(0),
+            }
+        }
+        //Packets can be split between reads. `TimepixRead` joins them again.
+        let size = (self.buffer.len() - self.position).min(buf.len());
+        buf[..size].copy_from_slice(&self.buffer[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+impl TimepixRead for Simulator {}
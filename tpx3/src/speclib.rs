@@ -1,17 +1,29 @@
 //!`speclib` is a collection of tools to set EELS/4D acquisition.
 
 use crate::packetlib::Packet;
-use crate::auxiliar::{Settings, misc::{TimepixRead, as_bytes, packet_change}};
+use crate::auxiliar::{Settings, misc::{TimepixRead, as_bytes}};
+use crate::pipelinelib::{self, Source, Pipeline, SpecAccumulator, Sink, Clustering, Output, Tdcs};
+use crate::readerlib::RawEvent;
 use crate::tdclib::TdcRef;
 use crate::errorlib::Tp3ErrorKind;
 use std::time::Instant;
-use std::io::Write;
 use crate::auxiliar::{value_types::*, FileManager, misc};
 use crate::constlib::*;
+use crate::configlib::config;
 use crate::ttx;
+use crate::controllib::{Control, StopReason};
+use crate::metricslib::metrics;
+use crate::timelib::TDC_UNITS_PER_US;
+use crate::geometrylib::EdgePixels;
+use crate::walklib;
+use crate::clusterlib::engine;
 use rayon::prelude::*;
 
-const CAM_DESIGN: (POSITION, POSITION) = Packet::chip_array();
+//Width and height of the frames.
+#[inline]
+fn cam_design() -> (POSITION, POSITION) {
+    Packet::chip_array()
+}
 
 #[derive(Default)]
 pub struct ShutterControl {
@@ -63,17 +75,17 @@ impl ShutterControl {
     fn get_index_range_to_send(&self) -> std::ops::Range<usize> {
         let (start_pixel, end_pixel) = self.hyperspec_pixels_to_send;
         if !self.is_2d {
-            (start_pixel * CAM_DESIGN.0) as usize..(end_pixel * CAM_DESIGN.0) as usize
+            (start_pixel * cam_design().0) as usize..(end_pixel * cam_design().0) as usize
         } else {
-            (start_pixel * CAM_DESIGN.0 * CAM_DESIGN.1) as usize..(end_pixel * CAM_DESIGN.0 * CAM_DESIGN.1) as usize
+            (start_pixel * cam_design().0 * cam_design().1) as usize..(end_pixel * cam_design().0 * cam_design().1) as usize
         }
     }
     fn get_data_size_to_send(&self) -> usize {
         let (start_pixel, end_pixel) = self.hyperspec_pixels_to_send;
         if !self.is_2d {
-            ((end_pixel - start_pixel) * CAM_DESIGN.0) as usize
+            ((end_pixel - start_pixel) * cam_design().0) as usize
         } else {
-            ((end_pixel - start_pixel) * CAM_DESIGN.0 * CAM_DESIGN.1) as usize
+            ((end_pixel - start_pixel) * cam_design().0 * cam_design().1) as usize
         }
     }
     fn get_counter(&self) -> [COUNTER; 4] {
@@ -87,22 +99,27 @@ pub trait SpecKind {
     fn new(settings: &Settings) -> Self;
     fn build_main_tdc<V: TimepixRead>(&self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
         // The default is to build a periodic TDC in order to sync with other instruments.
-        TdcRef::new_periodic(MAIN_TDC, pack, my_settings, file_to_write)
+        TdcRef::new_periodic(config().main_tdc, pack, my_settings, file_to_write)
     }
     fn build_aux_tdc<V: TimepixRead>(&self, _pack: &mut V, _my_settings: &Settings, _file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_no_read(SECONDARY_TDC)
+        TdcRef::new_no_read(config().secondary_tdc)
     }
-    fn add_electron_hit(&mut self, pack: Packet, settings: &Settings, frame_tdc: &TdcRef, ref_tdc: &TdcRef);
+    fn add_electron_hit(&mut self, pack: Packet, ele_time: TIME, settings: &Settings, frame_tdc: &TdcRef, ref_tdc: &TdcRef);
     fn add_tdc_hit1(&mut self, pack: Packet, frame_tdc: &mut TdcRef, _settings: &Settings);
     fn add_tdc_hit2(&mut self, pack: Packet, settings: &Settings, ref_tdc: &mut TdcRef);
     fn add_shutter_hit(&mut self, _pack: Packet, _frame_tdc: &mut TdcRef, _settings: &Settings) {}
     fn reset_or_else(&mut self, _frame_tdc: &TdcRef, settings: &Settings);
+    ///Adds the electrons of the clusters still open when the measurement ends. True if there were any.
+    fn flush_clusters(&mut self) -> bool {false}
     fn shutter_control(&self) -> Option<&ShutterControl> {None}
     fn get_frame_counter(&self, tdc_value: &TdcRef) -> COUNTER {
         tdc_value.counter() / 2
     }
     fn data_size_in_bytes(&self) -> usize;
     fn data_height(&self) -> COUNTER;
+    fn data_width(&self) -> POSITION {
+        cam_design().0
+    }
     fn ttx_index(&mut self, _ts: u64, _channel: i32, _ts_correction: Option<TIME>) {}
 }
 
@@ -111,15 +128,21 @@ macro_rules! add_index {
         {
             $x.data[$y as usize] += 1;
         }
-    }
+    };
+    ($x: ident, $y: expr, $counts: expr) => {
+        {
+            let counts = $counts;
+            $x.data[$y as usize] += counts;
+        }
+    };
 }
 
 macro_rules! tp3_vec {
     ($x: expr) => {
         {
             let len = match $x {
-                1 => CAM_DESIGN.0,
-                2 => CAM_DESIGN.0 * CAM_DESIGN.1,
+                1 => cam_design().0,
+                2 => cam_design().0 * cam_design().1,
                 _ => {panic!("One or two dimensions only!")},
             } as usize;
             let temp_vec: Vec<u32> = vec![0; len];
@@ -134,6 +157,7 @@ pub struct Live2D {
     is_ready: bool,
     frame_counter: COUNTER,
     last_time: TIME,
+    clustering: Clustering,
     timer: Instant,
     hist: ttx::Histogram,
 }
@@ -148,29 +172,21 @@ impl SpecKind for Live2D {
     fn new(settings: &Settings) -> Self {
         let data = tp3_vec!(2);
         misc::check_bitdepth_and_data(&data, settings);
-        Self{ data, is_ready: false, frame_counter: 0, last_time: 0, timer: Instant::now(), hist: ttx::Histogram::new(1, 20) }
+        Self{ data, is_ready: false, frame_counter: 0, last_time: 0, clustering: Clustering::new(1), timer: Instant::now(), hist: ttx::Histogram::new(1, 20) }
     }
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, settings: &Settings, _frame_tdc: &TdcRef, ref_tdc: &TdcRef) {
-        let index = pack.x() + CAM_DESIGN.0 * pack.y();
-        if settings.time_resolved {
-            if ref_tdc.tr_electron_check_if_in(&pack, settings).is_some() {
-                add_index!(self, index);
-            } 
-        } else {
-            add_index!(self, index);
+    fn add_electron_hit(&mut self, pack: Packet, ele_time: TIME, settings: &Settings, _frame_tdc: &TdcRef, ref_tdc: &TdcRef) {
+        let is_in = !settings.time_resolved || ref_tdc.tr_electron_check_if_in(&pack, settings).is_some();
+        for electron in self.clustering.add(&pack, ele_time, 0, is_in) {
+            let index = electron.x + cam_design().0 * electron.y;
+            add_index!(self, index, electron.counts);
         }
         
-        let ele_time = pack.fast_electron_time();
-        //This is an overflow. We correct it.
-        if self.last_time > ele_time + (ELECTRON_OVERFLOW >> 2) {
-            self.last_time = ele_time;
-        }
         //We check if the frame must be ready or not.
-        if ele_time > self.last_time + settings.acquisition_us * 640 {
+        if ele_time > self.last_time + settings.acquisition_us * TDC_UNITS_PER_US {
             self.last_time = ele_time;
             self.frame_counter += 1;
-            if self.timer.elapsed().as_millis() < TIME_INTERVAL_FRAMES {
+            if self.timer.elapsed().as_millis() < config().time_interval_frames as u128 {
                 self.is_ready = false;
                 if !settings.cumul {
                     self.data.iter_mut().for_each(|x| *x = 0);
@@ -180,22 +196,31 @@ impl SpecKind for Live2D {
             }
         }
     }
+    fn flush_clusters(&mut self) -> bool {
+        let mut flushed = false;
+        for electron in self.clustering.flush() {
+            let index = electron.x + cam_design().0 * electron.y;
+            add_index!(self, index, electron.counts);
+            flushed = true;
+        }
+        flushed
+    }
     fn build_aux_tdc<V: TimepixRead>(&self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
         if my_settings.time_resolved {
-            TdcRef::new_periodic(SECONDARY_TDC, pack, my_settings, file_to_write)
+            TdcRef::new_periodic(config().secondary_tdc, pack, my_settings, file_to_write)
         } else {
-            TdcRef::new_no_read(SECONDARY_TDC)
+            TdcRef::new_no_read(config().secondary_tdc)
         }
     } 
     fn add_tdc_hit2(&mut self, pack: Packet, _settings: &Settings, ref_tdc: &mut TdcRef) {
         ref_tdc.upt(&pack);
         self.hist.add_event(pack.tdc_time_abs_norm(), 2);
-        add_index!(self, CAM_DESIGN.0-1);
+        add_index!(self, cam_design().0-1);
     }
     fn add_tdc_hit1(&mut self, pack: Packet, frame_tdc: &mut TdcRef, _settings: &Settings) {
         frame_tdc.upt(&pack);
         self.hist.add_event(pack.tdc_time_abs_norm(), 1);
-        add_index!(self, CAM_DESIGN.0-2);
+        add_index!(self, cam_design().0-2);
     }
     fn reset_or_else(&mut self, _frame_tdc: &TdcRef, settings: &Settings) {
         // Printing the histogram here //
@@ -215,11 +240,11 @@ impl SpecKind for Live2D {
         misc::vector_len_in_bytes(&self.data)
     }
     fn data_height(&self) -> COUNTER {
-        CAM_DESIGN.1
+        cam_design().1
     }
     fn ttx_index(&mut self, _ttx_time: u64, ttx_channel: i32, _ts_correction: Option<TIME>) {
         if ttx_channel == 2 {
-            add_index!(self, CAM_DESIGN.0-1);
+            add_index!(self, cam_design().0-1);
         }
     }
 }
@@ -230,6 +255,8 @@ pub struct Live1D {
     is_ready: bool,
     frame_counter: COUNTER,
     last_time: TIME,
+    edges: EdgePixels,
+    clustering: Clustering,
     timer: Instant,
 }
 
@@ -241,31 +268,25 @@ impl SpecKind for Live1D {
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let data = tp3_vec!(1);
+        let clustering = Clustering::new(engine::super_resolution());
+        let data = vec![0; (cam_design().0 * clustering.super_resolution()) as usize];
         misc::check_bitdepth_and_data(&data, settings);
-        Self{ data, is_ready: false, frame_counter: 0, last_time: 0, timer: Instant::now()}
+        Self{ data, is_ready: false, frame_counter: 0, last_time: 0, edges: EdgePixels::default(), clustering, timer: Instant::now()}
     }
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, settings: &Settings, _frame_tdc: &TdcRef, ref_tdc: &TdcRef) {
-        let index = pack.x();
-        if settings.time_resolved {
-            if ref_tdc.tr_electron_check_if_in(&pack, settings).is_some() {
-                add_index!(self, index);
-            } 
-        } else {
-            add_index!(self, index);
+    fn add_electron_hit(&mut self, pack: Packet, ele_time: TIME, settings: &Settings, _frame_tdc: &TdcRef, ref_tdc: &TdcRef) {
+        let is_in = !settings.time_resolved || ref_tdc.tr_electron_check_if_in(&pack, settings).is_some();
+        let super_resolution = self.clustering.super_resolution();
+        for electron in self.clustering.add(&pack, ele_time, 0, is_in) {
+            let index = self.edges.x(electron.x, electron.y) * super_resolution + electron.subpixel;
+            add_index!(self, index, electron.counts);
         }
         
-        let ele_time = pack.fast_electron_time();
-        //This is an overflow. We correct it.
-        if self.last_time > ele_time + (ELECTRON_OVERFLOW >> 2) {
-            self.last_time = ele_time;
-        }
         //We check if the frame must be ready or not.
-        if ele_time > self.last_time + settings.acquisition_us * 640 {
+        if ele_time > self.last_time + settings.acquisition_us * TDC_UNITS_PER_US {
             self.last_time = ele_time;
             self.frame_counter += 1;
-            if self.timer.elapsed().as_millis() < TIME_INTERVAL_FRAMES {
+            if self.timer.elapsed().as_millis() < config().time_interval_frames as u128 {
                 self.is_ready = false;
                 if !settings.cumul {
                     self.data.iter_mut().for_each(|x| *x = 0);
@@ -275,20 +296,32 @@ impl SpecKind for Live1D {
             }
         }
     }
+    fn flush_clusters(&mut self) -> bool {
+        let super_resolution = self.clustering.super_resolution();
+        let mut flushed = false;
+        for electron in self.clustering.flush() {
+            let index = self.edges.x(electron.x, electron.y) * super_resolution + electron.subpixel;
+            add_index!(self, index, electron.counts);
+            flushed = true;
+        }
+        flushed
+    }
     fn build_aux_tdc<V: TimepixRead>(&self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
         if my_settings.time_resolved {
-            TdcRef::new_periodic(SECONDARY_TDC, pack, my_settings, file_to_write)
+            TdcRef::new_periodic(config().secondary_tdc, pack, my_settings, file_to_write)
         } else {
-            TdcRef::new_no_read(SECONDARY_TDC)
+            TdcRef::new_no_read(config().secondary_tdc)
         }
     }
     fn add_tdc_hit2(&mut self, pack: Packet, _settings: &Settings, ref_tdc: &mut TdcRef) {
         ref_tdc.upt(&pack);
-        add_index!(self, CAM_DESIGN.0-1);
+        let tdc_pixel = self.data_width()-1;
+        add_index!(self, tdc_pixel);
     }
     fn add_tdc_hit1(&mut self, pack: Packet, frame_tdc: &mut TdcRef, _settings: &Settings) {
         frame_tdc.upt(&pack);
-        add_index!(self, CAM_DESIGN.0-2);
+        let tdc_pixel = self.data_width()-2;
+        add_index!(self, tdc_pixel);
     }
     fn reset_or_else(&mut self, _frame_tdc: &TdcRef, settings: &Settings) {
         self.timer = Instant::now();
@@ -306,9 +339,13 @@ impl SpecKind for Live1D {
     fn data_height(&self) -> COUNTER {
         1
     }
+    fn data_width(&self) -> POSITION {
+        cam_design().0 * self.clustering.super_resolution()
+    }
     fn ttx_index(&mut self, _ttx_time: u64, ttx_channel: i32, _ts_correction: Option<TIME>) {
         if ttx_channel == 2 {
-            add_index!(self, CAM_DESIGN.0-1);
+            let tdc_pixel = self.data_width()-1;
+            add_index!(self, tdc_pixel);
         }
     }
 }
@@ -326,7 +363,7 @@ pub struct Coincidence2D {
 
 impl SpecKind for Coincidence2D {
     fn is_ready(&self) -> bool {
-        self.timer.elapsed().as_millis() > TIME_INTERVAL_COINCIDENCE_HISTOGRAM
+        self.timer.elapsed().as_millis() > config().time_interval_coincidence_histogram as u128
     }
     fn build_output(&mut self, settings: &Settings) -> &[u8] {
         self.electrons.par_sort_unstable_by_key(|&(time, _pos)| time);
@@ -345,23 +382,23 @@ impl SpecKind for Coincidence2D {
             let photon_slice = &self.photons[start_pointer..end_pointer];
             for photon in photon_slice {
                 let delay = (electron.0 + settings.time_width + settings.time_delay - photon.0) as POSITION;
-                let index = (electron.1 + delay * CAM_DESIGN.0 + (photon.1 - 1) * 2*settings.time_width as u32 * CAM_DESIGN.0) as usize;
+                let index = (electron.1 + delay * cam_design().0 + (photon.1 - 1) * 2*settings.time_width as u32 * cam_design().0) as usize;
                 self.data[index] += 1;
             }
         }
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len = 4*settings.time_width as usize * CAM_DESIGN.0 as usize;
+        let len = 4*settings.time_width as usize * cam_design().0 as usize;
         let data = vec![0; len];
         misc::check_bitdepth_and_data(&data, settings);
         Self { data, electrons: Vec::new(), hits: 0, photons: Vec::new(), timer: Instant::now()}
     }
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         self.hits += 1;
         if self.hits % 2 == 0 {
-            self.electrons.push((pack.electron_time_in_tdc_units(), pack.x()));
+            self.electrons.push((walklib::correct_time(&pack, pack.electron_time_in_tdc_units()), pack.x()));
         }
     }
     fn add_tdc_hit2(&mut self, pack: Packet, _settings: &Settings, ref_tdc: &mut TdcRef) {
@@ -381,7 +418,7 @@ impl SpecKind for Coincidence2D {
         misc::vector_len_in_bytes(&self.data)
     }
     fn data_height(&self) -> COUNTER {
-        self.data.len() as COUNTER / CAM_DESIGN.0
+        self.data.len() as COUNTER / cam_design().0
     }    
     fn ttx_index(&mut self, _ts: u64, channel: i32, ts_correction: Option<TIME>) {
         if let Some(time_tpx3) = ts_correction {
@@ -394,6 +431,8 @@ impl SpecKind for Coincidence2D {
 pub struct Chrono {
     data: Vec<u32>,
     last_time: TIME,
+    edges: EdgePixels,
+    clustering: Clustering,
     frame_counter: COUNTER,
     current_line: COUNTER,
     timer: Instant,
@@ -401,40 +440,40 @@ pub struct Chrono {
 
 impl SpecKind for Chrono {
     fn is_ready(&self) -> bool {
-        self.timer.elapsed().as_millis() > TIME_INTERVAL_FRAMES
+        self.timer.elapsed().as_millis() > config().time_interval_frames as u128
     }
     fn build_output(&mut self, _settings: &Settings) -> &[u8] {
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len = (settings.xspim_size*CAM_DESIGN.0) as usize;
+        let clustering = Clustering::new(engine::super_resolution());
+        let len = (settings.xspim_size*cam_design().0*clustering.super_resolution()) as usize;
         let data = vec![0; len];
         misc::check_bitdepth_and_data(&data, settings);
-        Self{ data, last_time: 0, frame_counter: 0, current_line: 0, timer: Instant::now()}
+        Self{ data, last_time: 0, edges: EdgePixels::default(), clustering, frame_counter: 0, current_line: 0, timer: Instant::now()}
     }
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, ele_time: TIME, settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         
-        let ele_time = pack.fast_electron_time();
-        //This is an overflow. We correct it.
-        if self.last_time > ele_time + (ELECTRON_OVERFLOW >> 2) {
-            self.last_time = ele_time;
-        }
+        let width = self.data_width();
         
         //We check for a new line and if true we erase it.
-        if ele_time > self.last_time + settings.acquisition_us * 640 {
+        if ele_time > self.last_time + settings.acquisition_us * TDC_UNITS_PER_US {
             self.last_time = ele_time;
             self.frame_counter += 1;
             self.current_line = self.frame_counter % settings.xspim_size;
 
-            let start = ((self.frame_counter % settings.xspim_size) * CAM_DESIGN.0) as usize;
-            let end = start + PIXELS_X as usize;
+            let start = ((self.frame_counter % settings.xspim_size) * width) as usize;
+            let end = start + width as usize;
             self.data[start..end].iter_mut().for_each(|x| *x = 0);
         }
 
         //We determine the current line
-        let index = pack.x() + self.current_line * CAM_DESIGN.0;
-        add_index!(self, index);
+        let super_resolution = self.clustering.super_resolution();
+        for electron in self.clustering.add(&pack, ele_time, 0, true) {
+            let x = self.edges.x(electron.x, electron.y) * super_resolution + electron.subpixel;
+            add_index!(self, x + self.current_line * width, electron.counts);
+        }
 
     }
     fn add_tdc_hit2(&mut self, pack: Packet, _settings: &Settings, ref_tdc: &mut TdcRef) {
@@ -446,6 +485,17 @@ impl SpecKind for Chrono {
     fn reset_or_else(&mut self, _frame_tdc: &TdcRef, _settings: &Settings) {
         self.timer = Instant::now();
     }
+    fn flush_clusters(&mut self) -> bool {
+        let width = self.data_width();
+        let super_resolution = self.clustering.super_resolution();
+        let mut flushed = false;
+        for electron in self.clustering.flush() {
+            let x = self.edges.x(electron.x, electron.y) * super_resolution + electron.subpixel;
+            add_index!(self, x + self.current_line * width, electron.counts);
+            flushed = true;
+        }
+        flushed
+    }
     fn get_frame_counter(&self, _tdc_value: &TdcRef) -> COUNTER {
         self.frame_counter
     }
@@ -453,7 +503,10 @@ impl SpecKind for Chrono {
         misc::vector_len_in_bytes(&self.data)
     }
     fn data_height(&self) -> COUNTER {
-        self.data.len() as COUNTER / CAM_DESIGN.0
+        self.data.len() as COUNTER / self.data_width()
+    }
+    fn data_width(&self) -> POSITION {
+        cam_design().0 * self.clustering.super_resolution()
     }
 }
 
@@ -469,13 +522,13 @@ pub struct ChronoFrame {
 
 impl SpecKind for ChronoFrame {
     fn is_ready(&self) -> bool {
-        self.timer.elapsed().as_millis() > TIME_INTERVAL_FRAMES
+        self.timer.elapsed().as_millis() > config().time_interval_frames as u128
     }
     fn build_output(&mut self, _settings: &Settings) -> &[u8] {
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len = (settings.xspim_size*CAM_DESIGN.0) as usize;
+        let len = (settings.xspim_size*cam_design().0) as usize;
         let shutter = ShutterControl::default();
         let data = vec![0; len];
         misc::check_bitdepth_and_data(&data, settings);
@@ -483,7 +536,7 @@ impl SpecKind for ChronoFrame {
     }
 
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         let shut = self.shutter.as_ref().unwrap();
         let frame_number = shut.get_counter()[pack.ci() as usize] as POSITION;
         
@@ -492,12 +545,12 @@ impl SpecKind for ChronoFrame {
             self.frame_counter = frame_number;
             self.current_line = self.frame_counter % settings.xspim_size;
             
-            let start = ((self.frame_counter % settings.xspim_size) * CAM_DESIGN.0) as usize;
-            let end = start + PIXELS_X as usize;
+            let start = ((self.frame_counter % settings.xspim_size) * cam_design().0) as usize;
+            let end = start + cam_design().0 as usize;
             self.data[start..end].iter_mut().for_each(|x| *x = 0);
         }
         //We determine the current line
-        let index = pack.x() + self.current_line * CAM_DESIGN.0;
+        let index = pack.x() + self.current_line * cam_design().0;
         self.data[index as usize] += pack.tot() as u32;
     }
     fn add_tdc_hit2(&mut self, pack: Packet, _settings: &Settings, ref_tdc: &mut TdcRef) {
@@ -522,7 +575,7 @@ impl SpecKind for ChronoFrame {
         misc::vector_len_in_bytes(&self.data)
     }
     fn data_height(&self) -> COUNTER {
-        self.data.len() as COUNTER / CAM_DESIGN.0
+        self.data.len() as COUNTER / cam_design().0
     }
 }
 
@@ -536,7 +589,7 @@ pub struct Live2DFrame {
 
 impl SpecKind for Live2DFrame {
     fn is_ready(&self) -> bool {
-        self.is_ready && self.timer.elapsed().as_millis() > TIME_INTERVAL_FRAMES
+        self.is_ready && self.timer.elapsed().as_millis() > config().time_interval_frames as u128
     }
     fn build_output(&mut self, _settings: &Settings) -> &[u8] {
         as_bytes(&self.data)
@@ -548,19 +601,19 @@ impl SpecKind for Live2DFrame {
     }
 
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         if !self.is_ready || settings.cumul {
-            let index = pack.x() + CAM_DESIGN.0 * pack.y();
+            let index = pack.x() + cam_design().0 * pack.y();
             self.data[index as usize] += pack.tot() as u32;
         }
     }
     fn add_tdc_hit2(&mut self, pack: Packet, _settings: &Settings, ref_tdc: &mut TdcRef) {
         ref_tdc.upt(&pack);
-        add_index!(self, CAM_DESIGN.0-1);
+        add_index!(self, cam_design().0-1);
     }
     fn add_tdc_hit1(&mut self, pack: Packet, frame_tdc: &mut TdcRef, _settings: &Settings) {
         frame_tdc.upt(&pack);
-        add_index!(self, CAM_DESIGN.0-2);
+        add_index!(self, cam_design().0-2);
     }
     fn add_shutter_hit(&mut self, pack: Packet, _frame_tdc: &mut TdcRef, settings: &Settings) {
         let temp_ready = self.shutter.as_mut().unwrap().try_set_time(pack.frame_time(), pack.ci(), pack.tdc_type() == 10);
@@ -588,7 +641,7 @@ impl SpecKind for Live2DFrame {
         misc::vector_len_in_bytes(&self.data)
     }
     fn data_height(&self) -> COUNTER {
-        CAM_DESIGN.1
+        cam_design().1
     }
 }
 
@@ -602,7 +655,7 @@ pub struct Live1DFrame {
 
 impl SpecKind for Live1DFrame {
     fn is_ready(&self) -> bool {
-        self.is_ready && self.timer.elapsed().as_millis() > TIME_INTERVAL_FRAMES
+        self.is_ready && self.timer.elapsed().as_millis() > config().time_interval_frames as u128
     }
     fn build_output(&mut self, _settings: &Settings) -> &[u8] {
         as_bytes(&self.data)
@@ -614,7 +667,7 @@ impl SpecKind for Live1DFrame {
     }
 
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         //If you are in cumulation mode, save all the electrons. If not, only save those that the
         //frame has not yet been sent
         if !self.is_ready || settings.cumul{
@@ -624,11 +677,11 @@ impl SpecKind for Live1DFrame {
     }
     fn add_tdc_hit2(&mut self, pack: Packet, _settings: &Settings, ref_tdc: &mut TdcRef) {
         ref_tdc.upt(&pack);
-        add_index!(self, CAM_DESIGN.0-1);
+        add_index!(self, cam_design().0-1);
     }
     fn add_tdc_hit1(&mut self, pack: Packet, frame_tdc: &mut TdcRef, _settings: &Settings) {
         frame_tdc.upt(&pack);
-        add_index!(self, CAM_DESIGN.0-2);
+        add_index!(self, cam_design().0-2);
     }
     fn add_shutter_hit(&mut self, pack: Packet, _frame_tdc: &mut TdcRef, settings: &Settings) {
         let temp_ready = self.shutter.as_mut().unwrap().try_set_time(pack.frame_time(), pack.ci(), pack.tdc_type() == 10);
@@ -681,7 +734,7 @@ impl SpecKind for Live1DFrameHyperspec {
         as_bytes(&self.data[range])
     }
     fn new(settings: &Settings) -> Self {
-        let len = (CAM_DESIGN.0 * settings.xscan_size * settings.yscan_size) as usize;
+        let len = (cam_design().0 * settings.xscan_size * settings.yscan_size) as usize;
         let mut shutter = ShutterControl::default();
         shutter.set_as_hyperspectral(false);
         let data = vec![0; len];
@@ -689,16 +742,16 @@ impl SpecKind for Live1DFrameHyperspec {
         Self{ data, is_ready: false, timer: Instant::now(), shutter: Some(shutter)}
     }
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         let shut = self.shutter.as_ref().unwrap();
         if shut.is_hyperspectral_complete() { return }
         let pixel_number = shut.get_counter()[pack.ci() as usize] as POSITION;
         //We cannot depass frame_number otherwise the indexation will be bad
-        let index = pixel_number * CAM_DESIGN.0 + pack.x();
+        let index = pixel_number * cam_design().0 + pack.x();
         self.data[index as usize] += pack.tot() as u32;
     }
     fn build_main_tdc<V: TimepixRead>(&self, _pack: &mut V, _my_settings: &Settings, _file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_no_read(MAIN_TDC)
+        TdcRef::new_no_read(config().main_tdc)
     }
     fn add_tdc_hit2(&mut self, _pack: Packet, _settings: &Settings, _ref_tdc: &mut TdcRef) {}
     fn add_tdc_hit1(&mut self, pack: Packet, frame_tdc: &mut TdcRef, _settings: &Settings) {
@@ -711,7 +764,8 @@ impl SpecKind for Live1DFrameHyperspec {
             self.shutter.as_mut().unwrap().set_hyperspectral_as_complete();
         }
         let pixels_sent = self.shutter.as_ref().expect("Shutter must be present in Frame-based mode.").hyperspec_pixels_to_send.1;
-        if shutter_counter > pixels_sent + HYPERSPECTRAL_PIXEL_CHUNK {
+        //A chunk that is not sent yet is kept, or it would be lost when several shutters arrive in the same buffer.
+        if !self.is_ready && shutter_counter > pixels_sent + HYPERSPECTRAL_PIXEL_CHUNK {
             self.is_ready = true;
             let begin_pixel = pixels_sent;
             let end_pixel = std::cmp::min(pixels_sent + HYPERSPECTRAL_PIXEL_CHUNK, settings.xscan_size * settings.yscan_size);
@@ -755,7 +809,7 @@ impl SpecKind for Live2DFrameHyperspec {
         as_bytes(&self.data[range])
     }
     fn new(settings: &Settings) -> Self {
-        let len = (CAM_DESIGN.0 * CAM_DESIGN.1 * settings.xscan_size * settings.yscan_size) as usize;
+        let len = (cam_design().0 * cam_design().1 * settings.xscan_size * settings.yscan_size) as usize;
         let mut shutter = ShutterControl::default();
         shutter.set_as_hyperspectral(true);
         let data = vec![0; len];
@@ -763,16 +817,16 @@ impl SpecKind for Live2DFrameHyperspec {
         Self{ data, is_ready: false, timer: Instant::now(), shutter: Some(shutter)}
     }
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         let shut = self.shutter.as_ref().unwrap();
         if shut.is_hyperspectral_complete() { return }
         let pixel_number = shut.get_counter()[pack.ci() as usize] as POSITION;
         //We cannot depass frame_number otherwise the indexation will be bad
-        let index = pixel_number * CAM_DESIGN.0 * CAM_DESIGN.1 + (pack.y() * CAM_DESIGN.0 + pack.x());
+        let index = pixel_number * cam_design().0 * cam_design().1 + (pack.y() * cam_design().0 + pack.x());
         self.data[index as usize] += pack.tot() as u32;
     }
     fn build_main_tdc<V: TimepixRead>(&self, _pack: &mut V, _my_settings: &Settings, _file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_no_read(MAIN_TDC)
+        TdcRef::new_no_read(config().main_tdc)
     }
     fn add_tdc_hit2(&mut self, _pack: Packet, _settings: &Settings, _ref_tdc: &mut TdcRef) {}
     fn add_tdc_hit1(&mut self, pack: Packet, frame_tdc: &mut TdcRef, _settings: &Settings) {
@@ -785,7 +839,8 @@ impl SpecKind for Live2DFrameHyperspec {
             self.shutter.as_mut().unwrap().set_hyperspectral_as_complete();
         }
         let pixels_sent = self.shutter.as_ref().expect("Shutter must be present in Frame-based mode.").hyperspec_pixels_to_send.1;
-        if shutter_counter > pixels_sent + HYPERSPECTRAL_PIXEL_CHUNK {
+        //A chunk that is not sent yet is kept, or it would be lost when several shutters arrive in the same buffer.
+        if !self.is_ready && shutter_counter > pixels_sent + HYPERSPECTRAL_PIXEL_CHUNK {
             self.is_ready = true;
             let begin_pixel = pixels_sent;
             let end_pixel = std::cmp::min(pixels_sent + HYPERSPECTRAL_PIXEL_CHUNK, settings.xscan_size * settings.yscan_size);
@@ -807,89 +862,94 @@ impl SpecKind for Live2DFrameHyperspec {
         self.shutter.as_ref().unwrap().get_data_size_to_send() * std::mem::size_of_val(&self.data[0])
     }
     fn data_height(&self) -> COUNTER {
-        CAM_DESIGN.1
+        cam_design().1
     }
 }
 
-pub fn build_spectrum<V, U, W>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut frame_tdc: TdcRef, mut ref_tdc: TdcRef, mut meas_type: W, mut file_to_write: FileManager, mut ttx: Option<ttx::TTXRef>) -> Result<(), Tp3ErrorKind> 
+///Reads the packets of `source` and writes frames in the output socket until the source is over
+///or `control` is stopped. Files are flushed before returning.
+pub fn build_spectrum<V, U, W>(mut source: Source<V>, mut ns_sock: U, mut pipeline: Pipeline, meas_type: W, control: Control) -> Result<(), Tp3ErrorKind> 
     where V: TimepixRead,
-          U: Write,
+          U: Sink,
           W: SpecKind
 {
 
-    let mut last_ci = 0;
-    let mut buffer_pack_data: [u8; BUFFER_SIZE] = [0; BUFFER_SIZE];
+    let mut meas_type = SpecAccumulator(meas_type);
     let start = Instant::now();
 
-    if let Some(in_ttx) = &mut ttx {
+    if let Some(in_ttx) = pipeline.ttx_mut() {
         in_ttx.add_channel(1, false, true, true); //Not test, both edges ON, periodic
         //in_ttx.add_channel(2, false, false, false); //Not test, both edges off, non-periodic
         //in_ttx.add_channel(4, true, false, false); //Not test, both edges off, non-periodic
         //in_ttx.add_channel(5, true, false, false); //Not test, both edges off, non-periodic
         in_ttx.prepare();
-        in_ttx.inform_scan_tdc(&mut frame_tdc);
     };
+    pipeline.inform_ttx();
 
-    while let Ok(size) = pack_sock.read_timepix(&mut buffer_pack_data) {
-        file_to_write.write_all(&buffer_pack_data[0..size])?;
-        if build_data(&buffer_pack_data[0..size], &mut meas_type, &mut last_ci, &my_settings, &mut frame_tdc, &mut ref_tdc, &mut ttx) {
-            let msg = create_header(&meas_type, &my_settings, &frame_tdc, 0, meas_type.shutter_control());
-            if ns_sock.write(&msg).is_err() {println!("Client disconnected on header."); break;}
-            if ns_sock.write(meas_type.build_output(&my_settings)).is_err() {println!("Client disconnected on data."); break;}
-            meas_type.reset_or_else(&frame_tdc, &my_settings);
+    while !control.should_stop() {
+        let data = match source.next_buffer()? {
+            Some([]) => continue,
+            Some(data) => data,
+            None => break,
+        };
+        pipeline.process(data, &mut meas_type);
+        if meas_type.0.is_ready() {
+            if control.is_aborted() {break;}
+            let frame_tdc = &pipeline.tdcs().main;
+            if send_frame(&mut meas_type.0, &mut ns_sock, pipeline.settings(), frame_tdc).is_err() {println!("Client disconnected."); control.stop(StopReason::ClientDisconnected); break;}
+            meas_type.0.reset_or_else(frame_tdc, pipeline.settings());
             if frame_tdc.counter() % 1000 == 0 { let elapsed = start.elapsed(); println!("Total elapsed time is: {:?}. Counter is {}.", elapsed, frame_tdc.counter());};
         }
     }
+    //The last clusters are only complete once there is no more data, so they go in a last frame.
+    if meas_type.0.flush_clusters() && !control.is_aborted() && control.reason() != StopReason::ClientDisconnected
+        && send_frame(&mut meas_type.0, &mut ns_sock, pipeline.settings(), &pipeline.tdcs().main).is_err() {
+        println!("Client disconnected.");
+        control.stop(StopReason::ClientDisconnected);
+    }
+    source.finish()?;
+    pipeline.finish()?;
     println!("Total elapsed time is: {:?}.", start.elapsed());
     Ok(())
 
 }
 
-fn build_data<W: SpecKind>(data: &[u8], final_data: &mut W, last_ci: &mut u8, settings: &Settings, frame_tdc: &mut TdcRef, ref_tdc: &mut TdcRef, ttx: &mut Option<ttx::TTXRef>) -> bool {
+fn send_frame<W: SpecKind, U: Sink>(measurement: &mut W, sink: &mut U, settings: &Settings, frame_tdc: &TdcRef) -> std::io::Result<()> {
+    let msg = create_header(measurement, settings, frame_tdc, 0, measurement.shutter_control());
+    let output = measurement.build_output(settings);
+    let write_start = Instant::now();
+    sink.send(&[&msg, output])?;
+    metrics().add_output_sent(msg.len() + output.len(), write_start.elapsed());
+    Ok(())
+}
 
-    let mut first_tpx = 0;
-    let mut last_tpx = 0;
-    let mut set_tpx_times = {|time: TIME| {
-        if first_tpx == 0 {first_tpx = time;}
-        last_tpx = time;
-    }};
-    for x in data.chunks_exact(8) {
-        match *x {
-            [84, 80, 88, 51, nci, _, _, _] => *last_ci = nci,
-            _ => {
-                let packet = Packet::new(*last_ci, packet_change(x)[0]);
-                match packet.id() {
-                    11 | 10 => { //Event or frame based
-                        final_data.add_electron_hit(packet, settings, frame_tdc, ref_tdc);
-                    },
-                    6 if packet.tdc_type() == frame_tdc.id() => { //Tdc value 1
-                        final_data.add_tdc_hit1(packet, frame_tdc, settings);
-                        set_tpx_times(packet.tdc_time_abs_norm());
-                    },
-                    6 if packet.tdc_type() == ref_tdc.id() => { //Tdc value 2
-                        final_data.add_tdc_hit2(packet, settings, ref_tdc);
-                        set_tpx_times(packet.tdc_time_abs_norm());
-                    },
-                    5 if packet.tdc_type() == 10 || packet.tdc_type() == 15  => { //Shutter value.
-                        final_data.add_shutter_hit(packet, frame_tdc, settings);
-                    },
-                    _ => {
-                        if packet.id() == 6 {
-                            //println!("{} and {}", packet.tdc_type(), packet.tdc_time_abs());
-                        }
-                    },
-                };
-            },
-        };
-    };
-    //println!("***Spec Lib***: The values inside the loop is min/max/nevents: {} / {} / {}", first_tpx, last_tpx, nevents);
-    
-    if let Some(in_ttx) = ttx {
-        in_ttx.inform_scan_tdc(frame_tdc);
-        in_ttx.build_spec_data(final_data);
+///A spectral measurement that runs as an extra output of another measurement.
+pub struct SpecOutput<W, U> {
+    measurement: SpecAccumulator<W>,
+    tdcs: Tdcs,
+    settings: Settings,
+    sink: U,
+}
+
+impl<W: SpecKind, U: Sink> SpecOutput<W, U> {
+    pub fn new(settings: Settings, tdcs: Tdcs, sink: U) -> Self {
+        SpecOutput {measurement: SpecAccumulator(W::new(&settings)), tdcs, settings, sink}
     }
+}
 
-    final_data.is_ready()
+impl<W: SpecKind + Send, U: Sink + Send> Output for SpecOutput<W, U> {
+    fn add_event(&mut self, raw: RawEvent) {
+        pipelinelib::dispatch(raw, &mut self.measurement, &mut self.tdcs, &self.settings);
+    }
+    fn send_if_ready(&mut self) -> std::io::Result<()> {
+        let SpecOutput {measurement, tdcs, settings, sink} = self;
+        if !measurement.0.is_ready() {return Ok(());}
+        let msg = create_header(&measurement.0, settings, &tdcs.main, 0, measurement.0.shutter_control());
+        let output = measurement.0.build_output(settings);
+        sink.send(&[&msg, output])?;
+        measurement.0.reset_or_else(&tdcs.main, settings);
+        Ok(())
+    }
 }
 
 fn create_header<W: SpecKind>(measurement: &W, set: &Settings, tdc: &TdcRef, extra_pixels: POSITION, _shutter_control: Option<&ShutterControl>) -> Vec<u8> {
@@ -897,12 +957,12 @@ fn create_header<W: SpecKind>(measurement: &W, set: &Settings, tdc: &TdcRef, ext
     msg.push_str(&(tdc.time().to_string()));
     msg.push_str(",\"frameNumber\":");
     msg.push_str(&((measurement.get_frame_counter(tdc)).to_string()));
-    msg.push_str(",\"measurementID:\"Null\",\"dataSize\":");
+    msg.push_str(",\"measurementID\":\"Null\",\"dataSize\":");
     msg.push_str(&((measurement.data_size_in_bytes().to_string())));
     msg.push_str(",\"bitDepth\":");
     msg.push_str(&((set.bytedepth<<3).to_string()));
     msg.push_str(",\"width\":");
-    msg.push_str(&((CAM_DESIGN.0+extra_pixels).to_string()));
+    msg.push_str(&((measurement.data_width()+extra_pixels).to_string()));
     msg.push_str(",\"height\":");
     msg.push_str(&(measurement.data_height().to_string()));
     msg.push_str("}\n");
@@ -924,7 +984,7 @@ pub struct Coincidence2DV3 {
 
 impl SpecKind for Coincidence2DV3 {
     fn is_ready(&self) -> bool {
-        self.timer.elapsed().as_millis() > TIME_INTERVAL_COINCIDENCE_HISTOGRAM
+        self.timer.elapsed().as_millis() > config().time_interval_coincidence_histogram as u128
     }
     fn build_output(&mut self, settings: &Settings) -> &[u8] {
         let mut rpi = Vec::new();
@@ -933,19 +993,19 @@ impl SpecKind for Coincidence2DV3 {
         let coinc_electron = self.electron_buffer.search_coincidence(&mut self.photon_buffer, &mut rpi, settings.time_delay, settings.time_width);
         coinc_electron.iter().for_each(|ele| {
             let delay = (ele.relative_time_from_coincident_photon().unwrap() + settings.time_width as i64 + settings.time_delay as i64) as POSITION;
-            let index = (ele.x() + delay * CAM_DESIGN.0) as usize;
+            let index = (ele.x() + delay * cam_design().0) as usize;
             self.data[index] += 1;
         });
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len = 4*settings.time_width as usize * CAM_DESIGN.0 as usize;
+        let len = 4*settings.time_width as usize * cam_design().0 as usize;
         let data = vec![0; len];
         misc::check_bitdepth_and_data(&data, settings);
         Self { data, electron_buffer: CollectionElectron::new(), photon_buffer: CollectionPhoton::new(), timer: Instant::now()}
     }
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         let se = SingleElectron::new(pack, None, 0, None);
         self.electron_buffer.add_electron(se);
     }
@@ -966,7 +1026,7 @@ impl SpecKind for Coincidence2DV3 {
         misc::vector_len_in_bytes(&self.data)
     }
     fn data_height(&self) -> COUNTER {
-        self.data.len() as COUNTER / CAM_DESIGN.0
+        self.data.len() as COUNTER / cam_design().0
     }
 }
 
@@ -982,19 +1042,19 @@ pub struct Coincidence2DV2 {
 
 impl SpecKind for Coincidence2DV2 {
     fn is_ready(&self) -> bool {
-        self.timer.elapsed().as_millis() > TIME_INTERVAL_COINCIDENCE_HISTOGRAM
+        self.timer.elapsed().as_millis() > config().time_interval_coincidence_histogram as u128
     }
     fn build_output(&mut self, _settings: &Settings) -> &[u8] {
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len = 4*settings.time_width as usize * CAM_DESIGN.0 as usize;
+        let len = 4*settings.time_width as usize * cam_design().0 as usize;
         let data = vec![0; len];
         misc::check_bitdepth_and_data(&data, settings);
         Self { data, electron_buffer: vec![(0, 0); CIRCULAR_BUFFER], photon_buffer: vec![0; LIST_SIZE_AUX_EVENTS], timer: Instant::now(), index: 0}
     }
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, _settings: &Settings, _frame_tdc: &TdcRef, _ref_tdc: &TdcRef) {
         self.electron_buffer[self.index] = (pack.electron_time_in_tdc_units(), pack.x());
         self.index += 1;
         self.index %= CIRCULAR_BUFFER;
@@ -1009,7 +1069,7 @@ impl SpecKind for Coincidence2DV2 {
                 if (*phtime < ele.0 + settings.time_delay + settings.time_width) &&
                     (ele.0 + settings.time_delay < phtime + settings.time_width) {
                         let delay = (phtime - settings.time_delay + settings.time_width - ele.0) as POSITION;
-                        let index = ele.1 + delay * CAM_DESIGN.0;
+                        let index = ele.1 + delay * cam_design().0;
                         *ele = (0, 0); //this electron should not appear again. It is already send.
                         self.data[index as usize] += 1;
                 }
@@ -1027,7 +1087,7 @@ impl SpecKind for Coincidence2DV2 {
                             && (ele.0 + settings.time_delay < phtime + settings.time_width)
                         {
                             let delay = (phtime - settings.time_delay + settings.time_width - ele.0) as POSITION;
-                            let index = ele.1 + delay * CAM_DESIGN.0;
+                            let index = ele.1 + delay * cam_design().0;
                             *ele = (0, 0); // This part needs to be handled carefully
                             Some(index)
                         } else {
@@ -1055,7 +1115,7 @@ impl SpecKind for Coincidence2DV2 {
         misc::vector_len_in_bytes(&self.data)
     }
     fn data_height(&self) -> COUNTER {
-        self.data.len() as COUNTER / CAM_DESIGN.0
+        self.data.len() as COUNTER / cam_design().0
     }
 }
 
@@ -1070,13 +1130,13 @@ pub struct Coincidence2D {
 impl SpecKind for Coincidence2D {
     fn is_ready(&self) -> bool {
         //Be careful with the timer. This is quite slow.
-        self.timer.elapsed().as_millis() > TIME_INTERVAL_COINCIDENCE_HISTOGRAM
+        self.timer.elapsed().as_millis() > config().time_interval_coincidence_histogram as u128
     }
     fn build_output(&mut self, _settings: &Settings) -> &[u8] {
         as_bytes(&self.data)
     }
     fn new(settings: &Settings) -> Self {
-        let len = 4*settings.time_width as usize * CAM_DESIGN.0 as usize;
+        let len = 4*settings.time_width as usize * cam_design().0 as usize;
         let data = vec![0; len];
         misc::check_bitdepth_and_data(&data, settings);
         Self { data, aux_data: vec![0; LIST_SIZE_AUX_EVENTS], aux_data2: vec![0; LIST_SIZE_AUX_EVENTS], timer: Instant::now()}
@@ -1084,18 +1144,18 @@ impl SpecKind for Coincidence2D {
     //This func gets electrons in which the TDC has already arrived by TCP. So it could be
     //electrons second tcp, first time, or electrons second tdc, second time.
     #[inline]
-    fn add_electron_hit(&mut self, pack: Packet, settings: &Settings, frame_tdc: &TdcRef, ref_tdc: &TdcRef) {
+    fn add_electron_hit(&mut self, pack: Packet, _ele_time: TIME, settings: &Settings, frame_tdc: &TdcRef, ref_tdc: &TdcRef) {
         let etime = pack.electron_time_in_tdc_units();
         if settings.time_resolved {
             if let Some(phtime) = frame_tdc.tr_electron_check_if_in(&pack, settings) {
                 let delay = (phtime - settings.time_delay + settings.time_width - etime) as POSITION;
-                let index = pack.x() + delay * CAM_DESIGN.0 + 2*settings.time_width as u32 * CAM_DESIGN.0;
+                let index = pack.x() + delay * cam_design().0 + 2*settings.time_width as u32 * cam_design().0;
                 add_index!(self, index);
             }
             if let Some(phtime) = ref_tdc.tr_electron_check_if_in(&pack, settings) {
                 if let Some(etime) = ref_tdc.tr_electron_correct_by_blanking(&pack) {
                     let delay = (phtime - settings.time_delay + settings.time_width - etime) as POSITION;
-                    let index = pack.x() + delay * CAM_DESIGN.0;
+                    let index = pack.x() + delay * cam_design().0;
                     add_index!(self, index);
                 }
             }
@@ -1103,14 +1163,14 @@ impl SpecKind for Coincidence2D {
             for phtime in self.aux_data.iter() {
                 if check_if_in(&etime, phtime, settings) {
                     let delay = (phtime - settings.time_delay + settings.time_width - etime) as POSITION;
-                    let index = pack.x() + delay * CAM_DESIGN.0;
+                    let index = pack.x() + delay * cam_design().0;
                     add_index!(self, index);
                 }
             }
             for phtime in self.aux_data2.iter() {
                 if check_if_in(&etime, phtime, settings) {
                     let delay = (phtime - settings.time_delay + settings.time_width - etime) as POSITION;
-                    let index = pack.x() + delay * CAM_DESIGN.0 + 2*settings.time_width as u32 * CAM_DESIGN.0;
+                    let index = pack.x() + delay * cam_design().0 + 2*settings.time_width as u32 * cam_design().0;
                     add_index!(self, index);
                 }
             }
@@ -1118,16 +1178,16 @@ impl SpecKind for Coincidence2D {
     }
     fn build_aux_tdc<V: TimepixRead>(&self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
         if my_settings.time_resolved {
-            TdcRef::new_periodic(SECONDARY_TDC, pack, my_settings, file_to_write)
+            TdcRef::new_periodic(config().secondary_tdc, pack, my_settings, file_to_write)
         } else {
-            TdcRef::new_no_read(SECONDARY_TDC)
+            TdcRef::new_no_read(config().secondary_tdc)
         }
     }
     fn build_main_tdc<V: TimepixRead>(&self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
         if my_settings.time_resolved {
-            TdcRef::new_periodic(MAIN_TDC, pack, my_settings, file_to_write)
+            TdcRef::new_periodic(config().main_tdc, pack, my_settings, file_to_write)
         } else {
-            TdcRef::new_no_read(MAIN_TDC)
+            TdcRef::new_no_read(config().main_tdc)
         }
     }
     fn add_tdc_hit2(&mut self, pack: Packet, _settings: &Settings, ref_tdc: &mut TdcRef) {
@@ -1150,7 +1210,7 @@ impl SpecKind for Coincidence2D {
         misc::vector_len_in_bytes(&self.data)
     }
     fn data_height(&self) -> COUNTER {
-        self.data.len() as COUNTER / CAM_DESIGN.0
+        self.data.len() as COUNTER / cam_design().0
     }
 }
 */
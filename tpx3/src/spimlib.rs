@@ -1,16 +1,21 @@
 //!`spimlib` is a collection of tools to set hyperspectral EELS acquisition.
 
 use crate::packetlib::Packet;
-use crate::auxiliar::{Settings, misc::{TimepixRead, as_bytes, as_bytes_mut, packet_change}, FileManager};
+use crate::auxiliar::{Settings, misc::{TimepixRead, as_bytes, as_bytes_mut}, FileManager};
+use crate::pipelinelib::{self, Source, Pipeline, SpimAccumulator, Sink, Clustering, Output, Tdcs};
+use crate::readerlib::RawEvent;
 use crate::tdclib::TdcRef;
 use crate::errorlib::Tp3ErrorKind;
 use std::time::Instant;
-use std::io::Write;
 use std::sync::mpsc;
 use std::thread;
 use crate::auxiliar::value_types::*;
 use crate::constlib::*;
-use crate::ttx;
+use crate::configlib::config;
+use crate::controllib::{Control, StopReason};
+use crate::metricslib::metrics;
+use crate::geometrylib::EdgePixels;
+use crate::clusterlib::engine;
 
 ///`SpimKind` is the main trait that measurement types must obey. Custom measurements must all
 ///implement these methods.
@@ -20,7 +25,8 @@ pub trait SpimKind {
     fn data(&self) -> &Vec<Self::InputData>;
     fn build_main_tdc<V: TimepixRead>(&mut self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind>;
     fn build_aux_tdc<V: TimepixRead>(&self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind>;
-    fn add_electron_hit(&mut self, packet: &Packet, line_tdc: &TdcRef, ref_tdc: &TdcRef, set: &Settings);
+    ///`time` is the time of the electron extended by the decoder, in TDC units.
+    fn add_electron_hit(&mut self, packet: &Packet, time: TIME, line_tdc: &TdcRef, ref_tdc: &TdcRef, set: &Settings);
     fn add_tdc_hit(&mut self, packet: &Packet, line_tdc: &TdcRef, ref_tdc: &mut TdcRef);
     fn upt_line(&self, packet: &Packet, settings: &Settings, line_tdc: &mut TdcRef);
     fn build_output(&mut self, set: &Settings, spim_tdc: &TdcRef, list_scan: SlType) -> &[u8];
@@ -28,31 +34,35 @@ pub trait SpimKind {
     fn is_ready(&mut self, line_tdc: &TdcRef) -> bool;
     fn new(settings: &Settings) -> Self;
     fn ttx_index(&mut self, _ts: u64, _channel: i32, _ts_correction: Option<TIME>) {}
+    ///Adds the electrons of the clusters still open when the measurement ends. True if there were any.
+    fn flush_clusters(&mut self) -> bool {false}
 }
 
 #[inline]
 pub fn get_spimindex(x: POSITION, dt: TIME, spim_tdc: &TdcRef, xspim: POSITION, yspim: POSITION, list_scan: SlType) -> Option<INDEXHYPERSPEC> {
-    Some(spim_tdc.get_positional_index(dt, xspim, yspim, list_scan)? * PIXELS_X + x)
+    Some(spim_tdc.get_positional_index(dt, xspim, yspim, list_scan)? * Packet::chip_array().0 + x)
 }
 
 #[inline]
 pub fn get_return_spimindex(x: POSITION, dt: TIME, spim_tdc: &TdcRef, xspim: POSITION, yspim: POSITION, list_scan: SlType) -> Option<INDEXHYPERSPEC> {
-    Some(spim_tdc.get_return_positional_index(dt, xspim, yspim, list_scan)? * PIXELS_X + x)
+    Some(spim_tdc.get_return_positional_index(dt, xspim, yspim, list_scan)? * Packet::chip_array().0 + x)
 }
 
 #[inline]
 pub fn get_4dindex(x: POSITION, y: POSITION, dt: TIME, spim_tdc: &TdcRef, xspim: POSITION, yspim: POSITION, list_scan: SlType) -> Option<INDEX4D> {
-    Some(spim_tdc.get_positional_index(dt, xspim, yspim, list_scan)? as INDEX4D * (PIXELS_X * PIXELS_Y) as INDEX4D + (y * PIXELS_X + x)as INDEX4D)
+    let (width, height) = Packet::chip_array();
+    Some(spim_tdc.get_positional_index(dt, xspim, yspim, list_scan)? as INDEX4D * (width * height) as INDEX4D + (y * width + x) as INDEX4D)
 }
 
 #[inline]
 pub fn get_return_4dindex(x: POSITION, y: POSITION, dt: TIME, spim_tdc: &TdcRef, xspim: POSITION, yspim: POSITION, list_scan: SlType) -> Option<INDEX4D> {
-    Some(spim_tdc.get_return_positional_index(dt, xspim, yspim, list_scan)? as INDEX4D * (PIXELS_X * PIXELS_Y) as INDEX4D + (y * PIXELS_X + x) as INDEX4D)
+    let (width, height) = Packet::chip_array();
+    Some(spim_tdc.get_return_positional_index(dt, xspim, yspim, list_scan)? as INDEX4D * (width * height) as INDEX4D + (y * width + x) as INDEX4D)
 }
 
 #[inline]
 pub fn get_coincidence_spimindex(x: POSITION, dt: TIME, spim_tdc: &TdcRef, xspim: POSITION, yspim: POSITION, list_scan: SlType, my_settings: &Settings) -> Option<INDEX4D> {
-    Some(spim_tdc.get_positional_index(dt, xspim, yspim, list_scan)? as INDEX4D * (PIXELS_X as INDEX4D * my_settings.time_width * 2) + x as INDEX4D)
+    Some(spim_tdc.get_positional_index(dt, xspim, yspim, list_scan)? as INDEX4D * (Packet::chip_array().0 as INDEX4D * my_settings.time_width * 2) + x as INDEX4D)
 }
 
 ///It outputs list of indices (max `u32`) that
@@ -60,6 +70,8 @@ pub fn get_coincidence_spimindex(x: POSITION, dt: TIME, spim_tdc: &TdcRef, xspim
 pub struct Live {
     data: Vec<(POSITION, TIME)>,
     data_out: Vec<INDEXHYPERSPEC>,
+    edges: EdgePixels,
+    clustering: Clustering,
     _timer: Instant,
 }
 
@@ -136,30 +148,32 @@ impl SpimKind for Live {
         &self.data
     }
     #[inline]
-    fn add_electron_hit(&mut self, packet: &Packet, line_tdc: &TdcRef, ref_tdc: &TdcRef, set: &Settings) {
+    fn add_electron_hit(&mut self, packet: &Packet, time: TIME, line_tdc: &TdcRef, ref_tdc: &TdcRef, set: &Settings) {
         let ele_time = line_tdc.sync_electron_frame_time(packet).unwrap();
-        if set.time_resolved {
-            if ref_tdc.tr_electron_check_if_in(packet, set).is_some() {
-                self.data.push((packet.x(), ele_time)); //This added the overflow.
-            } 
-        } else {
-            self.data.push((packet.x(), ele_time)); //This added the overflow.
+        //The output is a list of indexes, so the gain of the pixel is applied by repeating it. The
+        //row is not kept in the data, so it must be done here.
+        let is_in = !set.time_resolved || ref_tdc.tr_electron_check_if_in(packet, set).is_some();
+        //A cluster has the time in the scan of its first hit.
+        let super_resolution = self.clustering.super_resolution();
+        for electron in self.clustering.add(packet, time, ele_time as usize, is_in) {
+            let x = self.edges.x(electron.x, electron.y) * super_resolution + electron.subpixel;
+            self.data.extend(std::iter::repeat_n((x, electron.index as TIME), electron.counts as usize)); //This added the overflow.
         }
     }
     fn build_main_tdc<V: TimepixRead>(&mut self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_periodic(MAIN_TDC, pack, my_settings, file_to_write)
+        TdcRef::new_periodic(config().main_tdc, pack, my_settings, file_to_write)
     }
     fn build_aux_tdc<V: TimepixRead>(&self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
         if my_settings.time_resolved {
-            TdcRef::new_periodic(SECONDARY_TDC, pack, my_settings, file_to_write)
+            TdcRef::new_periodic(config().secondary_tdc, pack, my_settings, file_to_write)
         } else {
-            TdcRef::new_no_read(SECONDARY_TDC)
+            TdcRef::new_no_read(config().secondary_tdc)
         }
     }
     fn add_tdc_hit(&mut self, packet: &Packet, line_tdc: &TdcRef, ref_tdc: &mut TdcRef) {
         ref_tdc.upt(packet);
         let tdc_time = line_tdc.sync_tdc_frame_time(packet).unwrap();
-        self.data.push((PIXELS_X-1, tdc_time));
+        self.data.push((Packet::chip_array().0 * self.clustering.super_resolution() - 1, tdc_time));
     }
     fn upt_line(&self, packet: &Packet, _settings: &Settings, line_tdc: &mut TdcRef) {
         line_tdc.upt(packet);
@@ -182,11 +196,14 @@ impl SpimKind for Live {
         //thus add the pixel address to correct reconstruct the spectral image
         //
         //index = index + x
+        //
+        //With super-resolution, every spatial point has SPIM_PIXELS * super_resolution channels.
         
         
+        let channels = Packet::chip_array().0 * self.clustering.super_resolution();
         self.data_out = self.data.iter()
             .filter_map(|&(x, dt)| {
-                get_spimindex(x, dt, spim_tdc, set.xspim_size, set.yspim_size, list_scan)
+                Some(spim_tdc.get_positional_index(dt, set.xspim_size, set.yspim_size, list_scan)? * channels + x)
             }).collect::<Vec<POSITION>>();
 
         /*
@@ -203,13 +220,22 @@ impl SpimKind for Live {
         true
     }
     fn copy_empty(&mut self) -> Self {
-        Live{ data: Vec::with_capacity(BUFFER_SIZE / 8) , data_out: Vec::new(), _timer: Instant::now()}
+        Live{ data: Vec::with_capacity(BUFFER_SIZE / 8) , data_out: Vec::new(), edges: std::mem::take(&mut self.edges), clustering: std::mem::take(&mut self.clustering), _timer: Instant::now()}
     }
     fn new(_settings: &Settings) -> Self {
-        Live{ data: Vec::with_capacity(BUFFER_SIZE / 8), data_out: Vec::new(), _timer: Instant::now()}
+        Live{ data: Vec::with_capacity(BUFFER_SIZE / 8), data_out: Vec::new(), edges: EdgePixels::default(), clustering: Clustering::new(engine::super_resolution()), _timer: Instant::now()}
     }
     fn ttx_index(&mut self, _ts: u64, _channel: i32, dt: Option<TIME>) {
-        self.data.push((PIXELS_X-1, dt.unwrap() / 260));
+        self.data.push((Packet::chip_array().0 * self.clustering.super_resolution() - 1, dt.unwrap() / 260));
+    }
+    fn flush_clusters(&mut self) -> bool {
+        let super_resolution = self.clustering.super_resolution();
+        let data_len = self.data.len();
+        for electron in self.clustering.flush() {
+            let x = self.edges.x(electron.x, electron.y) * super_resolution + electron.subpixel;
+            self.data.extend(std::iter::repeat_n((x, electron.index as TIME), electron.counts as usize));
+        }
+        self.data.len() > data_len
     }
 }
 
@@ -220,22 +246,22 @@ impl SpimKind for LiveCoincidence {
         &self.data
     }
     #[inline]
-    fn add_electron_hit(&mut self, packet: &Packet, line_tdc: &TdcRef, _ref_tdc: &TdcRef, set: &Settings) {
+    fn add_electron_hit(&mut self, packet: &Packet, _time: TIME, line_tdc: &TdcRef, _ref_tdc: &TdcRef, set: &Settings) {
         let ele_time = packet.electron_time_in_tdc_units();
         for phtime in self.aux_data.iter() {
             if (*phtime < ele_time + set.time_delay + set.time_width) && (ele_time + set.time_delay < *phtime + set.time_width) {
                 let delay = (phtime - set.time_delay + set.time_width - ele_time) as POSITION;
                 let ele_time_corr = line_tdc.sync_electron_frame_time(packet).unwrap();
-                let index = packet.x() + delay * PIXELS_X;
+                let index = packet.x() + delay * Packet::chip_array().0;
                 self.data.push((index, ele_time_corr)); //This added the overflow.
             }
         }
     }
     fn build_main_tdc<V: TimepixRead>(&mut self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_periodic(MAIN_TDC, pack, my_settings, file_to_write)
+        TdcRef::new_periodic(config().main_tdc, pack, my_settings, file_to_write)
     }
     fn build_aux_tdc<V: TimepixRead>(&self, _pack: &mut V, _my_settings: &Settings, _file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_no_read(SECONDARY_TDC)
+        TdcRef::new_no_read(config().secondary_tdc)
     }
     fn add_tdc_hit(&mut self, packet: &Packet, _line_tdc: &TdcRef, ref_tdc: &mut TdcRef) {
         ref_tdc.upt(packet);
@@ -273,24 +299,19 @@ impl SpimKind for Live4D {
         &self.data
     }
     #[inline]
-    fn add_electron_hit(&mut self, packet: &Packet, line_tdc: &TdcRef, ref_tdc: &TdcRef, set: &Settings) {
+    fn add_electron_hit(&mut self, packet: &Packet, _time: TIME, line_tdc: &TdcRef, _ref_tdc: &TdcRef, _set: &Settings) {
+        //The time-resolved electrons are selected by the `TimeGate` of the pipeline.
         let ele_time = line_tdc.sync_electron_frame_time(packet).unwrap();
-        if set.time_resolved {
-            if ref_tdc.tr_electron_check_if_in(packet, set).is_some() {
-                self.data.push(((packet.x() << 16) + (packet.y() & 65535), ele_time)); //This added the overflow.
-            } 
-        } else {
-            self.data.push(((packet.x() << 16) + (packet.y() & 65535), ele_time)); //This added the overflow.
-        }
+        self.data.push(((packet.x() << 16) + (packet.y() & 65535), ele_time)); //This added the overflow.
     }
     fn build_main_tdc<V: TimepixRead>(&mut self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_periodic(MAIN_TDC, pack, my_settings, file_to_write)
+        TdcRef::new_periodic(config().main_tdc, pack, my_settings, file_to_write)
     }
     fn build_aux_tdc<V: TimepixRead>(&self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
         if my_settings.time_resolved {
-            TdcRef::new_periodic(SECONDARY_TDC, pack, my_settings, file_to_write)
+            TdcRef::new_periodic(config().secondary_tdc, pack, my_settings, file_to_write)
         } else {
-            TdcRef::new_no_read(SECONDARY_TDC)
+            TdcRef::new_no_read(config().secondary_tdc)
         }
     }
     fn add_tdc_hit(&mut self, packet: &Packet, _line_tdc: &TdcRef, ref_tdc: &mut TdcRef) {
@@ -327,16 +348,16 @@ impl SpimKind for LiveFrame4D<MaskValues> {
         &self.data
     }
     #[inline]
-    fn add_electron_hit(&mut self, packet: &Packet, line_tdc: &TdcRef, _ref_tdc: &TdcRef, _set: &Settings) {
+    fn add_electron_hit(&mut self, packet: &Packet, _time: TIME, line_tdc: &TdcRef, _ref_tdc: &TdcRef, _set: &Settings) {
         let ele_time = line_tdc.sync_electron_frame_time(packet);
         self.data.push(((packet.x() << 16) + (packet.y() & 65535), ele_time.unwrap())); //This added the overflow.
 
     }
     fn build_main_tdc<V: TimepixRead>(&mut self, pack: &mut V, my_settings: &Settings, file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_periodic(MAIN_TDC, pack, my_settings, file_to_write)
+        TdcRef::new_periodic(config().main_tdc, pack, my_settings, file_to_write)
     }
     fn build_aux_tdc<V: TimepixRead>(&self, _pack: &mut V, _my_settings: &Settings, _file_to_write: &mut FileManager) -> Result<TdcRef, Tp3ErrorKind> {
-        TdcRef::new_no_read(SECONDARY_TDC)
+        TdcRef::new_no_read(config().secondary_tdc)
     }
     fn add_tdc_hit(&mut self, _packet: &Packet, _line_tdc: &TdcRef, _ref_tdc: &mut TdcRef) {
     }
@@ -346,18 +367,20 @@ impl SpimKind for LiveFrame4D<MaskValues> {
     #[inline]
     fn build_output(&mut self, set: &Settings, spim_tdc: &TdcRef, _list_scan: SlType) -> &[u8] {
 
+        let detector_limits = config().detector_limits;
         let channel_array_index = |x: POSITION, y: POSITION| -> usize
         {
-            (y * DETECTOR_SIZE.0 + (x - DETECTOR_LIMITS.0.0)) as usize
+            (y * DETECTOR_SIZE.0 + (x - detector_limits.0.0)) as usize
         };
         let is_inside = |x: POSITION, y: POSITION| -> bool {
-            (x > DETECTOR_LIMITS.0.0) && (x < DETECTOR_LIMITS.0.1) && (y > DETECTOR_LIMITS.1.0) && (y < DETECTOR_LIMITS.1.1)
+            (x > detector_limits.0.0) && (x < detector_limits.0.1) && (y > detector_limits.1.0) && (y < detector_limits.1.1)
         };
 
-        let mut frequency = vec![0i16; (set.xspim_size * set.yspim_size) as usize];
+        //The sums of a frame can exceed the 16 bits of the output, so they are done in 32 bits.
+        let mut frequency = vec![0i32; (set.xspim_size * set.yspim_size) as usize];
+        let mut sums = vec![0i32; self.data_out.len()];
         let number_of_masks = self.number_of_masks() as POSITION;
         let com = self.com;
-        let temp = &mut self.data_out;
         let temp2 = &self.channels;
 
 
@@ -370,22 +393,26 @@ impl SpimKind for LiveFrame4D<MaskValues> {
             .filter(|&(x, y, _index)| is_inside(x, y))
             .for_each(|(x, y, index)| {
                 frequency[index as usize] += 1;
-                temp[(index * number_of_masks) as usize + 0] += x as i16 - com.0;
-                temp[(index * number_of_masks) as usize + 1] += y as i16 - com.1;
+                sums[(index * number_of_masks) as usize + 0] += x as i32 - com.0 as i32;
+                sums[(index * number_of_masks) as usize + 1] += y as i32 - com.1 as i32;
                 for (channel_number, channel) in temp2.iter().enumerate() {
                     let value = channel.mask[channel_array_index(x, y)];
-                    temp[(index * number_of_masks) as usize + channel_number+2] += value;
+                    sums[(index * number_of_masks) as usize + channel_number+2] += value as i32;
                 }
                   
             });
         
         self.data_out
             .chunks_exact_mut(number_of_masks as usize)
+            .zip(sums.chunks_exact_mut(number_of_masks as usize))
             .zip(frequency.iter())
-            .for_each(|(chunk, frequency)| {
+            .for_each(|((chunk, sum), frequency)| {
                 if *frequency > 0 {
-                    chunk[0] /= frequency;
-                    chunk[1] /= frequency;
+                    sum[0] /= frequency;
+                    sum[1] /= frequency;
+                }
+                for (value, sum) in chunk.iter_mut().zip(sum.iter()) {
+                    *value = (*value as i32 + sum).clamp(MaskValues::MIN as i32, MaskValues::MAX as i32) as MaskValues;
                 }
             });
 
@@ -397,7 +424,7 @@ impl SpimKind for LiveFrame4D<MaskValues> {
         if is_new_frame {
             if self.debouncer { 
                 self.debouncer = false;
-                if self.timer.elapsed().as_millis() < TIME_INTERVAL_4DFRAMES {
+                if self.timer.elapsed().as_millis() < config().time_interval_4dframes as u128 {
                     self.data.clear();
                     return false
                 }
@@ -410,85 +437,102 @@ impl SpimKind for LiveFrame4D<MaskValues> {
     }
     fn copy_empty(&mut self) -> Self {
         let mut frame = LiveFrame4D{ data: Vec::with_capacity(BUFFER_SIZE / 8), data_out: vec![0; (self.scan_size.0 * self.scan_size.1) as usize * self.number_of_masks() as usize], com: self.com, scan_size: self.scan_size, channels: Vec::new(), debouncer: false, timer: Instant::now()};
-        let file = std::fs::File::open(MASK_FILE).unwrap();
+        let file = std::fs::File::open(&config().mask_file).unwrap();
         frame.create_mask(file).unwrap();
         frame.create_data_channels();
         frame
     }
     fn new(settings: &Settings) -> Self {
         let mut frame = LiveFrame4D{ data: Vec::with_capacity(BUFFER_SIZE / 8), data_out: Vec::new(), com: (0, 0), scan_size: (settings.xspim_size, settings.yspim_size), channels: Vec::new(), debouncer: false, timer: Instant::now()};
-        let file = std::fs::File::open(MASK_FILE).unwrap();
+        let file = std::fs::File::open(&config().mask_file).unwrap();
         frame.create_mask(file).unwrap();
         frame.create_data_channels();
         frame
     }
 }
 
-///Reads timepix3 socket and writes in the output socket a list of frequency followed by a list of unique indexes. First TDC must be a periodic reference, while the second can be nothing, periodic tdc or a non periodic tdc.
-pub fn build_spim<V, W, U>(mut pack_sock: V, mut ns_sock: U, my_settings: Settings, mut line_tdc: TdcRef, mut ref_tdc: TdcRef, mut meas_type: W, scan_list: SlType, mut file_to_write: FileManager, mut ttx: Option<ttx::TTXRef>) -> Result<(), Tp3ErrorKind>
+///A hyperspectral measurement that runs as an extra output of another measurement. It has no
+///scan list, and its frames are built in the thread of the output.
+pub struct SpimOutput<W, U> {
+    measurement: SpimAccumulator<W>,
+    tdcs: Tdcs,
+    settings: Settings,
+    sink: U,
+}
+
+impl<W: SpimKind, U: Sink> SpimOutput<W, U> {
+    pub fn new(settings: Settings, tdcs: Tdcs, sink: U) -> Self {
+        SpimOutput {measurement: SpimAccumulator(W::new(&settings)), tdcs, settings, sink}
+    }
+}
+
+impl<W: SpimKind + Send, U: Sink + Send> Output for SpimOutput<W, U> {
+    fn add_event(&mut self, raw: RawEvent) {
+        pipelinelib::dispatch(raw, &mut self.measurement, &mut self.tdcs, &self.settings);
+    }
+    fn send_if_ready(&mut self) -> std::io::Result<()> {
+        let SpimOutput {measurement, tdcs, settings, sink} = self;
+        if !measurement.0.is_ready(&tdcs.main) {return Ok(());}
+        let empty = measurement.0.copy_empty();
+        let mut tl = std::mem::replace(&mut measurement.0, empty);
+        let result = tl.build_output(settings, &tdcs.main, None);
+        sink.send(&[result])?;
+        Ok(())
+    }
+}
+
+///Reads the packets of `source` and writes in the output socket a list of frequency followed by a list of unique indexes. First TDC must be a periodic reference, while the second can be nothing, periodic tdc or a non periodic tdc.
+///The reader thread stops when the source is over or when `control` is stopped, and flushes the
+///files before returning.
+pub fn build_spim<V, W, U>(mut source: Source<V>, mut ns_sock: U, mut pipeline: Pipeline, meas_type: W, scan_list: SlType, control: Control) -> Result<(), Tp3ErrorKind>
     where V: 'static + Send + TimepixRead,
           W: 'static + Send + SpimKind,
-          U: 'static + Send + Write,
+          U: 'static + Send + Sink,
 {
     let (tx, rx) = mpsc::channel();
-    let mut last_ci = 0;
-    let mut buffer_pack_data = [0; BUFFER_SIZE];
-    let line_tdc_clone = line_tdc.clone();
+    let my_settings = *pipeline.settings();
+    let line_tdc_clone = pipeline.tdcs().main.clone();
 
     // Starting TTX
-    if let Some(in_ttx) = &mut ttx {
+    if let Some(in_ttx) = pipeline.ttx_mut() {
         in_ttx.add_channel(1, false, true, true); //Both edges ON
-        in_ttx.inform_scan_tdc(&mut line_tdc);
     };
-
-    thread::spawn(move || {
-        while let Ok(size) = pack_sock.read_timepix(&mut buffer_pack_data) {
-            file_to_write.write_all(&buffer_pack_data[0..size]).expect("Could not save data into file.");
-            build_spim_data(&mut meas_type, &buffer_pack_data[0..size], &mut last_ci, &my_settings, &mut line_tdc, &mut ref_tdc, &mut ttx);
-            if meas_type.is_ready(&line_tdc) {
-               let list2 = meas_type.copy_empty();
-               if tx.send(meas_type).is_err() {println!("Cannot send data over the thread channel."); break;}
-               meas_type = list2;
+    pipeline.inform_ttx();
+
+    let reader_control = control.clone();
+    let reader = thread::spawn(move || -> Result<(), Tp3ErrorKind> {
+        let mut meas_type = SpimAccumulator(meas_type);
+        while !reader_control.should_stop() {
+            let data = match source.next_buffer()? {
+                Some([]) => continue,
+                Some(data) => data,
+                None => break,
+            };
+            pipeline.process(data, &mut meas_type);
+            if meas_type.0.is_ready(&pipeline.tdcs().main) {
+               let list2 = meas_type.0.copy_empty();
+               if tx.send(std::mem::replace(&mut meas_type.0, list2)).is_err() {println!("Cannot send data over the thread channel."); break;}
             }
         }
+        //The last clusters are only complete once there is no more data.
+        if meas_type.0.flush_clusters() && tx.send(meas_type.0).is_err() {println!("Cannot send data over the thread channel.");}
+        source.finish()?;
+        pipeline.finish()?;
+        Ok(())
     });
  
     let start = Instant::now();
+    //The reader thread drops the sender when it is over, after flushing the files.
     for mut tl in rx {
+        if control.is_aborted() || control.reason() == StopReason::ClientDisconnected {continue;}
         let result = tl.build_output(&my_settings, &line_tdc_clone, scan_list);
-        if ns_sock.write(result).is_err() {println!("Client disconnected on data."); break;}
+        let write_start = Instant::now();
+        if ns_sock.send(&[result]).is_err() {println!("Client disconnected on data."); control.stop(StopReason::ClientDisconnected); continue;}
+        metrics().add_output_sent(result.len(), write_start.elapsed());
     }
+    reader.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))?;
 
     let elapsed = start.elapsed(); 
     println!("Total elapsed time is: {:?}.", elapsed);
     Ok(())
 }
-
-fn build_spim_data<W: SpimKind>(list: &mut W, data: &[u8], last_ci: &mut u8, settings: &Settings, line_tdc: &mut TdcRef, ref_tdc: &mut TdcRef, ttx: &mut Option<ttx::TTXRef>) {
-    data.chunks_exact(8).for_each(|x| {
-        match *x {
-            [84, 80, 88, 51, nci, _, _, _] => *last_ci = nci,
-            _ => {
-                let packet = Packet::new(*last_ci, packet_change(x)[0]);
-                let id = packet.id();
-                match id {
-                    11 => {
-                        list.add_electron_hit(&packet, line_tdc, ref_tdc, settings);
-                    },
-                    6 if packet.tdc_type() == line_tdc.id() => {
-                        list.upt_line(&packet, settings, line_tdc);
-                    },
-                    6 if packet.tdc_type() == ref_tdc.id()=> {
-                        list.add_tdc_hit(&packet, line_tdc, ref_tdc);
-                    },
-                    _ => {},
-                };
-            },
-        };
-    });
-
-    if let Some(in_ttx) = ttx {
-        in_ttx.inform_scan_tdc(line_tdc);
-        in_ttx.build_spim_data(list);
-    }
-}
@@ -1,12 +1,16 @@
 //!`tdclib` is a collection of tools to facilitate manipulation and choice of tdcs. Module is built
 //!in around `TdcType` enum.
 
+use serde::{Deserialize, Serialize};
 
 mod prepare_tdc {
     use crate::errorlib::Tp3ErrorKind;
     use crate::tdclib::TdcType;
     use crate::packetlib::Packet;
-    use crate::auxiliar::{misc::packet_change, value_types::*};
+    use crate::auxiliar::value_types::*;
+    use crate::readerlib::{Decoder, Event};
+    use crate::configlib::config;
+    use crate::constlib::OSCILLATOR_PERIOD_TOLERANCE;
 
     ///This struct is used to search for the tdc in case of periodic signals.
     pub struct TdcSearch<'a> {
@@ -71,12 +75,14 @@ mod prepare_tdc {
 
         fn check_ascending_order(&self) -> Result<(), Tp3ErrorKind> {
             let time_list = self.get_auto_timelist();
-            let result = time_list.iter().zip(time_list.iter().skip(1)).find(|(a, b)| a>b);
-            if result.is_some() {Err(Tp3ErrorKind::TdcNotAscendingOrder)}
-            else {Ok(())}
+            let result = time_list.iter().zip(time_list.iter().skip(1)).enumerate().find(|(_, (a, b))| a>b);
+            match result {
+                Some((index, (previous, next))) => Err(Tp3ErrorKind::TdcNotAscendingOrder {tdc_type: *self.tdc_choosen, index: index + 1, previous: *previous, next: *next}),
+                None => Ok(()),
+            }
         }
 
-        pub fn find_high_time(&self) -> Option<TIME> {
+        pub fn find_high_time(&self) -> Result<TIME, Tp3ErrorKind> {
             let fal_tdc_type = match self.tdc_choosen {
                 TdcType::TdcOneRisingEdge | TdcType::TdcOneFallingEdge => TdcType::TdcOneFallingEdge,
                 TdcType::TdcTwoRisingEdge | TdcType::TdcTwoFallingEdge => TdcType::TdcTwoFallingEdge,
@@ -91,7 +97,8 @@ mod prepare_tdc {
 
             let fal = self.get_timelist(&fal_tdc_type);
             let ris = self.get_timelist(&ris_tdc_type);
-            if fal.len() == 0 || ris.len() == 0 { return None };
+            let bad_high_time = Tp3ErrorKind::TdcBadHighTime {tdc_type: *self.tdc_choosen};
+            if fal.is_empty() || ris.is_empty() { return Err(bad_high_time) };
             
             let mut high_times = Vec::new();
             let mut fal_iter = fal.iter();
@@ -107,7 +114,8 @@ mod prepare_tdc {
                     current_fal = fal_iter.next();
                 }
             }
-            Some(high_times.iter().sum::<TIME>() / high_times.len() as TIME)
+            if high_times.is_empty() { return Err(bad_high_time) };
+            Ok(high_times.iter().sum::<TIME>() / high_times.len() as TIME)
         }
         
         pub fn find_period(&self) -> Result<f64, Tp3ErrorKind> {
@@ -116,7 +124,29 @@ mod prepare_tdc {
                 .skip(1))
                 .map(|(t0,t1)| t1 - t0)
                 .collect();
-            Ok(periods.iter().sum::<TIME>() as f64 / periods.len() as f64)
+            let mut sorted = periods.clone();
+            sorted.sort_unstable();
+            let expected = sorted[sorted.len() / 2];
+            let tolerance = config().tdc_period_tolerance * expected as f64;
+
+            //A missed edge gives a whole multiple of the period and is skipped. A spurious edge splits
+            //a period in two parts that are not.
+            let mut single_periods = Vec::new();
+            for observed in &periods {
+                let multiple = (*observed as f64 / expected as f64).round().max(1.0);
+                if (*observed as f64 - multiple * expected as f64).abs() > tolerance * multiple {
+                    return Err(Tp3ErrorKind::TdcBadPeriod {tdc_type: *self.tdc_choosen, expected, observed: *observed});
+                }
+                if multiple == 1.0 {
+                    single_periods.push(*observed);
+                }
+            }
+            let period = single_periods.iter().sum::<TIME>() as f64 / single_periods.len() as f64;
+            //The oscillator period is known in advance, as it is divided before reaching the TDC.
+            if config().period_divider > 1 && expected.abs_diff(config().blanking_period) >= OSCILLATOR_PERIOD_TOLERANCE {
+                return Err(Tp3ErrorKind::TdcBadPeriod {tdc_type: *self.tdc_choosen, expected: config().blanking_period, observed: expected});
+            }
+            Ok(period)
         }
         
         pub fn get_counter_offset(&self) -> COUNTER {
@@ -140,16 +170,12 @@ mod prepare_tdc {
         }
 
         pub fn search_specific_tdc(&mut self, data: &[u8]) {
-            data.chunks_exact(8).for_each(|x| {
-                match *x {
-                    [84, 80, 88, 51, _, _, _, _] => {},
-                    _ => {
-                        let packet = Packet::new(0, packet_change(x)[0]);
-                        if packet.id() == 6 && self.tdc_choosen.is_same_inputline(packet.tdc_type()) {
-                            self.add_tdc(&packet);
-                        }
-                    },
-                };
+            Decoder::new().decode(data).for_each(|raw| {
+                if let Event::Tdc(packet) = raw.event {
+                    if self.tdc_choosen.is_same_inputline(packet.tdc_type()) {
+                        self.add_tdc(&packet);
+                    }
+                }
             });
         }
     }
@@ -174,16 +200,10 @@ mod prepare_tdc {
             self.data.len() > self.how_many
         }
         pub fn search_for_electrons(&mut self, data: &[u8]) {
-            data.chunks_exact(8).for_each(|x| {
-                match *x {
-                    [84, 80, 88, 51, _, _, _, _] => {},
-                    _ => {
-                        let packet = Packet::new(0, packet_change(x)[0]);
-                        if packet.id() == 10 || packet.id() == 11 {
-                            self.add_electron(packet);
-                        }
-                    }
-                };
+            Decoder::new().decode(data).for_each(|raw| {
+                if let Event::Pixel(packet) | Event::FramePixel(packet) = raw.event {
+                    self.add_electron(packet);
+                }
             })
         }
         pub fn extract_amplitude(&mut self, percentile_min: f64, percentile_max: f64) -> (POSITION, POSITION) {
@@ -205,6 +225,7 @@ mod prepare_tdc {
 
 
 ///The four types of TDC's.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub enum TdcType {
     TdcOneRisingEdge,
     TdcOneFallingEdge,
@@ -213,18 +234,6 @@ pub enum TdcType {
     NoTdc,
 }
 
-impl Clone for TdcType {
-    fn clone(&self) -> TdcType {
-        match self {
-            TdcType::TdcOneRisingEdge => TdcType::TdcOneRisingEdge,
-            TdcType::TdcOneFallingEdge => TdcType::TdcOneFallingEdge,
-            TdcType::TdcTwoRisingEdge => TdcType::TdcTwoRisingEdge,
-            TdcType::TdcTwoFallingEdge => TdcType::TdcTwoFallingEdge,
-            TdcType::NoTdc => TdcType::NoTdc,
-        }
-    }
-}
-
 impl TdcType {
     ///Convenient method. Return value is the 4 bits associated to each TDC.
     pub fn associate_value(&self) -> u8 {
@@ -237,7 +246,7 @@ impl TdcType {
         }
     }
 
-    fn associate_str(&self) -> String {
+    pub(crate) fn associate_str(&self) -> String {
         match *self {
             TdcType::TdcOneRisingEdge => String::from("Tdc 01 Rising Edge"),
             TdcType::TdcOneFallingEdge => String::from("Tdc 01 Falling Edge"),
@@ -273,7 +282,10 @@ use crate::errorlib::Tp3ErrorKind;
 use crate::auxiliar::{Settings, misc::{check_if_in, TimepixRead}};
 use crate::auxiliar::{value_types::*, FileManager};
 use crate::constlib::*;
+use crate::metricslib::metrics;
+use crate::configlib::config;
 use crate::packetlib::Packet;
+use crate::timelib::TimestampExtender;
 use std::io::Write;
 
 #[derive(Debug, Clone)]
@@ -296,6 +308,9 @@ pub struct TdcRef {
     begin_frame: TIME,
     new_frame: bool,
     oscillator_size: Option<(POSITION, POSITION)>,
+    period_divider: TIME,
+    blanking_period: TIME,
+    timestamps: TimestampExtender, //Extends the TDC times to find the overflows.
 }
 
 impl TdcRef {
@@ -311,7 +326,12 @@ impl TdcRef {
         }
         self.last_hard_counter = hard_counter;
         self.counter = self.last_hard_counter as COUNTER + self.counter_overflow * 4096 - self.counter_offset;
-        let time_overflow = self.time > time;
+        let (overflows, previous) = (self.timestamps.overflows(), self.timestamps.latest());
+        let extended = self.timestamps.extend(time);
+        let time_overflow = self.timestamps.overflows() > overflows;
+        if let (Some(period), Some(dt)) = (self.period, extended.checked_sub(previous)) {
+            metrics().add_tdc_period(self.tdctype, period, dt);
+        }
         //if let Some(ticks) = self.ticks_to_frame {
         //    println!("very absolute time {}. absolut time {}. updating tdc {}. Counter is {}. Ticks to frame is {:?}. Line is {:?}", packet.tdc_time_abs(), time, time - self.time, self.counter, self.ticks_to_frame, (self.counter / 2) % (self.subsample * ticks));
         //}
@@ -393,6 +413,22 @@ impl TdcRef {
     //the last frame begin
     #[inline]
     pub fn get_positional_index(&self, dt: TIME, xspim: POSITION, yspim: POSITION, list_scan: SlType) -> Option<POSITION> {
+        let index = self.positional_index(dt, xspim, yspim, list_scan);
+        if index.is_none() && !self.is_flyback(dt, list_scan) {metrics().add_dropped_index();}
+        index
+    }
+
+    //Electrons during the return of the beam are left out on purpose, so they are not lost.
+    #[inline]
+    fn is_flyback(&self, dt: TIME, list_scan: SlType) -> bool {
+        match (self.period, self.low_time) {
+            (Some(period), Some(low_time)) => REMOVE_RETURN && list_scan.is_none() && dt % period >= low_time,
+            _ => false,
+        }
+    }
+
+    #[inline]
+    fn positional_index(&self, dt: TIME, xspim: POSITION, yspim: POSITION, list_scan: SlType) -> Option<POSITION> {
         if let Some(custom_list) = list_scan {
          
             //exceding time is always with respect to the current pixel time, so this values varies
@@ -516,11 +552,11 @@ impl TdcRef {
         //This case TDC time is always greater than electron time
         let xper;
         let eff_tdc = if last_tdc_time > time {
-            xper = ((last_tdc_time - time) * PERIOD_DIVIDER) / period;
-            last_tdc_time - (xper * period) / PERIOD_DIVIDER
+            xper = ((last_tdc_time - time) * self.period_divider) / period;
+            last_tdc_time - (xper * period) / self.period_divider
         } else {
-            xper = ((time - last_tdc_time) * PERIOD_DIVIDER) / period + 1;
-            last_tdc_time + (xper * period) / PERIOD_DIVIDER
+            xper = ((time - last_tdc_time) * self.period_divider) / period + 1;
+            last_tdc_time + (xper * period) / self.period_divider
         };
         eff_tdc
     } 
@@ -570,8 +606,8 @@ impl TdcRef {
 
             let eff_tdc = self.get_closest_tdc(ele_time, offset);
             let delta = eff_tdc - ele_time;
-            let quarter_period = ((delta * 4 * PERIOD_DIVIDER) / BLANKING_PERIOD) as usize;
-            //let quarter_period_frac = ((delta * 4 * PERIOD_DIVIDER) as f64 / BLANKING_PERIOD as f64).fract();
+            let quarter_period = ((delta * 4 * self.period_divider) / self.blanking_period) as usize;
+            //let quarter_period_frac = ((delta * 4 * self.period_divider) as f64 / self.blanking_period as f64).fract();
             //if quarter_period_frac < 0.25 || quarter_period_frac > 0.75 {
             //    return None
             //}
@@ -581,8 +617,8 @@ impl TdcRef {
 
             const PI: f64 = std::f64::consts::PI;
             const INV_PI: f64 = 1.0 / PI;
-            const QUADRANT_SIZE: f64 = (BLANKING_PERIOD as f64 / PERIOD_DIVIDER as f64) / 4.0;
-            const SCALE: f64 = QUADRANT_SIZE * INV_PI;
+            let quadrant_size: f64 = (self.blanking_period as f64 / self.period_divider as f64) / 4.0;
+            let scale: f64 = quadrant_size * INV_PI;
             //const SCALE: f64 = 24.0 * INV_PI;
             const HALF_PI: f64 = PI / 2.0;
 
@@ -601,10 +637,10 @@ impl TdcRef {
             }
     
             let y_corr = match quarter_period {
-                0 => ((y_normalized.asin() + HALF_PI) * SCALE) - 1.0 * QUADRANT_SIZE,
-                1 => (y_normalized.acos() * SCALE) - 2.0 * QUADRANT_SIZE,
-                2 => ((y_normalized.asin() + HALF_PI) * SCALE) - 3.0 * QUADRANT_SIZE,
-                3 => (y_normalized.acos() * SCALE) - 4.0 * QUADRANT_SIZE,
+                0 => ((y_normalized.asin() + HALF_PI) * scale) - 1.0 * quadrant_size,
+                1 => (y_normalized.acos() * scale) - 2.0 * quadrant_size,
+                2 => ((y_normalized.asin() + HALF_PI) * scale) - 3.0 * quadrant_size,
+                3 => (y_normalized.acos() * scale) - 4.0 * quadrant_size,
                 _ => return None,
             };
             
@@ -626,7 +662,7 @@ impl TdcRef {
 
         println!("***Tdc Lib***: Searching for Tdc: {}.", tdc_type.associate_str());
         loop {
-            if start.elapsed() > Duration::from_secs(TDC_TIMEOUT) {return Err(Tp3ErrorKind::TdcNoReceived)}
+            if start.elapsed() > Duration::from_secs(config().tdc_timeout) {return Err(Tp3ErrorKind::TdcNoReceived {tdc_type, timeout: config().tdc_timeout})}
             if let Ok(size) = sock.read_timepix(&mut buffer_pack_data) {
                 file_to_write.write_all(&buffer_pack_data[0..size])?;
                 tdc_search.search_specific_tdc(&buffer_pack_data[0..size]);
@@ -638,8 +674,7 @@ impl TdcRef {
         //tick.
         
         //These are the modes that we use the spatia resolution:
-        let mode = my_settings.mode;
-        let ticks_to_frame = if mode == 2 || mode == 3 || mode == 12 || mode == 13 || mode == 14 {
+        let ticks_to_frame = if my_settings.mode.is_spatial() {
             Some(my_settings.yspim_size)
         } else {
             None
@@ -649,21 +684,27 @@ impl TdcRef {
         let counter_offset = tdc_search.get_counter_offset();
         let begin_time = tdc_search.get_begintime();
         let last_time = tdc_search.get_lasttime();
-        let period = tdc_search.find_period()? as TIME;
         let period_float = tdc_search.find_period()?;
-        let high_time = tdc_search.find_high_time().map(|time| time);
-        let low_time = tdc_search.find_high_time().map(|time| period - time);
+        let period = period_float as TIME;
+        //Only the spatial modes need the high time, to place the electrons in the line.
+        let high_time = match tdc_search.find_high_time() {
+            Ok(time) if time >= period => return Err(Tp3ErrorKind::TdcBadHighTime {tdc_type}),
+            Ok(time) => Some(time),
+            Err(error) if ticks_to_frame.is_some() => return Err(error),
+            Err(_) => None,
+        };
+        let low_time = high_time.map(|time| period - time);
 
 
         //If the TDC is periodic, we check if the fast oscillator is ON.
         let mut oscillator_size: Option<(POSITION, POSITION)> = None;
-        if ((period as i64 - BLANKING_PERIOD as i64).abs() as TIME) < 100 {
+        if period.abs_diff(config().blanking_period) < OSCILLATOR_PERIOD_TOLERANCE {
             println!("***Tdc Lib***: The fast oscillator has been detected.");
             println!("***Tdc Lib***: Estimating the values of the beam...");
             let mut osc_estimate = prepare_tdc::OscillatorEstimate::new(NUMBER_OF_ELECTRONS_FOR_OSCILLATOR);
             let start = Instant::now();
             loop {
-                if start.elapsed() > Duration::from_secs(TDC_TIMEOUT) {return Err(Tp3ErrorKind::TdcNoReceived)}
+                if start.elapsed() > Duration::from_secs(config().tdc_timeout) {return Err(Tp3ErrorKind::TdcNoReceived {tdc_type, timeout: config().tdc_timeout})}
                 if let Ok(size) = sock.read_timepix(&mut buffer_pack_data) {
                     file_to_write.write_all(&buffer_pack_data[0..size])?;
                     osc_estimate.search_for_electrons(&buffer_pack_data[0..size]);
@@ -693,6 +734,9 @@ impl TdcRef {
             new_frame: false,
             time: last_time,
             oscillator_size,
+            period_divider: config().period_divider,
+            blanking_period: config().blanking_period,
+            timestamps: TimestampExtender::new(ELECTRON_OVERFLOW_IN_TDC_UNITS).starting_at(last_time),
         };
         println!("***Tdc Lib***: Creating a new tdc reference: {:?}.", per_ref);
         Ok(per_ref)
@@ -722,6 +766,9 @@ impl TdcRef {
             new_frame: false,
             time: last_time,
             oscillator_size: None,
+            period_divider: config().period_divider,
+            blanking_period: config().blanking_period,
+            timestamps: TimestampExtender::new(ELECTRON_OVERFLOW_IN_TDC_UNITS).starting_at(last_time),
         };
         println!("***Tdc Lib***: Creating a new tdc reference: {:?}.", per_ref);
         Ok(per_ref)
@@ -737,6 +784,7 @@ pub mod isi_box {
     use std::thread;
     use std::time::Duration;
     use crate::constlib::*;
+    use crate::configlib::config;
 
     pub trait IsiBoxTools {
         fn bind_and_connect(&mut self) -> Result<(), Tp3ErrorKind>;
@@ -783,15 +831,15 @@ pub mod isi_box {
             impl IsiBoxTools for $x<$y> {
                 fn bind_and_connect(&mut self) -> Result<(), Tp3ErrorKind>{
                     for _ in 0..self.nchannels {
-                        let sock = match TcpStream::connect(ISI_IP_PORT) {
+                        let sock = match TcpStream::connect(config().isi_ip_port.as_str()) {
                             Ok(val) => val,
-                            Err(_) => return Err(Tp3ErrorKind::IsiBoxCouldNotConnect),
+                            Err(source) => return Err(Tp3ErrorKind::IsiBoxCouldNotConnect {address: config().isi_ip_port.clone(), source}),
                         };
                         self.sockets.push(sock);
                     }
-                    let sock = match TcpStream::connect(ISI_IP_PORT) {
+                    let sock = match TcpStream::connect(config().isi_ip_port.as_str()) {
                         Ok(val) => val,
-                        Err(_) => return Err(Tp3ErrorKind::IsiBoxCouldNotConnect),
+                        Err(source) => return Err(Tp3ErrorKind::IsiBoxCouldNotConnect {address: config().isi_ip_port.clone(), source}),
                     };
                     self.ext_socket = Some(sock);
                     Ok(())
@@ -804,7 +852,7 @@ pub mod isi_box {
                     let mut sock = &self.sockets[0];
                     match sock.write(as_bytes(&config_array)) {
                         Ok(size) => {println!("data sent to configure scan parameters: {}", size);},
-                        Err(_) => {return Err(Tp3ErrorKind::IsiBoxCouldNotSetParameters);},
+                        Err(source) => {return Err(Tp3ErrorKind::IsiBoxCouldNotSetParameters(source));},
                     };
                     Ok(())
                 }
@@ -815,7 +863,7 @@ pub mod isi_box {
                     let mut sock = &self.sockets[0];
                     match sock.write(as_bytes(&config_array)) {
                         Ok(size) => {println!("data sent to configure the measurement type: {}", size);},
-                        Err(_) => {return Err(Tp3ErrorKind::IsiBoxCouldNotConfigure);},
+                        Err(source) => {return Err(Tp3ErrorKind::IsiBoxCouldNotConfigure(source));},
                     };
                     Ok(())
                 }
@@ -0,0 +1,330 @@
+//!`timelib` is a collection of tools to extend the timestamps of the Timepix3 into 64-bit absolute
+//!times. The electron ToA wraps around every `ELECTRON_OVERFLOW` (34 bits of 1.5625 ns) and the
+//!TDC times are normalized to the same period, so both wrap every `ELECTRON_OVERFLOW_IN_TDC_UNITS`.
+//!
+//!Packets are not strictly in time order, as every chip sends its data independently. A packet up
+//!to a tolerance behind the latest time is taken as late, and one further behind as a new period.
+//!`ChipTimestamps` keeps one `TimestampExtender` per chip and one for the TDCs, so a late chip does
+//!not move the time of the others. Extended times are in TDC units (0.260 ns).
+//!
+//!The global time packets carry the full 48-bit time of a chip. When they are present, they
+//!correct the clocks that are off by whole periods (when the acquisition did not start at zero or
+//!data was lost), and `SyncMonitor` compares the chips to find desynchronized chips and uses the
+//!SPIDR packet ids to count the packets dropped.
+use crate::auxiliar::value_types::*;
+use crate::constlib::{ELECTRON_OVERFLOW_IN_TDC_UNITS, GLOBAL_TIME_IN_TDC_UNITS, CHIP_DESYNC_IN_TDC_UNITS};
+use crate::packetlib::{ControlPacket, Packet};
+use crate::readerlib::Event;
+
+pub const TDC_UNITS_PER_US: TIME = 3_840;
+
+///Converts a time in TDC units to picoseconds. A TDC unit is 1/3.84 GHz, or 3125/12 ps.
+pub fn to_picoseconds(time: TIME) -> TIME {
+    (time as u128 * 3125 / 12) as TIME
+}
+
+///Extends the timestamps of a single clock.
+#[derive(Copy, Clone, Debug)]
+pub struct TimestampExtender {
+    period: TIME,
+    tolerance: TIME,
+    overflow: TIME,
+    latest: TIME,
+    absolute: bool, //Set by a global time, so the period is known.
+}
+
+impl TimestampExtender {
+    ///An extender for timestamps wrapping around every `period`. The tolerance starts at half of
+    ///the period, the largest that is not ambiguous.
+    pub fn new(period: TIME) -> Self {
+        TimestampExtender {period, tolerance: period / 2, overflow: 0, latest: 0, absolute: false}
+    }
+
+    ///Sets how far behind the latest time a packet can be and still be taken as late.
+    pub fn with_tolerance(mut self, tolerance: TIME) -> Self {
+        self.tolerance = tolerance.min(self.period / 2);
+        self
+    }
+
+    ///An extender with the same settings, starting at the absolute time `latest`.
+    pub fn starting_at(&self, latest: TIME) -> Self {
+        TimestampExtender {overflow: latest / self.period * self.period, latest, ..*self}
+    }
+
+    ///Extends a timestamp.
+    pub fn extend(&mut self, time: TIME) -> TIME {
+        let mut time = self.overflow + time;
+        if time + self.tolerance < self.latest {
+            //The timestamp wrapped around.
+            self.overflow += self.period;
+            time += self.period;
+        } else if time >= self.period && time - self.period <= self.latest && self.latest - (time - self.period) <= self.tolerance {
+            //A late packet from before the wrap.
+            time -= self.period;
+        }
+        self.latest = self.latest.max(time);
+        time
+    }
+
+    ///The latest extended time.
+    pub fn latest(&self) -> TIME {
+        self.latest
+    }
+
+    ///How many times the timestamps wrapped around.
+    pub fn overflows(&self) -> TIME {
+        self.overflow / self.period
+    }
+
+    //Moves the clock to a global time if it is more than half a period away from it.
+    fn anchor(&mut self, time: TIME) -> bool {
+        let wrong = self.latest.abs_diff(time) > self.period / 2;
+        if wrong {
+            *self = self.starting_at(time);
+        }
+        self.absolute = true;
+        wrong
+    }
+
+    fn shifted(&self, offset: i64) -> Self {
+        if self.absolute {return *self;}
+        let shift = |time: TIME| (time as i64 + offset) as TIME;
+        TimestampExtender {overflow: shift(self.overflow), latest: shift(self.latest), ..*self}
+    }
+}
+
+//The value of the chip `ci`, growing the list if needed.
+fn chip_slot<T: Clone + Default>(values: &mut Vec<T>, ci: u8) -> &mut T {
+    let ci = ci as usize;
+    if values.len() <= ci {
+        values.resize(ci + 1, T::default());
+    }
+    &mut values[ci]
+}
+
+///Follows the global time and the SPIDR packet ids of every chip.
+#[derive(Clone, Debug, Default)]
+pub struct SyncMonitor {
+    global_low: Vec<Option<TIME>>, //The 0x44 packet waiting for its 0x45
+    offsets: Vec<Option<i64>>, //Last global time of each chip minus the time of the stream
+    packet_ids: Vec<Option<u64>>,
+    global_times: u64,
+    corrections: u64,
+    desyncs: u64,
+    dropped_packets: u64,
+    max_spread: TIME,
+}
+
+impl SyncMonitor {
+    //Follows a control packet. Returns the global time of the chip, in TDC units, once both of
+    //its packets are received.
+    fn add(&mut self, packet: &Packet) -> Option<TIME> {
+        let ci = packet.ci();
+        match packet.control()? {
+            ControlPacket::GlobalTimeLow(low) => {
+                *chip_slot(&mut self.global_low, ci) = Some(low);
+                None
+            },
+            ControlPacket::GlobalTimeHigh(high) => {
+                let low = chip_slot(&mut self.global_low, ci).take()?;
+                let time = ((high << 32) | low) * GLOBAL_TIME_IN_TDC_UNITS;
+                self.global_times += 1;
+                Some(time)
+            },
+            ControlPacket::PacketId(id) => {
+                let last = chip_slot(&mut self.packet_ids, ci).replace(id);
+                if let Some(last) = last.filter(|last| id > last + 1) {
+                    self.dropped_packets += id - last - 1;
+                }
+                None
+            },
+            _ => None,
+        }
+    }
+
+    //Compares the global time of a chip to the others. `stream_time` is the latest time of the
+    //data, so chips whose global time is off from the data by different amounts are desynchronized.
+    fn compare(&mut self, ci: u8, time: TIME, stream_time: TIME) {
+        *chip_slot(&mut self.offsets, ci) = Some(time as i64 - stream_time as i64);
+        let known = self.offsets.iter().flatten();
+        if let (Some(max), Some(min)) = (known.clone().max(), known.min()) {
+            let spread = max.abs_diff(*min);
+            self.max_spread = self.max_spread.max(spread);
+            if spread > CHIP_DESYNC_IN_TDC_UNITS {
+                self.desyncs += 1;
+            }
+        }
+    }
+
+    ///Adds the counters of a monitor that followed another part of the stream, such as another
+    ///chunk of a file.
+    pub fn merge(&mut self, other: &SyncMonitor) {
+        self.global_times += other.global_times;
+        self.corrections += other.corrections;
+        self.desyncs += other.desyncs;
+        self.dropped_packets += other.dropped_packets;
+        self.max_spread = self.max_spread.max(other.max_spread);
+    }
+
+    ///Number of complete global times received.
+    pub fn global_times(&self) -> u64 {
+        self.global_times
+    }
+
+    ///Number of clocks moved by a global time.
+    pub fn corrections(&self) -> u64 {
+        self.corrections
+    }
+
+    ///Number of global times received while the chips were desynchronized.
+    pub fn desyncs(&self) -> u64 {
+        self.desyncs
+    }
+
+    ///Number of packets missing between the SPIDR packet ids.
+    pub fn dropped_packets(&self) -> u64 {
+        self.dropped_packets
+    }
+
+    ///Largest difference between the clocks of two chips, in TDC units.
+    pub fn max_spread(&self) -> TIME {
+        self.max_spread
+    }
+}
+
+///Extends the electron and TDC timestamps of a detector, with one clock per chip.
+#[derive(Clone, Debug)]
+pub struct ChipTimestamps {
+    reference: TimestampExtender, //Settings of the clocks
+    tdc: Option<TimestampExtender>,
+    electrons: Vec<Option<TimestampExtender>>, //Indexed by the chip index
+    latest: TIME,
+    first: Option<(Option<u8>, TIME, TIME)>, //The clock, the timestamp and its extended time
+    sync: SyncMonitor,
+}
+
+impl Default for ChipTimestamps {
+    fn default() -> Self {
+        ChipTimestamps::new()
+    }
+}
+
+impl ChipTimestamps {
+    pub fn new() -> Self {
+        ChipTimestamps {
+            reference: TimestampExtender::new(ELECTRON_OVERFLOW_IN_TDC_UNITS),
+            tdc: None,
+            electrons: Vec::new(),
+            latest: 0,
+            first: None,
+            sync: SyncMonitor::default(),
+        }
+    }
+
+    ///Sets how late a packet can be. See `TimestampExtender::with_tolerance`.
+    pub fn with_tolerance(mut self, tolerance: TIME) -> Self {
+        self.reference = self.reference.with_tolerance(tolerance);
+        self
+    }
+
+    fn clocks(&self) -> impl Iterator<Item = &TimestampExtender> {
+        self.tdc.iter().chain(self.electrons.iter().flatten())
+    }
+
+    //The clock of the chip `ci`, or of the TDCs if `None`. A new clock starts at the latest time
+    //of the others, so a chip that starts late is not put in the first period.
+    fn clock(&mut self, ci: Option<u8>) -> &mut TimestampExtender {
+        let mut reference = self.reference.starting_at(self.latest);
+        reference.absolute = self.clocks().any(|clock| clock.absolute);
+        let clock = match ci {
+            None => &mut self.tdc,
+            Some(ci) => chip_slot(&mut self.electrons, ci),
+        };
+        clock.get_or_insert(reference)
+    }
+
+    fn extend(&mut self, ci: Option<u8>, time: TIME) -> TIME {
+        let clock = self.clock(ci);
+        let absolute = clock.absolute;
+        let extended = clock.extend(time);
+        self.latest = self.latest.max(extended);
+        if !absolute {
+            self.first.get_or_insert((ci, time, extended));
+        }
+        extended
+    }
+
+    ///Extended time of an electron, in TDC units.
+    pub fn electron(&mut self, packet: &Packet) -> TIME {
+        self.extend(Some(packet.ci()), packet.electron_time_in_tdc_units())
+    }
+
+    ///Extended time of a TDC.
+    pub fn tdc(&mut self, packet: &Packet) -> TIME {
+        self.extend(None, packet.tdc_time_abs_norm())
+    }
+
+    ///Follows a global time or control packet. A global time moves the clocks of its chip and of
+    ///the TDCs if they are off by whole periods.
+    pub fn control(&mut self, packet: &Packet) {
+        if let Some(time) = self.sync.add(packet) {
+            let has_data = self.electrons.get(packet.ci() as usize).is_some_and(Option::is_some);
+            for ci in [Some(packet.ci()), None] {
+                if self.clock(ci).anchor(time) {
+                    self.sync.corrections += 1;
+                }
+            }
+            self.latest = self.clocks().map(|clock| clock.latest).max().unwrap_or(time);
+            //Chips without data cannot be compared to the stream.
+            if has_data {
+                self.sync.compare(packet.ci(), time, self.latest);
+            }
+        }
+    }
+
+    ///Extended time of an event, if it has one. Global time and control packets are followed.
+    pub fn event(&mut self, event: &Event) -> Option<TIME> {
+        match event {
+            Event::Pixel(packet) | Event::FramePixel(packet) => Some(self.electron(packet)),
+            Event::Tdc(packet) => Some(self.tdc(packet)),
+            Event::GlobalTime(packet) | Event::Control(packet) => {
+                self.control(packet);
+                None
+            },
+            _ => None,
+        }
+    }
+
+    ///The latest extended time of all clocks.
+    pub fn latest(&self) -> TIME {
+        self.latest
+    }
+
+    pub fn sync(&self) -> &SyncMonitor {
+        &self.sync
+    }
+
+    ///Continues these clocks with `next`, which extended the following packets starting from
+    ///zero. Used to extend the chunks of a file independently. Clocks set by a global time in
+    ///`next` are already absolute and are kept as they are.
+    pub fn chain(&self, next: &ChipTimestamps) -> ChipTimestamps {
+        let offset = match next.first {
+            Some((ci, time, extended)) => self.clone().clock(ci).extend(time) as i64 - extended as i64,
+            None => 0,
+        };
+        let merge = |own: &Option<TimestampExtender>, next: &Option<TimestampExtender>| {
+            next.map(|clock| clock.shifted(offset)).or(*own)
+        };
+        let length = self.electrons.len().max(next.electrons.len());
+        let mut chained = ChipTimestamps {
+            reference: self.reference,
+            tdc: merge(&self.tdc, &next.tdc),
+            electrons: (0..length).map(|ci| merge(self.electrons.get(ci).unwrap_or(&None), next.electrons.get(ci).unwrap_or(&None))).collect(),
+            latest: self.latest,
+            first: self.first.or(next.first.map(|(ci, time, extended)| (ci, time, (extended as i64 + offset) as TIME))),
+            sync: self.sync.clone(),
+        };
+        chained.latest = chained.clocks().map(|clock| clock.latest).fold(self.latest, TIME::max);
+        chained
+    }
+}
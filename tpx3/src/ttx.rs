@@ -182,6 +182,13 @@ impl TTXRef {
         self.is_running = false;
     }
 
+    ///Stops the stream and flushes the timestamp files.
+    pub fn finish(&mut self) -> std::io::Result<()> {
+        self.stop_stream();
+        self.ts_file.finish()?;
+        self.ch_file.finish()
+    }
+
     pub fn add_channel(&mut self, channel: i32, is_test: bool, both_edges: bool, is_periodic: bool) {
         self.ttx.add_channel(channel, is_test);
         if both_edges {
@@ -0,0 +1,203 @@
+//!`walklib` is a collection of tools to correct the time walk of the electrons. The time of arrival
+//!of a hit is taken when its signal crosses the threshold, so the hits with a small ToT arrive
+//!late. `TimeWalk` holds this delay as a curve over the 1024 ToT values and, optionally, a delay
+//!for every pixel. Both are in TDC units (0.260 ns) and relative to the hits of large ToT. They are
+//!subtracted from the electron times in `SingleElectron::corrected_time`, so they reach the `tcorH`
+//!column of `postlib::coincidence`, and in the live `speclib::Coincidence2D` histogram.
+//!
+//!The correction is fitted from the events of `postlib::coincidence`, in which the photon TDC is
+//!the reference (see `WalkSample`). For every ToT, the peak of the electron minus photon times
+//!gives the curve; pixels with enough hits take the median of what is left around the peak as their
+//!delay. It is saved as JSON. Set `config().time_walk_file` to use it.
+use crate::auxiliar::value_types::*;
+use crate::configlib::config;
+use crate::errorlib::{Tp3ErrorKind, PathContext};
+use crate::eventlib::EventReader;
+use crate::packetlib::Packet;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::Path;
+use std::sync::OnceLock;
+
+static TIME_WALK: OnceLock<Option<TimeWalk>> = OnceLock::new();
+
+///The ToT has 10 bits.
+pub const TOT_VALUES: usize = 1024;
+///Hits a ToT value (or a pixel) needs to be fitted.
+const MIN_HITS: usize = 10;
+///Half width, in bins, of the centroid around the peak of every ToT value.
+const PEAK_HALF_WIDTH: usize = 2;
+///Largest range of electron minus photon times that is fitted, in TDC units.
+const MAX_RANGE: i64 = 1 << 16;
+
+///An electron coincident with a photon. `dt` is the electron time minus the photon time.
+#[derive(Copy, Clone, Debug)]
+pub struct WalkSample {
+    pub x: POSITION,
+    pub y: POSITION,
+    pub tot: u16,
+    pub dt: i64,
+}
+
+///Reads the columns `xH`, `yH`, `tot` and `tH` of a coincidence event file.
+pub fn samples_from_events<P: AsRef<Path>>(path: P) -> Result<Vec<WalkSample>, Tp3ErrorKind> {
+    let mut reader = EventReader::open(path)?;
+    let x = reader.read_column::<u16>("xH")?;
+    let y = reader.read_column::<u16>("yH")?;
+    let tot = reader.read_column::<u16>("tot")?;
+    let dt = reader.read_column::<i16>("tH")?;
+    Ok((0..x.len()).map(|index| WalkSample {x: x[index] as POSITION, y: y[index] as POSITION, tot: tot[index], dt: dt[index] as i64}).collect())
+}
+
+///Delay of the hits by their ToT and, optionally, by their pixel.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimeWalk {
+    curve: Vec<f32>, //One value per ToT
+    offsets: Option<Vec<f32>>, //Indexed by `x + width * y`
+}
+
+impl TimeWalk {
+    pub fn from_curve(curve: Vec<f32>) -> Result<Self, Tp3ErrorKind> {
+        if curve.len() != TOT_VALUES {
+            return Err(Tp3ErrorKind::WalkBadCorrection {reason: "the curve must have a value for every ToT"});
+        }
+        Ok(TimeWalk {curve, offsets: None})
+    }
+
+    pub fn curve(&self) -> &[f32] {
+        &self.curve
+    }
+
+    pub fn has_pixel_offsets(&self) -> bool {
+        self.offsets.is_some()
+    }
+
+    ///Delay, in TDC units, of a hit at (`x`, `y`).
+    #[inline]
+    pub fn delay(&self, x: POSITION, y: POSITION, tot: u16) -> f32 {
+        let offset = self.offsets.as_ref().map_or(0.0, |offsets| offsets[(x + Packet::chip_array().0 * y) as usize]);
+        self.curve[(tot as usize).min(TOT_VALUES - 1)] + offset
+    }
+
+    ///Subtracts the delay of an electron packet from its time, in TDC units.
+    #[inline]
+    pub fn correct(&self, packet: &Packet, time: TIME) -> TIME {
+        let delay = self.delay(packet.x(), packet.y(), packet.tot()).round() as i64;
+        (time as i64 - delay).max(0) as TIME
+    }
+
+    ///Fits the curve and, if `per_pixel`, the delay of every pixel.
+    pub fn fit(samples: &[WalkSample], per_pixel: bool) -> Result<Self, Tp3ErrorKind> {
+        let min = samples.iter().map(|sample| sample.dt).min().ok_or(Tp3ErrorKind::WalkBadCorrection {reason: "there are no coincidences"})?;
+        let max = samples.iter().map(|sample| sample.dt).max().unwrap_or(min);
+        if max - min >= MAX_RANGE {
+            return Err(Tp3ErrorKind::WalkBadCorrection {reason: "the coincidence window is too large"});
+        }
+        let range = (max - min + 1) as usize;
+
+        //A histogram of the times for every ToT. Its peak is the delay, even over a flat background.
+        let mut histogram = vec![0_u32; TOT_VALUES * range];
+        let mut hits = vec![0_usize; TOT_VALUES];
+        for sample in samples {
+            let tot = (sample.tot as usize).min(TOT_VALUES - 1);
+            histogram[tot * range + (sample.dt - min) as usize] += 1;
+            hits[tot] += 1;
+        }
+        let peaks: Vec<(usize, f32)> = (0..TOT_VALUES).filter(|tot| hits[*tot] >= MIN_HITS).map(|tot| {
+            let row = &histogram[tot * range..(tot + 1) * range];
+            let peak = (0..range).max_by_key(|bin| row[*bin]).unwrap_or(0);
+            let bins = peak.saturating_sub(PEAK_HALF_WIDTH)..(peak + PEAK_HALF_WIDTH + 1).min(range);
+            let weight: u32 = row[bins.clone()].iter().sum();
+            let centroid = bins.map(|bin| bin as f32 * row[bin] as f32).sum::<f32>() / weight as f32;
+            (tot, centroid + min as f32)
+        }).collect();
+        if peaks.len() < 2 {
+            return Err(Tp3ErrorKind::WalkBadCorrection {reason: "two ToT values need enough coincidences"});
+        }
+
+        //Values between the fitted ToT are interpolated, and the ones out of them are constant.
+        let mut curve: Vec<f32> = (0..TOT_VALUES).map(|tot| {
+            match peaks.iter().position(|(fitted, _)| *fitted >= tot) {
+                Some(0) => peaks[0].1,
+                Some(next) => {
+                    let ((tot0, value0), (tot1, value1)) = (peaks[next - 1], peaks[next]);
+                    value0 + (value1 - value0) * (tot - tot0) as f32 / (tot1 - tot0) as f32
+                },
+                None => peaks[peaks.len() - 1].1,
+            }
+        }).collect();
+        //The reference is the mean over the largest quarter of the fitted ToT.
+        let (lowest, highest) = (peaks[0].0, peaks[peaks.len() - 1].0);
+        let large: Vec<f32> = peaks.iter().filter(|(tot, _)| 4 * (*tot - lowest) >= 3 * (highest - lowest)).map(|(_, value)| *value).collect();
+        let reference = large.iter().sum::<f32>() / large.len() as f32;
+        curve.iter_mut().for_each(|value| *value -= reference);
+
+        let mut walk = TimeWalk {curve, offsets: None};
+        if per_pixel {
+            walk.offsets = Some(walk.fit_offsets(samples, reference, range as f32 / 4.0));
+        }
+        Ok(walk)
+    }
+
+    //Median of the residuals of every pixel, ignoring the ones farther than `cut` from the peak.
+    fn fit_offsets(&self, samples: &[WalkSample], reference: f32, cut: f32) -> Vec<f32> {
+        let (width, height) = Packet::chip_array();
+        let mut residuals: Vec<(usize, f32)> = samples.iter()
+            .map(|sample| ((sample.x + width * sample.y) as usize, sample.dt as f32 - reference - self.delay(sample.x, sample.y, sample.tot)))
+            .filter(|(_, residual)| residual.abs() <= cut)
+            .collect();
+        residuals.sort_unstable_by(|first, second| first.0.cmp(&second.0).then(first.1.total_cmp(&second.1)));
+
+        let mut offsets = vec![0.0; (width * height) as usize];
+        for pixel in residuals.chunk_by(|first, second| first.0 == second.0) {
+            if pixel.len() >= MIN_HITS {
+                offsets[pixel[0].0] = pixel[pixel.len() / 2].1;
+            }
+        }
+        offsets
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Tp3ErrorKind> {
+        let path = path.as_ref();
+        let file = File::create(path).with_path(path)?;
+        serde_json::to_writer(file, self)?;
+        Ok(())
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Tp3ErrorKind> {
+        let path = path.as_ref();
+        let file = File::open(path).with_path(path)?;
+        let walk: TimeWalk = serde_json::from_reader(file)?;
+        let (width, height) = Packet::chip_array();
+        if walk.curve.len() != TOT_VALUES || walk.offsets.as_ref().is_some_and(|offsets| offsets.len() != (width * height) as usize) {
+            return Err(Tp3ErrorKind::WalkBadCorrection {reason: "wrong number of values"});
+        }
+        Ok(walk)
+    }
+}
+
+///Sets the global correction. It can only be set once, and before the first call to `time_walk`.
+pub fn init(walk: TimeWalk) -> Result<(), Tp3ErrorKind> {
+    TIME_WALK.set(Some(walk)).map_err(|_| Tp3ErrorKind::WalkAlreadyLoaded)
+}
+
+///Sets the global correction from `config().time_walk_file`, when there is one.
+pub fn init_from_config() -> Result<(), Tp3ErrorKind> {
+    let path = &config().time_walk_file;
+    if path.is_empty() {
+        return Ok(());
+    }
+    println!("***Walklib***: Reading the time walk correction from {}.", path);
+    init(TimeWalk::load(path)?)
+}
+
+///The global correction. There is none if it was not initialized.
+pub fn time_walk() -> Option<&'static TimeWalk> {
+    TIME_WALK.get_or_init(|| None).as_ref()
+}
+
+///Time of an electron packet without its time walk, if there is a global correction.
+#[inline]
+pub fn correct_time(packet: &Packet, time: TIME) -> TIME {
+    time_walk().map_or(time, |walk| walk.correct(packet, time))
+}
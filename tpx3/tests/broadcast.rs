@@ -0,0 +1,124 @@
+//! Subscribes other clients to the live output of `modelib::run_measurement`. They receive the
+//! frames sent to Nionswift, whole, and the slow ones either skip frames or hold the acquisition.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::broadcastlib::{self, SlowConsumer};
+use timepix3::metricslib::metrics;
+use timepix3::simlib::SimSettings;
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+///The subscribers are global, so every measurement of this file is published to all of them.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn stream(seed: u64) -> SimSettings {
+    SimSettings {seed, duration: 960_000_000, ..SimSettings::default()} //250 ms
+}
+
+///Connects a subscriber and waits until it is registered.
+fn subscribe(policy: SlowConsumer, queue: usize) -> TcpStream {
+    let address = broadcastlib::serve_subscribers("127.0.0.1:0", policy, queue).unwrap().unwrap();
+    let before = broadcastlib::subscribers();
+    let sock = TcpStream::connect(address).unwrap();
+    let start = Instant::now();
+    while broadcastlib::subscribers() == before {
+        assert!(start.elapsed() < Duration::from_secs(5), "Subscriber was not registered.");
+        thread::sleep(Duration::from_millis(10));
+    }
+    sock
+}
+
+///Reads until nothing arrives for a while. Subscribers stay connected after the measurement.
+fn read_available(sock: &mut TcpStream) -> Vec<u8> {
+    sock.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut raw = Vec::new();
+    let mut buffer = vec![0_u8; 1 << 16];
+    loop {
+        match sock.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => raw.extend_from_slice(&buffer[..size]),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+            Err(e) => panic!("Subscriber could not read: {:?}.", e),
+        }
+    }
+    raw
+}
+
+#[test]
+fn subscriber_receives_the_frames() {
+    setup();
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mut subscriber = subscribe(SlowConsumer::Drop, 1024);
+    let output = run_live(settings(json!({"mode": 0, "bin": true})), stream(21), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    let received = read_available(&mut subscriber);
+    assert!(!output.raw.is_empty());
+    assert_eq!(received, output.raw);
+}
+
+#[test]
+fn slow_subscriber_skips_frames() {
+    setup();
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mut subscriber = subscribe(SlowConsumer::Drop, 1);
+    //The 2D frames fill the socket buffers of a subscriber that does not read.
+    let output = run_live(settings(json!({"mode": 0, "bin": false})), stream(22), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    let dropped = metrics().snapshot().outputs_dropped;
+    assert!(dropped > 0);
+
+    let frames = output.frames();
+    let received = parse_frames(&read_available(&mut subscriber));
+    assert_eq!(received.len() as u64 + dropped, frames.len() as u64);
+    //The frames that were not dropped are whole and in order.
+    let numbers = |frames: &[Frame]| frames.iter().map(|frame| frame.header["frameNumber"].as_u64().unwrap()).collect::<Vec<_>>();
+    assert!(numbers(&received).iter().all(|number| numbers(&frames).contains(number)));
+    assert!(numbers(&received).windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[test]
+fn blocking_subscriber_receives_every_frame() {
+    setup();
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mut subscriber = subscribe(SlowConsumer::Block, 1);
+    let reader = thread::spawn(move || {
+        let mut raw = Vec::new();
+        let mut buffer = vec![0_u8; 1 << 16];
+        subscriber.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        while let Ok(size) = subscriber.read(&mut buffer) {
+            if size == 0 {break;}
+            raw.extend_from_slice(&buffer[..size]);
+            //Slower than the acquisition.
+            thread::sleep(Duration::from_micros(500));
+        }
+        raw
+    });
+    let output = run_live(settings(json!({"mode": 0, "bin": false})), stream(23), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    assert_eq!(metrics().snapshot().outputs_dropped, 0);
+    let received = reader.join().unwrap();
+    assert_eq!(received.len(), output.raw.len());
+    assert!(received == output.raw);
+}
+
+#[test]
+fn blocked_subscriber_lets_others_connect() {
+    setup();
+    let _serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+    let mut blocked = subscribe(SlowConsumer::Block, 1);
+    let measurement = thread::spawn(|| run_live(settings(json!({"mode": 0, "bin": false})), stream(24), None));
+    //The 2D frames fill the socket buffers of the subscriber that does not read, and the
+    //acquisition waits for it.
+    thread::sleep(Duration::from_millis(500));
+    let other = subscribe(SlowConsumer::Drop, 1024);
+    drop(other);
+    let received = read_available(&mut blocked);
+    let output = measurement.join().unwrap();
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    assert!(!received.is_empty());
+}
@@ -0,0 +1,39 @@
+//! Loads the calibrations named in the configuration, which points to files that do not exist.
+//! The configuration is global, so these tests have their own binary.
+mod common;
+
+use common::*;
+use timepix3::{energylib, pixellib, walklib};
+
+fn missing_file(name: &str) -> String {
+    std::env::temp_dir().join(format!("tp3_missing_{}_{}", std::process::id(), name)).to_str().unwrap().to_owned()
+}
+
+fn setup_missing_files() {
+    setup_with(|config| {
+        config.pixel_calibration_file = missing_file("pixels.tpev");
+        config.tot_calibration_file = missing_file("tot.tpev");
+        config.time_walk_file = missing_file("walk.json");
+    });
+}
+
+#[test]
+fn missing_pixel_calibration() {
+    setup_missing_files();
+    assert!(pixellib::init_from_config().is_err());
+    assert!(pixellib::calibration().is_uniform());
+}
+
+#[test]
+fn missing_tot_calibration() {
+    setup_missing_files();
+    assert!(energylib::init_from_config().is_err());
+    assert!(energylib::tot_calibration().is_none());
+}
+
+#[test]
+fn missing_time_walk() {
+    setup_missing_files();
+    assert!(walklib::init_from_config().is_err());
+    assert!(walklib::time_walk().is_none());
+}
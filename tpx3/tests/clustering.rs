@@ -0,0 +1,143 @@
+//! Groups the hits of the electrons with `clusterlib::engine`, in post-processing and as a stage of
+//! the live 1D spectra, where every electron also fires its neighbouring pixels.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::clusterlib::cluster::{CentroidEngine, ClusterCorrectionTypes, CollectionElectron, SingleElectron};
+use timepix3::clusterlib::engine::{ClusterEngine, Hit};
+use timepix3::constlib::PIXELS_X;
+use timepix3::packetlib::Packet;
+use timepix3::simlib::SimSettings;
+
+fn setup_clustering() {
+    setup_with(|config| config.live_clustering = true);
+}
+
+fn hit(x: u32, y: u32, time: u64, tot: u16) -> Hit {
+    Hit {x, y, time, tot, index: 0}
+}
+
+#[test]
+fn connected_hits_are_one_cluster() {
+    let mut engine = ClusterEngine::new(32, 1);
+    for hit in [hit(10, 10, 0, 30), hit(11, 10, 6, 10), hit(20, 10, 3, 5), hit(12, 11, 12, 20)] {
+        engine.add(hit);
+    }
+    //Same pixel, but later.
+    engine.add(hit(10, 10, 1000, 40));
+    let mut clusters: Vec<_> = engine.finished().collect();
+    clusters.sort_by_key(|cluster| cluster.x());
+    assert_eq!(clusters.len(), 2);
+    assert_eq!((clusters[0].size(), clusters[0].time(), clusters[0].tot()), (3, 0, 60));
+    let centroid = clusters[0].centroid();
+    assert!((centroid.0 - 650.0 / 60.0).abs() < 1e-4 && (centroid.1 - 620.0 / 60.0).abs() < 1e-4);
+    assert_eq!((clusters[0].x(), clusters[0].y()), (11, 10));
+    assert_eq!((clusters[1].x(), clusters[1].size()), (20, 1));
+    //Without a ToT calibration there is no energy.
+    assert_eq!(clusters[0].energy(), None);
+
+    //A hit between two clusters joins them.
+    for hit in [hit(30, 10, 2000, 10), hit(32, 10, 2001, 10), hit(31, 10, 2002, 10)] {
+        engine.add(hit);
+    }
+    engine.flush();
+    let clusters: Vec<_> = engine.finished().collect();
+    assert_eq!(clusters.iter().map(|cluster| cluster.size()).collect::<Vec<u32>>(), [1, 3]);
+    assert_eq!(clusters[1].centroid(), (31.0, 10.0));
+}
+
+#[test]
+fn clusters_span_the_buffers() {
+    let mut engine = ClusterEngine::new(32, 1);
+    let buffers = [vec![hit(50, 5, 100, 10), hit(51, 5, 110, 10)], vec![hit(52, 5, 125, 10), hit(80, 5, 140, 10)], vec![hit(90, 5, 400, 10)]];
+    let mut sizes = Vec::new();
+    for buffer in buffers {
+        for hit in buffer {
+            engine.add(hit);
+        }
+        sizes.extend(engine.finished().map(|cluster| cluster.size()));
+    }
+    assert_eq!(sizes.len(), 2);
+    assert!(sizes.contains(&3) && sizes.contains(&1));
+    assert_eq!(engine.open_clusters(), 1);
+    engine.flush();
+    assert_eq!(engine.finished().count(), 1);
+    assert_eq!(engine.open_clusters(), 0);
+}
+
+#[test]
+fn centroid_correction_of_the_electrons() {
+    setup_clustering();
+    let mut electrons = CollectionElectron::new();
+    for (x, time, tot) in [(100, 100, 30), (101, 101, 10), (300, 100, 40), (100, 1000, 20)] {
+        electrons.add_electron(SingleElectron::new(Packet::new_inverse_electron(x, 10, time, tot), None, 0, None));
+    }
+    electrons.sort();
+    let mut clusters = CentroidEngine::default();
+    assert!(electrons.try_clean(0, &ClusterCorrectionTypes::Centroid, &mut clusters));
+    //The last cluster can still grow with the next buffer.
+    assert_eq!(electrons.len(), 2);
+    electrons.extend(clusters.flush());
+    assert_eq!(electrons.len(), 3);
+    //The packet times are in units of 1.5625 ns.
+    assert_eq!((electrons[0].time(), electrons[0].cluster_size(), electrons[0].tot()), (600, 2, 40));
+    assert_eq!(electrons[0].centroid(), (100.25, 10.0));
+    assert_eq!(electrons[1].centroid(), (300.0, 10.0));
+    assert_eq!(electrons[2].time(), 6000);
+}
+
+#[test]
+fn centroid_clusters_span_the_buffers() {
+    setup_clustering();
+    let mut clusters = CentroidEngine::default();
+    //The cluster at x = 100 has a hit at the end of the first buffer and another at the start of
+    //the second one.
+    let buffers = [[(300, 100, 40), (100, 1_000, 30)], [(101, 1_001, 10), (300, 100_000, 40)]];
+    let mut found = Vec::new();
+    for buffer in buffers {
+        let mut electrons = CollectionElectron::new();
+        for (x, time, tot) in buffer {
+            electrons.add_electron(SingleElectron::new(Packet::new_inverse_electron(x, 10, time, tot), None, 0, None));
+        }
+        electrons.sort();
+        assert!(electrons.try_clean(0, &ClusterCorrectionTypes::Centroid, &mut clusters));
+        assert_eq!(electrons.len(), 1);
+        assert_eq!(clusters.open_clusters(), 1);
+        found.extend(electrons);
+    }
+    found.extend(clusters.flush());
+    assert_eq!(clusters.open_clusters(), 0);
+    let sizes: Vec<(u32, u16)> = found.iter().map(|electron| (electron.x(), electron.cluster_size())).collect();
+    assert_eq!(sizes, vec![(300, 1), (100, 2), (300, 1)]);
+    assert_eq!(found[1].centroid(), (100.25, 10.0));
+}
+
+#[test]
+fn live_spectrum_counts_the_clusters() {
+    setup_clustering();
+    let stream = SimSettings {seed: 5, duration: 960_000_000, satellites: 2, ..SimSettings::default()};
+    let output = run_live(settings(json!({"mode": 0, "bin": true})), stream, None);
+    let spectrum = &output.frames().pop().unwrap().x_projection;
+    let width = PIXELS_X as usize;
+    //Three hits per electron, but it counts once.
+    let electrons: u64 = spectrum[..width - 2].iter().sum();
+    assert!(electrons <= output.summary.electrons);
+    assert!(electrons as f64 > 0.9 * output.summary.electrons as f64);
+    //The satellites have less ToT, so the centroid stays within the first pixel.
+    let peak = argmax(&spectrum[..width - 2]);
+    assert!((198..=202).contains(&peak), "Zero loss found at {}.", peak);
+}
+
+#[test]
+fn last_clusters_are_sent_at_the_end() {
+    setup_clustering();
+    //Shorter than a frame, so the only frame is the one of the clusters left open.
+    let stream = SimSettings {seed: 9, duration: 960_000_000, satellites: 2, ..SimSettings::default()};
+    let output = run_live(settings(json!({"mode": 0, "bin": true, "acquisition_us": 10_000_000})), stream, None);
+    let frames = output.frames();
+    assert_eq!(frames.len(), 1);
+    let electrons: u64 = frames[0].x_projection[..PIXELS_X as usize - 2].iter().sum();
+    assert!(electrons <= output.summary.electrons);
+    assert!(electrons as f64 > 0.9 * output.summary.electrons as f64, "{} of {}", electrons, output.summary.electrons);
+}
@@ -0,0 +1,89 @@
+//! Replaces the clusters of electrons with the `ClusterCorrectionTypes`, on a cluster of three
+//! electrons followed by a single electron.
+mod common;
+
+use common::*;
+use timepix3::clusterlib::cluster::{grab_cluster_correction, CentroidEngine, ClusterCorrectionTypes, CollectionElectron, SingleElectron};
+use timepix3::energylib::{self, TotCalibration, TotCoefficients};
+use timepix3::packetlib::Packet;
+
+//The packet times are in units of 1.5625 ns, so the electron times are six times larger.
+fn cleaned(correction: ClusterCorrectionTypes) -> CollectionElectron {
+    let mut electrons = CollectionElectron::new();
+    for (x, y, time, tot) in [(100, 10, 100, 30), (101, 10, 101, 20), (102, 11, 102, 10), (300, 50, 1000, 40)] {
+        electrons.add_electron(SingleElectron::new(Packet::new_inverse_electron(x, y, time, tot), None, 0, None));
+    }
+    electrons.sort();
+    assert!(electrons.try_clean(0, &correction, &mut CentroidEngine::default()));
+    electrons
+}
+
+fn tots(electrons: &CollectionElectron) -> Vec<u16> {
+    electrons.iter().map(|electron| electron.tot()).collect()
+}
+
+#[test]
+fn corrections_by_number_and_name() {
+    assert_eq!(grab_cluster_correction("0").unwrap(), ClusterCorrectionTypes::NoCorrection);
+    assert_eq!(grab_cluster_correction("average").unwrap(), ClusterCorrectionTypes::AverageCorrection);
+    assert_eq!(grab_cluster_correction("3").unwrap(), ClusterCorrectionTypes::LargestToTWithThreshold(20, 100));
+    assert_eq!(grab_cluster_correction("closest_tot:40:10").unwrap(), ClusterCorrectionTypes::ClosestToTWithThreshold(40, 10, 100));
+    assert_eq!(grab_cluster_correction("fixed_tot:12").unwrap(), ClusterCorrectionTypes::FixedToT(12));
+    assert_eq!(grab_cluster_correction("9").unwrap(), ClusterCorrectionTypes::MuonTrack);
+    assert_eq!(grab_cluster_correction("centroid").unwrap(), ClusterCorrectionTypes::Centroid);
+    for name in ["11", "", "fixed_tot:a", "fixed_tot:1:2", "average:1"] {
+        assert!(grab_cluster_correction(name).is_err(), "{}", name);
+    }
+
+    let mut correction = grab_cluster_correction("4").unwrap();
+    correction.set_reference(60);
+    correction.set_thresholds(5, 80);
+    assert_eq!(correction, ClusterCorrectionTypes::ClosestToTWithThreshold(60, 5, 80));
+}
+
+#[test]
+fn clusters_are_replaced() {
+    setup();
+    let electrons = cleaned(ClusterCorrectionTypes::NoCorrection);
+    assert_eq!(electrons.len(), 4);
+    assert!(electrons.iter().all(|electron| electron.cluster_size() == 1));
+
+    let electrons = cleaned(ClusterCorrectionTypes::AverageCorrection);
+    assert_eq!(electrons.len(), 2);
+    assert_eq!((electrons[0].time(), electrons[0].x(), electrons[0].y(), electrons[0].tot()), (606, 101, 10, 60));
+    assert_eq!(electrons[0].cluster_size(), 3);
+    assert_eq!((electrons[1].x(), electrons[1].tot(), electrons[1].cluster_size()), (300, 40, 1));
+
+    assert_eq!(tots(&cleaned(ClusterCorrectionTypes::LargestToT)), [30, 40]);
+    assert_eq!(tots(&cleaned(ClusterCorrectionTypes::LargestToTWithThreshold(35, 100))), [40]);
+    assert_eq!(tots(&cleaned(ClusterCorrectionTypes::ClosestToTWithThreshold(22, 0, 100))), [20, 40]);
+    assert_eq!(tots(&cleaned(ClusterCorrectionTypes::ClosestToTWithThreshold(22, 0, 30))), [20]);
+    assert_eq!(tots(&cleaned(ClusterCorrectionTypes::SingleClusterToTCalibration)), [40]);
+
+    //The time is the one of the reference ToT.
+    let electrons = cleaned(ClusterCorrectionTypes::FixedToT(20));
+    assert_eq!(electrons.len(), 1);
+    assert_eq!((electrons[0].time(), electrons[0].x(), electrons[0].tot()), (606, 101, 60));
+
+    let electrons = cleaned(ClusterCorrectionTypes::NoCorrectionVerbose);
+    assert_eq!(electrons.iter().map(|electron| electron.cluster_size()).collect::<Vec<u16>>(), [3, 3, 3, 1]);
+
+    //The tracks keep the time of their first electron.
+    let electrons = cleaned(ClusterCorrectionTypes::MuonTrack);
+    assert_eq!(tots(&electrons), [30, 20, 10]);
+    assert!(electrons.iter().all(|electron| electron.frame_dt() == 600));
+}
+
+#[test]
+fn clusters_with_a_reference_energy() {
+    setup();
+    energylib::init(TotCalibration::uniform(TotCoefficients::linear(0.5, 5.0))).unwrap();
+    //The energies are 50, 30 and 10 keV. Their ToT is replaced by them.
+    let electrons = cleaned(ClusterCorrectionTypes::FixedToTCalibration(30, 90));
+    assert_eq!(tots(&electrons), [50, 30, 10]);
+    assert!(electrons.iter().all(|electron| electron.frame_dt() == 606 && electron.cluster_size() == 3));
+    assert_eq!(electrons[2].time(), 612);
+
+    assert!(cleaned(ClusterCorrectionTypes::FixedToTCalibration(30, 60)).is_empty());
+    assert!(cleaned(ClusterCorrectionTypes::FixedToTCalibration(40, 90)).is_empty());
+}
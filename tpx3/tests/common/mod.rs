@@ -0,0 +1,234 @@
+//! Loopback harness shared by the integration tests. A synthetic stream is played into a local
+//! `TcpStream` acting as the TP3 packet source, and the Nionswift side of the measurement is read
+//! back from another local socket.
+#![allow(dead_code)]
+
+use std::convert::TryInto;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Once};
+use std::thread;
+use std::time::Duration;
+use timepix3::auxiliar::{FileManager, Settings, misc::as_bytes};
+use timepix3::configlib::{self, Config};
+use timepix3::constlib::DETECTOR_SIZE;
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::modelib::{self, AcquisitionMode, OutputAddress, OutputSink};
+use timepix3::simlib::{SimSettings, Simulator, SimSummary};
+
+static SETUP: Once = Once::new();
+
+///Frames larger than this are only kept as projections.
+const MAX_KEPT_FRAME: usize = 16 * 1024 * 1024;
+
+///Loads a configuration without frame throttling and with a mask file for the 4D modes.
+pub fn setup() {
+    setup_with(|_| {});
+}
+
+///As `setup`, with other configuration values. Only the first call sets the configuration.
+pub fn setup_with<F: FnOnce(&mut Config)>(edit: F) {
+    SETUP.call_once(|| {
+        let mask_file = std::env::temp_dir().join(format!("tp3_test_masks_{}.dat", std::process::id()));
+        let pixels = (DETECTOR_SIZE.0 * DETECTOR_SIZE.1) as usize;
+        let mut masks: Vec<i16> = vec![1; pixels];
+        masks.extend((0..pixels).map(|index| if index % 2 == 0 {1} else {0}));
+        std::fs::write(&mask_file, as_bytes(&masks)).expect("Could not create the mask file.");
+
+        let mut config = Config {
+            time_interval_frames: 0,
+            time_interval_coincidence_histogram: 0,
+            time_interval_4dframes: 0,
+            mask_file: mask_file.to_str().unwrap().to_owned(),
+            ..Config::default()
+        };
+        edit(&mut config);
+        configlib::init(config).expect("Configuration was already loaded.");
+    });
+}
+
+///Creates the acquisition settings as sent by Nionswift. `extra` overrides the default fields.
+pub fn settings(extra: serde_json::Value) -> Settings {
+    let mut value = serde_json::json!({
+        "bin": true, "bytedepth": 4, "cumul": true, "mode": 0,
+        "xspim_size": 16, "yspim_size": 16, "xscan_size": 16, "yscan_size": 16,
+        "pixel_time": 320, "time_delay": 0, "time_width": 0, "video_time": 0,
+        "time_resolved": false, "save_locally": false, "pixel_mask": 0, "threshold": 0,
+        "bias_voltage": 0, "destination_port": 0, "acquisition_us": 1000, "sup0": 0.0, "sup1": 0.0
+    });
+    for (key, val) in extra.as_object().expect("Settings must be an object.") {
+        value[key] = val.clone();
+    }
+    serde_json::from_value(value).expect("Invalid settings.")
+}
+
+///A frame received by Nionswift, with the JSON header created by `speclib`.
+pub struct Frame {
+    pub header: serde_json::Value,
+    pub width: usize,
+    pub sum: u64,
+    pub x_projection: Vec<u64>,
+    pub row_projection: Vec<u64>,
+    pub data: Option<Vec<u32>>,
+}
+
+///Output of a single measurement.
+pub struct LiveOutput {
+    pub result: Result<u8, Tp3ErrorKind>,
+    pub summary: SimSummary,
+    pub raw: Vec<u8>,
+    ///What the client of each extra output received.
+    pub outputs: Vec<Vec<u8>>,
+}
+
+impl LiveOutput {
+    ///Output of `speclib` modes: a JSON header line followed by the binary frame.
+    pub fn frames(&self) -> Vec<Frame> {
+        parse_frames(&self.raw)
+    }
+
+    ///Output of `spimlib` list modes: a flat list of indexes.
+    pub fn indexes_u32(&self) -> Vec<u32> {
+        assert_eq!(self.raw.len() % 4, 0);
+        self.raw.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect()
+    }
+
+    pub fn indexes_u64(&self) -> Vec<u64> {
+        assert_eq!(self.raw.len() % 8, 0);
+        self.raw.chunks_exact(8).map(|x| u64::from_le_bytes(x.try_into().unwrap())).collect()
+    }
+
+    pub fn values_i16(&self) -> Vec<i16> {
+        assert_eq!(self.raw.len() % 2, 0);
+        self.raw.chunks_exact(2).map(|x| i16::from_le_bytes(x.try_into().unwrap())).collect()
+    }
+}
+
+///Plays the synthetic stream into the measurement selected by `settings.mode` and collects
+///everything sent to Nionswift. `scan_list` is written on the Nionswift socket before the stream.
+pub fn run_live(settings: Settings, sim: SimSettings, scan_list: Option<Vec<u32>>) -> LiveOutput {
+    run_live_with_control(settings, sim, scan_list, None)
+}
+
+///Same as `run_live`, but `control` is a message written on the Nionswift socket after a delay.
+pub fn run_live_with_control(settings: Settings, sim: SimSettings, scan_list: Option<Vec<u32>>, control: Option<(Duration, &'static [u8])>) -> LiveOutput {
+    run(settings, sim, scan_list, control, &[], false)
+}
+
+///Same as `run_live_with_control`, but the packet source stays connected and silent after the
+///stream, as an idle detector, until the measurement is over.
+pub fn run_live_idle(settings: Settings, sim: SimSettings, control: (Duration, &'static [u8])) -> LiveOutput {
+    run(settings, sim, None, Some(control), &[], true)
+}
+
+///Same as `run_live`, with an extra output for each mode and binning of `outputs`.
+pub fn run_live_with_outputs(settings: Settings, sim: SimSettings, outputs: &[(u8, bool)]) -> LiveOutput {
+    run(settings, sim, None, None, outputs, false)
+}
+
+fn run(settings: Settings, sim: SimSettings, scan_list: Option<Vec<u32>>, control: Option<(Duration, &'static [u8])>, outputs: &[(u8, bool)], idle: bool) -> LiveOutput {
+    setup();
+
+    //Packet source
+    let tp3_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let tp3_address = tp3_listener.local_addr().unwrap();
+    let (over_sender, over) = mpsc::channel::<()>();
+    let source = thread::spawn(move || {
+        let mut simulator = Simulator::new(sim).unwrap();
+        let mut sock = TcpStream::connect(tp3_address).unwrap();
+        //The measurement can finish before the stream, so write errors are not fatal.
+        let _ = simulator.write_all(&mut sock);
+        if idle {
+            let _ = over.recv();
+        }
+        simulator.summary().clone()
+    });
+    let (pack, _) = tp3_listener.accept().unwrap();
+    //As the server does, so an idle detector does not block the measurement.
+    pack.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+
+    //Nionswift
+    let ns_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(ns_listener.local_addr().unwrap()).unwrap();
+    let (ns, _) = ns_listener.accept().unwrap();
+    if let Some(list) = scan_list {
+        client.write_all(as_bytes(&list)).unwrap();
+    }
+    if let Some((delay, message)) = control {
+        let mut control_client = client.try_clone().unwrap();
+        thread::spawn(move || {
+            thread::sleep(delay);
+            let _ = control_client.write_all(message);
+        });
+    }
+    let receiver = thread::spawn(move || {
+        let mut raw = Vec::new();
+        client.read_to_end(&mut raw).unwrap();
+        raw
+    });
+
+    //Extra outputs
+    let mut sinks: Vec<OutputSink> = Vec::new();
+    let mut output_receivers = Vec::new();
+    for (mode, bin) in outputs {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(address).unwrap();
+        let (sock, _) = listener.accept().unwrap();
+        sinks.push((OutputAddress {mode: AcquisitionMode::from(*mode), bin: *bin, address: address.to_string()}, Box::new(sock)));
+        output_receivers.push(thread::spawn(move || {
+            let mut raw = Vec::new();
+            client.read_to_end(&mut raw).unwrap();
+            raw
+        }));
+    }
+
+    let result = modelib::run_measurement_with_outputs(settings, Box::new(pack), ns, FileManager::new_empty(), None, sinks);
+    drop(over_sender);
+    let summary = source.join().unwrap();
+    let raw = receiver.join().unwrap();
+    let outputs = output_receivers.into_iter().map(|receiver| receiver.join().unwrap()).collect();
+    LiveOutput {result, summary, raw, outputs}
+}
+
+///Splits the `speclib` output in frames. Each frame has a JSON header line followed by
+///`dataSize` bytes.
+pub fn parse_frames(raw: &[u8]) -> Vec<Frame> {
+    let mut reader = BufReader::new(raw);
+    let mut frames = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 {
+            break;
+        }
+        let header: serde_json::Value = serde_json::from_str(&line).expect("Header is not a valid JSON.");
+        let size = header["dataSize"].as_u64().unwrap() as usize;
+        let width = header["width"].as_u64().unwrap() as usize;
+        assert_eq!(header["bitDepth"].as_u64().unwrap(), 32);
+
+        let mut data = vec![0_u8; size];
+        reader.read_exact(&mut data).expect("Frame is shorter than its header.");
+        let values: Vec<u32> = data.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect();
+        let mut x_projection = vec![0; width];
+        let mut row_projection = vec![0; values.len().div_ceil(width)];
+        for (index, value) in values.iter().enumerate() {
+            x_projection[index % width] += *value as u64;
+            row_projection[index / width] += *value as u64;
+        }
+        let sum = x_projection.iter().sum();
+        frames.push(Frame {
+            header,
+            width,
+            sum,
+            x_projection,
+            row_projection,
+            data: if size <= MAX_KEPT_FRAME {Some(values)} else {None},
+        });
+    }
+    frames
+}
+
+///Position of the maximum value.
+pub fn argmax(values: &[u64]) -> usize {
+    values.iter().enumerate().max_by_key(|(_, value)| **value).map(|(index, _)| index).unwrap()
+}
@@ -0,0 +1,102 @@
+//! Reads the configuration from TOML files, which give the same `Config` as the JSON ones.
+mod common;
+
+use common::*;
+use timepix3::configlib::{self, Config};
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::geometrylib::Layout;
+use timepix3::modelib::AcquisitionMode;
+use timepix3::tdclib::TdcType;
+
+fn from_toml(name: &str, text: &str) -> Result<Config, Tp3ErrorKind> {
+    let path = std::env::temp_dir().join(format!("tp3_test_{}_{}.toml", name, std::process::id()));
+    std::fs::write(&path, text).unwrap();
+    let config = Config::from_file(&path);
+    std::fs::remove_file(&path).unwrap();
+    config
+}
+
+#[test]
+fn toml_file() {
+    setup();
+    let config = from_toml("file", r#"
+# Instrument of the lab
+nionswift_ip_address = [192, 168, 0, 12]
+nionswift_port = 8_089
+save_locally_file = 'C:\Data\TP3'
+read_debug_file = "C:\\Data\\debug.tpx3"
+main_tdc = "TdcTwoFallingEdge"
+blanking_period = 0x10   # Hexadecimal
+inverse_detector = false
+detector_limits = [
+    [512, 768],
+    [0, 256], # Trailing comma
+]
+layout = { quad = { gap = 2 } }
+
+[[extra_outputs]]
+mode = 6
+address = "127.0.0.1:8100"
+
+[[extra_outputs]]
+mode = 0
+bin = true
+address = "127.0.0.1:8101"
+"#).unwrap();
+    assert_eq!(config.nionswift_ip_address, [192, 168, 0, 12]);
+    assert_eq!(config.nionswift_port, 8089);
+    assert_eq!(config.save_locally_file, "C:\\Data\\TP3");
+    assert_eq!(config.read_debug_file, "C:\\Data\\debug.tpx3");
+    assert_eq!(config.main_tdc, TdcType::TdcTwoFallingEdge);
+    assert_eq!(config.blanking_period, 16);
+    assert!(!config.inverse_detector);
+    assert_eq!(config.detector_limits, ((512, 768), (0, 256)));
+    assert_eq!(config.layout, Layout::Quad {gap: 2});
+    assert_eq!(config.extra_outputs.len(), 2);
+    assert_eq!(config.extra_outputs[0].mode, AcquisitionMode::Chrono);
+    assert!(!config.extra_outputs[0].bin);
+    assert!(config.extra_outputs[1].bin);
+    //Missing fields take their default values.
+    assert_eq!(config.timepix_port, Config::default().timepix_port);
+}
+
+#[test]
+fn toml_table_headers() {
+    setup();
+    let config = from_toml("tables", "[layout.linear]\ngap = 3\n").unwrap();
+    assert_eq!(config.layout, Layout::Linear {gap: 3});
+}
+
+#[test]
+fn bad_toml_gives_the_line() {
+    setup();
+    let error = from_toml("bad", "nionswift_port = 8089\ntimepix_port = 8098 8099\n").unwrap_err();
+    assert!(matches!(error, Tp3ErrorKind::ConfigBadToml {line: 2, ..}), "{:?}", error);
+    let error = from_toml("twice", "nionswift_port = 8089\nnionswift_port = 8090\n").unwrap_err();
+    assert!(matches!(error, Tp3ErrorKind::ConfigBadToml {line: 2, ..}), "{:?}", error);
+    //Unknown fields are rejected, as in JSON.
+    assert!(from_toml("unknown", "nionswift_prt = 8089\n").is_err());
+}
+
+#[test]
+fn bad_layout_is_refused_at_load() {
+    setup();
+    let layout = r#"{"custom": {"size": [256, 256], "chips": [{"ci": 1, "offset": [10, 0]}]}}"#;
+    let args = vec![String::from("--set"), format!("layout={}", layout)];
+    let error = Config::from_args(args).unwrap_err();
+    assert!(matches!(error, Tp3ErrorKind::GeometryBadLayout {ci: 1, ..}), "{:?}", error);
+
+    let config = Config {layout: serde_json::from_str(layout).unwrap(), ..Config::default()};
+    let error = configlib::init(config).unwrap_err();
+    assert!(matches!(error, Tp3ErrorKind::GeometryBadLayout {ci: 1, ..}), "{:?}", error);
+}
+
+#[test]
+fn bad_extra_output_is_refused_at_load() {
+    setup();
+    for (mode, expected) in [(14, Tp3ErrorKind::MiscModeNotAnOutput(14)), (5, Tp3ErrorKind::MiscModeNotImplemented(5))] {
+        let args = vec![String::from("--set"), format!(r#"extra_outputs=[{{"mode": {}, "address": "127.0.0.1:0"}}]"#, mode)];
+        let error = Config::from_args(args).unwrap_err();
+        assert_eq!(error.to_string(), expected.to_string());
+    }
+}
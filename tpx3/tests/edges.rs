@@ -0,0 +1,68 @@
+//! Spreads the electrons of the wide pixels at the chip edges over the gaps, for a linear detector
+//! with 4-pixel gaps, in the live spectra and in the flood-field calibration.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::geometrylib::{EdgePixels, EdgeSpan, Geometry, Layout};
+use timepix3::packetlib::Packet;
+use timepix3::pixellib::{FloodThresholds, PixelCalibration};
+use timepix3::simlib::{SimSettings, SpectrumShape};
+
+fn setup_gaps() {
+    setup_with(|config| {
+        config.layout = Layout::Linear {gap: 4};
+        config.edge_pixel_width = 3;
+    });
+}
+
+#[test]
+fn spans_fill_the_gaps() {
+    let linear = Geometry::linear(4, true);
+    let spans = linear.edge_spans(3);
+    assert_eq!(spans.len(), 6);
+    assert!(spans.contains(&EdgeSpan {x: 255, rows: (0, 255), slots: (255, 257)}));
+    assert!(spans.contains(&EdgeSpan {x: 260, rows: (0, 255), slots: (258, 260)}));
+    //The outer edges and the TDC column are kept.
+    assert!(spans.iter().all(|span| span.x != 0 && span.x != 1035));
+    assert!(Geometry::linear(0, true).edge_spans(3).is_empty());
+    assert!(Geometry::linear(10, true).edge_spans(3).is_empty());
+    assert_eq!(Geometry::quad(4).edge_spans(3).len(), 4);
+
+    let mut edges = EdgePixels::new(&linear, 3);
+    let slots: Vec<u32> = (0..4).map(|_| edges.x(255, 10)).collect();
+    assert_eq!(slots, [255, 256, 257, 255]);
+    assert_eq!(edges.x(254, 10), 254);
+    assert_eq!(edges.span(260, 10).map(EdgeSpan::width), Some(3));
+    //Width one keeps every electron in its pixel.
+    let mut kept = EdgePixels::new(&linear, 1);
+    assert_eq!(kept.x(255, 10), 255);
+}
+
+#[test]
+fn live_spectra_have_no_gaps() {
+    setup_gaps();
+    let stream = SimSettings {seed: 13, duration: 384_000_000, spectrum: SpectrumShape::Uniform, ..SimSettings::default()};
+    let output = run_live(settings(json!({"mode": 0, "bin": true})), stream, None);
+    let spectrum = &output.frames().pop().unwrap().x_projection;
+    assert_eq!(spectrum.len(), 3 * 260 + 257);
+    //The simulated pixels are all of the same size, so the edges now count about a third.
+    let normal = spectrum[200..250].iter().sum::<u64>() / 50;
+    for (x, counts) in spectrum.iter().enumerate().take(261).skip(255) {
+        assert!(*counts > 0 && *counts < normal * 2 / 3, "{} at {}", counts, x);
+    }
+}
+
+#[test]
+fn flood_field_of_the_wide_pixels() {
+    setup_gaps();
+    let (width, height) = Packet::chip_array();
+    let counts: Vec<u64> = (0..width * height).map(|index| match index % width {
+        255 | 260 => 300, //Three times wider
+        256..=259 => 0, //Gap
+        _ => 100,
+    }).collect();
+    let calibration = PixelCalibration::from_counts(&counts, FloodThresholds::default()).unwrap();
+    assert_eq!(calibration.masked_pixels(), 0);
+    assert_eq!((calibration.gain(255, 3), calibration.gain(260, 3)), (1.0, 1.0));
+}
@@ -0,0 +1,98 @@
+//! Converts the ToT to energy with `energylib`, from the former tables and from coefficients fitted
+//! over runs at known energies, and filters the electrons by their energy.
+mod common;
+
+use common::*;
+use std::convert::TryInto;
+use std::path::Path;
+use timepix3::clusterlib::cluster::{CollectionElectron, SingleElectron};
+use timepix3::energylib::{self, TotCalibration, TotCoefficients, TotSamples};
+use timepix3::eventlib::Provenance;
+use timepix3::packetlib::Packet;
+
+const SURROGATE: TotCoefficients = TotCoefficients {a: 1.6, b: 20.0, c: 120.0, t: 4.0};
+
+//Ten hits whose mean is the ToT of the energy.
+fn add_hits(samples: &mut TotSamples, x: u32, y: u32, coefficients: &TotCoefficients, energy: f32) {
+    let tot = coefficients.tot(energy);
+    for hit in 0..10 {
+        samples.add(x, y, (tot + (hit as f32 - 4.5) / 10.0).round() as u16);
+    }
+}
+
+#[test]
+fn coefficients_invert_the_tot() {
+    let linear = TotCoefficients::linear(2.0, 10.0);
+    assert_eq!(linear.tot(30.0), 70.0);
+    assert_eq!(linear.energy(70.0), 30.0);
+    //Below the offset there is no energy.
+    assert_eq!(linear.energy(5.0), 0.0);
+    for energy in [6.0, 10.0, 30.0, 80.0] {
+        assert!((SURROGATE.energy(SURROGATE.tot(energy)) - energy).abs() < 1e-3);
+    }
+}
+
+#[test]
+fn fit_from_runs_at_known_energies() {
+    setup();
+    let energies = [10.0, 20.0, 40.0, 60.0, 80.0];
+    let linear = TotCoefficients::linear(1.5, 12.0);
+    let runs: Vec<(f32, TotSamples)> = energies.iter().map(|energy| {
+        let mut samples = TotSamples::new();
+        add_hits(&mut samples, 100, 5, &SURROGATE, *energy);
+        add_hits(&mut samples, 101, 5, &linear, *energy);
+        (*energy, samples)
+    }).collect();
+
+    let calibration = TotCalibration::fit(&runs, true).unwrap();
+    let fitted = calibration.coefficients(100, 5);
+    assert!(fitted.c > 0.0);
+    for energy in [15.0, 50.0, 70.0] {
+        let tot = SURROGATE.tot(energy).round() as u16;
+        assert!((calibration.energy(100, 5, tot) - energy).abs() < 1.0);
+        let tot = linear.tot(energy).round() as u16;
+        assert!((calibration.energy(101, 5, tot) - energy).abs() < 1.0);
+    }
+    //Pixels without hits follow the whole detector.
+    assert_ne!(calibration.coefficients(300, 5), fitted);
+
+    //Two energies only give linear pixels.
+    let calibration = TotCalibration::fit(&runs[1..3], true).unwrap();
+    assert_eq!(calibration.coefficients(101, 5).c, 0.0);
+    assert!((calibration.coefficients(101, 5).a - 1.5).abs() < 0.01);
+    assert!(TotCalibration::fit(&runs[..1], false).is_err());
+
+    let path = std::env::temp_dir().join(format!("tp3_energy_{}.tpev", std::process::id()));
+    calibration.save(&path, Provenance::from_source("runs", "tests")).unwrap();
+    assert_eq!(TotCalibration::load(&path).unwrap(), calibration);
+}
+
+#[test]
+fn former_tables_are_read_at_runtime() {
+    setup();
+    let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("src");
+    let calibration = TotCalibration::from_tables(source.join("atot_v2.dat"), source.join("btot_v2.dat")).unwrap();
+    let table = |name: &str, index: usize| {
+        let bytes = std::fs::read(source.join(name)).unwrap();
+        f32::from_le_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap())
+    };
+    let index = 300 + 1024 * 20;
+    assert_eq!(*calibration.coefficients(300, 20), TotCoefficients::linear(table("atot_v2.dat", index), table("btot_v2.dat", index)));
+    //The TDC column is not in the tables.
+    assert_eq!(*calibration.coefficients(Packet::chip_array().0 - 1, 20), TotCoefficients::default());
+    assert!(TotCalibration::from_tables(source.join("atot_v2.dat"), source.join("lib.rs")).is_err());
+}
+
+#[test]
+fn electrons_carry_their_energy() {
+    setup();
+    energylib::init(TotCalibration::uniform(TotCoefficients::linear(2.0, 10.0))).unwrap();
+    let mut electrons = CollectionElectron::new();
+    for tot in [30, 70, 150] {
+        electrons.add_electron(SingleElectron::new(Packet::new_inverse_electron(10, 10, 0, tot), None, 0, None));
+    }
+    assert_eq!(electrons[1].energy(), Some(30.0));
+    electrons.retain_energy(20.0, 50.0);
+    assert_eq!(electrons.len(), 1);
+    assert_eq!(electrons[0].tot(), 70);
+}
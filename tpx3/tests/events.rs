@@ -0,0 +1,133 @@
+//! Writes and reads event files with `eventlib`, and reads the ones written by post-processing.
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::path::PathBuf;
+use timepix3::auxiliar::raw_into_readable;
+use timepix3::clusterlib::cluster::ClusterCorrectionTypes;
+use timepix3::postlib::coincidence::{search_coincidence, ElectronDataSettings};
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::eventlib::{Column, ColumnData, EventReader, EventWriter, Provenance, EVENT_FILE, VERSION};
+use timepix3::simlib::{PhotonSignal, SimSettings, Simulator};
+use timepix3::tdclib::TdcType;
+
+fn path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("tp3_events_{}_{}.tpev", name, std::process::id()))
+}
+
+fn columns() -> Vec<Column> {
+    vec![
+        Column::new::<u16>("x", "pixel", "Dispersive position."),
+        Column::new::<i16>("t", "0.260 ns", "Relative time."),
+        Column::new::<u64>("packet", "", "Raw packet."),
+    ]
+}
+
+#[test]
+fn events_round_trip() {
+    setup();
+    let path = path("round_trip");
+    let my_settings = settings(json!({"mode": 2}));
+    let mut writer = EventWriter::create(&path, columns(), Some(&my_settings), Provenance::from_source("run.tpx3", "test")).unwrap();
+    writer.write_block(&[ColumnData::new::<u16>(&[1, 2]), ColumnData::new::<i16>(&[-1, 5]), ColumnData::new::<u64>(&[10, 20])]).unwrap();
+    writer.write_block(&[ColumnData::new::<u16>(&[]), ColumnData::new::<i16>(&[]), ColumnData::new::<u64>(&[])]).unwrap();
+    writer.write_block(&[ColumnData::new::<u16>(&[3]), ColumnData::new::<i16>(&[-7]), ColumnData::new::<u64>(&[30])]).unwrap();
+    assert_eq!(writer.rows(), 3);
+    writer.finish().unwrap();
+
+    let mut reader = EventReader::open(&path).unwrap();
+    assert_eq!(reader.header().version, VERSION);
+    assert_eq!(reader.header().columns, columns());
+    assert_eq!(reader.header().provenance.tool, "test");
+    assert_eq!(reader.header().settings.unwrap().mode, my_settings.mode);
+    assert_eq!(reader.rows().unwrap(), 3);
+    assert_eq!(reader.read_column::<i16>("t").unwrap(), vec![-1, 5, -7]);
+    assert_eq!(reader.read_column::<u64>("packet").unwrap(), vec![10, 20, 30]);
+    assert_eq!(reader.read_column::<u16>("x").unwrap(), vec![1, 2, 3]);
+}
+
+#[test]
+fn events_are_checked() {
+    setup();
+    let path = path("checked");
+    let mut writer = EventWriter::create(&path, columns(), None, Provenance::from_source("run.tpx3", "test")).unwrap();
+    let wrong_rows = writer.write_block(&[ColumnData::new::<u16>(&[1, 2]), ColumnData::new::<i16>(&[1]), ColumnData::new::<u64>(&[1, 2])]);
+    assert!(matches!(wrong_rows, Err(Tp3ErrorKind::EventBadColumn {..})));
+    let wrong_dtype = writer.write_block(&[ColumnData::new::<u32>(&[1]), ColumnData::new::<i16>(&[1]), ColumnData::new::<u64>(&[1])]);
+    assert!(matches!(wrong_dtype, Err(Tp3ErrorKind::EventBadColumn {..})));
+    writer.write_block(&[ColumnData::new::<u16>(&[1]), ColumnData::new::<i16>(&[1]), ColumnData::new::<u64>(&[1])]).unwrap();
+    writer.finish().unwrap();
+
+    let mut reader = EventReader::open(&path).unwrap();
+    assert!(matches!(reader.read_column::<u32>("x"), Err(Tp3ErrorKind::EventBadColumn {..})));
+    assert!(matches!(reader.read_column::<u16>("y"), Err(Tp3ErrorKind::EventBadColumn {..})));
+
+    //A row count that overflows the size of the block is an error, even if the size wraps around
+    //to the right one.
+    let data = std::fs::read(&path).unwrap();
+    let mut corrupted = data.clone();
+    let count = corrupted.len() - 12 - 8;
+    corrupted[count..count + 8].copy_from_slice(&((1u64 << 62) + 1).to_le_bytes());
+    std::fs::write(&path, &corrupted).unwrap();
+    assert!(matches!(EventReader::open(&path).unwrap().rows(), Err(Tp3ErrorKind::EventBadFile {..})));
+    let result = EventReader::open(&path).unwrap().read_column::<u16>("x");
+    assert!(matches!(result, Err(Tp3ErrorKind::EventBadFile {..})));
+
+    //A truncated block is an error, not a short column.
+    std::fs::write(&path, &data[..data.len() - 1]).unwrap();
+    let result = EventReader::open(&path).unwrap().read_column::<u16>("x");
+    assert!(matches!(result, Err(Tp3ErrorKind::EventBadFile {..})));
+
+    std::fs::write(&path, b"not an event file").unwrap();
+    assert!(matches!(EventReader::open(&path), Err(Tp3ErrorKind::EventBadFile {..})));
+}
+
+#[test]
+fn readable_events_of_recorded_file() {
+    setup();
+    let raw = std::env::temp_dir().join(format!("tp3_events_readable_{}.tpx3", std::process::id()));
+    let _ = std::fs::remove_dir_all(raw.with_extension(""));
+    let mut simulator = Simulator::new(SimSettings {seed: 7, duration: 96_000_000, ..SimSettings::default()}).unwrap();
+    simulator.write_all(&mut std::fs::File::create(&raw).unwrap()).unwrap();
+
+    raw_into_readable::build_data(raw.to_str().unwrap(), 0).unwrap();
+    let mut reader = EventReader::open(raw.with_extension("").join(EVENT_FILE)).unwrap();
+    assert_eq!(reader.header().provenance.source, raw.display().to_string());
+    assert_eq!(reader.header().provenance.source_size, Some(simulator.summary().bytes));
+    let x = reader.read_column::<u32>("xH").unwrap();
+    assert!(x.len() as u64 >= simulator.summary().electrons);
+    //The event file has the same events as the raw dumps.
+    let x_raw = std::fs::read(raw.with_extension("").join("xH.txt")).unwrap();
+    assert_eq!(x_raw.len(), 4 * x.len());
+    assert_eq!(reader.read_column::<u64>("tH").unwrap().len(), x.len());
+}
+
+#[test]
+fn coincidence_events_of_recorded_file() {
+    setup();
+    let prefix = std::env::temp_dir().join(format!("tp3_events_coincidence_{}", std::process::id()));
+    let raw = prefix.with_extension("tpx3");
+    let _ = std::fs::remove_dir_all(&prefix);
+    let my_settings = settings(json!({"mode": 0, "time_delay": 1000, "time_width": 50, "save_locally": true}));
+    std::fs::write(prefix.with_extension("json"), serde_json::to_vec(&my_settings).unwrap()).unwrap();
+    let photons = PhotonSignal {tdc_type: TdcType::TdcTwoRisingEdge, rate: 20_000.0, coincidence_fraction: 0.5, delay: 1000, jitter: 5.0};
+    let mut simulator = Simulator::new(SimSettings {seed: 3, duration: 96_000_000, periodic: Vec::new(), photons: Some(photons), ..SimSettings::default()}).unwrap();
+    simulator.write_all(&mut std::fs::File::create(&raw).unwrap()).unwrap();
+
+    let mut coinc_data = ElectronDataSettings::new(raw.to_str().unwrap().to_owned(), ClusterCorrectionTypes::NoCorrection, my_settings, true);
+    coinc_data.prepare_to_search().unwrap();
+    search_coincidence(coinc_data, 0).unwrap();
+
+    let mut reader = EventReader::open(prefix.join(EVENT_FILE)).unwrap();
+    assert_eq!(reader.header().provenance.tool, "postlib::coincidence");
+    let rows = reader.rows().unwrap();
+    assert!(rows > 0);
+    let relative_time = reader.read_column::<i16>("tH").unwrap();
+    assert!(relative_time.iter().all(|time| (*time as i64 + 1000).abs() <= 50));
+    //The event file has the same events as the raw dumps.
+    let x = reader.read_column::<u16>("xH").unwrap();
+    let x_raw = std::fs::read(prefix.join("xH.txt")).unwrap();
+    assert_eq!(x_raw, timepix3::auxiliar::misc::as_bytes(&x));
+    assert_eq!(x.len() as u64, rows);
+}
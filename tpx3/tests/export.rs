@@ -0,0 +1,108 @@
+//! Exports post-processed data with `exportlib` and checks the files and the metadata written.
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use timepix3::auxiliar::{misc::as_bytes, raw_into_readable};
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::exportlib::{ExportFormat, Exporter, Metadata, METADATA_FILE};
+use timepix3::simlib::{SimSettings, Simulator};
+
+fn folder(name: &str) -> PathBuf {
+    let folder = std::env::temp_dir().join(format!("tp3_export_{}_{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&folder);
+    std::fs::create_dir(&folder).unwrap();
+    folder
+}
+
+fn metadata(folder: &Path) -> Metadata {
+    serde_json::from_slice(&std::fs::read(folder.join(METADATA_FILE)).unwrap()).unwrap()
+}
+
+#[test]
+fn raw_export_with_metadata() {
+    setup();
+    let folder = folder("raw");
+    let my_settings = settings(json!({"mode": 2, "sup0": 0.5, "sup1": 2.0}));
+    let mut exporter = Exporter::with_format(&folder, Some(&my_settings), ExportFormat::Raw).unwrap();
+    exporter.append::<u16>("xH", &[], &[1, 2, 3]).unwrap();
+    exporter.append::<u16>("xH", &[], &[4]).unwrap();
+    exporter.append::<u32>("spec", &[4], &[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+    exporter.finish().unwrap();
+
+    //The raw files are the same as before the metadata was added.
+    let x: Vec<u16> = vec![1, 2, 3, 4];
+    assert_eq!(std::fs::read(folder.join("xH.txt")).unwrap(), as_bytes(&x));
+
+    let metadata = metadata(&folder);
+    assert_eq!(metadata.settings.unwrap().xspim_size, my_settings.xspim_size);
+    let x = &metadata.datasets["xH"];
+    assert_eq!((x.dtype.as_str(), x.shape.clone(), x.file.as_deref()), ("<u2", vec![4], Some("xH.txt")));
+    let spec = &metadata.datasets["spec"];
+    assert_eq!((spec.dtype.as_str(), spec.shape.clone()), ("<u4", vec![2, 4]));
+}
+
+#[test]
+fn export_rejects_bad_shapes() {
+    setup();
+    let folder = folder("bad_shape");
+    let mut exporter = Exporter::with_format(&folder, None, ExportFormat::Raw).unwrap();
+    let result = exporter.append::<u32>("spec", &[4], &[0; 6]);
+    assert!(matches!(result, Err(Tp3ErrorKind::ExportBadShape {..})));
+    exporter.append::<u32>("spec", &[4], &[0; 4]).unwrap();
+    //Once created, the type and the row shape of a dataset are fixed.
+    let result = exporter.append::<u16>("spec", &[4], &[0; 4]);
+    assert!(matches!(result, Err(Tp3ErrorKind::ExportBadShape {..})));
+    let result = exporter.append::<u32>("spec", &[2], &[0; 4]);
+    assert!(matches!(result, Err(Tp3ErrorKind::ExportBadShape {..})));
+    assert_eq!(exporter.datasets()["spec"].shape, vec![1, 4]);
+}
+
+#[cfg(not(feature = "hdf5"))]
+#[test]
+fn hdf5_needs_the_feature() {
+    setup();
+    let folder = folder("hdf5");
+    let result = Exporter::with_format(&folder, None, ExportFormat::Hdf5);
+    assert!(matches!(result, Err(Tp3ErrorKind::ExportFormatNotCompiled(ExportFormat::Hdf5))));
+}
+
+#[cfg(feature = "hdf5")]
+#[test]
+fn hdf5_export_closes_its_handles() {
+    use hdf5_sys::h5f::{H5Fget_obj_count, H5F_OBJ_ALL};
+    setup();
+    let folder = folder("hdf5");
+    let my_settings = settings(json!({"mode": 2, "sup0": 0.5, "sup1": 2.0}));
+    let mut exporter = Exporter::with_format(&folder, Some(&my_settings), ExportFormat::Hdf5).unwrap();
+    exporter.append::<u16>("xH", &[], &[1, 2, 3]).unwrap();
+    exporter.append::<u32>("spec", &[4], &[0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+    let result = exporter.append::<u32>("spec", &[4], &[0; 6]);
+    assert!(matches!(result, Err(Tp3ErrorKind::ExportBadShape {..})));
+    exporter.finish().unwrap();
+    assert_eq!(&std::fs::read(folder.join("data.h5")).unwrap()[..8], b"\x89HDF\r\n\x1a\n");
+
+    //A file that cannot be created leaves nothing open either.
+    let missing = folder.join("missing");
+    let result = Exporter::with_format(&missing, None, ExportFormat::Hdf5);
+    assert!(matches!(result, Err(Tp3ErrorKind::ExportHdf5 {..})));
+    assert_eq!(unsafe {H5Fget_obj_count(H5F_OBJ_ALL as _, H5F_OBJ_ALL)}, 0);
+}
+
+#[test]
+fn readable_export_of_recorded_file() {
+    setup();
+    let raw = std::env::temp_dir().join(format!("tp3_export_readable_{}.tpx3", std::process::id()));
+    let _ = std::fs::remove_dir_all(raw.with_extension(""));
+    let mut simulator = Simulator::new(SimSettings {seed: 5, duration: 96_000_000, ..SimSettings::default()}).unwrap();
+    simulator.write_all(&mut std::fs::File::create(&raw).unwrap()).unwrap();
+
+    raw_into_readable::build_data(raw.to_str().unwrap(), 0).unwrap();
+    let metadata = metadata(&raw.with_extension(""));
+    let rows = metadata.datasets["xH"].shape[0];
+    assert!(rows as u64 >= simulator.summary().electrons);
+    assert_eq!(metadata.datasets["yH"].shape, vec![rows]);
+    assert_eq!(metadata.datasets["tH"].dtype, "<u8");
+    assert_eq!(metadata.datasets["tH"].shape, vec![rows]);
+}
@@ -0,0 +1,95 @@
+//! Places the chips with `geometrylib`, for the presets and custom layouts, and keeps the chips out
+//! of the layout from the decoded pixels.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::auxiliar::misc::as_bytes;
+use timepix3::constlib::{PIXELS_X, PIXELS_Y};
+use timepix3::geometrylib::{geometry, ChipPlacement, Geometry, Layout, Rotation, CHIP_SIZE};
+use timepix3::packetlib::Packet;
+use timepix3::readerlib::{Decoder, Event};
+
+#[test]
+fn linear_layout_keeps_the_legacy_positions() {
+    let inverse = Geometry::linear(0, true);
+    assert_eq!(inverse.size(), (PIXELS_X, PIXELS_Y));
+    assert_eq!(inverse.position(1, 5, 7), (5, 7));
+    assert_eq!(inverse.position(2, 5, 7), (261, 7));
+    assert_eq!(inverse.position(3, 5, 7), (517, 7));
+    assert_eq!(inverse.position(0, 5, 7), (773, 7));
+
+    let direct = Geometry::linear(0, false);
+    assert_eq!(direct.position(0, 5, 7), (250, 7));
+    assert_eq!(direct.position(3, 5, 7), (506, 7));
+    assert_eq!(direct.position(2, 5, 7), (762, 7));
+    assert_eq!(direct.position(1, 5, 7), (1018, 7));
+    //The last column counts the TDCs.
+    assert!(!direct.is_pixel(PIXELS_X - 1, 0));
+
+    //With gaps, the positions between the chips are not covered.
+    let gapped = Geometry::linear(2, true);
+    assert_eq!(gapped.size(), (3 * 258 + 257, 256));
+    assert_eq!(gapped.position(2, 0, 0), (258, 0));
+    assert!(!gapped.is_pixel(256, 10) && !gapped.is_pixel(257, 10));
+    assert_eq!(gapped.pixel(258, 10), Some((2, 0, 10)));
+}
+
+#[test]
+fn presets_round_trip() {
+    let quad = Geometry::quad(0);
+    assert_eq!(quad.size(), (512, 512));
+    assert_eq!(quad.position(0, 5, 7), (250, 7));
+    assert_eq!(quad.position(1, 5, 7), (5, 504));
+    assert_eq!(quad.position(2, 5, 7), (261, 504));
+    assert_eq!(quad.position(3, 5, 7), (506, 7));
+    assert_eq!(Geometry::single().position(0, 5, 7), (5, 7));
+    assert!(!Geometry::single().has_chip(1));
+
+    for geometry in [quad, Geometry::quad(3), Geometry::linear(4, false), Geometry::single()] {
+        let (width, height) = geometry.size();
+        for ci in 0..4 {
+            if !geometry.has_chip(ci) {continue;}
+            for (column, row) in [(0, 0), (255, 0), (0, 255), (17, 200)] {
+                let (x, y) = geometry.position(ci, column, row);
+                assert!(x < width && y < height);
+                assert_eq!(geometry.pixel(x, y), Some((ci, column, row)));
+            }
+        }
+    }
+}
+
+#[test]
+fn custom_layouts() {
+    let layout: Layout = serde_json::from_value(json!({"custom": {"size": [512, 256], "chips": [
+        {"ci": 0, "offset": [0, 0], "rotation": "Deg90"},
+        {"ci": 2, "offset": [256, 0], "flip": true},
+    ]}})).unwrap();
+    let custom = Geometry::from_layout(&layout, true).unwrap();
+    assert!(custom.has_chip(0) && !custom.has_chip(1) && custom.has_chip(2));
+    assert_eq!(custom.position(0, 5, 7), (248, 5));
+    assert_eq!(custom.position(2, 5, 7), (506, 7));
+    for column in [0, 100, 255] {
+        let (x, y) = custom.position(0, column, 30);
+        assert_eq!(custom.pixel(x, y), Some((0, column, 30)));
+    }
+
+    let chip = |ci, offset| ChipPlacement::new(ci, offset, Rotation::Deg0, false);
+    assert!(Geometry::new((CHIP_SIZE, CHIP_SIZE), &[chip(0, (1, 0))]).is_err());
+    assert!(Geometry::new((512, 256), &[chip(0, (0, 0)), chip(1, (100, 0))]).is_err());
+    assert!(Geometry::new((512, 256), &[chip(0, (0, 0)), chip(0, (256, 0))]).is_err());
+}
+
+#[test]
+fn chips_out_of_the_layout_are_unknown() {
+    setup();
+    assert_eq!(Packet::chip_array(), geometry().size());
+    let electron = Packet::new_inverse_electron(300, 20, 1_000, 50);
+    assert_eq!((electron.x(), electron.y()), (300, 20));
+
+    //The same packet from a fifth chip.
+    let mut data = vec![84, 80, 88, 51, 5, 0, 8, 0];
+    data.extend_from_slice(as_bytes(&[electron.data()]));
+    let events: Vec<Event> = Decoder::new().decode(&data).map(|raw| raw.event).collect();
+    assert!(matches!(events[1], Event::Unknown(packet) if packet.ci() == 5));
+}
@@ -0,0 +1,331 @@
+//! Drives every acquisition mode of `modelib::run_measurement` with synthetic streams over
+//! loopback sockets and checks what Nionswift receives.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::constlib::{ELECTRON_OVERFLOW_IN_TDC_UNITS, PIXELS_X, PIXELS_Y};
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::simlib::{PeriodicSignal, PhotonSignal, ShutterSignal, SimSettings, SpectrumShape};
+use timepix3::tdclib::TdcType;
+use std::time::{Duration, Instant};
+
+const ZLP: usize = 200;
+const WIDTH: usize = PIXELS_X as usize;
+
+fn stream() -> SimSettings {
+    SimSettings {
+        seed: 7,
+        duration: 960_000_000, //250 ms
+        ..SimSettings::default()
+    }
+}
+
+fn stream_with_shutter() -> SimSettings {
+    SimSettings {
+        shutter: Some(ShutterSignal {period: 384_000, open_time: 300_000}),
+        ..stream()
+    }
+}
+
+fn stream_with_photons() -> SimSettings {
+    SimSettings {
+        photons: Some(PhotonSignal {
+            tdc_type: TdcType::TdcTwoRisingEdge,
+            rate: 10_000.0,
+            coincidence_fraction: 0.2,
+            delay: 1_000,
+            jitter: 5.0,
+        }),
+        ..stream()
+    }
+}
+
+fn assert_zlp(projection: &[u64]) {
+    let peak = argmax(&projection[..WIDTH - 2]);
+    assert!((ZLP - 5..=ZLP + 5).contains(&peak), "Zero loss found at {}.", peak);
+}
+
+#[test]
+fn live_1d() {
+    let output = run_live(settings(json!({"mode": 0, "bin": true})), stream(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    let frames = output.frames();
+    assert!(frames.len() > 10);
+    for frame in &frames {
+        assert_eq!(frame.header["measurementID"], "Null");
+        assert_eq!(frame.header["width"], PIXELS_X);
+        assert_eq!(frame.header["height"], 1);
+        assert_eq!(frame.header["dataSize"], PIXELS_X * 4);
+    }
+    let numbers: Vec<u64> = frames.iter().map(|frame| frame.header["frameNumber"].as_u64().unwrap()).collect();
+    assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]));
+
+    //Cumulative, so the last frame has every electron received after the TDC search.
+    let last = frames.last().unwrap();
+    let electrons: u64 = last.x_projection[..WIDTH - 2].iter().sum();
+    assert!(electrons <= output.summary.electrons);
+    assert!(electrons as f64 > 0.9 * output.summary.electrons as f64);
+    assert!(last.x_projection[WIDTH - 2] > 0); //Main TDC
+    assert_zlp(&last.x_projection);
+}
+
+#[test]
+fn live_1d_across_timestamp_overflow() {
+    let sim = SimSettings {
+        start_time: ELECTRON_OVERFLOW_IN_TDC_UNITS - 384_000_000,
+        ..stream()
+    };
+    let output = run_live(settings(json!({"mode": 0, "bin": true})), sim, None);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    let frames = output.frames();
+    let last = frames.last().unwrap();
+    let electrons: u64 = last.x_projection[..WIDTH - 2].iter().sum();
+    assert!(electrons as f64 > 0.9 * output.summary.electrons as f64);
+    assert_zlp(&last.x_projection);
+}
+
+#[test]
+fn live_2d() {
+    let sim = SimSettings {y_range: (100, 110), ..stream()};
+    let output = run_live(settings(json!({"mode": 0, "bin": false})), sim, None);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    let last = output.frames().pop().unwrap();
+    assert_eq!(last.header["height"], PIXELS_Y);
+    assert_eq!(last.header["dataSize"], PIXELS_X * PIXELS_Y * 4);
+    assert!(last.row_projection[1..100].iter().all(|row| *row == 0));
+    assert!(last.row_projection[110..].iter().all(|row| *row == 0));
+    let electrons: u64 = last.row_projection[100..110].iter().sum();
+    assert!(electrons as f64 > 0.9 * output.summary.electrons as f64);
+    assert_zlp(&last.x_projection);
+}
+
+#[test]
+fn live_spim() {
+    let output = run_live(settings(json!({"mode": 2})), stream(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 2);
+    let indexes = output.indexes_u32();
+    let mut spectrum = vec![0; WIDTH];
+    let mut image = vec![0; 16 * 16];
+    for index in &indexes {
+        assert!(*index < 16 * 16 * PIXELS_X);
+        spectrum[(*index % PIXELS_X) as usize] += 1;
+        image[(*index / PIXELS_X) as usize] += 1;
+    }
+    assert_zlp(&spectrum);
+    assert!(image.iter().all(|pixel| *pixel > 0));
+    //The flyback is 20% of the line period and it is removed.
+    let fraction = indexes.len() as f64 / output.summary.electrons as f64;
+    assert!(fraction > 0.7 && fraction < 0.82, "Fraction of electrons is {}.", fraction);
+}
+
+#[test]
+fn live_frame_4d() {
+    let sim = SimSettings {spectrum: SpectrumShape::Uniform, ..stream()};
+    let output = run_live(settings(json!({"mode": 3})), sim, None);
+    assert_eq!(*output.result.as_ref().unwrap(), 3);
+    let values = output.values_i16();
+    let frame_size = 16 * 16 * 4;
+    assert!(!values.is_empty());
+    assert_eq!(values.len() % frame_size, 0);
+    let channel = |number: usize| -> i64 {
+        values.chunks_exact(4).map(|pixel| pixel[number] as i64).sum()
+    };
+    assert!(channel(2) > 0);
+    assert!(channel(3) > 0 && channel(3) < channel(2));
+}
+
+#[test]
+fn chrono() {
+    let output = run_live(settings(json!({"mode": 6})), stream(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 6);
+    let frames = output.frames();
+    let last = frames.last().unwrap();
+    assert_eq!(last.header["height"], 16);
+    assert_eq!(last.row_projection.len(), 16);
+    assert!(last.sum > 0);
+    assert_zlp(&last.x_projection);
+}
+
+#[test]
+fn coincidence_2d() {
+    let output = run_live(settings(json!({"mode": 7, "bin": false, "time_delay": 1000, "time_width": 50})), stream_with_photons(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 7);
+    let last = output.frames().pop().expect("No coincidence histogram received.");
+    assert_eq!(last.header["height"], 200);
+    //Second TDC is in the upper half. The delay is centered in the time width.
+    let second_tdc: u64 = last.row_projection[100..].iter().sum();
+    let peak: u64 = last.row_projection[140..160].iter().sum();
+    assert!(second_tdc > 0);
+    assert!(peak as f64 > 0.9 * second_tdc as f64);
+}
+
+#[test]
+fn chrono_frame() {
+    let output = run_live(settings(json!({"mode": 8})), stream_with_shutter(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 8);
+    let last = output.frames().pop().unwrap();
+    assert_eq!(last.row_projection.len(), 16);
+    assert!(last.sum > 0);
+    assert_zlp(&last.x_projection);
+}
+
+#[test]
+fn live_1d_frame() {
+    let output = run_live(settings(json!({"mode": 10, "bin": true})), stream_with_shutter(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 10);
+    let frames = output.frames();
+    assert!(frames.len() > 1);
+    let last = frames.last().unwrap();
+    assert_eq!(last.header["height"], 1);
+    assert_zlp(&last.x_projection);
+}
+
+#[test]
+fn live_2d_frame() {
+    let output = run_live(settings(json!({"mode": 10, "bin": false})), stream_with_shutter(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 10);
+    let last = output.frames().pop().unwrap();
+    assert_eq!(last.header["height"], PIXELS_Y);
+    assert_zlp(&last.x_projection);
+}
+
+#[test]
+fn live_1d_frame_hyperspec() {
+    let output = run_live(settings(json!({"mode": 11, "xscan_size": 32, "yscan_size": 32})), stream_with_shutter(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 11);
+    let frames = output.frames();
+    let starts: Vec<u64> = frames.iter().map(|frame| frame.header["frameNumber"].as_u64().unwrap()).collect();
+    assert_eq!(&starts[..3], &[0, 500, 1000]);
+    assert_eq!(frames[0].header["dataSize"], 500 * PIXELS_X * 4);
+    assert_eq!(frames[2].header["dataSize"], 24 * PIXELS_X * 4);
+    assert!(frames[0].row_projection.iter().all(|pixel| *pixel > 0));
+    assert_zlp(&frames[0].x_projection);
+}
+
+#[test]
+fn live_coincidence_spim() {
+    let output = run_live(settings(json!({"mode": 12, "time_delay": 1000, "time_width": 50})), stream_with_photons(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 12);
+    let indexes = output.indexes_u64();
+    let slice = (PIXELS_X as u64) * 100;
+    assert!(!indexes.is_empty());
+    assert!(indexes.iter().all(|index| *index < 16 * 16 * slice));
+    //Electron time resolution is coarser than the TDC one, so the peak is wider than the jitter.
+    let in_peak = indexes.iter().filter(|index| (30..70).contains(&((*index % slice) / PIXELS_X as u64))).count();
+    assert!(in_peak as f64 > 0.9 * indexes.len() as f64);
+}
+
+#[test]
+fn live_4d() {
+    let sim = SimSettings {y_range: (100, 110), ..stream()};
+    let output = run_live(settings(json!({"mode": 13})), sim, None);
+    assert_eq!(*output.result.as_ref().unwrap(), 13);
+    let indexes = output.indexes_u64();
+    let frame = (PIXELS_X * PIXELS_Y) as u64;
+    let mut spectrum = vec![0; WIDTH];
+    for index in &indexes {
+        assert!(*index < 16 * 16 * frame);
+        let y = (*index % frame) / PIXELS_X as u64;
+        assert!((100..110).contains(&y));
+        spectrum[((*index % frame) % PIXELS_X as u64) as usize] += 1;
+    }
+    assert_zlp(&spectrum);
+}
+
+#[test]
+fn live_spim_with_scan_list() {
+    //The scan list is sent in DAC units, 14 bits for each direction.
+    let scan_list: Vec<u32> = (0..16 * 16)
+        .map(|pixel: u32| ((pixel % 16) << 10) | ((pixel / 16) << 24))
+        .collect();
+    let output = run_live(settings(json!({"mode": 14})), stream(), Some(scan_list));
+    assert_eq!(*output.result.as_ref().unwrap(), 14);
+    let indexes = output.indexes_u32();
+    let mut spectrum = vec![0; WIDTH];
+    let mut image = vec![0; 16 * 16];
+    for index in &indexes {
+        assert!(*index < 16 * 16 * PIXELS_X);
+        spectrum[(*index % PIXELS_X) as usize] += 1;
+        image[(*index / PIXELS_X) as usize] += 1;
+    }
+    assert_zlp(&spectrum);
+    assert!(image.iter().all(|pixel| *pixel > 0));
+    assert!(indexes.len() as f64 > 0.9 * output.summary.electrons as f64);
+}
+
+#[test]
+fn live_2d_frame_hyperspec() {
+    let output = run_live(settings(json!({"mode": 15, "xscan_size": 4, "yscan_size": 4})), stream_with_shutter(), None);
+    assert_eq!(*output.result.as_ref().unwrap(), 15);
+    let frames = output.frames();
+    assert_eq!(frames[0].header["frameNumber"], 0);
+    assert_eq!(frames[0].header["dataSize"], 16 * PIXELS_X * PIXELS_Y * 4);
+    assert!(frames[0].sum > 0);
+    assert_zlp(&frames[0].x_projection);
+    assert!(frames[1..].iter().all(|frame| frame.header["dataSize"] == 0));
+}
+
+#[test]
+fn stop_from_client() {
+    let sim = SimSettings {duration: 384_000_000_000, ..stream()}; //100 s
+    let start = Instant::now();
+    let output = run_live_with_control(settings(json!({"mode": 0, "bin": true})), sim, None, Some((Duration::from_millis(500), b"stop")));
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(30));
+    assert!(!output.frames().is_empty());
+}
+
+#[test]
+fn stop_from_client_spim() {
+    let sim = SimSettings {duration: 384_000_000_000, ..stream()}; //100 s
+    let start = Instant::now();
+    let output = run_live_with_control(settings(json!({"mode": 2})), sim, None, Some((Duration::from_millis(500), b"stop")));
+    assert_eq!(*output.result.as_ref().unwrap(), 2);
+    assert!(start.elapsed() < Duration::from_secs(30));
+    assert!(!output.indexes_u32().is_empty());
+}
+
+#[test]
+fn stop_from_client_with_an_idle_detector() {
+    let start = Instant::now();
+    let output = run_live_idle(settings(json!({"mode": 0, "bin": true})), stream(), (Duration::from_millis(1500), b"stop"));
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(30));
+}
+
+#[test]
+fn stop_from_client_spim_with_an_idle_detector() {
+    let start = Instant::now();
+    let output = run_live_idle(settings(json!({"mode": 2})), stream(), (Duration::from_millis(1500), b"stop"));
+    assert_eq!(*output.result.as_ref().unwrap(), 2);
+    assert!(start.elapsed() < Duration::from_secs(30));
+}
+
+#[test]
+fn mode_not_implemented() {
+    let output = run_live(settings(json!({"mode": 5})), stream(), None);
+    assert!(matches!(output.result, Err(Tp3ErrorKind::MiscModeNotImplemented(5))));
+    assert!(output.raw.is_empty());
+}
+
+#[test]
+fn irregular_line_period() {
+    //A second signal in the same line looks like spurious edges between the lines.
+    let line = PeriodicSignal {tdc_type: TdcType::TdcOneRisingEdge, period: 384_000, high_time: 76_800, offset: 1_000};
+    let sim = SimSettings {
+        periodic: vec![line.clone(), PeriodicSignal {offset: 101_000, ..line}],
+        ..stream()
+    };
+    let output = run_live(settings(json!({"mode": 2})), sim, None);
+    match output.result {
+        Err(Tp3ErrorKind::TdcBadPeriod {tdc_type, expected, observed}) => {
+            assert_eq!(tdc_type, TdcType::TdcOneRisingEdge);
+            //The median is one of the two periods, and the other is not a multiple of it.
+            assert!(expected == 100_000 || expected == 284_000);
+            assert!(observed == 100_000 || observed == 284_000);
+            assert_ne!(expected, observed);
+        },
+        other => panic!("Expected a bad period, got {:?}.", other),
+    }
+}
@@ -0,0 +1,113 @@
+//! Runs extra outputs along a measurement of `modelib`. Each output has its own mode, client and
+//! headers, and all of them are built from the same packets.
+mod common;
+
+use common::*;
+use std::convert::TryInto;
+use serde_json::json;
+use timepix3::auxiliar::OutputListeners;
+use timepix3::constlib::PIXELS_X;
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::modelib::{AcquisitionMode, OutputAddress};
+use timepix3::simlib::{PhotonSignal, SimSettings};
+use timepix3::tdclib::TdcType;
+
+fn stream_with_photons() -> SimSettings {
+    SimSettings {
+        seed: 11,
+        duration: 960_000_000, //250 ms
+        photons: Some(PhotonSignal {
+            tdc_type: TdcType::TdcTwoRisingEdge,
+            rate: 10_000.0,
+            coincidence_fraction: 0.2,
+            delay: 1_000,
+            jitter: 5.0,
+        }),
+        ..SimSettings::default()
+    }
+}
+
+#[test]
+fn spectrum_chrono_and_coincidence_at_once() {
+    setup();
+    let set = settings(json!({"mode": 0, "bin": true, "time_delay": 1000, "time_width": 50}));
+    let output = run_live_with_outputs(set, stream_with_photons(), &[(6, false), (7, false)]);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    assert_eq!(output.outputs.len(), 2);
+
+    let spectrum = output.frames().pop().expect("No spectrum received.");
+    assert_eq!(spectrum.header["height"], 1);
+    assert_eq!(spectrum.width, PIXELS_X as usize);
+
+    let chrono = parse_frames(&output.outputs[0]).pop().expect("No chrono received.");
+    assert_eq!(chrono.header["height"], 16);
+    assert_eq!(chrono.width, PIXELS_X as usize);
+    //The spectrum is cumulative, so it has at least the electrons of the chrono.
+    assert!(chrono.sum > 0);
+    assert!(chrono.sum <= spectrum.sum);
+
+    let coincidence = parse_frames(&output.outputs[1]).pop().expect("No coincidence histogram received.");
+    assert_eq!(coincidence.header["height"], 200);
+    let second_tdc: u64 = coincidence.row_projection[100..].iter().sum();
+    let peak: u64 = coincidence.row_projection[140..160].iter().sum();
+    assert!(second_tdc > 0);
+    assert!(peak as f64 > 0.9 * second_tdc as f64);
+}
+
+#[test]
+fn outputs_see_the_same_electrons() {
+    setup();
+    let sim = SimSettings {seed: 12, duration: 960_000_000, ..SimSettings::default()};
+    let output = run_live_with_outputs(settings(json!({"mode": 0, "bin": true})), sim, &[(0, true), (0, false)]);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    let main = output.frames().pop().unwrap();
+    let same = parse_frames(&output.outputs[0]).pop().unwrap();
+    assert_eq!(same.header, main.header);
+    assert_eq!(same.x_projection, main.x_projection);
+    //The 2D spectrum has the same electrons in every column.
+    let image = parse_frames(&output.outputs[1]).pop().unwrap();
+    assert_eq!(image.header["height"], 256);
+    assert_eq!(image.x_projection, main.x_projection);
+}
+
+#[test]
+fn hyperspectral_output() {
+    setup();
+    let sim = SimSettings {seed: 14, duration: 960_000_000, ..SimSettings::default()};
+    let output = run_live_with_outputs(settings(json!({"mode": 0, "bin": true})), sim, &[(2, false)]);
+    assert_eq!(*output.result.as_ref().unwrap(), 0);
+    let raw = &output.outputs[0];
+    assert_eq!(raw.len() % 4, 0);
+    let indexes: Vec<u32> = raw.chunks_exact(4).map(|x| u32::from_le_bytes(x.try_into().unwrap())).collect();
+    assert!(indexes.iter().all(|index| *index < 16 * 16 * PIXELS_X));
+    //The flyback is 20% of the line period and it is removed.
+    let fraction = indexes.len() as f64 / output.summary.electrons as f64;
+    assert!(fraction > 0.7 && fraction < 0.82, "Fraction of electrons is {}.", fraction);
+}
+
+#[test]
+fn scan_list_mode_is_not_an_output() {
+    setup();
+    let sim = SimSettings {seed: 13, duration: 96_000_000, ..SimSettings::default()};
+    let output = run_live_with_outputs(settings(json!({"mode": 0, "bin": true})), sim, &[(14, false)]);
+    assert!(matches!(output.result, Err(Tp3ErrorKind::MiscModeNotAnOutput(14))));
+    assert!(output.outputs[0].is_empty());
+}
+
+#[test]
+fn output_without_a_client_is_skipped() {
+    setup();
+    let free_address = || std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+    let outputs = vec![
+        OutputAddress {mode: AcquisitionMode::Chrono, bin: false, address: free_address()},
+        OutputAddress {mode: AcquisitionMode::Live, bin: true, address: free_address()},
+    ];
+    let listeners = OutputListeners::bind(&outputs).unwrap();
+    assert!(listeners.accept().unwrap().is_empty());
+
+    let _client = std::net::TcpStream::connect(&outputs[1].address).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    let sinks = listeners.accept().unwrap();
+    assert_eq!(sinks.len(), 1);
+    assert_eq!(sinks[0].0.mode, AcquisitionMode::Live);
+}
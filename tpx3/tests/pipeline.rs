@@ -0,0 +1,119 @@
+//! Assembles a measurement from the stages of `pipelinelib`: the simulated buffers go through the
+//! decoder and the filters into an accumulator made of a `Clustering` stage.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::auxiliar::Settings;
+use timepix3::constlib::ELECTRON_OVERFLOW_IN_TDC_UNITS;
+use timepix3::packetlib::Packet;
+use timepix3::pipelinelib::{Accumulator, Clustering, Filter, Pipeline, Tdcs};
+use timepix3::simlib::{SimSettings, Simulator};
+use timepix3::tdclib::{TdcRef, TdcType};
+
+///Counts the electrons in a 1D spectrum and the main TDCs.
+struct Counter {
+    clustering: Clustering,
+    spectrum: Vec<u64>,
+    main_tdcs: u64,
+    times: (u64, u64), //First and last electron times
+}
+
+impl Counter {
+    fn new(super_resolution: u32) -> Self {
+        Counter {clustering: Clustering::new(super_resolution), spectrum: vec![0; Packet::chip_array().0 as usize], main_tdcs: 0, times: (u64::MAX, 0)}
+    }
+}
+
+impl Accumulator for Counter {
+    fn add_electron(&mut self, packet: Packet, time: u64, _tdcs: &mut Tdcs, _settings: &Settings) {
+        self.times = (self.times.0.min(time), self.times.1.max(time));
+        for electron in self.clustering.add(&packet, time, 0, true) {
+            self.spectrum[electron.x as usize] += electron.counts as u64;
+        }
+    }
+    fn add_main_tdc(&mut self, _packet: Packet, _tdcs: &mut Tdcs, _settings: &Settings) {
+        self.main_tdcs += 1;
+    }
+    fn add_aux_tdc(&mut self, _packet: Packet, _tdcs: &mut Tdcs, _settings: &Settings) {}
+}
+
+///Keeps the left half of the detector.
+struct LeftHalf;
+
+impl Filter for LeftHalf {
+    fn keep(&mut self, packet: &Packet, _tdcs: &Tdcs, _settings: &Settings) -> bool {
+        packet.x() < Packet::chip_array().0 / 2
+    }
+}
+
+fn run<F: Filter + 'static>(sim: SimSettings, filter: Option<F>, accumulator: &mut Counter) -> Simulator {
+    let tdcs = Tdcs {main: TdcRef::new_no_read(TdcType::TdcOneRisingEdge).unwrap(), aux: TdcRef::new_no_read(TdcType::TdcTwoRisingEdge).unwrap()};
+    let mut pipeline = Pipeline::new(settings(json!({})), tdcs, None);
+    if let Some(filter) = filter {
+        pipeline = pipeline.with_filter(filter);
+    }
+    let mut simulator = Simulator::new(sim).unwrap();
+    while let Some(slice) = simulator.next_slice() {
+        pipeline.process(&slice, accumulator);
+    }
+    simulator
+}
+
+#[test]
+fn stages_build_a_spectrum() {
+    setup();
+    let mut counter = Counter::new(1);
+    let simulator = run::<LeftHalf>(SimSettings {seed: 3, duration: 38_400_000, ..SimSettings::default()}, None, &mut counter);
+    assert_eq!(counter.spectrum.iter().sum::<u64>(), simulator.summary().electrons);
+    assert_eq!(counter.main_tdcs, 100);
+    let peak = argmax(&counter.spectrum);
+    assert!((198..=202).contains(&peak), "Zero loss found at {}.", peak);
+}
+
+#[test]
+fn electrons_take_the_time_of_the_decoder() {
+    setup();
+    //The packets only have the time inside the period. The global time places them in the fourth.
+    let start_time = 3 * ELECTRON_OVERFLOW_IN_TDC_UNITS + 1_000_000;
+    let duration = 38_400_000;
+    let mut counter = Counter::new(1);
+    run::<LeftHalf>(SimSettings {seed: 6, start_time, duration, global_time_period: Some(3_840_000), ..SimSettings::default()}, None, &mut counter);
+    let (first, last) = counter.times;
+    assert!(first + 1_000 >= start_time && last <= start_time + duration + 1_000, "Times from {} to {}.", first, last);
+}
+
+#[test]
+fn filters_drop_electrons() {
+    setup();
+    let sim = SimSettings {seed: 4, duration: 38_400_000, ..SimSettings::default()};
+    let mut all = Counter::new(1);
+    run::<LeftHalf>(sim.clone(), None, &mut all);
+    let mut left = Counter::new(1);
+    run(sim, Some(LeftHalf), &mut left);
+    let half = Packet::chip_array().0 as usize / 2;
+    assert!(left.spectrum[half..].iter().all(|counts| *counts == 0));
+    assert_eq!(left.spectrum[..half], all.spectrum[..half]);
+    //The TDCs are not filtered.
+    assert_eq!(left.main_tdcs, all.main_tdcs);
+}
+
+#[test]
+fn clustering_stage_counts_the_clusters() {
+    setup();
+    let sim = SimSettings {seed: 5, duration: 38_400_000, satellites: 2, ..SimSettings::default()};
+    let mut hits = Counter::new(1);
+    let simulator = run::<LeftHalf>(sim.clone(), None, &mut hits);
+    //Without clustering, every hit counts.
+    assert!(!hits.clustering.is_clustering());
+    assert!(hits.spectrum.iter().sum::<u64>() > 2 * simulator.summary().electrons);
+
+    //A super-resolution above 1 always groups the hits.
+    let mut clusters = Counter::new(2);
+    run::<LeftHalf>(sim, None, &mut clusters);
+    assert!(clusters.clustering.is_clustering());
+    let open: Vec<_> = clusters.clustering.flush().collect();
+    //About one cluster per electron. The chips are interleaved, so a few hits come too late.
+    let ratio = (clusters.spectrum.iter().sum::<u64>() + open.len() as u64) as f64 / simulator.summary().electrons as f64;
+    assert!((0.9..1.01).contains(&ratio), "{} clusters per electron.", ratio);
+}
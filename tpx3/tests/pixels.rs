@@ -0,0 +1,89 @@
+//! Computes the pixel calibration from a flood field and applies its mask and gains in the live
+//! modes.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::constlib::{PIXELS_X, PIXELS_Y};
+use timepix3::eventlib::Provenance;
+use timepix3::pixellib::{self, count_pixels, FlatField, FloodThresholds, PixelCalibration};
+use timepix3::simlib::{SimSettings, SpectrumShape, Simulator};
+
+const PIXELS: usize = (PIXELS_X * PIXELS_Y) as usize;
+
+fn index(x: u32, y: u32) -> usize {
+    (x + PIXELS_X * y) as usize
+}
+
+#[test]
+fn calibration_from_flood_field() {
+    setup();
+    let mut counts = vec![100; PIXELS];
+    counts[index(10, 20)] = 0; //Dead
+    counts[index(11, 20)] = 10_000; //Hot
+    counts[index(255, 20)] = 200; //A larger pixel at the chip edge
+    let calibration = PixelCalibration::from_counts(&counts, FloodThresholds::default()).unwrap();
+    assert!(calibration.is_masked(10, 20) && calibration.is_masked(11, 20));
+    assert_eq!(calibration.masked_pixels(), 2);
+    assert_eq!(calibration.gain(255, 20), 0.5);
+    assert_eq!(calibration.gain(12, 20), 1.0);
+    //The last column counts the TDCs.
+    assert!(!calibration.is_masked(PIXELS_X - 1, 20));
+    assert!(PixelCalibration::from_counts(&vec![0; PIXELS], FloodThresholds::default()).is_err());
+
+    let path = std::env::temp_dir().join(format!("tp3_pixels_{}.tpev", std::process::id()));
+    calibration.save(&path, Provenance::from_source("flood.tpx3", "tests")).unwrap();
+    assert_eq!(PixelCalibration::load(&path).unwrap(), calibration);
+
+    //Every electron of a recorded file is counted.
+    let raw = std::env::temp_dir().join(format!("tp3_pixels_flood_{}.tpx3", std::process::id()));
+    let mut simulator = Simulator::new(SimSettings {seed: 5, duration: 38_400_000, spectrum: SpectrumShape::Uniform, periodic: Vec::new(), ..SimSettings::default()}).unwrap();
+    simulator.write_all(&mut std::fs::File::create(&raw).unwrap()).unwrap();
+    let counts = count_pixels(&raw).unwrap();
+    assert_eq!(counts.iter().sum::<u64>(), simulator.summary().electrons);
+    assert_eq!(counts[index(PIXELS_X - 1, 0)], 0);
+}
+
+#[test]
+fn gains_are_applied_to_integer_counts() {
+    setup();
+    let mut calibration = PixelCalibration::uniform();
+    assert!(calibration.is_uniform());
+    calibration.set_gain(10, 0, 0.5);
+    calibration.set_gain(11, 0, 1.5);
+    calibration.mask_pixel(12, 0);
+    calibration.mask_pixel(PIXELS_X - 1, 0);
+    assert!(!calibration.is_uniform());
+    assert!(!calibration.is_masked(PIXELS_X - 1, 0));
+
+    let mut flat = FlatField::new(Box::leak(Box::new(calibration)));
+    let total = |flat: &mut FlatField, x| (0..10).map(|_| flat.counts(x, 0)).sum::<u32>();
+    assert_eq!(total(&mut flat, 10), 5);
+    assert_eq!(total(&mut flat, 11), 15);
+    assert_eq!(total(&mut flat, 12), 0);
+    assert_eq!(total(&mut flat, 13), 10);
+}
+
+#[test]
+fn live_modes_use_the_calibration() {
+    setup();
+    //The zero loss is masked and the pixels of the column 450 count twice.
+    let mut calibration = PixelCalibration::uniform();
+    for y in 0..PIXELS_Y {
+        calibration.mask_pixel(200, y);
+        calibration.set_gain(450, y, 2.0);
+    }
+    pixellib::init(calibration).unwrap();
+    let stream = SimSettings {seed: 9, duration: 384_000_000, ..SimSettings::default()};
+
+    let output = run_live(settings(json!({"mode": 0, "bin": true})), stream.clone(), None);
+    let last = output.frames().pop().unwrap();
+    assert_eq!(last.x_projection[200], 0);
+    assert!(last.x_projection[199] > 0 && last.x_projection[201] > 0);
+    assert!(last.x_projection[450] > 0 && last.x_projection[450].is_multiple_of(2));
+
+    let output = run_live(settings(json!({"mode": 2})), stream, None);
+    let indexes = output.indexes_u32();
+    assert!(!indexes.is_empty());
+    assert!(indexes.iter().all(|index| index % PIXELS_X != 200));
+}
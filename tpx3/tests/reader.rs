@@ -0,0 +1,208 @@
+//! Decodes synthetic streams with `readerlib`, reading them in uneven pieces as a socket would,
+//! and from memory-mapped files split in chunks.
+mod common;
+
+use common::*;
+use std::io::Read;
+use std::path::PathBuf;
+use timepix3::errorlib::Tp3ErrorKind;
+use timepix3::constlib::ELECTRON_OVERFLOW_IN_TDC_UNITS;
+use timepix3::readerlib::{Decoder, Event, MappedFile, PacketReader, RawEvent};
+use timepix3::timelib::ChipTimestamps;
+use timepix3::simlib::{PhotonSignal, ShutterSignal, SimSettings, SimSummary, Simulator};
+use timepix3::tdclib::TdcType;
+
+///Gives at most `piece` bytes per read.
+struct Trickle {
+    data: Vec<u8>,
+    position: usize,
+    piece: usize,
+}
+
+impl Read for Trickle {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let size = self.piece.min(buf.len()).min(self.data.len() - self.position);
+        buf[..size].copy_from_slice(&self.data[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+fn stream() -> (Vec<u8>, SimSummary) {
+    let photons = PhotonSignal {tdc_type: TdcType::TdcTwoRisingEdge, rate: 20_000.0, coincidence_fraction: 0.0, delay: 0, jitter: 0.0};
+    let shutter = ShutterSignal {period: 384_000, open_time: 300_000};
+    let mut simulator = Simulator::new(SimSettings {seed: 13, duration: 38_400_000, photons: Some(photons), shutter: Some(shutter), ..SimSettings::default()}).unwrap();
+    let mut data = Vec::new();
+    simulator.write_all(&mut data).unwrap();
+    (data, simulator.summary().clone())
+}
+
+#[derive(Default, Debug, PartialEq)]
+struct Counts {
+    headers: u64,
+    pixels: u64,
+    tdcs: u64,
+    shutters: u64,
+    others: u64,
+}
+
+impl Counts {
+    fn add(&mut self, raw: &RawEvent) {
+        match raw.event {
+            Event::ChipHeader(_) => self.headers += 1,
+            Event::Pixel(packet) => {
+                assert!(packet.ci() < 4);
+                self.pixels += 1;
+            },
+            Event::Tdc(_) => self.tdcs += 1,
+            Event::Shutter(_) => self.shutters += 1,
+            _ => self.others += 1,
+        }
+    }
+}
+
+#[test]
+fn reader_decodes_uneven_reads() {
+    setup();
+    let (data, summary) = stream();
+    let mut counts = Counts::default();
+    let mut expected_offset = 0;
+    for raw in PacketReader::new(Trickle {data: data.clone(), position: 0, piece: 13}) {
+        let raw = raw.unwrap();
+        assert_eq!(raw.offset, expected_offset);
+        expected_offset += 8;
+        counts.add(&raw);
+    }
+    assert_eq!(expected_offset, data.len() as u64);
+    assert!(counts.headers > 0);
+    assert_eq!(counts.pixels, summary.electrons);
+    assert_eq!(counts.tdcs, summary.photons + summary.periodic_edges.iter().sum::<u64>());
+    assert_eq!(counts.shutters, 4 * summary.shutters);
+    assert_eq!(counts.others, 0);
+}
+
+#[test]
+fn simulator_reads_in_small_pieces() {
+    setup();
+    let (data, summary) = stream();
+    let photons = PhotonSignal {tdc_type: TdcType::TdcTwoRisingEdge, rate: 20_000.0, coincidence_fraction: 0.0, delay: 0, jitter: 0.0};
+    let shutter = ShutterSignal {period: 384_000, open_time: 300_000};
+    let mut simulator = Simulator::new(SimSettings {seed: 13, duration: 38_400_000, photons: Some(photons), shutter: Some(shutter), ..SimSettings::default()}).unwrap();
+    //Smaller than a packet, so the packets are split between reads.
+    let mut read = Vec::new();
+    let mut piece = [0_u8; 3];
+    loop {
+        let size = simulator.read(&mut piece).unwrap();
+        if size == 0 {break;}
+        read.extend_from_slice(&piece[..size]);
+    }
+    assert!(read == data);
+    assert_eq!(simulator.summary().photons, summary.photons);
+    assert!(summary.photons > 0);
+}
+
+#[test]
+fn reader_buffers_match_events() {
+    setup();
+    let (data, _) = stream();
+    let mut by_event = Counts::default();
+    PacketReader::new(data.as_slice()).for_each(|raw| by_event.add(&raw.unwrap()));
+
+    let mut by_buffer = Counts::default();
+    let mut reader = PacketReader::with_capacity(Trickle {data: data.clone(), position: 0, piece: 1000}, 100);
+    let mut buffers = 0;
+    while let Some(events) = reader.next_buffer().unwrap() {
+        assert!(events.data().len() <= 104 && events.data().len() % 8 == 0);
+        buffers += 1;
+        events.for_each(|raw| by_buffer.add(&raw));
+    }
+    assert!(buffers > 1);
+    assert_eq!(by_event, by_buffer);
+    assert_eq!(reader.decoder().offset(), data.len() as u64);
+}
+
+#[test]
+fn reader_reports_truncated_packets() {
+    setup();
+    let (mut data, _) = stream();
+    data.truncate(data.len() - 3);
+    let results: Vec<_> = PacketReader::new(Trickle {data, position: 0, piece: 64}).collect();
+    assert!(results[..results.len() - 1].iter().all(|raw| raw.is_ok()));
+    assert!(matches!(results.last(), Some(Err(Tp3ErrorKind::ReaderTruncatedPacket {size: 5, ..}))));
+}
+
+fn write_file(name: &str, data: &[u8]) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("tp3_reader_{}_{}.tpx3", name, std::process::id()));
+    std::fs::write(&path, data).unwrap();
+    path
+}
+
+#[test]
+fn mapped_chunks_match_reader() {
+    setup();
+    let (data, _) = stream();
+    let path = write_file("chunks", &data);
+    let file = MappedFile::open(&path).unwrap();
+    let chunks = file.chunks(4096, 0);
+    assert!(chunks.len() > 1);
+    assert_eq!(chunks.first().unwrap().start, 0);
+    assert_eq!(chunks.last().unwrap().end, data.len());
+    for (index, pair) in chunks.windows(2).enumerate() {
+        assert_eq!(pair[0].index, index);
+        assert_eq!(pair[0].end, pair[1].start);
+        assert_eq!(data[pair[1].start..pair[1].start + 4], [84, 80, 88, 51]);
+    }
+
+    //The chunks are decoded in parallel, but given back in the order of the file.
+    let by_reader: Vec<(u64, Option<u64>)> = PacketReader::new(data.as_slice()).map(|raw| {
+        let raw = raw.unwrap();
+        (raw.offset, raw.event.packet().map(|packet| packet.data()))
+    }).collect();
+    let mut by_chunk = Vec::new();
+    file.for_each_ordered(&chunks, |_, events| {
+        events.map(|raw| (raw.offset, raw.event.packet().map(|packet| packet.data()))).collect::<Vec<_>>()
+    }, |_, events| {
+        by_chunk.extend(events);
+        Ok(())
+    }).unwrap();
+    assert_eq!(by_reader, by_chunk);
+
+    //Only the first bytes are split when limited.
+    let limited = file.chunks(4096, 10_000);
+    assert!(limited.last().unwrap().end >= 10_000 - 8 && limited.last().unwrap().end < 10_000 + 4096);
+}
+
+#[test]
+fn mapped_chunks_track_overflows() {
+    setup();
+    let mut simulator = Simulator::new(SimSettings {seed: 17, start_time: ELECTRON_OVERFLOW_IN_TDC_UNITS - 19_200_000, duration: 38_400_000, ..SimSettings::default()}).unwrap();
+    let mut data = Vec::new();
+    simulator.write_all(&mut data).unwrap();
+    let path = write_file("overflows", &data);
+
+    let mut timestamps = ChipTimestamps::new();
+    let expected: Vec<u64> = Decoder::new().decode(&data).filter_map(|raw| timestamps.event(&raw.event)).collect();
+    assert!(timestamps.latest() > ELECTRON_OVERFLOW_IN_TDC_UNITS);
+
+    let file = MappedFile::open(&path).unwrap();
+    let mut chunks = file.chunks(4096, 0);
+    assert!(chunks.iter().all(|chunk| chunk.timestamps().is_none()));
+    file.track_overflows(&mut chunks);
+    let mut times = Vec::new();
+    file.for_each_ordered(&chunks, |chunk, events| {
+        let mut timestamps = chunk.timestamps().unwrap();
+        events.filter_map(|raw| timestamps.event(&raw.event)).collect::<Vec<_>>()
+    }, |_, chunk_times| {
+        times.extend(chunk_times);
+        Ok(())
+    }).unwrap();
+    assert_eq!(times, expected);
+}
+
+#[test]
+fn simulated_tdc_needs_an_input_line() {
+    setup();
+    let photons = PhotonSignal {tdc_type: TdcType::NoTdc, rate: 1_000.0, coincidence_fraction: 0.0, delay: 0, jitter: 0.0};
+    let error = Simulator::new(SimSettings {photons: Some(photons), ..SimSettings::default()}).err();
+    assert!(matches!(error, Some(Tp3ErrorKind::SimNoTdcLine)), "{:?}", error);
+}
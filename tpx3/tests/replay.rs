@@ -0,0 +1,104 @@
+//! Replays recorded `.tpx3` files through the live modes with `replaylib` and checks the output
+//! written to a file or to a socket.
+mod common;
+
+use common::*;
+use serde_json::json;
+use std::io::Read;
+use std::net::TcpListener;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+use timepix3::auxiliar::Settings;
+use timepix3::constlib::{PIXELS_X, PIXELS_Y};
+use timepix3::replaylib::{self, ReplaySink, ReplaySource};
+use timepix3::simlib::{ShutterSignal, SimSettings, SimSummary, Simulator};
+
+const WIDTH: usize = PIXELS_X as usize;
+
+///Records a synthetic stream as `<name>.tpx3`, with the settings in its `<name>.json` sidecar.
+fn record(name: &str, settings: &Settings, sim: SimSettings) -> (PathBuf, SimSummary) {
+    let prefix = std::env::temp_dir().join(format!("tp3_replay_{}_{}", name, std::process::id()));
+    let raw = prefix.with_extension("tpx3");
+    let mut file = std::fs::File::create(&raw).unwrap();
+    let mut simulator = Simulator::new(sim).unwrap();
+    simulator.write_all(&mut file).unwrap();
+    std::fs::write(prefix.with_extension("json"), serde_json::to_vec(settings).unwrap()).unwrap();
+    (raw, simulator.summary().clone())
+}
+
+fn stream() -> SimSettings {
+    SimSettings {
+        seed: 11,
+        duration: 960_000_000, //250 ms
+        ..SimSettings::default()
+    }
+}
+
+fn replay_to_file(raw: &PathBuf, speed: Option<f64>) -> Vec<u8> {
+    let output = raw.with_extension(format!("out{}", speed.unwrap_or(0.0)));
+    let my_settings = replaylib::settings_from_sidecar(raw).unwrap();
+    let source = ReplaySource::open(raw, speed).unwrap();
+    let result = replaylib::replay(my_settings, source, ReplaySink::to_file(&output).unwrap());
+    assert_eq!(result.unwrap(), my_settings.mode.number());
+    std::fs::read(output).unwrap()
+}
+
+#[test]
+fn replay_1d_to_file() {
+    setup();
+    let (raw, summary) = record("1d", &settings(json!({"mode": 0, "bin": true})), stream());
+    let frames = parse_frames(&replay_to_file(&raw, None));
+    assert!(frames.len() > 1);
+    let last = frames.last().unwrap();
+    let electrons: u64 = last.x_projection[..WIDTH - 2].iter().sum();
+    assert!(electrons <= summary.electrons);
+    assert!(electrons as f64 > 0.9 * summary.electrons as f64);
+}
+
+#[test]
+fn replay_in_real_time() {
+    setup();
+    let (raw, _) = record("real_time", &settings(json!({"mode": 0, "bin": true})), stream());
+    let fast = parse_frames(&replay_to_file(&raw, None));
+    let start = Instant::now();
+    let paced = parse_frames(&replay_to_file(&raw, Some(1.0)));
+    assert!(start.elapsed() > Duration::from_millis(200));
+    //Pacing does not change what is read, so the result is the same.
+    assert_eq!(fast.last().unwrap().x_projection, paced.last().unwrap().x_projection);
+}
+
+#[test]
+fn replay_spim_to_socket() {
+    setup();
+    let (raw, summary) = record("spim", &settings(json!({"mode": 2})), stream());
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let receiver = thread::spawn(move || {
+        let (mut sock, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        sock.read_to_end(&mut received).unwrap();
+        received
+    });
+
+    let my_settings = replaylib::settings_from_sidecar(&raw).unwrap();
+    let source = ReplaySource::open(&raw, None).unwrap();
+    let result = replaylib::replay(my_settings, source, ReplaySink::connect(&address).unwrap());
+    assert_eq!(result.unwrap(), 2);
+
+    let received = receiver.join().unwrap();
+    assert_eq!(received.len() % 4, 0);
+    let indexes = received.len() / 4;
+    assert!(indexes > 0 && indexes as u64 <= summary.electrons);
+}
+
+#[test]
+fn replay_2d_frame_hyperspec() {
+    setup();
+    let sim = SimSettings {shutter: Some(ShutterSignal {period: 384_000, open_time: 300_000}), ..stream()};
+    let (raw, _) = record("hyperspec", &settings(json!({"mode": 15, "xscan_size": 4, "yscan_size": 4})), sim);
+    let frames = parse_frames(&replay_to_file(&raw, None));
+    assert_eq!(frames[0].header["frameNumber"], 0);
+    assert_eq!(frames[0].header["dataSize"], 16 * PIXELS_X * PIXELS_Y * 4);
+    assert!(frames[0].sum > 0);
+}
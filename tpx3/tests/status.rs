@@ -0,0 +1,41 @@
+//! Serves the metrics of `metricslib` after a measurement. The counters are global and reset by
+//! every measurement, so this is the only measurement of this test binary.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::metricslib;
+use timepix3::simlib::SimSettings;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+#[test]
+fn status_endpoint() {
+    setup();
+    let sim = SimSettings {seed: 7, duration: 960_000_000, ..SimSettings::default()};
+    let output = run_live(settings(json!({"mode": 2})), sim, None);
+    assert_eq!(*output.result.as_ref().unwrap(), 2);
+
+    let address = metricslib::serve_status("127.0.0.1:0").unwrap().unwrap();
+    let mut sock = TcpStream::connect(address).unwrap();
+    sock.write_all(b"GET / HTTP/1.1\r\n\r\n").unwrap();
+    let mut response = String::new();
+    sock.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    let body = response.split("\r\n\r\n").nth(1).unwrap();
+    let snapshot: serde_json::Value = serde_json::from_str(body).unwrap();
+
+    assert_eq!(snapshot["running"], false);
+    assert_eq!(snapshot["mode"], 2);
+    assert_eq!(snapshot["chip_packets"].as_array().unwrap().len(), 4);
+    //The electrons sent during the TDC search are not decoded by the measurement.
+    let electrons = snapshot["electron_packets"].as_u64().unwrap();
+    assert!(electrons <= output.summary.electrons);
+    assert!(electrons as f64 > 0.9 * output.summary.electrons as f64);
+    let bytes_read = snapshot["bytes_read"].as_u64().unwrap();
+    assert!(bytes_read > 0 && bytes_read <= output.summary.bytes);
+    assert!(snapshot["outputs_sent"].as_u64().unwrap() > 0);
+    //A fifth of each line is the flyback, whose electrons are not counted as dropped.
+    assert_eq!(snapshot["dropped_indexes"], 0);
+    assert!(snapshot["client_blocked_s"].is_f64());
+}
@@ -0,0 +1,58 @@
+//! Bins the centroids of the clusters in sub-pixels, so the 1D and chrono spectra are four times
+//! wider than the detector.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::clusterlib::engine::{subpixel, super_resolution};
+use timepix3::constlib::PIXELS_X;
+use timepix3::simlib::SimSettings;
+
+const FACTOR: usize = 4;
+
+fn setup_super_resolution() {
+    setup_with(|config| config.super_resolution = FACTOR as u32);
+}
+
+#[test]
+fn centroids_fall_in_sub_pixels() {
+    setup_super_resolution();
+    assert_eq!(super_resolution(), FACTOR as u32);
+    //The pixel 10 spans from 9.5 to 10.5.
+    assert_eq!(subpixel(10.0, 4), 2);
+    assert_eq!(subpixel(10.3, 4), 3);
+    assert_eq!(subpixel(9.6, 4), 0);
+    assert_eq!(subpixel(10.49, 4), 3);
+    assert_eq!(subpixel(10.3, 1), 0);
+}
+
+#[test]
+fn live_spectrum_is_wider() {
+    setup_super_resolution();
+    let stream = SimSettings {seed: 7, duration: 960_000_000, satellites: 1, ..SimSettings::default()};
+    let output = run_live(settings(json!({"mode": 0, "bin": true})), stream, None);
+    let frame = output.frames().pop().unwrap();
+    let width = PIXELS_X as usize * FACTOR;
+    assert_eq!(frame.width, width);
+    assert_eq!(frame.header["dataSize"].as_u64().unwrap() as usize, width * 4);
+
+    //The satellite has half the ToT, so the centroid is a third of a pixel to the right.
+    let spectrum = &frame.x_projection[..width - 2];
+    let electrons: u64 = spectrum.iter().sum();
+    let last_bin: u64 = spectrum.iter().skip(FACTOR - 1).step_by(FACTOR).sum();
+    assert!(last_bin as f64 > 0.9 * electrons as f64);
+    let peak = argmax(spectrum);
+    assert!((198 * FACTOR..=202 * FACTOR).contains(&peak), "Zero loss found at {}.", peak);
+    assert_eq!(peak % FACTOR, FACTOR - 1);
+}
+
+#[test]
+fn chrono_lines_are_wider() {
+    setup_super_resolution();
+    let stream = SimSettings {seed: 8, duration: 384_000_000, ..SimSettings::default()};
+    let output = run_live(settings(json!({"mode": 6})), stream, None);
+    let frame = output.frames().pop().unwrap();
+    assert_eq!(frame.width, PIXELS_X as usize * FACTOR);
+    assert_eq!(frame.header["height"], 16);
+    assert!(frame.sum > 0);
+}
@@ -0,0 +1,151 @@
+//! Extends the wrapping timestamps with `timelib`, for single clocks, several chips and a recorded
+//! file crossing an overflow, and follows the global time and SPIDR control packets.
+mod common;
+
+use common::*;
+use serde_json::json;
+use timepix3::clusterlib::cluster::ClusterCorrectionTypes;
+use timepix3::auxiliar::misc::as_bytes;
+use timepix3::constlib::{CHIP_DESYNC_IN_TDC_UNITS, ELECTRON_OVERFLOW, ELECTRON_OVERFLOW_IN_TDC_UNITS};
+use timepix3::eventlib::{EventReader, EVENT_FILE};
+use timepix3::packetlib::{ControlPacket, Packet};
+use timepix3::readerlib::{Decoder, Event};
+use timepix3::postlib::coincidence::{search_coincidence, ElectronDataSettings};
+use timepix3::simlib::{PhotonSignal, SimSettings, Simulator};
+use timepix3::tdclib::TdcType;
+use timepix3::timelib::{to_picoseconds, ChipTimestamps, TimestampExtender};
+
+#[test]
+fn extender_wraps_and_takes_late_packets() {
+    let mut clock = TimestampExtender::new(1000);
+    assert_eq!(clock.extend(10), 10);
+    assert_eq!(clock.extend(900), 900);
+    assert_eq!(clock.extend(50), 1050);
+    assert_eq!(clock.overflows(), 1);
+    //From before the wrap.
+    assert_eq!(clock.extend(950), 950);
+    assert_eq!(clock.latest(), 1050);
+
+    //Out of order within the tolerance, and a new period beyond it.
+    let mut clock = TimestampExtender::new(1000).with_tolerance(10);
+    clock.extend(50);
+    assert_eq!(clock.extend(45), 45);
+    assert_eq!(clock.extend(30), 1030);
+    assert_eq!(clock.overflows(), 1);
+
+    assert_eq!(to_picoseconds(12), 3125);
+}
+
+#[test]
+fn chips_are_extended_independently() {
+    setup();
+    let mut timestamps = ChipTimestamps::new();
+    let before = Packet::new_inverse_electron(10, 10, ELECTRON_OVERFLOW - 1_000, 50);
+    let after = Packet::new_inverse_electron(10, 10, 500, 50);
+    let other_after = Packet::new_inverse_electron(300, 10, 600, 50);
+    let other_before = Packet::new_inverse_electron(300, 10, ELECTRON_OVERFLOW - 500, 50);
+    assert_ne!(after.ci(), other_after.ci());
+
+    assert!(timestamps.electron(&before) < ELECTRON_OVERFLOW_IN_TDC_UNITS);
+    assert!(timestamps.electron(&after) > ELECTRON_OVERFLOW_IN_TDC_UNITS);
+    //A chip seen for the first time after the wrap starts in the current period...
+    assert!(timestamps.electron(&other_after) > ELECTRON_OVERFLOW_IN_TDC_UNITS);
+    //...and its late packets are still from before it.
+    assert!(timestamps.electron(&other_before) < ELECTRON_OVERFLOW_IN_TDC_UNITS);
+    assert!(timestamps.electron(&before) < ELECTRON_OVERFLOW_IN_TDC_UNITS);
+}
+
+#[test]
+fn coincidences_across_an_overflow() {
+    setup();
+    let prefix = std::env::temp_dir().join(format!("tp3_timestamps_coincidence_{}", std::process::id()));
+    let raw = prefix.with_extension("tpx3");
+    let _ = std::fs::remove_dir_all(&prefix);
+    let my_settings = settings(json!({"mode": 0, "time_delay": 1000, "time_width": 50, "save_locally": true}));
+    std::fs::write(prefix.with_extension("json"), serde_json::to_vec(&my_settings).unwrap()).unwrap();
+    let photons = PhotonSignal {tdc_type: TdcType::TdcTwoRisingEdge, rate: 20_000.0, coincidence_fraction: 0.5, delay: 1000, jitter: 5.0};
+    let start_time = ELECTRON_OVERFLOW_IN_TDC_UNITS - 48_000_000;
+    let mut simulator = Simulator::new(SimSettings {seed: 3, start_time, duration: 96_000_000, periodic: Vec::new(), photons: Some(photons), ..SimSettings::default()}).unwrap();
+    simulator.write_all(&mut std::fs::File::create(&raw).unwrap()).unwrap();
+
+    let mut coinc_data = ElectronDataSettings::new(raw.to_str().unwrap().to_owned(), ClusterCorrectionTypes::NoCorrection, my_settings, true);
+    coinc_data.prepare_to_search().unwrap();
+    search_coincidence(coinc_data, 0).unwrap();
+
+    let mut reader = EventReader::open(prefix.join(EVENT_FILE)).unwrap();
+    let relative_time = reader.read_column::<i16>("tH").unwrap();
+    assert!(relative_time.iter().all(|time| (*time as i64 + 1000).abs() <= 50));
+    //Absolute times go on after the overflow, in order.
+    let time = reader.read_column::<u64>("tabsH").unwrap();
+    assert!(time.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(time.first().unwrap() < &ELECTRON_OVERFLOW_IN_TDC_UNITS && time.last().unwrap() > &ELECTRON_OVERFLOW_IN_TDC_UNITS);
+    //About as many coincidences on both sides.
+    let after = time.iter().filter(|time| **time > ELECTRON_OVERFLOW_IN_TDC_UNITS).count();
+    assert!(after * 3 > time.len() && after * 3 < 2 * time.len());
+}
+
+#[test]
+fn control_packets_are_decoded() {
+    setup();
+    let [low, high] = Packet::new_inverse_global_time(2, 0x1234_5678_9ABC);
+    assert_eq!(low.control(), Some(ControlPacket::GlobalTimeLow(0x5678_9ABC)));
+    assert_eq!(high.control(), Some(ControlPacket::GlobalTimeHigh(0x1234)));
+    let id = Packet::new_inverse_packet_id(2, 77);
+    assert_eq!(id.control(), Some(ControlPacket::PacketId(77)));
+    assert_eq!(Packet::new(2, 0x71A0 << 48).control(), Some(ControlPacket::Command(0x71A0)));
+    assert_eq!(Packet::new_inverse_shutter(2, true, 0).control(), Some(ControlPacket::Other(0x5A)));
+    assert_eq!(Packet::new_inverse_electron(10, 10, 0, 50).control(), None);
+
+    let mut data = vec![84, 80, 88, 51, 2, 0, 24, 0];
+    data.extend_from_slice(as_bytes(&[low.data(), high.data(), id.data()]));
+    let events: Vec<Event> = Decoder::new().decode(&data).map(|raw| raw.event).collect();
+    assert!(matches!(events[1], Event::GlobalTime(packet) if packet.ci() == 2));
+    assert!(matches!(events[2], Event::GlobalTime(_)));
+    assert!(matches!(events[3], Event::Control(packet) if packet.control() == Some(ControlPacket::PacketId(77))));
+}
+
+#[test]
+fn global_time_sets_the_period() {
+    setup();
+    let start_time = 3 * ELECTRON_OVERFLOW_IN_TDC_UNITS + 1_000_000;
+    let duration = 9_600_000;
+    let mut simulator = Simulator::new(SimSettings {seed: 21, start_time, duration, global_time_period: Some(3_840_000), ..SimSettings::default()}).unwrap();
+    let mut data = Vec::new();
+    simulator.write_all(&mut data).unwrap();
+
+    let mut timestamps = ChipTimestamps::new();
+    let times: Vec<u64> = Decoder::new().decode(&data).filter_map(|raw| timestamps.event(&raw.event)).collect();
+    assert!(!times.is_empty());
+    assert!(times.iter().all(|time| *time + 1_000 >= start_time && *time <= start_time + duration + 1_000));
+    let sync = timestamps.sync();
+    assert_eq!(sync.global_times(), 4 * simulator.summary().global_times);
+    assert!(sync.corrections() > 0);
+    assert_eq!((sync.desyncs(), sync.dropped_packets()), (0, 0));
+    assert!(sync.max_spread() < CHIP_DESYNC_IN_TDC_UNITS);
+}
+
+#[test]
+fn desync_and_dropped_packets() {
+    setup();
+    let mut timestamps = ChipTimestamps::new();
+    //25 ms, in units of 1.5625 ns and of 25 ns.
+    timestamps.electron(&Packet::new_inverse_electron(10, 10, 16_000_000, 50));
+    timestamps.electron(&Packet::new_inverse_electron(300, 10, 16_000_000, 50));
+    let first = Packet::new_inverse_electron(10, 10, 0, 50).ci();
+    for packet in Packet::new_inverse_global_time(first, 1_000_000) {
+        timestamps.control(&packet);
+    }
+    assert_eq!(timestamps.sync().desyncs(), 0);
+    //The second chip is 10 ms ahead.
+    let second = Packet::new_inverse_electron(300, 10, 0, 50).ci();
+    for packet in Packet::new_inverse_global_time(second, 1_400_000) {
+        timestamps.control(&packet);
+    }
+    assert_eq!(timestamps.sync().desyncs(), 1);
+    assert!(timestamps.sync().max_spread() > CHIP_DESYNC_IN_TDC_UNITS);
+
+    for id in [0, 1, 5, 6] {
+        timestamps.control(&Packet::new_inverse_packet_id(0, id));
+    }
+    assert_eq!(timestamps.sync().dropped_packets(), 3);
+}
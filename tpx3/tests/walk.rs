@@ -0,0 +1,105 @@
+//! Fits the time walk of the electrons with `walklib`, from coincidences over a flat background,
+//! and subtracts it from the corrected time of the electrons.
+mod common;
+
+use common::*;
+use timepix3::clusterlib::cluster::SingleElectron;
+use timepix3::eventlib::{Column, ColumnData, EventWriter, Provenance};
+use timepix3::packetlib::Packet;
+use timepix3::walklib::{self, TimeWalk, WalkSample, TOT_VALUES};
+
+fn delay(tot: u16) -> f32 {
+    400.0 / (tot as f32 + 5.0)
+}
+
+//Coincidences at `delay(tot) + offset`, spread by a few units, plus an uncorrelated background.
+fn samples(offset: impl Fn(u32) -> i64) -> Vec<WalkSample> {
+    let mut samples = Vec::new();
+    let mut state = 17_u64;
+    let mut random = move |max: u64| {
+        state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (state >> 33) % max
+    };
+    for tot in (2..200).step_by(3) {
+        for hit in 0..60_i64 {
+            let x = 100 + random(4) as u32;
+            let dt = delay(tot).round() as i64 + offset(x) + hit % 3 - 1;
+            samples.push(WalkSample {x, y: 20, tot, dt});
+        }
+        for _ in 0..20 {
+            samples.push(WalkSample {x: 100, y: 20, tot, dt: random(200) as i64 - 100});
+        }
+    }
+    samples
+}
+
+#[test]
+fn fit_recovers_the_curve() {
+    setup();
+    let walk = TimeWalk::fit(&samples(|_| 0), false).unwrap();
+    assert_eq!(walk.curve().len(), TOT_VALUES);
+    assert!(!walk.has_pixel_offsets());
+    //Relative to the large ToT.
+    let reference = walk.curve()[197] - delay(197);
+    for tot in [5, 20, 50, 100, 150] {
+        assert!((walk.curve()[tot] - reference - delay(tot as u16)).abs() < 1.0, "{} at {}", walk.curve()[tot], tot);
+    }
+    assert!(walk.curve()[199].abs() < 1.0);
+    assert_eq!(walk.curve()[TOT_VALUES - 1], walk.curve()[197]);
+    assert!(TimeWalk::fit(&[], false).is_err());
+    assert!(TimeWalk::fit(&samples(|_| 0)[..60], false).is_err());
+}
+
+#[test]
+fn fit_of_every_pixel() {
+    setup();
+    let walk = TimeWalk::fit(&samples(|x| if x == 102 {8} else {0}), true).unwrap();
+    assert!(walk.has_pixel_offsets());
+    assert!((walk.delay(102, 20, 80) - walk.delay(101, 20, 80) - 8.0).abs() < 1.5);
+    //Pixels without hits only follow the curve.
+    assert_eq!(walk.delay(500, 20, 80), walk.curve()[80]);
+
+    let path = std::env::temp_dir().join(format!("tp3_walk_{}.json", std::process::id()));
+    walk.save(&path).unwrap();
+    assert_eq!(TimeWalk::load(&path).unwrap(), walk);
+}
+
+#[test]
+fn samples_from_coincidence_events() {
+    setup();
+    let path = std::env::temp_dir().join(format!("tp3_walk_{}.tpev", std::process::id()));
+    let columns = vec![
+        Column::new::<u16>("xH", "pixel", ""),
+        Column::new::<u16>("yH", "pixel", ""),
+        Column::new::<u16>("tot", "25 ns", ""),
+        Column::new::<i16>("tH", "0.260 ns", ""),
+    ];
+    let mut events = EventWriter::create(&path, columns, None, Provenance::from_source("coincidences", "tests")).unwrap();
+    events.write_block(&[ColumnData::new(&[3_u16, 4]), ColumnData::new(&[7_u16, 8]), ColumnData::new(&[30_u16, 40]), ColumnData::new(&[-5_i16, 12])]).unwrap();
+    events.finish().unwrap();
+
+    let samples = walklib::samples_from_events(&path).unwrap();
+    assert_eq!(samples.len(), 2);
+    assert_eq!((samples[1].x, samples[1].y, samples[1].tot, samples[1].dt), (4, 8, 40, 12));
+    assert_eq!(samples[0].dt, -5);
+}
+
+#[test]
+fn electrons_are_corrected() {
+    setup();
+    let mut curve = vec![0.0; TOT_VALUES];
+    curve[10] = 30.0;
+    walklib::init(TimeWalk::from_curve(curve).unwrap()).unwrap();
+    assert!(walklib::init(TimeWalk::from_curve(vec![0.0; TOT_VALUES]).unwrap()).is_err());
+    assert!(TimeWalk::from_curve(vec![0.0; 10]).is_err());
+
+    let slow = SingleElectron::new(Packet::new_inverse_electron(10, 10, 1000, 10), None, 0, None);
+    //The packet time is in units of 1.5625 ns.
+    assert_eq!(slow.time(), 6000);
+    assert_eq!(slow.corrected_time(), Some(5970));
+    let fast = SingleElectron::new(Packet::new_inverse_electron(10, 10, 1000, 11), None, 0, None);
+    assert_eq!(fast.corrected_time(), Some(6000));
+    //An already corrected time is also corrected.
+    let corrected = SingleElectron::new(Packet::new_inverse_electron(10, 10, 1000, 10), None, 0, Some(2000));
+    assert_eq!(corrected.corrected_time(), Some(1970));
+}